@@ -0,0 +1,47 @@
+//! Drives Miri from Rust with the `miri-script` library: builds Miri and a sysroot, runs a program
+//! with many seeds, and says which seeds failed.
+//!
+//! `cargo run --example seed_sweep -- tests/pass/threadleak_ignored.rs 0 64`, from the
+//! `miri-script` dir; the program is relative to the Miri checkout.
+
+use std::env;
+use std::process::ExitCode;
+
+use anyhow::{Context, Result};
+use miri_script::{BuildOptions, MiriEnv, RunOptions, Status};
+
+fn main() -> Result<ExitCode> {
+    let mut args = env::args().skip(1);
+    let usage = "usage: seed_sweep <program.rs> [<first seed> <end seed>]";
+    let program = args.next().context(usage)?;
+    let from = args.next().map_or(Ok(0), |seed| seed.parse()).context(usage)?;
+    let to = args.next().map_or(Ok(from + 16), |seed| seed.parse()).context(usage)?;
+
+    let mut e = MiriEnv::builder().build()?;
+    let mut build = BuildOptions::default();
+    build.quiet = true;
+    let built = e.build_miri(&build)?;
+    println!("built {} with sysroot {}", built.miri.display(), built.sysroot.display());
+
+    let mut options = RunOptions::default();
+    options.flags = vec![e.miri_dir().join(program).into()];
+    // Instead of whatever MIRIFLAGS says.
+    options.miri_flags = Some("-Zmiri-strict-provenance".into());
+    let report = e.run_seeds(&options, from..to)?;
+    for result in &report.seeds {
+        let status = match result.status {
+            Status::Ok => "ok",
+            Status::Failed => "FAILED",
+            Status::TimedOut => "TIMED OUT",
+            _ => "?",
+        };
+        println!("seed {}: {status} ({:.1?})", result.seed, result.duration);
+    }
+    let failed: Vec<u32> = report.failed().collect();
+    if failed.is_empty() {
+        println!("all {} seeds passed", report.seeds.len());
+        return Ok(ExitCode::SUCCESS);
+    }
+    println!("failing seeds: {failed:?}");
+    Ok(ExitCode::FAILURE)
+}
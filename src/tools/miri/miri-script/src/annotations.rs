@@ -0,0 +1,367 @@
+//! GitHub Actions annotations: in a workflow, lines like `::error file=src/lib.rs,line=3::...` on
+//! stdout show up on the lines of the PR that they are about, instead of only in the raw log. With
+//! `--github-annotations` (the default when `GITHUB_ACTIONS=true`), `check` and `clippy` turn the
+//! diagnostics of cargo into those, and `test` the failures that the test harnesses report.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::output::{self, Stream};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns annotations on if `flag` (`--github-annotations`) is set, or if we run in a workflow.
+pub fn init(flag: bool) {
+    let in_workflow = std::env::var_os("GITHUB_ACTIONS").is_some_and(|v| v == "true");
+    ENABLED.store(flag || in_workflow, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+/// One `::error` (or `::warning`) line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Annotation {
+    pub level: Level,
+    /// Relative to the root of the repository, with `/` as the separator.
+    pub file: Option<String>,
+    pub line: Option<u64>,
+    pub end_line: Option<u64>,
+    pub col: Option<u64>,
+    pub end_col: Option<u64>,
+    pub title: Option<String>,
+    pub message: String,
+}
+
+impl Annotation {
+    fn new(level: Level, message: impl Into<String>) -> Annotation {
+        Annotation {
+            level,
+            file: None,
+            line: None,
+            end_line: None,
+            col: None,
+            end_col: None,
+            title: None,
+            // The rendered diagnostics are colored if we color the output.
+            message: output::strip_colors(&message.into()),
+        }
+    }
+
+    /// Prints it on stdout, where the runner looks for it.
+    pub fn emit(&self) {
+        output::write(Stream::Stdout, format!("{self}\n").as_bytes());
+    }
+}
+
+impl fmt::Display for Annotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level = match self.level {
+            Level::Error => "error",
+            Level::Warning => "warning",
+        };
+        write!(f, "::{level}")?;
+        let numbers = [
+            ("line", self.line),
+            ("endLine", self.end_line),
+            ("col", self.col),
+            ("endColumn", self.end_col),
+        ];
+        let properties = self
+            .file
+            .iter()
+            .map(|file| ("file", escape_property(file)))
+            .chain(numbers.into_iter().filter_map(|(name, n)| Some((name, n?.to_string()))))
+            .chain(self.title.iter().map(|title| ("title", escape_property(title))));
+        for (i, (name, value)) in properties.enumerate() {
+            let sep = if i == 0 { " " } else { "," };
+            write!(f, "{sep}{name}={value}")?;
+        }
+        write!(f, "::{}", escape_data(self.message.trim_end()))
+    }
+}
+
+/// Escapes the message of a workflow command, which ends at the end of the line.
+fn escape_data(s: &str) -> String {
+    s.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+/// Escapes a property of a workflow command, which also ends at a `,` or `::`.
+fn escape_property(s: &str) -> String {
+    escape_data(s).replace(':', "%3A").replace(',', "%2C")
+}
+
+/// What annotations are relative to: the checkout of the workflow (which, for the rustc repo, is
+/// not the Miri dir), or else `miri_dir`.
+pub fn root(miri_dir: &Path) -> PathBuf {
+    std::env::var_os("GITHUB_WORKSPACE")
+        .filter(|dir| !dir.is_empty())
+        .map_or_else(|| miri_dir.to_owned(), PathBuf::from)
+}
+
+/// `file`, which is relative to `base` or one of its parents (rustc gets the paths relative to the
+/// workspace root, which we do not know), relative to `root`.
+pub fn relative_to_root(file: &str, base: &Path, root: &Path) -> String {
+    let file = Path::new(file);
+    let path = if file.is_absolute() {
+        file.to_owned()
+    } else {
+        base.ancestors()
+            .map(|dir| dir.join(file))
+            .find(|path| path.exists())
+            .unwrap_or_else(|| base.join(file))
+    };
+    let path = std::path::absolute(&path).unwrap_or(path);
+    let root = std::path::absolute(root).unwrap_or_else(|_| root.to_owned());
+    let path = path.strip_prefix(&root).unwrap_or(&path);
+    path.to_string_lossy().replace('\\', "/")
+}
+
+/// What a line of `cargo --message-format=json` says, if it is a diagnostic: how rustc showed it,
+/// and the annotation for it (if it is an error or a warning).
+fn cargo_diagnostic(line: &str, base: &Path, root: &Path) -> Option<(String, Option<Annotation>)> {
+    let msg = serde_json::from_str::<serde_json::Value>(line).ok()?;
+    if msg["reason"] != "compiler-message" {
+        return None;
+    }
+    let diag = &msg["message"];
+    let rendered = diag["rendered"].as_str()?.to_owned();
+    let level = match diag["level"].as_str() {
+        Some("error" | "error: internal compiler error") => Level::Error,
+        Some("warning") => Level::Warning,
+        // Like the `aborting due to ...` notes.
+        _ => return Some((rendered, None)),
+    };
+    let title = diag["message"].as_str().unwrap_or_default();
+    let mut annotation = Annotation::new(level, &rendered);
+    annotation.title = Some(title.to_owned());
+    let primary = diag["spans"].as_array().into_iter().flatten().find(|s| s["is_primary"] == true);
+    if let Some(span) = primary {
+        annotation.file = span["file_name"].as_str().map(|f| relative_to_root(f, base, root));
+        annotation.line = span["line_start"].as_u64();
+        annotation.end_line = span["line_end"].as_u64();
+        // Columns only mean something on a single line.
+        if annotation.line == annotation.end_line {
+            annotation.col = span["column_start"].as_u64();
+            annotation.end_col = span["column_end"].as_u64();
+        }
+    }
+    Some((rendered, Some(annotation)))
+}
+
+/// Shows the diagnostics in `messages` (the stdout of a `cargo --message-format=json`, for the
+/// crate in `base`) on stderr, like cargo would have, and (if enabled) emits annotations for them
+/// on stdout.
+pub fn cargo_diagnostics(messages: &str, base: &Path, root: &Path) {
+    for (rendered, annotation) in messages.lines().filter_map(|l| cargo_diagnostic(l, base, root)) {
+        output::write(Stream::Stderr, rendered.as_bytes());
+        if let Some(annotation) = annotation.filter(|_| enabled()) {
+            annotation.emit();
+        }
+    }
+}
+
+/// A failure that a test harness reported.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TestFailure {
+    /// Like `FAILED TEST: tests/pass/foo.rs` or `---- tests::foo stdout ----`.
+    pub header: String,
+    /// What the harness printed about it, after the header.
+    pub lines: Vec<String>,
+    /// Where it failed, if the harness says, as it says it.
+    pub file: Option<String>,
+    pub line: Option<u64>,
+    /// What went wrong, in one line.
+    pub message: String,
+}
+
+/// Finds the failures in the output of `cargo test`: those of ui_test (`FAILED TEST: <path>`),
+/// and those of libtest (`---- <name> stdout ----`, with the location of the panic).
+pub fn test_failures(output: &str) -> Vec<TestFailure> {
+    let output = output::strip_colors(output);
+    let mut failures: Vec<TestFailure> = Vec::new();
+    let mut in_failure = false;
+    for line in output.lines() {
+        let trimmed = line.trim();
+        if let Some(path) = trimmed.strip_prefix("FAILED TEST: ") {
+            let path = path.split(" (revision").next().unwrap();
+            failures.push(TestFailure {
+                header: trimmed.to_owned(),
+                lines: Vec::new(),
+                file: Some(path.to_owned()),
+                line: None,
+                message: format!("{path} failed"),
+            });
+            in_failure = true;
+        } else if let Some(name) =
+            trimmed.strip_prefix("---- ").and_then(|t| t.strip_suffix(" stdout ----"))
+        {
+            failures.push(TestFailure {
+                header: trimmed.to_owned(),
+                lines: Vec::new(),
+                file: None,
+                line: None,
+                message: format!("{name} failed"),
+            });
+            in_failure = true;
+        } else if trimmed == "FAILURES:" || trimmed == "failures:" {
+            in_failure = false;
+        } else if let (true, Some(failure)) = (in_failure, failures.last_mut()) {
+            if failure.file.is_none() {
+                // `thread 'tests::foo' panicked at src/lib.rs:12:5:`
+                if let Some((_, at)) = trimmed.split_once("' panicked at ") {
+                    let mut parts = at.trim_end_matches(':').rsplitn(3, ':');
+                    let (_col, line, file) = (parts.next(), parts.next(), parts.next());
+                    if let (Some(file), Some(line)) = (file, line.and_then(|l| l.parse().ok())) {
+                        failure.file = Some(file.to_owned());
+                        failure.line = Some(line);
+                    }
+                }
+            }
+            if failure.lines.is_empty() && trimmed.is_empty() {
+                continue;
+            }
+            failure.lines.push(line.to_owned());
+        }
+    }
+    for failure in &mut failures {
+        while failure.lines.last().is_some_and(|l| l.trim().is_empty()) {
+            failure.lines.pop();
+        }
+        // ui_test says what went wrong in its first `error:` line, libtest after the location.
+        let reason = match failure.line {
+            None => failure.lines.iter().find(|l| l.starts_with("error: ")),
+            Some(_) =>
+                failure
+                    .lines
+                    .iter()
+                    .skip_while(|l| !l.contains("' panicked at "))
+                    .nth(1)
+                    .filter(|l| !l.trim().is_empty()),
+        };
+        if let Some(reason) = reason {
+            failure.message = format!("{}: {}", failure.message, reason.trim());
+        }
+    }
+    failures
+}
+
+/// Emits the failures in the output of `cargo test` for the crate in `base`, each in its own
+/// group (which the log shows folded), with an annotation. The latter only if the harness did
+/// not emit any itself, like ui_test does in a workflow.
+pub fn test_failures_of(output: &str, base: &Path, root: &Path) {
+    let own_annotations = output.lines().any(|l| l.starts_with("::error"));
+    let mut out = String::new();
+    for failure in test_failures(output) {
+        out.push_str(&format!("::group::{}\n", failure.header));
+        for line in &failure.lines {
+            out.push_str(line);
+            out.push('\n');
+        }
+        out.push_str("::endgroup::\n");
+        if !own_annotations {
+            let mut annotation = Annotation::new(Level::Error, &failure.message);
+            annotation.title = Some("test failed".to_owned());
+            annotation.file = failure.file.as_deref().map(|f| relative_to_root(f, base, root));
+            annotation.line = failure.line;
+            out.push_str(&format!("{annotation}\n"));
+        }
+    }
+    output::write(Stream::Stdout, out.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_annotations() {
+        let mut annotation = Annotation::new(Level::Warning, "unused variable: `x`\n100%\n");
+        assert_eq!(annotation.to_string(), "::warning::unused variable: `x`%0A100%25");
+        annotation.file = Some("src/a,b.rs".into());
+        annotation.line = Some(3);
+        annotation.col = Some(9);
+        annotation.title = Some("lint: unused".into());
+        assert_eq!(
+            annotation.to_string(),
+            "::warning file=src/a%2Cb.rs,line=3,col=9,title=lint%3A unused::unused variable: `x`%0A100%25"
+        );
+    }
+
+    #[test]
+    fn translates_cargo_messages() {
+        let root = Path::new("/repo");
+        let base = Path::new("/repo/src/tools/miri");
+        let line = r#"{"reason":"compiler-message","message":{"level":"warning","message":"unused variable: `x`","rendered":"warning: unused variable: `x`\n","spans":[{"file_name":"src/lib.rs","line_start":3,"line_end":3,"column_start":9,"column_end":10,"is_primary":true}]}}"#;
+        let (rendered, annotation) = cargo_diagnostic(line, base, root).unwrap();
+        assert_eq!(rendered, "warning: unused variable: `x`\n");
+        assert_eq!(
+            annotation.unwrap().to_string(),
+            "::warning file=src/tools/miri/src/lib.rs,line=3,endLine=3,col=9,endColumn=10,title=unused variable%3A `x`::warning: unused variable: `x`"
+        );
+
+        // With `json-diagnostic-rendered-ansi`, the terminal gets the colors, the annotation not.
+        let colored = line.replace(r#""rendered":"warning"#, r#""rendered":"\u001b[1;33mwarning"#);
+        let (rendered, annotation) = cargo_diagnostic(&colored, base, root).unwrap();
+        assert!(rendered.starts_with("\x1b[1;33mwarning:"), "{rendered}");
+        assert_eq!(annotation.unwrap().message, "warning: unused variable: `x`\n");
+
+        let note = r#"{"reason":"compiler-message","message":{"level":"failure-note","message":"aborting","rendered":"aborting\n","spans":[]}}"#;
+        assert_eq!(cargo_diagnostic(note, base, root), Some(("aborting\n".into(), None)));
+        let artifact = r#"{"reason":"compiler-artifact","target":{"name":"miri"}}"#;
+        assert_eq!(cargo_diagnostic(artifact, base, root), None);
+        assert_eq!(cargo_diagnostic("Compiling miri", base, root), None);
+    }
+
+    #[test]
+    fn finds_test_failures() {
+        let output = "\
+tests/pass/ok.rs ... ok
+tests/fail/bad.rs ... FAILED
+
+\x1b[1m\x1b[4mFAILED TEST: tests/fail/bad.rs\x1b[0m
+command: \"miri\" \"tests/fail/bad.rs\"
+
+error: actual output differed from expected
+full stderr:
+oops
+
+
+FAILURES:
+    tests/fail/bad.rs
+
+test result: FAIL. 1 failed; 1 passed;
+
+failures:
+
+---- util::tests::parses stdout ----
+
+thread 'util::tests::parses' panicked at src/util.rs:12:5:
+assertion failed: ok
+
+failures:
+    util::tests::parses
+";
+        let failures = test_failures(output);
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures[0].header, "FAILED TEST: tests/fail/bad.rs");
+        assert_eq!(failures[0].file.as_deref(), Some("tests/fail/bad.rs"));
+        assert_eq!(
+            failures[0].message,
+            "tests/fail/bad.rs failed: error: actual output differed from expected"
+        );
+        assert_eq!(failures[0].lines.first().unwrap(), "command: \"miri\" \"tests/fail/bad.rs\"");
+        assert_eq!(failures[0].lines.last().unwrap(), "oops");
+        assert_eq!(failures[1].file.as_deref(), Some("src/util.rs"));
+        assert_eq!(failures[1].line, Some(12));
+        assert_eq!(failures[1].message, "util::tests::parses failed: assertion failed: ok");
+    }
+}
@@ -0,0 +1,385 @@
+//! The part of the library that is its API (see the crate docs). The commands of `./miri` that
+//! do the same things go through here as well, so that this does what they do.
+
+use std::ffi::{OsStr, OsString};
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use path_macro::path;
+use xshell::Shell;
+
+use crate::events::{self, Event, Status};
+use crate::inject::{Injected, Injection};
+use crate::output::{error, status};
+use crate::record::cmd;
+use crate::tee::TimedOut;
+use crate::util::{
+    bin_artifact, check_writable, miri_sysroot, miriflags, rust_files, rustfmt_configs,
+    with_rustflags, ArgQuery, MiriEnv, Verbosity,
+};
+use crate::GlobalArgs;
+
+/// Sets up a [`MiriEnv`]. Nothing is set by default, and the environment decides instead, as it
+/// does for `./miri`.
+#[derive(Clone, Debug, Default)]
+#[must_use]
+pub struct MiriEnvBuilder {
+    global: GlobalArgs,
+}
+
+impl MiriEnvBuilder {
+    /// The rustup toolchain to use, like `./miri +toolchain`. By default, that is the one in
+    /// `RUSTUP_TOOLCHAIN`, or else the one rustup picks for the Miri checkout (usually `miri`).
+    pub fn toolchain(mut self, toolchain: impl Into<String>) -> Self {
+        self.global.toolchain = Some(toolchain.into());
+        self
+    }
+
+    /// A locally built rustc to use instead of a rustup toolchain, like `./miri --rustc <path>`.
+    /// By default, the one in `MIRI_SCRIPT_RUSTC`, if any.
+    pub fn rustc(mut self, rustc: impl Into<PathBuf>) -> Self {
+        self.global.rustc = Some(rustc.into());
+        self
+    }
+
+    /// Whether to always run `cargo build`, even when the sources did not change since the last
+    /// build, like `./miri --no-skip-build`.
+    pub fn always_build(mut self, yes: bool) -> Self {
+        self.global.no_skip_build = yes;
+        self
+    }
+
+    /// Whether to pass `--locked` to all cargo commands, like `./miri --locked`, so that they fail
+    /// instead of updating a lock file.
+    pub fn locked(mut self, yes: bool) -> Self {
+        self.global.locked = yes;
+        self
+    }
+
+    /// rustc flags for building with cargo, on top of ours and those in `RUSTFLAGS`, like
+    /// `./miri --rustflags`. Only the commands of this environment get them.
+    pub fn rustflags(mut self, flags: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.global.rustflags = flags.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Determines the toolchain and where the build artifacts go. This does not build anything.
+    pub fn build(self) -> Result<MiriEnv> {
+        MiriEnv::new(&self.global)
+    }
+}
+
+/// What [`MiriEnv::build_miri`] builds.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct BuildOptions {
+    /// The target to build the sysroot for, instead of the host.
+    pub target: Option<String>,
+    /// Whether to leave out the output of cargo (unless something fails).
+    pub quiet: bool,
+}
+
+/// What [`MiriEnv::build_miri`] built.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct BuildOutput {
+    /// The Miri driver.
+    pub miri: PathBuf,
+    pub cargo_miri: PathBuf,
+    /// The sysroot for the target that was asked for, which the driver needs to be given with
+    /// `--sysroot` (or `MIRI_SYSROOT`, for `cargo-miri`).
+    pub sysroot: PathBuf,
+}
+
+/// How [`MiriEnv::run`] and [`MiriEnv::run_seeds`] run the driver, as `./miri run` does.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct RunOptions {
+    /// Run the program the way the test suite runs the ones that have dependencies (`--dep`).
+    pub dep: bool,
+    /// Whether to show the output of cargo.
+    pub verbose: bool,
+    /// The flags for the driver, including the program to run. Those after `--` go to the
+    /// program.
+    pub flags: Vec<OsString>,
+    /// MIRIFLAGS, which go before `flags`. By default, those in the environment.
+    pub miri_flags: Option<OsString>,
+    /// How many seeds [`MiriEnv::run_seeds`] runs at the same time; by default, one per core.
+    pub jobs: Option<NonZeroUsize>,
+    /// With this, [`MiriEnv::run_seeds`] starts no further seeds while the load of the machine is
+    /// above it (the 1-minute load average; on Windows, how many cores are busy).
+    pub max_load: Option<f64>,
+    /// How [`MiriEnv::run_seeds`] hands the seed to each run; by default, as `-Zmiri-seed` in the
+    /// MIRIFLAGS.
+    pub inject: Vec<Injection>,
+}
+
+/// How one seed of [`MiriEnv::run_seeds`] went.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct SeedResult {
+    pub seed: u32,
+    pub status: Status,
+    pub duration: Duration,
+}
+
+/// How the seeds of [`MiriEnv::run_seeds`] went. Once a seed fails, no further seeds are started,
+/// so this only has the seeds that ran.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct ManySeedsReport {
+    /// By seed.
+    pub seeds: Vec<SeedResult>,
+    /// Why the first seed that failed did.
+    failure: Option<anyhow::Error>,
+}
+
+impl ManySeedsReport {
+    /// The seeds that failed (or timed out).
+    pub fn failed(&self) -> impl Iterator<Item = u32> + '_ {
+        self.seeds.iter().filter(|result| result.status != Status::Ok).map(|result| result.seed)
+    }
+
+    /// The error of the first seed that failed, if one did.
+    pub fn into_result(self) -> Result<()> {
+        match self.failure {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What [`MiriEnv::format`] does, as `./miri fmt` does.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct FormatOptions {
+    /// Only check whether the files are formatted, and change none of them.
+    pub check: bool,
+    /// Also format the files that git ignores.
+    pub no_ignore: bool,
+    /// Extra flags for rustfmt.
+    pub flags: Vec<OsString>,
+}
+
+/// What [`MiriEnv::format`] did.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub struct FormatOutcome {
+    /// How many files were formatted (or checked).
+    pub files: usize,
+    /// With [`FormatOptions::check`], the files that are not formatted.
+    pub unformatted: Vec<PathBuf>,
+    /// How many files were left alone since they say `// no-rustfmt` in their first lines.
+    pub skipped: usize,
+}
+
+/// The stable API. The methods in the other blocks are for the `./miri` binary only.
+impl MiriEnv {
+    pub fn builder() -> MiriEnvBuilder {
+        MiriEnvBuilder::default()
+    }
+
+    /// The root of the Miri checkout.
+    pub fn miri_dir(&self) -> &Path {
+        &self.miri_dir
+    }
+
+    /// Where the build artifacts go.
+    pub fn target_dir(&self) -> &Path {
+        &self.target_dir
+    }
+
+    /// The rustup toolchain we use, or `None` for a locally built rustc.
+    pub fn toolchain(&self) -> Option<&str> {
+        self.toolchain.as_deref()
+    }
+
+    /// Builds Miri and cargo-miri, and the sysroot, unless those are up to date. A sysroot given
+    /// in `MIRI_SYSROOT` is used as it is. Later runs use the sysroot for the target that was
+    /// built last.
+    pub fn build_miri(&mut self, options: &BuildOptions) -> Result<BuildOutput> {
+        let verbosity = if options.quiet { Verbosity::Quiet } else { self.verbosity };
+        let sysroot = self.with_verbosity(verbosity, |e| {
+            if miri_sysroot(&e.sh).is_some() {
+                // `build_miri_sysroot` does not build anything then.
+                e.build_if_changed(&path!(e.miri_dir / "Cargo.toml"), "miri")?;
+                e.build_if_changed(&path!(e.miri_dir / "cargo-miri" / "Cargo.toml"), "cargo-miri")?;
+            }
+            e.build_miri_sysroot(options.target.as_deref().map(OsStr::new))
+        })?;
+        let bin = |bin| bin_artifact(&self.target_dir, &self.cargo_extra_flags, bin);
+        Ok(BuildOutput { miri: bin("miri"), cargo_miri: bin("cargo-miri"), sysroot })
+    }
+
+    /// Installs Miri and cargo-miri into the sysroot of the toolchain (unless `cargo_flags` give a
+    /// `--root`), as `./miri install` does, with `cargo_flags` passed to `cargo install`. Fails
+    /// right away if that is not writable.
+    pub fn install(&self, cargo_flags: &[OsString]) -> Result<()> {
+        // Rather than after minutes of building.
+        let root = self.install_root(cargo_flags)?;
+        if let Err(err) = check_writable(&path!(root / "bin")) {
+            bail!(
+                "cannot install Miri into {}: {err:#}\n\
+                If the toolchain is shared (like one installed for all users), pass `--root <dir>` \
+                to install into `<dir>/bin` instead, and put that first in your PATH.",
+                root.display()
+            );
+        }
+        // Where binaries for another target go depends on the target dir, which is not the same
+        // for the two.
+        let mut cargo_flags = cargo_flags.to_vec();
+        if !ArgQuery::new(&["--root"]).is_present(&cargo_flags) {
+            cargo_flags.extend(["--root".into(), root.into()]);
+        }
+        let cargo_flags = &cargo_flags;
+        // Miri has a lot more to build, so unless the user says otherwise, cargo-miri only gets
+        // a few jobs.
+        let mut cargo_miri_flags = cargo_flags.to_vec();
+        if !ArgQuery::new(&["-j", "--jobs"]).is_present(cargo_flags) {
+            let jobs = std::thread::available_parallelism().map_or(1, |n| (n.get() / 4).max(1));
+            cargo_miri_flags.extend(["--jobs".into(), jobs.to_string().into()]);
+        }
+        self.concurrently(&[
+            ("miri", &|e: &MiriEnv| e.install_to_sysroot(e.miri_dir.clone(), cargo_flags)),
+            ("cargo-miri", &|e: &MiriEnv| {
+                // With a target dir of its own, the build does not wait for the lock on the one
+                // Miri is built in.
+                let mut e = e.clone();
+                e.set_target_dir(path!(e.target_dir / "cargo-miri"));
+                e.install_to_sysroot(path!(e.miri_dir / "cargo-miri"), &cargo_miri_flags)
+            }),
+        ])
+    }
+
+    /// Builds what is needed, and runs the driver once.
+    pub fn run(&mut self, options: &RunOptions) -> Result<()> {
+        let run = self.prepare_runs(options)?;
+        run.run(&self.sh, &Injected::none(&run.miri_flags))
+    }
+
+    /// Builds what is needed, and runs the driver once for each of `seeds`, with the seed
+    /// injected as [`RunOptions::inject`] says, several at the same time. An error means the
+    /// seeds could not be run at all; whether they passed is in the report.
+    pub fn run_seeds(
+        &mut self,
+        options: &RunOptions,
+        seeds: Range<u32>,
+    ) -> Result<ManySeedsReport> {
+        let run = self.prepare_runs(options)?;
+        status!("Running {} seeds...", seeds.len());
+        let results = Mutex::new(Vec::new());
+        let jobs = options.jobs.map(NonZeroUsize::get);
+        let res = self.run_many_times(seeds, jobs, options.max_load, |sh, seed| {
+            status!("Trying seed: {seed}");
+            let injected = Injected::new(&options.inject, &run.miri_flags, seed)?;
+            let start = Instant::now();
+            let result = run.run(sh, &injected);
+            let status = match &result {
+                Ok(()) => Status::Ok,
+                Err(err) if err.is::<TimedOut>() => Status::TimedOut,
+                Err(_) => Status::Failed,
+            };
+            let duration = start.elapsed();
+            let duration_secs = duration.as_secs_f64();
+            events::emit(&Event::SeedResult { seed, status, duration_secs });
+            results.lock().unwrap().push(SeedResult { seed, status, duration });
+            result.inspect_err(|_| {
+                error!("FAILING SEED: {seed}");
+            })
+        });
+        let mut seeds = results.into_inner().unwrap();
+        seeds.sort_by_key(|result| result.seed);
+        match res {
+            // Not the failure of a seed.
+            Err(err) if seeds.iter().all(|result| result.status == Status::Ok) => Err(err),
+            res => Ok(ManySeedsReport { seeds, failure: res.err() }),
+        }
+    }
+
+    /// Builds what is needed for running the driver as `options` say, and computes everything
+    /// the runs need (which does not include the environment, since they happen on several
+    /// threads).
+    fn prepare_runs(&mut self, options: &RunOptions) -> Result<PreparedRun> {
+        let miri_flags = match &options.miri_flags {
+            Some(miri_flags) => miri_flags.clone(),
+            None => self.sh.var_os("MIRIFLAGS").unwrap_or_default(),
+        };
+        let verbosity = if options.verbose { self.verbosity } else { Verbosity::Quiet };
+        let (flags, program_args) =
+            self.with_verbosity(verbosity, |e| e.prepare_run(options.flags.clone()))?;
+        Ok(PreparedRun {
+            miri_manifest: path!(self.miri_dir / "Cargo.toml"),
+            toolchain: self.toolchain_flag(),
+            extra_flags: self.shared_cargo_flags(&[]),
+            rustflags: self.merged_rustflags(&self.extra_rustflags)?,
+            dep: options.dep,
+            verbose: options.verbose,
+            miri_flags,
+            flags,
+            program_args,
+        })
+    }
+
+    /// Formats the Rust files of the checkout with rustfmt, as `./miri fmt` does (or checks that
+    /// they are formatted). Those in `tests` get `tests/rustfmt.toml`, which changes less.
+    pub fn format(&self, options: &FormatOptions) -> Result<FormatOutcome> {
+        let configs = rustfmt_configs(&self.miri_dir);
+        let files = rust_files(&self.miri_dir, options.no_ignore);
+        let toolchain = self.tool_toolchain("rustfmt")?;
+        self.format_files(files, toolchain.as_deref(), &configs, &options.flags, options.check)
+    }
+}
+
+/// See `MiriEnv::prepare_runs`.
+struct PreparedRun {
+    miri_manifest: PathBuf,
+    toolchain: Option<String>,
+    extra_flags: Vec<String>,
+    /// The rustc flags with `--rustflags`, if it was given (see `MiriEnv::merged_rustflags`).
+    rustflags: Option<Vec<OsString>>,
+    dep: bool,
+    verbose: bool,
+    /// Without the seed.
+    miri_flags: OsString,
+    flags: Vec<OsString>,
+    program_args: Option<Vec<OsString>>,
+}
+
+impl PreparedRun {
+    /// Runs the driver with the given MIRIFLAGS, environment, and extra arguments for the program.
+    fn run(&self, sh: &Shell, injected: &Injected) -> Result<()> {
+        let PreparedRun { miri_manifest, toolchain, extra_flags, .. } = self;
+        let quiet_flag = if self.verbose { None } else { Some("--quiet") };
+        // The basic command that executes the Miri driver.
+        let mut cmd = if self.dep {
+            cmd!(
+                sh,
+                "cargo {toolchain...} {quiet_flag...} test {extra_flags...} --manifest-path {miri_manifest} --test ui -- --miri-run-dep-mode"
+            )
+        } else {
+            cmd!(
+                sh,
+                "cargo {toolchain...} {quiet_flag...} run {extra_flags...} --manifest-path {miri_manifest} --"
+            )
+        };
+        cmd.set_quiet(!self.verbose);
+        if let Some(flags) = &self.rustflags {
+            cmd = with_rustflags(cmd, flags);
+        }
+        // Add Miri flags
+        let mut cmd =
+            cmd.label("run miri").args(miriflags::parse(&injected.miri_flags)).args(&self.flags);
+        for (var, value) in &injected.env {
+            cmd = cmd.env(var, value);
+        }
+        if self.program_args.is_some() || !injected.args.is_empty() {
+            cmd = cmd.arg("--").args(self.program_args.iter().flatten()).args(&injected.args);
+        }
+        // And run the thing.
+        Ok(cmd.run()?)
+    }
+}
@@ -0,0 +1,379 @@
+//! Parsing of the options of `./miri` commands, and the help texts generated from them.
+//!
+//! We are hand-rolling this since `clap` can't express what we need
+//! (<https://github.com/clap-rs/clap/issues/5055>): most commands forward everything after their
+//! own leading options to some other tool, and must not try to interpret those arguments.
+
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::util::arg_flag_parse;
+
+/// An option accepted by a command.
+pub struct Opt {
+    /// All spellings of the option. The first one is the canonical one.
+    pub names: &'static [&'static str],
+    pub value: OptValue,
+    pub help: &'static str,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OptValue {
+    /// The option is a plain flag.
+    None,
+    /// The option needs a value, given as `--opt value` or `--opt=value`.
+    Required(&'static str),
+    /// The option can have a value, which then must be given as `--opt=value`.
+    Optional(&'static str),
+}
+
+/// Describes the arguments of a command.
+pub struct CommandSpec {
+    /// The name of the command; empty for the options that come before the command.
+    pub name: &'static str,
+    pub opts: &'static [Opt],
+    /// Describes what may follow the options, e.g. `<flags>`. If empty, nothing may follow.
+    pub rest: &'static str,
+    /// Whether what follows the options may start with a flag that we do not know, since it is
+    /// forwarded to another tool.
+    pub forwards_flags: bool,
+    pub about: &'static str,
+}
+
+/// The arguments given to a command, parsed according to its `CommandSpec`.
+#[derive(Debug)]
+pub struct Matches {
+    /// The options that were given (by all their names), with their values.
+    opts: Vec<(&'static [&'static str], OptValue, Option<OsString>)>,
+    /// Everything after the options.
+    pub rest: Vec<OsString>,
+}
+
+impl Matches {
+    fn occurrences<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> impl DoubleEndedIterator<Item = &'a Option<OsString>> + 'a {
+        self.opts.iter().filter(move |(names, ..)| names.contains(&name)).map(|(_, _, value)| value)
+    }
+
+    /// Determines whether the option was given (and, for flags, not negated afterwards).
+    pub fn flag(&self, name: &str) -> bool {
+        self.occurrences(name)
+            .next_back()
+            .is_some_and(|value| value.as_deref() != Some("false".as_ref()))
+    }
+
+    /// Returns the value of the last occurrence of the option that has a value.
+    pub fn value(&self, name: &str) -> Option<OsString> {
+        self.occurrences(name).rev().find_map(|value| value.clone())
+    }
+
+    /// Returns the values of all occurrences of the option, for options that can be repeated.
+    pub fn values(&self, name: &str) -> Vec<OsString> {
+        self.occurrences(name).filter_map(|value| value.clone()).collect()
+    }
+
+    /// Parses the value of the option like `arg_flag_parse` does. Occurrences of an option with
+    /// an optional value that do not have one are ignored.
+    pub fn parse<T>(&self, name: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Into<anyhow::Error>,
+    {
+        let args = self.opts.iter().filter(|(names, ..)| names.contains(&name)).filter_map(
+            |(_, kind, value)| {
+                match (kind, value) {
+                    (_, Some(value)) => {
+                        let mut arg = OsString::from(name);
+                        arg.push("=");
+                        arg.push(value);
+                        Some(arg)
+                    }
+                    (OptValue::None, None) => Some(name.into()),
+                    (_, None) => None,
+                }
+            },
+        );
+        arg_flag_parse(args, name)
+    }
+}
+
+impl CommandSpec {
+    /// The way to invoke this command, like `./miri test`.
+    fn invocation(&self) -> String {
+        if self.name.is_empty() {
+            "./miri".into()
+        } else {
+            format!("./miri {}", self.name)
+        }
+    }
+
+    fn find(&self, name: &str) -> Option<&Opt> {
+        self.opts.iter().find(|opt| opt.names.contains(&name))
+    }
+
+    /// Parses the leading options in `args`. Returns `None` if `--help` was requested.
+    ///
+    /// For commands that forward their flags, parsing stops at the first flag we do not know,
+    /// unless it looks like a typo of one of ours. To forward `--help` (or such a flag) to the tool
+    /// we wrap, put it after `--`.
+    pub fn parse(&self, args: impl IntoIterator<Item = OsString>) -> Result<Option<Matches>> {
+        let mut args = args.into_iter().peekable();
+        let mut opts = Vec::new();
+        while let Some(arg) = args.peek().and_then(|a| a.to_str()) {
+            if arg == "--" || !arg.starts_with('-') {
+                break;
+            }
+            if arg == "--help" || arg == "-h" {
+                return Ok(None);
+            }
+            let (name, attached) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => (name, Some(value)),
+                _ => (arg, None),
+            };
+            // Flags can be negated, which they are by default.
+            if let Some(opt) = name
+                .strip_prefix("--no-")
+                .and_then(|flag| self.find(&format!("--{flag}")))
+                .filter(|opt| opt.value == OptValue::None && attached.is_none())
+            {
+                opts.push((opt.names, opt.value, Some("false".into())));
+                args.next().unwrap();
+                continue;
+            }
+            let Some(opt) = self.find(name) else {
+                if let Some(suggestion) = nearest(name, self.opts.iter().flat_map(|o| o.names)) {
+                    bail!(
+                        "unknown flag `{name}` for `{}`; did you mean `{suggestion}`? \
+                        (put it after `--` to forward it)",
+                        self.invocation()
+                    );
+                }
+                if self.forwards_flags {
+                    break;
+                }
+                bail!(
+                    "unknown flag `{name}` for `{}`\n\nUsage: {}",
+                    self.invocation(),
+                    self.usage()
+                );
+            };
+            let value = match (opt.value, attached) {
+                (OptValue::None, Some(_)) => bail!("`{name}` does not take a value"),
+                (_, Some(value)) => Some(value.into()),
+                (OptValue::None | OptValue::Optional(_), None) => None,
+                (OptValue::Required(value), None) => {
+                    // The value is the next argument.
+                    let name = name.to_owned();
+                    args.next().unwrap();
+                    Some(
+                        args.peek()
+                            .cloned()
+                            .ok_or_else(|| anyhow!("`{name}` must be followed by {value}"))?,
+                    )
+                }
+            };
+            opts.push((opt.names, opt.value, value));
+            // Consume the flag (or its value), look at the next one.
+            args.next().unwrap();
+        }
+        let rest: Vec<OsString> = args.collect();
+        if self.rest.is_empty() {
+            if let Some(arg) = rest.first() {
+                bail!(
+                    "unexpected argument `{}` for `{}`\n\nUsage: {}",
+                    arg.to_string_lossy(),
+                    self.invocation(),
+                    self.usage()
+                );
+            }
+        }
+        Ok(Some(Matches { opts, rest }))
+    }
+
+    /// A one-line summary of the arguments.
+    pub fn usage(&self) -> String {
+        let mut usage = self.invocation();
+        for opt in self.opts {
+            write!(usage, " [{}", opt.names.join("|")).unwrap();
+            match opt.value {
+                OptValue::None => {}
+                OptValue::Required(value) => write!(usage, " {value}").unwrap(),
+                OptValue::Optional(value) => write!(usage, "[={value}]").unwrap(),
+            }
+            usage.push(']');
+        }
+        if !self.rest.is_empty() {
+            write!(usage, " {}", self.rest).unwrap();
+        }
+        usage
+    }
+
+    /// The full help for this command, as shown by `--help` and `./miri help <command>`.
+    pub fn help(&self) -> String {
+        let mut help = format!("Usage: {}\n\n{}\n", self.usage(), self.about);
+        if !self.opts.is_empty() {
+            let names: Vec<String> = self
+                .opts
+                .iter()
+                .map(|opt| {
+                    let mut names = opt.names.join(", ");
+                    match opt.value {
+                        OptValue::None => {}
+                        OptValue::Required(value) => write!(names, " {value}").unwrap(),
+                        OptValue::Optional(value) => write!(names, "[={value}]").unwrap(),
+                    }
+                    names
+                })
+                .collect();
+            let width = names.iter().map(String::len).max().unwrap_or(0);
+            help.push_str("\nOptions:\n");
+            for (names, opt) in names.iter().zip(self.opts) {
+                writeln!(help, "  {names:width$}  {}", opt.help).unwrap();
+            }
+        }
+        help
+    }
+}
+
+/// Finds the candidate closest to `name`, if any is close enough to be a likely typo. For very
+/// short candidates like `-v`, nothing is close enough.
+pub fn nearest<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a &'a str>,
+) -> Option<&'a str> {
+    candidates
+        .into_iter()
+        .map(|c| (edit_distance(name, c), *c))
+        .filter(|&(dist, c)| dist <= c.len() / 3)
+        .min_by_key(|&(dist, _)| dist)
+        .map(|(_, c)| c)
+}
+
+/// The edit distance between `a` and `b`, where swapping two adjacent characters counts as a
+/// single edit.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // `dist[i][j]` is the distance between the first `i` chars of `a` and the first `j` of `b`.
+    let mut dist = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in dist.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    dist[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut d = (dist[i - 1][j] + 1).min(dist[i][j - 1] + 1).min(dist[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d = d.min(dist[i - 2][j - 2] + 1);
+            }
+            dist[i][j] = d;
+        }
+    }
+    dist[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: CommandSpec = CommandSpec {
+        name: "test",
+        opts: &[
+            Opt { names: &["--bless"], value: OptValue::None, help: "Bless." },
+            Opt { names: &["--target"], value: OptValue::Required("<target>"), help: "Target." },
+            Opt { names: &["--many-seeds"], value: OptValue::Optional("<range>"), help: "Seeds." },
+            Opt { names: &["-v", "--verbose"], value: OptValue::None, help: "Verbose." },
+        ],
+        rest: "<flags>",
+        forwards_flags: true,
+        about: "Test things.",
+    };
+
+    fn parse(spec: &CommandSpec, args: &[&str]) -> Result<Option<Matches>> {
+        spec.parse(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn parse_options() {
+        let m = parse(&SPEC, &["--bless", "--target", "a", "--verbose", "--target=b", "x", "-v"])
+            .unwrap()
+            .unwrap();
+        assert!(m.flag("--bless"));
+        assert!(m.flag("-v"));
+        assert!(!m.flag("--many-seeds"));
+        assert_eq!(m.value("--target"), Some("b".into()));
+        assert_eq!(m.rest, ["x", "-v"]);
+        // Optional values.
+        let m = parse(&SPEC, &["--many-seeds", "--", "--bless"]).unwrap().unwrap();
+        assert!(m.flag("--many-seeds"));
+        assert_eq!(m.value("--many-seeds"), None);
+        assert_eq!(m.rest, ["--", "--bless"]);
+        let m = parse(&SPEC, &["--many-seeds=0..4"]).unwrap().unwrap();
+        assert_eq!(m.value("--many-seeds"), Some("0..4".into()));
+        // Negation.
+        let m = parse(&SPEC, &["--bless", "--no-bless", "-v", "--no-verbose", "--verbose"])
+            .unwrap()
+            .unwrap();
+        assert!(!m.flag("--bless"));
+        assert!(m.flag("-v"));
+        assert_eq!(m.parse::<bool>("--bless").unwrap(), Some(false));
+        // Options with values cannot be negated, so this is just forwarded.
+        let m = parse(&SPEC, &["--no-target", "x"]).unwrap().unwrap();
+        assert_eq!(m.rest, ["--no-target", "x"]);
+        // Typed values.
+        let m = parse(&SPEC, &["--target=1", "--many-seeds", "--target", "3"]).unwrap().unwrap();
+        assert_eq!(m.parse::<u32>("--target").unwrap(), Some(3));
+        assert_eq!(m.parse::<u32>("--many-seeds").unwrap(), None);
+        let m = parse(&SPEC, &["--target="]).unwrap().unwrap();
+        assert!(m.parse::<u32>("--target").is_err());
+        // Help is ours, even if we forward the flags; after `--`, it is for the tool we wrap.
+        assert!(parse(&SPEC, &["--bless", "--help"]).unwrap().is_none());
+        assert!(parse(&SPEC, &["-h"]).unwrap().is_none());
+        let m = parse(&SPEC, &["--bless", "--", "--help"]).unwrap().unwrap();
+        assert!(m.flag("--bless"));
+        assert_eq!(m.rest, ["--", "--help"]);
+        let spec = CommandSpec { forwards_flags: false, ..SPEC };
+        assert!(parse(&spec, &["--help"]).unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_errors() {
+        let err = parse(&SPEC, &["--bles"]).unwrap_err().to_string();
+        assert!(err.contains("did you mean `--bless`?"), "{err}");
+        let err = parse(&SPEC, &["--many-sedes=1..2"]).unwrap_err().to_string();
+        assert!(err.contains("did you mean `--many-seeds`?"), "{err}");
+        assert!(parse(&SPEC, &["--tagret", "x"]).unwrap_err().to_string().contains("`--target`"));
+        assert!(parse(&SPEC, &["--bless=yes"]).is_err());
+        assert!(parse(&SPEC, &["--target"]).is_err());
+        // Unrelated flags are forwarded, even if they are just as similar to a short flag, and so
+        // are typos after `--`.
+        let m = parse(&SPEC, &["--nocapture", "--bless"]).unwrap().unwrap();
+        assert!(!m.flag("--bless"));
+        assert_eq!(m.rest, ["--nocapture", "--bless"]);
+        let m = parse(&SPEC, &["-q"]).unwrap().unwrap();
+        assert_eq!(m.rest, ["-q"]);
+        let m = parse(&SPEC, &["--", "--bles"]).unwrap().unwrap();
+        assert_eq!(m.rest, ["--", "--bles"]);
+        // ... unless the command does not forward anything.
+        let spec = CommandSpec { rest: "", forwards_flags: false, ..SPEC };
+        assert!(parse(&spec, &["--nocapture"]).is_err());
+        assert!(parse(&spec, &["foo"]).is_err());
+    }
+
+    #[test]
+    fn help_text() {
+        assert_eq!(
+            SPEC.usage(),
+            "./miri test [--bless] [--target <target>] [--many-seeds[=<range>]] [-v|--verbose] <flags>"
+        );
+        let help = SPEC.help();
+        assert!(help.contains("\n  --target <target>       Target.\n"), "{help}");
+        assert!(help.contains("\n  -v, --verbose           Verbose.\n"), "{help}");
+    }
+}
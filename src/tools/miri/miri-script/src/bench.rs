@@ -0,0 +1,403 @@
+//! The history of `./miri bench` results, so that a run can be compared with earlier ones, and
+//! the statistics of `./miri bench --wall`, which times the Miri driver itself.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::record::skip_in_dry_run;
+use crate::sync::now;
+
+/// The result of one benchmark in one `./miri bench` run. The history file has one of these per
+/// line.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BenchEntry {
+    pub bench: String,
+    /// When the benchmark ran, in seconds since the Unix epoch.
+    pub time: u64,
+    /// The Miri commit that was benchmarked.
+    pub commit: String,
+    /// The toolchain (or the path of the local rustc) Miri was built with.
+    pub toolchain: String,
+    pub target: Option<String>,
+    /// The name given with `--save-baseline`, if any.
+    pub baseline: Option<String>,
+    /// The median wall-clock time, in seconds.
+    pub median: f64,
+    /// The number of instructions executed, if `perf` is available.
+    pub instructions: Option<u64>,
+}
+
+/// Reads the history. A history that does not exist yet is empty.
+pub fn load(path: &Path) -> Result<Vec<BenchEntry>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("invalid entry in line {} of {}", i + 1, path.display()))
+        })
+        .collect()
+}
+
+pub fn append(path: &Path, entries: &[BenchEntry]) -> Result<()> {
+    if skip_in_dry_run(format_args!("append {} results to {}", entries.len(), path.display())) {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    for entry in entries {
+        writeln!(file, "{}", serde_json::to_string(entry)?)?;
+    }
+    Ok(())
+}
+
+/// Extracts the median time from what `hyperfine --export-json` wrote.
+pub fn hyperfine_median(json: &str) -> Result<f64> {
+    let json: serde_json::Value = serde_json::from_str(json)?;
+    match json["results"][0]["median"].as_f64() {
+        Some(median) => Ok(median),
+        None => bail!("hyperfine did not report a median time"),
+    }
+}
+
+/// What `./miri bench --wall` runs: one program, with the Miri driver, many times.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WallBench {
+    pub program: PathBuf,
+    /// How many of the runs count; the warmup runs come on top.
+    pub runs: usize,
+}
+
+/// What `./miri bench --compare-rev` compares the benchmarks with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompareRev {
+    /// The git revision, like `HEAD~` or `master`.
+    pub rev: String,
+    /// How many runs of each benchmark on each side count.
+    pub runs: usize,
+}
+
+/// The runs of `./miri bench --wall` (and `--compare-rev`) before the ones that count: the first one reads the sysroot
+/// from disk, and the ones after find it in the page cache.
+pub const WALL_WARMUP_RUNS: usize = 1;
+
+/// The wall-clock times of the runs of one Miri binary, in seconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WallStats {
+    pub runs: usize,
+    pub mean: f64,
+    /// The sample standard deviation, like hyperfine reports it.
+    pub stddev: f64,
+    pub median: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl WallStats {
+    pub fn of(times: &[f64]) -> WallStats {
+        assert!(!times.is_empty());
+        let mut sorted = times.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let n = sorted.len();
+        let mean = sorted.iter().sum::<f64>() / n as f64;
+        let variance = if n > 1 {
+            sorted.iter().map(|time| (time - mean).powi(2)).sum::<f64>() / (n - 1) as f64
+        } else {
+            0.0
+        };
+        let median =
+            if n % 2 == 1 { sorted[n / 2] } else { (sorted[n / 2 - 1] + sorted[n / 2]) / 2.0 };
+        WallStats {
+            runs: n,
+            mean,
+            stddev: variance.sqrt(),
+            median,
+            min: sorted[0],
+            max: sorted[n - 1],
+        }
+    }
+
+    /// Whether the means of `self` and `other` are further apart than the noise can explain:
+    /// by more than twice the standard error of their difference (roughly Welch's t-test at 95%).
+    pub fn differs_from(&self, other: &WallStats) -> bool {
+        let variance = |stats: &WallStats| stats.stddev.powi(2) / stats.runs as f64;
+        let error = (variance(self) + variance(other)).sqrt();
+        (self.mean - other.mean).abs() > 2.0 * error
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "{} ± {} (min {}, median {}, max {})",
+            format_time(self.mean),
+            format_time(self.stddev),
+            format_time(self.min),
+            format_time(self.median),
+            format_time(self.max)
+        )
+    }
+}
+
+/// The runs of `command`, as hyperfine writes them with `--export-json`, so that the results of
+/// `./miri bench --wall` can be read like those of the other benchmarks.
+pub fn hyperfine_json(command: &str, times: &[f64]) -> String {
+    let stats = WallStats::of(times);
+    let json = serde_json::json!({
+        "results": [{
+            "command": command,
+            "mean": stats.mean,
+            "stddev": stats.stddev,
+            "median": stats.median,
+            "min": stats.min,
+            "max": stats.max,
+            "times": times,
+        }]
+    });
+    serde_json::to_string_pretty(&json).unwrap()
+}
+
+/// Extracts the instruction count from the output of `perf stat -x, -e instructions:u`.
+pub fn perf_instructions(stderr: &str) -> Option<u64> {
+    stderr.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(',').collect();
+        // The count is the first field, and the event the third; the count is something like
+        // `<not supported>` if `perf` cannot count instructions here.
+        fields.get(2)?.starts_with("instructions").then(|| fields[0].parse().ok())?
+    })
+}
+
+/// Finds the entry to compare a new result for `bench` with: the latest one recorded for the
+/// given commit (prefix) or baseline name, or else just the latest one for the same target.
+pub fn reference<'a>(
+    history: &'a [BenchEntry],
+    bench: &str,
+    target: Option<&str>,
+    against: Option<&str>,
+) -> Option<&'a BenchEntry> {
+    history.iter().rev().filter(|entry| entry.bench == bench).find(|entry| {
+        match against {
+            Some(against) =>
+                entry.baseline.as_deref() == Some(against) || entry.commit.starts_with(against),
+            None => entry.target.as_deref() == target,
+        }
+    })
+}
+
+/// Whether any entry matches `against`, as a commit (prefix) or baseline name.
+pub fn has_entry(history: &[BenchEntry], against: &str) -> bool {
+    history.iter().any(|entry| {
+        entry.baseline.as_deref() == Some(against) || entry.commit.starts_with(against)
+    })
+}
+
+/// How `new` compares to `old`, in percent; positive means slower.
+pub fn change(old: f64, new: f64) -> f64 {
+    (new - old) / old * 100.0
+}
+
+/// Describes a change for humans, highlighting regressions above `threshold` percent.
+pub fn describe_change(change: f64, threshold: f64) -> String {
+    let mut description = format!("{change:+.1}%");
+    if change > threshold {
+        description.push_str(" REGRESSION");
+    } else if change < -threshold {
+        description.push_str(" (improvement)");
+    }
+    description
+}
+
+pub fn format_time(seconds: f64) -> String {
+    if seconds < 1.0 {
+        format!("{:.1} ms", seconds * 1000.0)
+    } else {
+        format!("{seconds:.3} s")
+    }
+}
+
+fn short(commit: &str) -> &str {
+    &commit[..commit.len().min(10)]
+}
+
+fn ago(time: u64) -> String {
+    let hours = now().saturating_sub(time) / (60 * 60);
+    if hours < 48 {
+        format!("{hours} hours ago")
+    } else {
+        format!("{} days ago", hours / 24)
+    }
+}
+
+/// Prints how the `current` results compare to the history, and returns the number of
+/// regressions above `threshold` percent.
+pub fn print_comparison(
+    current: &[BenchEntry],
+    history: &[BenchEntry],
+    against: Option<&str>,
+    threshold: f64,
+) -> usize {
+    let mut regressions = 0;
+    println!("Results (median):");
+    for entry in current {
+        let Some(old) = reference(history, &entry.bench, entry.target.as_deref(), against) else {
+            println!("  {}: {} (nothing to compare with)", entry.bench, format_time(entry.median));
+            continue;
+        };
+        let change = change(old.median, entry.median);
+        if change > threshold {
+            regressions += 1;
+        }
+        println!(
+            "  {}: {} (vs {} at {}: {})",
+            entry.bench,
+            format_time(entry.median),
+            format_time(old.median),
+            old.baseline.as_deref().unwrap_or(short(&old.commit)),
+            describe_change(change, threshold),
+        );
+    }
+    regressions
+}
+
+/// Prints the last `n` results of each benchmark (of all of them if `benches` is empty).
+pub fn print_history(history: &[BenchEntry], benches: &[String], n: usize, threshold: f64) {
+    let mut names: Vec<&str> = history.iter().map(|entry| &*entry.bench).collect();
+    names.sort();
+    names.dedup();
+    names.retain(|name| benches.is_empty() || benches.iter().any(|bench| bench == name));
+    if names.is_empty() {
+        println!("No benchmark results have been recorded yet.");
+    }
+    for name in names {
+        println!("{name}:");
+        let entries: Vec<&BenchEntry> =
+            history.iter().filter(|entry| entry.bench == name).collect();
+        let start = entries.len().saturating_sub(n);
+        for (i, entry) in entries.iter().enumerate().skip(start) {
+            let mut line = format!(
+                "  {} {:>14}  {}",
+                short(&entry.commit),
+                ago(entry.time),
+                format_time(entry.median)
+            );
+            if let Some(instructions) = entry.instructions {
+                line.push_str(&format!(", {instructions} instructions"));
+            }
+            if let Some(prev) = i.checked_sub(1).map(|i| entries[i]) {
+                line.push_str(&format!(
+                    " ({})",
+                    describe_change(change(prev.median, entry.median), threshold)
+                ));
+            }
+            if let Some(baseline) = &entry.baseline {
+                line.push_str(&format!(" [{baseline}]"));
+            }
+            println!("{line}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(bench: &str, commit: &str, median: f64) -> BenchEntry {
+        BenchEntry {
+            bench: bench.into(),
+            time: 0,
+            commit: commit.into(),
+            toolchain: "miri".into(),
+            target: None,
+            baseline: None,
+            median,
+            instructions: None,
+        }
+    }
+
+    #[test]
+    fn wall_stats() {
+        let stats = WallStats::of(&[1.5, 1.0, 2.0, 1.5]);
+        assert_eq!((stats.mean, stats.median, stats.min, stats.max), (1.5, 1.5, 1.0, 2.0));
+        assert!((stats.stddev - (1.0f64 / 6.0).sqrt()).abs() < 1e-12);
+        assert_eq!(WallStats::of(&[0.25]).stddev, 0.0);
+        assert_eq!(WallStats::of(&[3.0, 1.0, 2.0]).median, 2.0);
+        assert_eq!(
+            stats.describe(),
+            "1.500 s ± 408.2 ms (min 1.000 s, median 1.500 s, max 2.000 s)"
+        );
+        let faster = WallStats::of(&[1.0, 1.1, 0.9, 1.0]);
+        assert!(stats.differs_from(&faster) && faster.differs_from(&stats));
+        assert!(!stats.differs_from(&WallStats::of(&[1.4, 1.6, 1.1, 1.9])));
+        let json = hyperfine_json("miri hello.rs", &[1.0, 3.0]);
+        assert_eq!(hyperfine_median(&json).unwrap(), 2.0);
+    }
+
+    #[test]
+    fn history_file() {
+        let path = std::env::temp_dir()
+            .join(format!("miri-bench-{}", std::process::id()))
+            .join("bench-history.jsonl");
+        assert!(load(&path).unwrap().is_empty());
+        let entries = [entry("serde1", "abc", 1.5), entry("unicode", "abc", 0.25)];
+        append(&path, &entries[..1]).unwrap();
+        append(&path, &entries[1..]).unwrap();
+        assert_eq!(load(&path).unwrap(), entries);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn tool_output() {
+        let json = r#"{"results": [{"command": "cargo miri run", "mean": 1.2, "median": 1.1}]}"#;
+        assert_eq!(hyperfine_median(json).unwrap(), 1.1);
+        assert!(hyperfine_median(r#"{"results": []}"#).is_err());
+        let perf = "some output\n1234567,,instructions:u,1000,100.00,,\n";
+        assert_eq!(perf_instructions(perf), Some(1234567));
+        assert_eq!(perf_instructions("<not supported>,,instructions:u,0,100.00,,"), None);
+        assert_eq!(perf_instructions("no counters here"), None);
+    }
+
+    #[test]
+    fn references() {
+        let mut tagged = entry("serde1", "def", 1.0);
+        tagged.baseline = Some("before-refactor".into());
+        let mut cross = entry("serde1", "fff", 3.0);
+        cross.target = Some("i686-unknown-linux-gnu".into());
+        let history = [entry("serde1", "abc", 2.0), tagged, entry("unicode", "abc", 0.5), cross];
+        assert_eq!(reference(&history, "serde1", None, None).unwrap().commit, "def");
+        assert_eq!(reference(&history, "serde1", None, Some("ab")).unwrap().median, 2.0);
+        assert_eq!(
+            reference(&history, "serde1", None, Some("before-refactor")).unwrap().commit,
+            "def"
+        );
+        assert_eq!(
+            reference(&history, "serde1", Some("i686-unknown-linux-gnu"), None).unwrap().commit,
+            "fff"
+        );
+        assert!(reference(&history, "zip-equal", None, None).is_none());
+        assert!(has_entry(&history, "before-refactor") && !has_entry(&history, "xyz"));
+    }
+
+    #[test]
+    fn changes() {
+        assert_eq!(change(2.0, 2.5), 25.0);
+        assert_eq!(describe_change(25.0, 5.0), "+25.0% REGRESSION");
+        assert_eq!(describe_change(-10.0, 5.0), "-10.0% (improvement)");
+        assert_eq!(describe_change(3.0, 5.0), "+3.0%");
+        assert_eq!(format_time(0.0123), "12.3 ms");
+        assert_eq!(format_time(2.5), "2.500 s");
+    }
+}
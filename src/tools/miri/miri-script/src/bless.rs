@@ -0,0 +1,212 @@
+//! Blessing the ui test suites in parallel.
+//!
+//! Every suite gets its own copy of the `tests` directory to bless (the test harness learns about
+//! it via `MIRI_UI_ROOT`). Afterwards, we collect the reference files that changed in each copy
+//! and apply them to the real `tests` directory.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use walkdir::WalkDir;
+
+use crate::record::skip_in_dry_run;
+
+/// The ui test suites; keep in sync with `main` in `tests/ui.rs`.
+pub const SUITES: &[&str] = &[
+    "tests/pass",
+    "tests/pass-dep",
+    "tests/panic",
+    "tests/fail",
+    "tests/fail-dep",
+    "tests/native-lib/pass",
+    "tests/native-lib/fail",
+];
+
+/// The suites that the test harness runs on this host.
+pub fn default_suites() -> Vec<String> {
+    SUITES
+        .iter()
+        .filter(|suite| cfg!(target_os = "linux") || !suite.starts_with("tests/native-lib/"))
+        .map(|suite| suite.to_string())
+        .collect()
+}
+
+/// Turns `pass` or `tests/pass/` into `tests/pass`.
+pub fn parse_suite(suite: &str) -> Result<String> {
+    let suite = suite.trim_end_matches('/');
+    let suite =
+        if suite.starts_with("tests/") { suite.to_owned() } else { format!("tests/{suite}") };
+    if !SUITES.contains(&&*suite) {
+        bail!("unknown test suite `{suite}`, expected one of: {}", SUITES.join(", "));
+    }
+    Ok(suite)
+}
+
+/// The directory `./miri bless` uses for this suite, relative to the bless dir.
+pub fn shard_dir(suite: &str) -> String {
+    suite.replace('/', "-")
+}
+
+/// Whether this file holds the expected output of a test.
+fn is_reference_file(path: &Path) -> bool {
+    matches!(path.extension().and_then(|ext| ext.to_str()), Some("stderr" | "stdout"))
+}
+
+/// Reads all reference files below `dir`, keyed by their path relative to `dir`.
+pub fn reference_files(dir: &Path) -> Result<BTreeMap<PathBuf, Vec<u8>>> {
+    let mut files = BTreeMap::new();
+    for entry in WalkDir::new(dir) {
+        let entry = entry?;
+        if entry.file_type().is_file() && is_reference_file(entry.path()) {
+            let contents = fs::read(entry.path())
+                .with_context(|| format!("failed to read {}", entry.path().display()))?;
+            files.insert(entry.path().strip_prefix(dir)?.to_owned(), contents);
+        }
+    }
+    Ok(files)
+}
+
+/// Copies the directory `from` to `to`, which must not exist yet.
+pub fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    if skip_in_dry_run(format_args!("copy {} to {}", from.display(), to.display())) {
+        return Ok(());
+    }
+    for entry in WalkDir::new(from) {
+        let entry = entry?;
+        let dest = to.join(entry.path().strip_prefix(from)?);
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&dest)
+        } else {
+            fs::copy(entry.path(), &dest).map(drop)
+        }
+        .with_context(|| {
+            format!("failed to copy {} to {}", entry.path().display(), dest.display())
+        })?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    Write(Vec<u8>),
+    Delete,
+}
+
+/// The changes to the reference files between `before` and `after`.
+pub fn changes(
+    before: &BTreeMap<PathBuf, Vec<u8>>,
+    after: &BTreeMap<PathBuf, Vec<u8>>,
+) -> BTreeMap<PathBuf, Change> {
+    let written = after
+        .iter()
+        .filter(|(path, contents)| before.get(*path) != Some(contents))
+        .map(|(path, contents)| (path.clone(), Change::Write(contents.clone())));
+    let deleted = before
+        .keys()
+        .filter(|path| !after.contains_key(*path))
+        .map(|path| (path.clone(), Change::Delete));
+    written.chain(deleted).collect()
+}
+
+#[derive(Debug, Default)]
+pub struct Merged {
+    /// The changes to apply to the test directory.
+    pub changes: BTreeMap<PathBuf, Change>,
+    /// Files that several suites tried to change in different ways, with the names of those suites.
+    pub conflicts: BTreeMap<PathBuf, Vec<String>>,
+}
+
+/// Merges the changes each suite made. Files that several suites changed in the same way are
+/// fine; all other files changed by more than one suite are conflicts and remain unchanged.
+pub fn merge(shards: Vec<(String, BTreeMap<PathBuf, Change>)>) -> Merged {
+    let mut by_path: BTreeMap<PathBuf, Vec<(String, Change)>> = BTreeMap::new();
+    for (suite, changes) in shards {
+        for (path, change) in changes {
+            by_path.entry(path).or_default().push((suite.clone(), change));
+        }
+    }
+    let mut merged = Merged::default();
+    for (path, mut changes) in by_path {
+        if changes.iter().all(|(_, change)| *change == changes[0].1) {
+            merged.changes.insert(path, changes.swap_remove(0).1);
+        } else {
+            merged.conflicts.insert(path, changes.into_iter().map(|(suite, _)| suite).collect());
+        }
+    }
+    merged
+}
+
+impl Merged {
+    /// Applies the changes to the test directory `dir`.
+    pub fn apply(&self, dir: &Path) -> Result<()> {
+        if skip_in_dry_run(format_args!("update {} files in {}", self.changes.len(), dir.display()))
+        {
+            return Ok(());
+        }
+        for (path, change) in &self.changes {
+            let path = dir.join(path);
+            match change {
+                Change::Write(contents) => fs::write(&path, contents),
+                Change::Delete => fs::remove_file(&path),
+            }
+            .with_context(|| format!("failed to update {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// How many `.stderr` and `.stdout` files change, respectively.
+    pub fn counts(&self) -> (usize, usize) {
+        let count = |ext: &str| {
+            self.changes.keys().filter(|path| path.extension().is_some_and(|e| e == ext)).count()
+        };
+        (count("stderr"), count("stdout"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(files: &[(&str, &str)]) -> BTreeMap<PathBuf, Vec<u8>> {
+        files.iter().map(|(path, contents)| (path.into(), contents.as_bytes().to_vec())).collect()
+    }
+
+    #[test]
+    fn suites() {
+        assert_eq!(parse_suite("pass").unwrap(), "tests/pass");
+        assert_eq!(parse_suite("tests/native-lib/fail/").unwrap(), "tests/native-lib/fail");
+        assert!(parse_suite("utils").is_err());
+        assert!(parse_suite("tests/pass/").is_ok_and(|suite| shard_dir(&suite) == "tests-pass"));
+    }
+
+    #[test]
+    fn merging() {
+        let before = files(&[("pass/a.stderr", "a"), ("fail/b.stderr", "b"), ("x.stdout", "x")]);
+        let pass = changes(
+            &before,
+            &files(&[("pass/a.stderr", "A"), ("fail/b.stderr", "b"), ("x.stdout", "X")]),
+        );
+        let fail = changes(
+            &before,
+            &files(&[("pass/a.stderr", "a"), ("fail/c.stderr", "c"), ("x.stdout", "Y")]),
+        );
+        let dep = changes(&before, &files(&[("pass/a.stderr", "A"), ("fail/b.stderr", "b")]));
+        assert_eq!(fail.get(Path::new("fail/b.stderr")), Some(&Change::Delete));
+
+        let merged = merge(vec![
+            ("tests/pass".into(), pass),
+            ("tests/fail".into(), fail),
+            ("tests/pass-dep".into(), dep),
+        ]);
+        assert_eq!(merged.changes[Path::new("pass/a.stderr")], Change::Write(b"A".to_vec()));
+        assert_eq!(merged.changes[Path::new("fail/b.stderr")], Change::Delete);
+        assert_eq!(merged.changes[Path::new("fail/c.stderr")], Change::Write(b"c".to_vec()));
+        assert_eq!(merged.counts(), (3, 0));
+        assert_eq!(
+            merged.conflicts[Path::new("x.stdout")],
+            ["tests/pass", "tests/fail", "tests/pass-dep"]
+        );
+    }
+}
@@ -0,0 +1,632 @@
+//! `./miri ci`: the steps CI runs, so that they can be reproduced locally.
+//!
+//! `STEPS` is the only place that says what CI does: `ci/ci.sh` queries it with
+//! `./miri ci --list --names` and runs each step with `./miri ci --only <step>`.
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use path_macro::path;
+use serde::Serialize;
+use xshell::Shell;
+
+use crate::output::{status, warning};
+use crate::record::{cmd, skip_in_dry_run, Cmd};
+use crate::util::{miriflags, rustc_sysroot_and_libdir, Ambient, MiriEnv, Verbosity};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Group {
+    /// Checks that do not depend on the host; CI runs them in the separate `style` job.
+    Style,
+    /// Tests for the host target.
+    Host,
+    /// Tests for foreign targets, skipped with `--host-only`.
+    Cross,
+}
+
+pub struct Step {
+    pub name: &'static str,
+    pub group: Group,
+    pub about: &'static str,
+    /// Whether the step does anything on this host.
+    pub runs_on: fn(&Host) -> bool,
+    pub run: fn(&Ci) -> Result<()>,
+}
+
+/// A foreign target that gets the full test suite.
+pub struct ForeignTarget {
+    pub target: &'static str,
+    /// How many seeds to run the many-seeds tests with; 0 to not run them.
+    pub many_seeds: u32,
+    /// Whether to also run the cargo-miri tests in an environment that tries to confuse it.
+    pub cargo_miri_env: bool,
+}
+
+/// A foreign target for which we only run some of the tests.
+pub struct MinimalTarget {
+    pub target: &'static str,
+    /// The test filters to run.
+    pub tests: &'static [&'static [&'static str]],
+    /// Whether the target has no `std`.
+    pub no_std: bool,
+}
+
+/// What CI tests on a host.
+pub struct Host {
+    pub target: &'static str,
+    /// Whether to run the ui tests with the provenance GC running all the time.
+    pub gc_stress: bool,
+    /// Whether to also run some ui tests with all MIR optimizations.
+    pub mir_opt: bool,
+    pub many_seeds: u32,
+    /// Whether to check that the benchmarks build and run.
+    pub bench: bool,
+    pub cargo_miri_env: bool,
+    pub foreign: &'static [ForeignTarget],
+    pub minimal: &'static [MinimalTarget],
+    /// A target to build Miri itself for, to check that cross builds work.
+    pub cross_build: Option<&'static str>,
+}
+
+/// Common things we test on all targets (that have std); requires no target-specific shims.
+const VERY_BASIC: &[&str] = &["integer", "vec", "string", "btreemap"];
+/// Together with `VERY_BASIC`, ensures we have the shims for stdout and basic data structures.
+const BASIC: &[&str] = &["hello", "hashmap", "alloc", "align"];
+
+const fn foreign(target: &'static str, many_seeds: u32) -> ForeignTarget {
+    ForeignTarget { target, many_seeds, cargo_miri_env: false }
+}
+
+const fn minimal(target: &'static str, tests: &'static [&'static [&'static str]]) -> MinimalTarget {
+    MinimalTarget { target, tests, no_std: false }
+}
+
+/// The hosts CI runs on. In particular, these fully cover all tier 1 targets, and run the
+/// many-seeds tests on all of them.
+pub const HOSTS: &[Host] = &[
+    Host {
+        target: "x86_64-unknown-linux-gnu",
+        gc_stress: true,
+        mir_opt: true,
+        many_seeds: 64,
+        bench: true,
+        cargo_miri_env: true,
+        // With reduced many-seed count to avoid spending too much time on that. (All OSes and ABIs
+        // are run with 64 seeds at least once though via the macOS runner.)
+        foreign: &[
+            foreign("i686-unknown-linux-gnu", 16),
+            foreign("aarch64-unknown-linux-gnu", 16),
+            foreign("x86_64-apple-darwin", 16),
+            foreign("x86_64-pc-windows-gnu", 16),
+        ],
+        minimal: &[],
+        cross_build: None,
+    },
+    Host {
+        target: "aarch64-apple-darwin",
+        gc_stress: true,
+        mir_opt: true,
+        many_seeds: 64,
+        bench: true,
+        cargo_miri_env: true,
+        foreign: &[
+            // Extra tier 1.
+            foreign("i686-pc-windows-gnu", 64),
+            ForeignTarget {
+                target: "x86_64-pc-windows-msvc",
+                many_seeds: 64,
+                cargo_miri_env: true,
+            },
+            // Extra tier 2; s390x is our big-endian architecture of choice.
+            foreign("arm-unknown-linux-gnueabi", 0),
+            foreign("s390x-unknown-linux-gnu", 0),
+        ],
+        // Partially supported targets (tier 2).
+        minimal: &[
+            minimal(
+                "x86_64-unknown-freebsd",
+                &[
+                    VERY_BASIC,
+                    BASIC,
+                    &[
+                        "panic/panic",
+                        "concurrency/simple",
+                        "atomic",
+                        "threadname",
+                        "libc-mem",
+                        "libc-misc",
+                        "libc-random",
+                        "libc-time",
+                        "fs",
+                        "env",
+                        "num_cpus",
+                    ],
+                ],
+            ),
+            minimal(
+                "i686-unknown-freebsd",
+                &[
+                    VERY_BASIC,
+                    BASIC,
+                    &[
+                        "panic/panic",
+                        "concurrency/simple",
+                        "atomic",
+                        "threadname",
+                        "libc-mem",
+                        "libc-misc",
+                        "libc-random",
+                        "libc-time",
+                        "fs",
+                        "env",
+                        "num_cpus",
+                    ],
+                ],
+            ),
+            minimal(
+                "x86_64-unknown-illumos",
+                &[
+                    VERY_BASIC,
+                    &[
+                        "hello",
+                        "panic/panic",
+                        "concurrency/simple",
+                        "pthread-sync",
+                        "libc-mem",
+                        "libc-misc",
+                        "libc-random",
+                    ],
+                ],
+            ),
+            minimal(
+                "x86_64-pc-solaris",
+                &[
+                    VERY_BASIC,
+                    &[
+                        "hello",
+                        "panic/panic",
+                        "concurrency/simple",
+                        "pthread-sync",
+                        "libc-mem",
+                        "libc-misc",
+                        "libc-random",
+                    ],
+                ],
+            ),
+            minimal("aarch64-linux-android", &[VERY_BASIC, &["hello", "panic/panic"]]),
+            minimal("wasm32-wasi", &[VERY_BASIC, &["wasm"]]),
+            minimal("wasm32-unknown-unknown", &[VERY_BASIC, &["wasm"]]),
+            minimal("thumbv7em-none-eabihf", &[&["no_std"]]),
+            // A custom target JSON file.
+            MinimalTarget { target: "tests/avr.json", tests: &[&["no_std"]], no_std: true },
+        ],
+        // The linker of the host links for this as well. The workflow installs the components
+        // for it.
+        cross_build: Some("x86_64-apple-darwin"),
+    },
+    Host {
+        target: "i686-pc-windows-msvc",
+        // Without GC stress and with reduced many-seeds count as this is the slowest runner. (The
+        // macOS runner checks windows-msvc with the full many-seeds count.)
+        gc_stress: false,
+        mir_opt: true,
+        many_seeds: 16,
+        bench: true,
+        cargo_miri_env: false,
+        // We really want to ensure a Linux target works on a Windows host, and a 64bit target
+        // works on a 32bit host.
+        foreign: &[foreign("x86_64-unknown-linux-gnu", 0)],
+        minimal: &[],
+        cross_build: None,
+    },
+];
+
+/// What CI tests on any other host: just the basics.
+const OTHER_HOST: Host = Host {
+    target: "other",
+    gc_stress: false,
+    mir_opt: false,
+    many_seeds: 0,
+    bench: false,
+    cargo_miri_env: false,
+    foreign: &[],
+    minimal: &[],
+    cross_build: None,
+};
+
+impl Host {
+    /// What CI tests on `target`.
+    pub fn get(target: &str) -> &'static Host {
+        HOSTS.iter().find(|host| host.target == target).unwrap_or(&OTHER_HOST)
+    }
+}
+
+pub const STEPS: &[Step] = &[
+    Step {
+        name: "fmt",
+        group: Group::Style,
+        about: "Check the formatting.",
+        runs_on: |_| true,
+        run: |ci| ci.miri(&["fmt", "--check"]).run(),
+    },
+    Step {
+        name: "clippy",
+        group: Group::Style,
+        about: "Run clippy with the default features, no features, and all features.",
+        runs_on: |_| true,
+        run: |ci| {
+            for features in [None, Some("--no-default-features"), Some("--all-features")] {
+                ci.miri(&["clippy"]).args(features).args(["--", "-D", "warnings"]).run()?;
+            }
+            Ok(())
+        },
+    },
+    Step {
+        name: "doc",
+        group: Group::Style,
+        about: "Check that the docs build without warnings.",
+        runs_on: |_| true,
+        run: |ci| {
+            ci.miri(&["cargo", "doc", "--document-private-items"])
+                .env("RUSTDOCFLAGS", "-Dwarnings")
+                .run()
+        },
+    },
+    Step {
+        name: "install",
+        group: Group::Host,
+        about: "Install the release build of Miri; the cargo-miri smoke tests use it.",
+        runs_on: |_| true,
+        // This one is built without `--all-features`.
+        run: |ci| ci.miri(&["install"]).env("CARGO_EXTRA_FLAGS", &ci.locked_flags).run(),
+    },
+    Step {
+        name: "build",
+        group: Group::Host,
+        about: "Build the debug build all the tests use, with all features to make sure the \
+            Stacked Borrows consistency check runs.",
+        runs_on: |_| true,
+        run: |ci| ci.miri(&["build", "--all-targets"]).run(),
+    },
+    Step {
+        name: "test",
+        group: Group::Host,
+        about:
+            "Run the ui test suite (with the provenance GC running all the time, on some hosts).",
+        runs_on: |_| true,
+        run: |ci| {
+            if ci.host.gc_stress {
+                ci.miri(&["test"])
+                    .env("MIRIFLAGS", ci.miriflags(&["-Zmiri-provenance-gc=1"])?)
+                    .run()?;
+            } else {
+                ci.miri(&["test"]).run()?;
+            }
+            Ok(())
+        },
+    },
+    Step {
+        name: "test-mir-opt",
+        group: Group::Host,
+        about: "Run the passing ui tests with MIR optimizations cranked up all the way.",
+        runs_on: |host| host.mir_opt,
+        run: |ci| {
+            // `-O` is what cargo passes. Optimizations change diagnostics (mostly backtraces), so we
+            // don't check them. Also error locations change so we don't run the failing tests. We
+            // explicitly enable debug-assertions here, they are disabled by -O but we have tests
+            // which exist to check that we panic on debug assertion failures.
+            ci.miri(&["test", "tests/pass", "tests/panic"])
+                .env(
+                    "MIRIFLAGS",
+                    ci.miriflags(&["-O", "-Zmir-opt-level=4", "-Cdebug-assertions=yes"])?,
+                )
+                .env("MIRI_SKIP_UI_CHECKS", "1")
+                .run()
+        },
+    },
+    Step {
+        name: "many-seeds",
+        group: Group::Host,
+        about: "Run the many-seeds tests.",
+        runs_on: |host| host.many_seeds > 0,
+        run: |ci| ci.many_seeds(None, ci.host.many_seeds),
+    },
+    Step {
+        name: "bench",
+        group: Group::Host,
+        about: "Check that the benchmarks build and run, but only once.",
+        runs_on: |host| host.bench,
+        run: |ci| ci.miri(&["bench"]).env("HYPERFINE", "hyperfine -w0 -r1").run(),
+    },
+    Step {
+        name: "test-cargo-miri",
+        group: Group::Host,
+        about: "Run the cargo-miri test suite (also in an environment that tries to confuse \
+            cargo-miri, on some hosts).",
+        runs_on: |_| true,
+        run: |ci| ci.test_cargo_miri(None, ci.host.cargo_miri_env),
+    },
+    Step {
+        name: "cross",
+        group: Group::Cross,
+        about: "Run the ui, many-seeds and cargo-miri tests for foreign targets.",
+        runs_on: |host| !host.foreign.is_empty(),
+        run: |ci| {
+            for foreign in ci.host.foreign {
+                status!("$ (testing foreign target `{}`)", foreign.target);
+                ci.miri(&["test", "--target", foreign.target]).run()?;
+                ci.many_seeds(Some(foreign.target), foreign.many_seeds)?;
+                ci.test_cargo_miri(Some(foreign.target), foreign.cargo_miri_env)?;
+            }
+            Ok(())
+        },
+    },
+    Step {
+        name: "cross-minimal",
+        group: Group::Cross,
+        about: "Run some ui tests and a cargo-miri smoke test for partially supported targets.",
+        runs_on: |host| !host.minimal.is_empty(),
+        run: |ci| {
+            let toolchain = &ci.e.toolchain_flag();
+            let smoke = path!(ci.e.miri_dir / "test-cargo-miri" / "no-std-smoke" / "Cargo.toml");
+            for minimal in ci.host.minimal {
+                status!("$ (testing partially supported target `{}`)", minimal.target);
+                let tests = minimal.tests.iter().copied().flatten();
+                let mut test = ci.miri(&["test", "--target", minimal.target]).args(tests);
+                if minimal.no_std {
+                    test = test.env("MIRI_NO_STD", "1");
+                }
+                test.run()?;
+                // This uses what the `install` step installed.
+                let target = minimal.target;
+                cmd!(
+                    ci.sh,
+                    "cargo {toolchain...} miri run --manifest-path {smoke} --target {target}"
+                )
+                .run()?;
+            }
+            Ok(())
+        },
+    },
+    Step {
+        name: "cross-build",
+        group: Group::Cross,
+        about: "Build Miri itself for another target than the host.",
+        runs_on: |host| host.cross_build.is_some(),
+        run: |ci| ci.miri(&["build", "--target", ci.host.cross_build.unwrap()]).run(),
+    },
+];
+
+/// The state shared by all steps.
+pub struct Ci {
+    pub e: MiriEnv,
+    pub host: &'static Host,
+    /// The shell that runs the steps, with the environment CI uses. (`e.sh` has the environment of
+    /// cargo invocations in *this* `./miri`, which the nested ones would get confused by.)
+    pub sh: Shell,
+    /// `CARGO_EXTRA_FLAGS` with `--locked`, but not `--all-features`.
+    locked_flags: OsString,
+}
+
+impl Ci {
+    pub fn new(e: MiriEnv) -> Result<Self> {
+        // CI sets `HOST_TARGET`; otherwise we ask rustc.
+        let host = match env::var("HOST_TARGET") {
+            Ok(host) => host,
+            Err(_) => e.host()?.to_owned(),
+        };
+        if !HOSTS.iter().any(|known| known.target == host) {
+            warning!("CI does not run on `{host}`, so only the basic steps do anything");
+        }
+        let host = Host::get(&host);
+
+        let sh = e.build_sh()?.clone();
+        sh.change_dir(&e.miri_dir);
+        // Like CI, deny warnings and make sure the lockfile is up-to-date. CI also disables
+        // incremental compilation, but only to save space in its caches.
+        let ambient = Ambient::get();
+        let rustflags = ambient.rustflags.as_deref().unwrap_or_default().to_string_lossy();
+        sh.set_var("RUSTFLAGS", format!("{rustflags} -D warnings").trim_start());
+        let extra_flags = &ambient.cargo_extra_flags;
+        let locked_flags = format!("{extra_flags} --locked").trim_start().to_owned();
+        sh.set_var("CARGO_EXTRA_FLAGS", format!("{locked_flags} --all-features"));
+        Ok(Ci { e, host, sh, locked_flags: locked_flags.into() })
+    }
+
+    /// A nested `./miri` invocation, with the same toolchain as this one.
+    pub fn miri(&self, args: &[&str]) -> Cmd<'_> {
+        let exe = env::current_exe().unwrap_or_else(|_| path!(self.e.miri_dir / "miri"));
+        let toolchain = self.e.toolchain_flag();
+        let rustc = self.e.rustc.iter().flat_map(|rustc| ["--rustc".as_ref(), rustc.as_os_str()]);
+        let locked = self.e.locked.then_some("--locked");
+        let verbosity = match self.e.verbosity {
+            Verbosity::Quiet => Some("--quiet"),
+            Verbosity::Normal => None,
+            Verbosity::Verbose => Some("--verbose"),
+        };
+        // We do not want the auto-actions to change the files we are checking.
+        cmd!(self.sh, "{exe} {toolchain...} {rustc...} {locked...} {verbosity...} {args...}")
+            .env("MIRI_AUTO_OPS", "no")
+    }
+
+    /// The user's `MIRIFLAGS`, plus `flags` (which replace the user's flags of the same name).
+    fn miriflags(&self, flags: &[&str]) -> Result<OsString> {
+        let existing = env::var_os("MIRIFLAGS").unwrap_or_default();
+        flags.iter().try_fold(existing, |miriflags, flag| miriflags::with_flag(&miriflags, flag))
+    }
+
+    fn many_seeds(&self, target: Option<&str>, seeds: u32) -> Result<()> {
+        if seeds == 0 {
+            return Ok(());
+        }
+        let mut files = fs::read_dir(path!(self.e.miri_dir / "tests" / "many-seeds"))?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>>>()?;
+        files.retain(|file| file.extension().is_some_and(|ext| ext == "rs"));
+        files.sort();
+        let target_flag = target.iter().flat_map(|target| ["--target", target]);
+        for file in files {
+            self.miri(&["run", &format!("--many-seeds=0..{seeds}")])
+                .args(target_flag.clone())
+                .arg(file)
+                .run()?;
+        }
+        Ok(())
+    }
+
+    fn test_cargo_miri(&self, target: Option<&str>, confusing_env: bool) -> Result<()> {
+        let target_flag = target.iter().flat_map(|target| ["--target", target]);
+        self.miri(&["test-cargo-miri"]).args(target_flag.clone()).run()?;
+        if !confusing_env {
+            return Ok(());
+        }
+        status!("$ (testing cargo-miri in a confusing environment)");
+        // RUSTC is the main thing to set (it changes the first argument our wrapper will see).
+        // Unless MIRI is also set, that produces a warning.
+        let rustc = which::which("rustc").context("cannot find rustc")?;
+        let (sysroot, _) =
+            rustc_sysroot_and_libdir(&self.sh, "rustc".as_ref(), self.e.toolchain.as_deref())?;
+        let miri = path!(sysroot / "bin" / "miri");
+        // We entirely ignore other wrappers.
+        let config_dir = path!(self.e.miri_dir / ".cargo");
+        if config_dir.exists() {
+            bail!("{} already exists; please remove it first", config_dir.display());
+        }
+        let dry_run = skip_in_dry_run(format_args!("create {}", config_dir.display()));
+        if !dry_run {
+            fs::create_dir(&config_dir)?;
+            fs::write(
+                path!(config_dir / "config.toml"),
+                "build.rustc-wrapper = \"thisdoesnotexist\"\n",
+            )?;
+        }
+        // The config would break building Miri, so like CI we run the test script directly, with
+        // the cargo-miri and Miri of the `install` step.
+        // On Windows, there is always "python", not "python3" or "python2".
+        let python = if which::which("python3").is_ok() { "python3" } else { "python" };
+        let script = path!(self.e.miri_dir / "test-cargo-miri" / "run-test.py");
+        let result = cmd!(self.sh, "{python} {script} {target_flag...}")
+            .env("RUSTC", rustc)
+            .env("MIRI", miri)
+            .run();
+        if !dry_run {
+            fs::remove_dir_all(&config_dir)?;
+        }
+        Ok(result?)
+    }
+}
+
+/// The steps to run, given `--from` and `--only`.
+pub fn select(from: Option<&str>, only: Option<&str>) -> Result<&'static [Step]> {
+    let find = |name: &str| {
+        STEPS.iter().position(|step| step.name == name).with_context(|| {
+            let names = STEPS.iter().map(|step| &step.name);
+            match crate::args::nearest(name, names) {
+                Some(suggestion) => format!("unknown step `{name}`; did you mean `{suggestion}`?"),
+                None => format!("unknown step `{name}`; see `./miri ci --list`"),
+            }
+        })
+    };
+    Ok(match (from, only) {
+        (None, None) => STEPS,
+        (Some(from), None) => &STEPS[find(from)?..],
+        (None, Some(only)) => {
+            let i = find(only)?;
+            &STEPS[i..=i]
+        }
+        (Some(_), Some(_)) => bail!("`--from` and `--only` cannot be used together"),
+    })
+}
+
+/// How `./miri ci --list` prints the steps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListFormat {
+    /// The names and what the steps do, for humans.
+    Table,
+    /// `StepInfo`s, as JSON.
+    Json,
+    /// Just the names of the steps that would run, one per line, for scripts like `ci/ci.sh`.
+    Names,
+}
+
+/// What `./miri ci --list --json` prints for each step.
+#[derive(Debug, Serialize)]
+pub struct StepInfo {
+    pub name: &'static str,
+    pub group: Group,
+    pub about: &'static str,
+    /// Whether the step does anything on this host.
+    pub runs: bool,
+}
+
+pub fn list(host: &Host) -> Vec<StepInfo> {
+    STEPS
+        .iter()
+        .map(|step| {
+            StepInfo {
+                name: step.name,
+                group: step.group,
+                about: step.about,
+                runs: (step.runs_on)(host),
+            }
+        })
+        .collect()
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else if secs < 60 * 60 {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h {:02}m", secs / (60 * 60), secs / 60 % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selection() {
+        assert_eq!(select(None, None).unwrap().len(), STEPS.len());
+        let names = |steps: &[Step]| steps.iter().map(|step| step.name).collect::<Vec<_>>();
+        assert_eq!(names(select(None, Some("bench")).unwrap()), ["bench"]);
+        assert_eq!(
+            names(select(Some("cross"), None).unwrap()),
+            ["cross", "cross-minimal", "cross-build"]
+        );
+        let err = select(Some("clipy"), None).map(drop).unwrap_err().to_string();
+        assert_eq!(err, "unknown step `clipy`; did you mean `clippy`?");
+        assert!(select(Some("fmt"), Some("fmt")).is_err());
+    }
+
+    #[test]
+    fn steps_and_hosts() {
+        for (i, step) in STEPS.iter().enumerate() {
+            assert!(STEPS[..i].iter().all(|other| other.name != step.name), "{}", step.name);
+        }
+        // The groups come in the order CI runs them.
+        assert!(STEPS.windows(2).all(|w| w[0].group as u8 <= w[1].group as u8));
+
+        let linux = list(Host::get("x86_64-unknown-linux-gnu"));
+        assert!(linux.iter().all(|step| step.runs || step.name.starts_with("cross-")));
+        let unknown = list(Host::get("riscv64gc-unknown-linux-gnu"));
+        let runs: Vec<_> = unknown.iter().filter(|step| step.runs).map(|step| step.name).collect();
+        assert_eq!(runs, ["fmt", "clippy", "doc", "install", "build", "test", "test-cargo-miri"]);
+        let json = serde_json::to_value(&unknown[0]).unwrap();
+        assert_eq!(json["group"], "style");
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(Duration::from_millis(3450)), "3.5s");
+        assert_eq!(format_duration(Duration::from_secs(65)), "1m 05s");
+        assert_eq!(format_duration(Duration::from_secs(2 * 3600 + 7 * 60 + 3)), "2h 07m");
+    }
+}
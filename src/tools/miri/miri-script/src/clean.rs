@@ -0,0 +1,225 @@
+//! Finding (and removing) the things `./miri` leaves on disk.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use dunce::canonicalize;
+use walkdir::WalkDir;
+
+use crate::record::skip_in_dry_run;
+use crate::util::{Ambient, MiriEnv, BUILD_FINGERPRINTS_DIR, SYSROOTS_DIR};
+
+/// Directories in the target dir that hold caches (see `sync.rs`) rather than build output.
+const CACHE_DIRS: &[&str] = &["josh", "rust-commits.git"];
+
+/// What `./miri` itself puts in the target dir, besides the caches and the sysroots. Everything
+/// else there is cargo's, and might not be only ours if the target dir is shared.
+const SCRIPT_ENTRIES: &[&str] = &[
+    BUILD_FINGERPRINTS_DIR,
+    "toolchains",
+    "cargo-miri",
+    "bench",
+    "bench-history.jsonl",
+    "bless",
+    "compare-rev",
+    "fuzz",
+    "repro",
+    "completions",
+    "miri-script.log",
+    ".metrics.jsonl",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArtifactKind {
+    /// Build output.
+    Target,
+    /// The sysroots built by `cargo miri setup`.
+    Sysroot,
+    /// Caches for the rustc sync.
+    Caches,
+}
+
+impl FromStr for ArtifactKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "target" => ArtifactKind::Target,
+            "sysroot" => ArtifactKind::Sysroot,
+            "caches" => ArtifactKind::Caches,
+            _ => bail!("unknown kind of artifact `{s}`, expected one of: target, sysroot, caches"),
+        })
+    }
+}
+
+impl fmt::Display for ArtifactKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ArtifactKind::Target => "target",
+            ArtifactKind::Sysroot => "sysroot",
+            ArtifactKind::Caches => "caches",
+        })
+    }
+}
+
+pub struct Artifact {
+    pub kind: ArtifactKind,
+    pub path: PathBuf,
+    /// The total size of all files in it, in bytes.
+    pub size: u64,
+    /// Whether we are allowed to delete it: we only ever delete things in the Miri checkout, and
+    /// what `./miri` created in a target dir outside of it.
+    pub deletable: bool,
+}
+
+/// Formats a number of bytes for humans.
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+fn disk_usage(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok()?.metadata().ok())
+        .filter(|meta| meta.is_file())
+        .map(|meta| meta.len())
+        .sum()
+}
+
+#[doc(hidden)]
+impl MiriEnv {
+    /// Lists everything `./miri` created that can be cleaned up.
+    pub fn artifacts(&self) -> Result<Vec<Artifact>> {
+        let mut paths = Vec::new();
+        if self.target_dir.exists() {
+            for entry in fs::read_dir(&self.target_dir)? {
+                let path = entry?.path();
+                let name = path.file_name().unwrap().to_string_lossy();
+                if CACHE_DIRS.contains(&&*name) {
+                    paths.push((ArtifactKind::Caches, path));
+                } else if name == SYSROOTS_DIR {
+                    paths.push((ArtifactKind::Sysroot, path));
+                } else if name == "toolchains" && path.is_dir() {
+                    // Listed separately, since there is one per toolchain `./miri test` ran with.
+                    for entry in fs::read_dir(&path)? {
+                        paths.push((ArtifactKind::Target, entry?.path()));
+                    }
+                } else {
+                    paths.push((ArtifactKind::Target, path));
+                }
+            }
+        }
+        // This is where `cargo miri setup` puts the sysroot when not run by us. If `MIRI_SYSROOT`
+        // is set, the user manages the sysroot, not us.
+        let sysroot = directories::ProjectDirs::from("org", "rust-lang", "miri")
+            .map(|dirs| dirs.cache_dir().to_owned())
+            .filter(|dir| Ambient::get().miri_sysroot().is_none() && dir.exists());
+        if let Some(sysroot) = sysroot {
+            paths.push((ArtifactKind::Sysroot, sysroot));
+        }
+        paths
+            .into_iter()
+            .map(|(kind, path)| {
+                let deletable = self.may_delete(&path)?;
+                Ok(Artifact { kind, size: disk_usage(&path), path, deletable })
+            })
+            .collect()
+    }
+
+    fn may_delete(&self, path: &Path) -> Result<bool> {
+        let path = canonicalize(path)?;
+        let miri_dir = canonicalize(&self.miri_dir)?;
+        // The target dir may not exist (anymore).
+        let target_dir = canonicalize(&self.target_dir).ok();
+        Ok(may_delete(&path, &miri_dir, target_dir.as_deref()))
+    }
+
+    /// Deletes an artifact returned by `artifacts`.
+    pub fn delete_artifact(&self, artifact: &Artifact) -> Result<()> {
+        // Check again, in case something changed in the meantime.
+        if !artifact.deletable || !self.may_delete(&artifact.path)? {
+            bail!("refusing to delete {}", artifact.path.display());
+        }
+        if skip_in_dry_run(format_args!("delete {}", artifact.path.display())) {
+            return Ok(());
+        }
+        if artifact.path.is_dir() {
+            fs::remove_dir_all(&artifact.path)
+        } else {
+            fs::remove_file(&artifact.path)
+        }
+        .with_context(|| format!("failed to delete {}", artifact.path.display()))
+    }
+}
+
+/// Whether `path` is strictly inside `miri_dir`, or one of the entries `./miri` creates in
+/// `target_dir`. A target dir outside the Miri checkout may be shared with other projects, so we
+/// leave the build output of cargo there alone. All paths must be canonical.
+fn may_delete(path: &Path, miri_dir: &Path, target_dir: Option<&Path>) -> bool {
+    if path != miri_dir && path.starts_with(miri_dir) {
+        return true;
+    }
+    let Some(entry) = target_dir.and_then(|dir| path.strip_prefix(dir).ok()) else {
+        return false;
+    };
+    entry.components().next().is_some_and(|first| {
+        let first = first.as_os_str();
+        [SCRIPT_ENTRIES, CACHE_DIRS, &[SYSROOTS_DIR]].concat().iter().any(|name| first == *name)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1024), "1.0 KiB");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+        assert_eq!(human_size(u64::MAX), "16777216.0 TiB");
+    }
+
+    #[test]
+    fn artifact_kinds() {
+        for kind in [ArtifactKind::Target, ArtifactKind::Sysroot, ArtifactKind::Caches] {
+            assert_eq!(kind.to_string().parse::<ArtifactKind>().unwrap(), kind);
+        }
+        assert!("all".parse::<ArtifactKind>().is_err());
+    }
+
+    #[test]
+    fn deletable_paths() {
+        let miri_dir = Path::new("/src/miri");
+        // The default target dir is ours entirely.
+        let target_dir = Some(Path::new("/src/miri/target"));
+        assert!(may_delete(Path::new("/src/miri/target/debug"), miri_dir, target_dir));
+        assert!(!may_delete(miri_dir, miri_dir, target_dir));
+        assert!(!may_delete(Path::new("/src/miri-old/target"), miri_dir, target_dir));
+        // In one elsewhere, only what we created ourselves is.
+        let target_dir = Some(Path::new("/shared/target"));
+        assert!(!may_delete(Path::new("/shared/target/debug"), miri_dir, target_dir));
+        assert!(!may_delete(Path::new("/shared/target"), miri_dir, target_dir));
+        assert!(!may_delete(Path::new("/shared/target.bak"), miri_dir, target_dir));
+        for path in ["miri-sysroots", "josh", "toolchains/nightly", "repro", "miri-script.log"] {
+            assert!(may_delete(&Path::new("/shared/target").join(path), miri_dir, target_dir));
+        }
+        assert!(!may_delete(Path::new("/shared/target/debug"), miri_dir, None));
+    }
+}
@@ -0,0 +1,342 @@
+//! Showing the commands we run so that they can be copied into the user's shell to run them
+//! again, with the quoting that shell understands.
+
+use std::env;
+use std::ffi::OsStr;
+use std::path::Path;
+
+use crate::util::ShellKind;
+
+/// The kind of shell the user (most likely) runs us from: on Windows that is PowerShell, unless
+/// `PROMPT` is set, which only cmd does.
+pub fn current_shell() -> ShellKind {
+    if !cfg!(windows) {
+        ShellKind::Sh
+    } else if env::var_os("PROMPT").is_some() {
+        ShellKind::Cmd
+    } else {
+        ShellKind::Powershell
+    }
+}
+
+/// Renders the command `argv` (the program and its arguments), run in `cwd` with the env vars in
+/// `env` set (or removed, for `None`), so that it can be pasted into the user's shell.
+pub fn display_command(
+    argv: &[impl AsRef<OsStr>],
+    cwd: Option<&Path>,
+    env: &[(impl AsRef<OsStr>, Option<impl AsRef<OsStr>>)],
+) -> String {
+    render(current_shell(), argv, cwd, env)
+}
+
+fn render(
+    shell: ShellKind,
+    argv: &[impl AsRef<OsStr>],
+    cwd: Option<&Path>,
+    env: &[(impl AsRef<OsStr>, Option<impl AsRef<OsStr>>)],
+) -> String {
+    let lossy = |s: &OsStr| s.to_string_lossy().into_owned();
+    let mut words = Vec::new();
+    if let Some(cwd) = cwd {
+        let cwd = lossy(cwd.as_os_str());
+        words.push(match shell {
+            ShellKind::Sh | ShellKind::Fish => format!("cd {} &&", quote(shell, &cwd)),
+            ShellKind::Powershell => format!("Set-Location {};", quote(shell, &cwd)),
+            ShellKind::Cmd => format!("cd /d {} &&", quote(shell, &cwd)),
+        });
+    }
+    let env = env
+        .iter()
+        .map(|(var, value)| (lossy(var.as_ref()), value.as_ref().map(|v| lossy(v.as_ref()))));
+    match shell {
+        ShellKind::Sh | ShellKind::Fish => {
+            let env: Vec<_> = env.collect();
+            // Removing a variable needs `env`; setting one does not.
+            if env.iter().any(|(_, value)| value.is_none()) {
+                words.push("env".to_owned());
+            }
+            for (var, value) in env {
+                words.push(match value {
+                    Some(value) => format!("{var}={}", quote(shell, &value)),
+                    None => format!("-u {var}"),
+                });
+            }
+        }
+        ShellKind::Powershell =>
+            for (var, value) in env {
+                words.push(match value {
+                    // An assignment needs a string, even for plain words.
+                    Some(value) => format!("$env:{var}={};", powershell_string(&value)),
+                    None => format!("Remove-Item Env:{var};"),
+                });
+            },
+        ShellKind::Cmd =>
+            for (var, value) in env {
+                // `set "X=y"` takes everything up to the last quote literally.
+                words.push(format!("set \"{var}={}\" &&", value.unwrap_or_default()));
+            },
+    }
+    let mut argv = argv.iter().map(|arg| lossy(arg.as_ref()));
+    if let Some(program) = argv.next() {
+        let quoted = quote(shell, &program);
+        // PowerShell takes a quoted program for a string, unless it is told to call it.
+        if shell == ShellKind::Powershell && quoted != program {
+            words.push("&".to_owned());
+        }
+        words.push(quoted);
+    }
+    words.extend(argv.map(|arg| quote(shell, &arg)));
+    words.join(" ")
+}
+
+/// Quotes `arg` so that `shell` passes it on to the program as is. This is also how `./miri env`
+/// quotes the values of the variables (see `ShellKind::export`).
+pub fn quote(shell: ShellKind, arg: &str) -> String {
+    match shell {
+        ShellKind::Sh => shell_words::quote(arg).into_owned(),
+        ShellKind::Fish => {
+            let plain = |c: char| c.is_ascii_alphanumeric() || "_-./:=+,@".contains(c);
+            if !arg.is_empty() && arg.chars().all(plain) {
+                arg.to_owned()
+            } else {
+                // Unlike in POSIX shells, backslashes escape quotes and themselves inside of
+                // single quotes.
+                format!("'{}'", arg.replace('\\', r"\\").replace('\'', r"\'"))
+            }
+        }
+        ShellKind::Powershell => {
+            let plain = |c: char| c.is_ascii_alphanumeric() || "_-./\\:=+".contains(c);
+            if !arg.is_empty() && arg.chars().all(plain) && arg != "--%" {
+                arg.to_owned()
+            } else {
+                powershell_string(arg)
+            }
+        }
+        ShellKind::Cmd => {
+            let quoted = quote_windows(arg);
+            // When we need to escape a quote, cmd loses track of which parts are quoted (it does
+            // not know about backslashes), so it has to leave all special characters alone.
+            if arg.contains('"') {
+                escape_cmd(&quoted)
+            } else {
+                quoted
+            }
+        }
+    }
+}
+
+/// `s` as a literal PowerShell string, which is what assignments need even for plain words.
+pub fn powershell_string(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// Quotes `arg` the way Windows programs split their command line (see `CommandLineToArgvW`).
+fn quote_windows(arg: &str) -> String {
+    let special = |c: char| c.is_whitespace() || "\"&|<>^()%!".contains(c);
+    if !arg.is_empty() && !arg.contains(special) {
+        return arg.to_owned();
+    }
+    let mut quoted = String::from('"');
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                // The backslashes before a quote escape each other, and one more escapes it, so
+                // we double them and add one.
+                quoted.extend(std::iter::repeat_n('\\', backslashes + 1));
+                backslashes = 0;
+            }
+            _ => backslashes = 0,
+        }
+        quoted.push(c);
+    }
+    // The closing quote must not get escaped, so the backslashes before it are doubled too.
+    quoted.extend(std::iter::repeat_n('\\', backslashes));
+    quoted.push('"');
+    quoted
+}
+
+/// Escapes the characters cmd treats specially, with `^`.
+fn escape_cmd(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        if "\"&|<>^()%!".contains(c) {
+            escaped.push('^');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Splits a PowerShell command line the way PowerShell does, for the quoting we use.
+    fn split_powershell(line: &str) -> Vec<String> {
+        let mut words = Vec::new();
+        let mut chars = line.trim_start_matches("& ").chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                ' ' => continue,
+                '\'' => {
+                    let mut word = String::new();
+                    while let Some(c) = chars.next() {
+                        if c == '\'' && chars.next_if_eq(&'\'').is_none() {
+                            break;
+                        }
+                        word.push(c);
+                    }
+                    words.push(word);
+                }
+                c => {
+                    let mut word = String::from(c);
+                    while let Some(c) = chars.next_if(|&c| c != ' ') {
+                        word.push(c);
+                    }
+                    words.push(word);
+                }
+            }
+        }
+        words
+    }
+
+    /// Splits a fish command line the way fish does, for the quoting we use: inside of single
+    /// quotes, a backslash only escapes a quote or another backslash.
+    fn split_fish(line: &str) -> Vec<String> {
+        let mut words = Vec::new();
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                ' ' => continue,
+                '\'' => {
+                    let mut word = String::new();
+                    while let Some(c) = chars.next() {
+                        match c {
+                            '\'' => break,
+                            '\\' =>
+                                word.extend(chars.next_if(|&c| c == '\\' || c == '\'').or(Some(c))),
+                            c => word.push(c),
+                        }
+                    }
+                    words.push(word);
+                }
+                c => {
+                    let mut word = String::from(c);
+                    while let Some(c) = chars.next_if(|&c| c != ' ') {
+                        word.push(c);
+                    }
+                    words.push(word);
+                }
+            }
+        }
+        words
+    }
+
+    /// Splits a cmd command line: first the way cmd removes its escapes, then the way
+    /// `CommandLineToArgvW` splits what is left (treating the program like any other argument).
+    fn split_cmd(line: &str) -> Vec<String> {
+        let mut unescaped = String::new();
+        let (mut chars, mut quoted) = (line.chars(), false);
+        while let Some(c) = chars.next() {
+            match c {
+                '^' if !quoted => unescaped.extend(chars.next()),
+                '"' => {
+                    quoted = !quoted;
+                    unescaped.push(c);
+                }
+                c => unescaped.push(c),
+            }
+        }
+        let mut words = Vec::new();
+        let mut chars = unescaped.chars().peekable();
+        loop {
+            while chars.next_if_eq(&' ').is_some() {}
+            if chars.peek().is_none() {
+                return words;
+            }
+            let (mut word, mut quoted, mut backslashes) = (String::new(), false, 0);
+            while let Some(c) = chars.next_if(|&c| quoted || c != ' ') {
+                match c {
+                    '\\' => backslashes += 1,
+                    '"' => {
+                        word.extend(std::iter::repeat_n('\\', backslashes / 2));
+                        if backslashes % 2 == 1 {
+                            word.push('"');
+                        } else {
+                            quoted = !quoted;
+                        }
+                        backslashes = 0;
+                    }
+                    c => {
+                        word.extend(std::iter::repeat_n('\\', backslashes));
+                        backslashes = 0;
+                        word.push(c);
+                    }
+                }
+            }
+            word.extend(std::iter::repeat_n('\\', backslashes));
+            words.push(word);
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let argvs: &[&[&str]] = &[
+            &["cargo", "test", "--manifest-path", "C:\\my dir\\Cargo.toml"],
+            &["C:\\Program Files\\rustfmt.exe", "--edition=2021", "src/lib.rs"],
+            &["miri", "--", "a b", "", "it's", "say \"hi\"", "a & b", "100%"],
+            &["x", "trailing\\", "dir\\ \\", "\\\"", "--%", "$HOME", "a;b", "(1)", "^"],
+        ];
+        let none: &[(&str, Option<&str>)] = &[];
+        for &argv in argvs {
+            let sh = render(ShellKind::Sh, argv, None, none);
+            assert_eq!(shell_words::split(&sh).unwrap(), argv, "sh: {sh}");
+            let fish = render(ShellKind::Fish, argv, None, none);
+            assert_eq!(split_fish(&fish), argv, "fish: {fish}");
+            let powershell = render(ShellKind::Powershell, argv, None, none);
+            assert_eq!(split_powershell(&powershell), argv, "powershell: {powershell}");
+            let cmd = render(ShellKind::Cmd, argv, None, none);
+            assert_eq!(split_cmd(&cmd), argv, "cmd: {cmd}");
+        }
+    }
+
+    #[test]
+    fn quotes_like_exports() {
+        let value = r"it's a \\test\";
+        assert_eq!(quote(ShellKind::Sh, value), r"'it'\''s a \\test\'");
+        assert_eq!(quote(ShellKind::Fish, value), r"'it\'s a \\\\test\\'");
+        assert_eq!(quote(ShellKind::Powershell, value), r"'it''s a \\test\'");
+        // `./miri env` quotes the same way.
+        for shell in [ShellKind::Sh, ShellKind::Fish, ShellKind::Powershell] {
+            let export = shell.export("A", value).unwrap();
+            assert!(export.ends_with(&quote(shell, value)), "{export}");
+        }
+    }
+
+    #[test]
+    fn environments() {
+        let argv = ["cargo", "build"];
+        let env = [("MIRI_SYSROOT", Some("/tmp/my sysroot")), ("RUSTC", None)];
+        let cwd = Some(Path::new("/src/my miri"));
+        assert_eq!(
+            render(ShellKind::Sh, &argv, cwd, &env),
+            "cd '/src/my miri' && env MIRI_SYSROOT='/tmp/my sysroot' -u RUSTC cargo build"
+        );
+        assert_eq!(
+            render(ShellKind::Sh, &argv, None, &env[..1]),
+            "MIRI_SYSROOT='/tmp/my sysroot' cargo build"
+        );
+        assert_eq!(
+            render(ShellKind::Powershell, &argv, cwd, &env),
+            "Set-Location '/src/my miri'; $env:MIRI_SYSROOT='/tmp/my sysroot'; \
+             Remove-Item Env:RUSTC; cargo build"
+        );
+        assert_eq!(
+            render(ShellKind::Cmd, &argv, cwd, &env),
+            "cd /d \"/src/my miri\" && set \"MIRI_SYSROOT=/tmp/my sysroot\" && set \"RUSTC=\" && \
+             cargo build"
+        );
+    }
+}
@@ -1,76 +1,50 @@
 use std::env;
 use std::ffi::{OsStr, OsString};
-use std::io::Write;
+use std::fs;
+use std::io::{IsTerminal, Write};
+use std::num::NonZeroUsize;
 use std::ops::Not;
 use std::ops::Range;
-use std::path::PathBuf;
-use std::process;
-use std::thread;
-use std::time;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 use anyhow::{anyhow, bail, Context, Result};
 use path_macro::path;
-use walkdir::WalkDir;
-use xshell::{cmd, Shell};
+use xshell::Shell;
 
+use crate::bench::{CompareRev, WallBench};
+use crate::clean::{human_size, ArtifactKind};
+use crate::completions::{self, CompletionShell};
+use crate::debug::{self, Debugger};
+use crate::events::{self, Event};
+use crate::ide::{self, Editor};
+use crate::inject::Injected;
+use crate::output::{self, error, note, plain, status, success, warning};
+use crate::record::{self, cmd, is_dry_run, skip_in_dry_run};
+use crate::remote::{self, Workers};
+use crate::repro::Repro;
+use crate::sync::*;
+use crate::tee;
 use crate::util::*;
-use crate::Command;
-
-/// Used for rustc syncs.
-const JOSH_FILTER: &str =
-    ":rev(75dd959a3a40eb5b4574f8d2e23aa6efbeb33573:prefix=src/tools/miri):/src/tools/miri";
-const JOSH_PORT: &str = "42042";
-
-impl MiriEnv {
-    /// Returns the location of the sysroot.
-    ///
-    /// If the target is None the sysroot will be built for the host machine.
-    fn build_miri_sysroot(&mut self, quiet: bool, target: Option<&OsStr>) -> Result<PathBuf> {
-        if let Some(miri_sysroot) = self.sh.var_os("MIRI_SYSROOT") {
-            // Sysroot already set, use that.
-            return Ok(miri_sysroot.into());
-        }
-        let manifest_path = path!(self.miri_dir / "cargo-miri" / "Cargo.toml");
-        let Self { toolchain, cargo_extra_flags, .. } = &self;
-
-        // Make sure everything is built. Also Miri itself.
-        self.build(path!(self.miri_dir / "Cargo.toml"), &[], quiet)?;
-        self.build(&manifest_path, &[], quiet)?;
-
-        let target_flag =
-            if let Some(target) = target { vec![OsStr::new("--target"), target] } else { vec![] };
-        let target_flag = &target_flag;
+use crate::{bench, bless, ci, cross, fuzz, junit, metrics, sarif, squash};
+use crate::{doctor, Command, GlobalArgs, PullAction};
+use crate::{watch, wrapper};
+use miri_script::{BuildOptions, BuildOutput, FormatOptions, RunOptions};
 
-        if !quiet {
-            if let Some(target) = target {
-                eprintln!("$ (building Miri sysroot for {})", target.to_string_lossy());
-            } else {
-                eprintln!("$ (building Miri sysroot)");
-            }
-        }
+/// The commit messages used by `rustc-pull`.
+const PREPARING_COMMIT_MESSAGE: &str = "Preparing for merge from rustc";
+const MERGE_COMMIT_MESSAGE: &str = "Merge from rustc";
 
-        let output = cmd!(self.sh,
-            "cargo +{toolchain} --quiet run {cargo_extra_flags...} --manifest-path {manifest_path} --
-             miri setup --print-sysroot {target_flag...}"
-        ).read();
-        let Ok(output) = output else {
-            // Run it again (without `--print-sysroot` or `--quiet`) so the user can see the error.
-            cmd!(
-                self.sh,
-                "cargo +{toolchain} run {cargo_extra_flags...} --manifest-path {manifest_path} --
-                miri setup {target_flag...}"
-            )
-            .run()
-            .with_context(|| "`cargo miri setup` failed")?;
-            panic!("`cargo miri setup` didn't fail again the 2nd time?");
-        };
-        self.sh.set_var("MIRI_SYSROOT", &output);
-        Ok(output.into())
-    }
+/// Counts the root commits of `HEAD`.
+fn num_root_commits(sh: &Shell) -> Result<u32> {
+    Ok(cmd!(sh, "git rev-list HEAD --max-parents=0 --count")
+        .read()
+        .context("failed to determine the number of root commits")?
+        .parse::<u32>()?)
 }
 
 impl Command {
-    fn auto_actions() -> Result<()> {
+    fn auto_actions(global: &GlobalArgs) -> Result<()> {
         if env::var_os("MIRI_AUTO_OPS").is_some_and(|x| x == "no") {
             return Ok(());
         }
@@ -86,116 +60,490 @@ fn auto_actions() -> Result<()> {
             Self::toolchain(vec![])?;
         }
         if auto_fmt {
-            Self::fmt(vec![])?;
+            Self::fmt(false, vec![], global)?;
         }
         if auto_clippy {
-            Self::clippy(vec![])?;
+            Self::clippy(vec![], global)?;
         }
 
         Ok(())
     }
 
-    fn start_josh() -> Result<impl Drop> {
-        // Determine cache directory.
-        let local_dir = {
-            let user_dirs =
-                directories::ProjectDirs::from("org", "rust-lang", "miri-josh").unwrap();
-            user_dirs.cache_dir().to_owned()
-        };
-
-        // Start josh, silencing its output.
-        let mut cmd = process::Command::new("josh-proxy");
-        cmd.arg("--local").arg(local_dir);
-        cmd.arg("--remote").arg("https://github.com");
-        cmd.arg("--port").arg(JOSH_PORT);
-        cmd.arg("--no-background");
-        cmd.stdout(process::Stdio::null());
-        cmd.stderr(process::Stdio::null());
-        let josh = cmd.spawn().context("failed to start josh-proxy, make sure it is installed")?;
-        // Give it some time so hopefully the port is open. (100ms was not enough.)
-        thread::sleep(time::Duration::from_millis(200));
-
-        // Create a wrapper that stops it on drop.
-        struct Josh(process::Child);
-        impl Drop for Josh {
-            fn drop(&mut self) {
-                #[cfg(unix)]
-                {
-                    // Try to gracefully shut it down.
-                    process::Command::new("kill")
-                        .args(["-s", "INT", &self.0.id().to_string()])
-                        .output()
-                        .expect("failed to SIGINT josh-proxy");
-                    // Sadly there is no "wait with timeout"... so we just give it some time to finish.
-                    thread::sleep(time::Duration::from_millis(100));
-                    // Now hopefully it is gone.
-                    if self.0.try_wait().expect("failed to wait for josh-proxy").is_some() {
-                        return;
-                    }
-                }
-                // If that didn't work (or we're not on Unix), kill it hard.
-                eprintln!(
-                    "I have to kill josh-proxy the hard way, let's hope this does not break anything."
-                );
-                self.0.kill().expect("failed to SIGKILL josh-proxy");
-            }
-        }
-
-        Ok(Josh(josh))
-    }
-
-    pub fn exec(self) -> Result<()> {
+    pub fn exec(self, global: &GlobalArgs) -> Result<()> {
         // First, and crucially only once, run the auto-actions -- but not for all commands.
         match &self {
             Command::Install { .. }
             | Command::Build { .. }
             | Command::Check { .. }
             | Command::Test { .. }
+            | Command::TestCargoMiri { .. }
+            | Command::Bless { .. }
             | Command::Run { .. }
+            | Command::Debug { .. }
+            | Command::Fuzz { .. }
             | Command::Fmt { .. }
             | Command::Clippy { .. }
-            | Command::Cargo { .. } => Self::auto_actions()?,
+            | Command::Cargo { .. }
+            | Command::Watch { .. } => Self::auto_actions(global)?,
             | Command::Toolchain { .. }
             | Command::Bench { .. }
             | Command::RustcPull { .. }
-            | Command::RustcPush { .. } => {}
+            | Command::RustcPush { .. }
+            | Command::RustcStatus { .. }
+            | Command::Clean { .. }
+            | Command::Env { .. }
+            | Command::Doctor { .. }
+            | Command::IdeSetup { .. }
+            | Command::SelfInstallWrapper
+            | Command::Metrics { .. }
+            | Command::Ci { .. }
+            | Command::Squash { .. }
+            | Command::Completions { .. } => {}
         }
         // Then run the actual command.
         match self {
-            Command::Install { flags } => Self::install(flags),
-            Command::Build { flags } => Self::build(flags),
-            Command::Check { flags } => Self::check(flags),
-            Command::Test { bless, flags, target } => Self::test(bless, flags, target),
-            Command::Run { dep, verbose, many_seeds, flags } =>
-                Self::run(dep, verbose, many_seeds, flags),
-            Command::Fmt { flags } => Self::fmt(flags),
-            Command::Clippy { flags } => Self::clippy(flags),
-            Command::Cargo { flags } => Self::cargo(flags),
-            Command::Bench { target, benches } => Self::bench(target, benches),
+            Command::Install { strip, flags } => Self::install(strip, flags, global),
+            Command::Build { flags } => Self::build(flags, global),
+            Command::Check { flags } => Self::check(flags, global),
+            Command::Test { bless, doc, toolchains, flags, target, junit } => {
+                if junit.is_some() {
+                    junit::start();
+                }
+                let result = if toolchains.is_empty() {
+                    Self::test(bless, doc, flags, target, global)
+                } else {
+                    Self::test_matrix(bless, doc, toolchains, flags, target, global)
+                };
+                match junit {
+                    Some(path) => junit::finish(&path, result),
+                    None => result,
+                }
+            }
+            Command::TestCargoMiri { target, bless, keep_tmp, filters } =>
+                Self::test_cargo_miri(target, bless, keep_tmp, filters, global),
+            Command::Bless { suites, target, jobs } => Self::bless(suites, target, jobs, global),
+            Command::Run { many_seeds: Some(seeds), workers: Some(workers), .. } =>
+                Self::run_remote(workers, seeds, global),
+            Command::Run {
+                dep,
+                verbose,
+                many_seeds,
+                jobs,
+                max_load,
+                repro_for,
+                inject,
+                flags,
+                ..
+            } => {
+                let mut options = RunOptions::default();
+                options.dep = dep;
+                options.verbose = verbose;
+                options.flags = flags;
+                options.jobs = jobs;
+                options.max_load = max_load;
+                options.inject = inject;
+                Self::run(options, many_seeds, repro_for, global)
+            }
+            Command::Debug { debugger, flags } => Self::debug(debugger, flags, global),
+            Command::Fuzz { target, time, list } => Self::fuzz(target, time, list, global),
+            Command::Fmt { no_ignore, flags } => Self::fmt(no_ignore, flags, global),
+            Command::Clippy { flags, sarif: None } => Self::clippy(flags, global),
+            Command::Clippy { flags, sarif: Some(path) } => {
+                sarif::start();
+                sarif::finish(&path, Self::clippy(flags, global))
+            }
+            Command::Cargo { krate, flags } => Self::cargo(krate, flags, global),
+            Command::Bench { history: Some(n), benches, threshold, .. } =>
+                Self::bench_history(benches, n, threshold, global),
+            Command::Bench {
+                target,
+                wall: Some(wall),
+                against,
+                save_baseline,
+                threshold,
+                fail_on_regression,
+                ..
+            } =>
+                Self::bench_wall(
+                    wall,
+                    target,
+                    against,
+                    save_baseline,
+                    threshold,
+                    fail_on_regression,
+                    global,
+                ),
+            Command::Bench {
+                target,
+                benches,
+                compare_rev: Some(compare),
+                threshold,
+                fail_on_regression,
+                ..
+            } =>
+                Self::bench_compare_rev(
+                    compare,
+                    benches,
+                    target,
+                    threshold,
+                    fail_on_regression,
+                    global,
+                ),
+            Command::Bench {
+                target,
+                benches,
+                history: None,
+                wall: None,
+                compare_rev: None,
+                against,
+                save_baseline,
+                threshold,
+                fail_on_regression,
+            } =>
+                Self::bench(
+                    target,
+                    benches,
+                    against,
+                    save_baseline,
+                    threshold,
+                    fail_on_regression,
+                    global,
+                ),
             Command::Toolchain { flags } => Self::toolchain(flags),
-            Command::RustcPull { commit } => Self::rustc_pull(commit.clone()),
-            Command::RustcPush { github_user, branch } => Self::rustc_push(github_user, branch),
+            Command::RustcPull {
+                commit,
+                allow_downgrade,
+                verify,
+                action,
+                allow_any_josh,
+                retries,
+            } => {
+                Self::rustc_pull(commit, allow_downgrade, action, allow_any_josh, retries)?;
+                if verify && action != Some(PullAction::Abort) {
+                    Self::verify_pull()?;
+                }
+                Ok(())
+            }
+            Command::RustcPush {
+                github_user,
+                remote,
+                branch,
+                force_with_lease,
+                dry_run,
+                allow_any_josh,
+                retries,
+            } => {
+                let sh = ScriptCtx::new()?.miri_dir_shell();
+                let target = PushTarget::resolve(&sh, github_user.as_deref(), remote.as_deref())?;
+                if dry_run {
+                    Self::rustc_push_dry_run(
+                        target,
+                        branch,
+                        force_with_lease,
+                        allow_any_josh,
+                        retries,
+                    )
+                } else {
+                    Self::rustc_push(target, branch, force_with_lease, allow_any_josh, retries)
+                }
+            }
+            Command::RustcStatus { json, retries } => Self::rustc_status(json, retries),
+            Command::Squash { onto, allow_merges } => Self::squash(onto, allow_merges),
+            Command::Clean { only, yes } => Self::clean(only, yes, global),
+            Command::Env { shell, json } => Self::env(shell, json, global),
+            Command::Doctor { json } => Self::doctor(json, global),
+            Command::IdeSetup { editor, print } => Self::ide_setup(editor, print, global),
+            Command::SelfInstallWrapper => wrapper::install(&miri_dir()?),
+            Command::Watch { command } => Self::watch(command, global),
+            Command::Ci { host_only, skip_style, from, only, list } =>
+                Self::ci(host_only, skip_style, from, only, list, global),
+            Command::Metrics { last, command, steps } =>
+                metrics::print(&ScriptCtx::new()?.target_dir, last, command.as_deref(), steps),
+            Command::Completions { shell, complete, words } =>
+                Self::completions(shell, complete, words, global),
         }
     }
 
+    fn clean(only: Option<ArtifactKind>, yes: bool, global: &GlobalArgs) -> Result<()> {
+        let e = MiriEnv::new(global)?;
+        let artifacts = e
+            .artifacts()?
+            .into_iter()
+            .filter(|a| only.is_none_or(|kind| a.kind == kind))
+            .collect::<Vec<_>>();
+        if artifacts.is_empty() {
+            println!("Nothing to clean.");
+            return Ok(());
+        }
+        for a in &artifacts {
+            let note = if a.deletable { "" } else { " (not only ours, not deleted)" };
+            println!("{:>7}  {:>10}  {}{note}", a.kind, human_size(a.size), a.path.display());
+        }
+        let (deletable, kept): (Vec<_>, Vec<_>) = artifacts.iter().partition(|a| a.deletable);
+        if deletable.is_empty() {
+            println!("Nothing to clean.");
+            return Ok(());
+        }
+        let total = deletable.iter().map(|a| a.size).sum();
+        if !yes {
+            if !std::io::stdin().is_terminal() {
+                bail!("not deleting anything without confirmation; use `--yes`");
+            }
+            print!("Delete {} items ({})? [y/N] ", deletable.len(), human_size(total));
+            std::io::stdout().flush()?;
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer)?;
+            if !answer.trim().eq_ignore_ascii_case("y") {
+                return Ok(());
+            }
+        }
+        for a in deletable {
+            e.delete_artifact(a)?;
+        }
+        println!("Freed {}.", human_size(total));
+        if kept.iter().any(|a| a.kind == ArtifactKind::Sysroot) {
+            println!("The Miri sysroot can be removed with `cargo miri clean`.");
+        }
+        Ok(())
+    }
+
+    fn env(shell: ShellKind, json: bool, global: &GlobalArgs) -> Result<()> {
+        let e = MiriEnv::new(global)?;
+        // Only the variables go to stdout, so that the output can be `eval`ed.
+        e.print_toolchain();
+        let vars = e
+            .exported_vars()?
+            .into_iter()
+            .map(|(key, value)| {
+                let value = value
+                    .into_string()
+                    .map_err(|v| anyhow!("the value of {key} is not valid UTF-8: {v:?}"))?;
+                Ok((key, value))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if json {
+            let vars: serde_json::Map<_, _> =
+                vars.into_iter().map(|(k, v)| (k.to_owned(), v.into())).collect();
+            println!("{}", serde_json::to_string_pretty(&vars)?);
+        } else {
+            for (key, value) in vars {
+                println!("{}", shell.export(key, &value)?);
+            }
+        }
+        Ok(())
+    }
+
+    fn watch(command: Vec<OsString>, global: &GlobalArgs) -> Result<()> {
+        use itertools::Itertools;
+
+        let e = MiriEnv::new(global)?;
+        let roots = watch::WATCHED_DIRS.iter().map(|dir| path!(e.miri_dir / dir)).collect();
+        if skip_in_dry_run(format_args!(
+            "run `{}` whenever files change",
+            command.iter().map(|arg| arg.to_string_lossy()).join(" ")
+        )) {
+            return Ok(());
+        }
+        let mut watcher = watch::Watcher::new(roots, vec![e.target_dir.clone()])?;
+        let display =
+            format!("./miri {}", command.iter().map(|arg| arg.to_string_lossy()).join(" "));
+
+        loop {
+            status!("{}", watch::separator(now(), &display));
+            let mut cmd = std::process::Command::new(env::current_exe()?);
+            if let Some(toolchain) = &global.toolchain {
+                cmd.arg(format!("+{toolchain}"));
+            }
+            if let Some(rustc) = &global.rustc {
+                cmd.arg("--rustc").arg(rustc);
+            }
+            // We already did the auto-actions; doing them again for every run could even
+            // change files, and thus trigger another run.
+            cmd.args(&command).env("MIRI_AUTO_OPS", "no");
+            // It does not go through our shell, so it has to be told whether to color separately.
+            for (var, value) in output::child_env(output::color()) {
+                match value {
+                    Some(value) => cmd.env(var, value),
+                    None => cmd.env_remove(var),
+                };
+            }
+            let mut run = watch::Run::start(cmd)?;
+
+            // Wait for the run to finish, or for files to change. Ctrl-C stops the run, and us.
+            let changes = loop {
+                if let Some(status) = run.try_wait()? {
+                    // This stays the last thing we print until the next run starts.
+                    if status.success() {
+                        success!("`{display}` succeeded; waiting for changes...");
+                    } else {
+                        error!("`{display}` failed ({status}); waiting for changes...");
+                    }
+                    break loop {
+                        if let Some(changes) = watcher.poll() {
+                            break changes;
+                        }
+                    };
+                }
+                if let Some(changes) = watcher.poll() {
+                    status!("Files changed; stopping the current run.");
+                    run.cancel()?;
+                    break changes;
+                }
+            };
+            eprintln!("Changed: {}", watch::describe(&changes, &e.miri_dir));
+        }
+    }
+
+    fn ci(
+        host_only: bool,
+        skip_style: bool,
+        from: Option<String>,
+        only: Option<String>,
+        list: Option<ci::ListFormat>,
+        global: &GlobalArgs,
+    ) -> Result<()> {
+        let ci = ci::Ci::new(MiriEnv::new(global)?)?;
+        let skipped = |group| {
+            match group {
+                ci::Group::Cross if host_only => Some("skipped (--host-only)"),
+                ci::Group::Style if skip_style => Some("skipped (--skip-style)"),
+                _ => None,
+            }
+        };
+        if let Some(format) = list {
+            let steps = ci::list(ci.host);
+            match format {
+                ci::ListFormat::Json => println!("{}", serde_json::to_string_pretty(&steps)?),
+                ci::ListFormat::Names =>
+                    for step in steps {
+                        if step.runs && skipped(step.group).is_none() {
+                            println!("{}", step.name);
+                        }
+                    },
+                ci::ListFormat::Table => {
+                    let width = steps.iter().map(|step| step.name.len()).max().unwrap_or(0);
+                    for step in steps {
+                        let note = if step.runs { "" } else { " (nothing to do on this host)" };
+                        println!("{:width$}  {}{note}", step.name, step.about);
+                    }
+                }
+            }
+            return Ok(());
+        }
+
+        let steps = ci::select(from.as_deref(), only.as_deref())?;
+        let mut results = Vec::new();
+        let mut failed = None;
+        for (i, step) in steps.iter().enumerate() {
+            if failed.is_some() {
+                results.push((step.name, "not run".to_owned(), None));
+                continue;
+            }
+            if let Some(status) = skipped(step.group) {
+                results.push((step.name, status.to_owned(), None));
+                continue;
+            }
+            if !(step.runs_on)(ci.host) {
+                results.push((step.name, "skipped (not on this host)".to_owned(), None));
+                continue;
+            }
+            status!("──────── [{}/{}] {} ──────── {}", i + 1, steps.len(), step.name, step.about);
+            events::emit(&Event::StepStarted { name: step.name });
+            let start = Instant::now();
+            let result = (step.run)(&ci);
+            let duration_secs = start.elapsed().as_secs_f64();
+            let status = match result {
+                Ok(()) => {
+                    let status = events::Status::Ok;
+                    events::emit(&Event::StepFinished { name: step.name, status, duration_secs });
+                    "ok".to_owned()
+                }
+                Err(err) => {
+                    let status = events::Status::Failed;
+                    events::emit(&Event::StepFinished { name: step.name, status, duration_secs });
+                    error!("step `{}` failed: {err:#}", step.name);
+                    failed = Some(step.name);
+                    "FAILED".to_owned()
+                }
+            };
+            results.push((step.name, status, Some(start.elapsed())));
+        }
+
+        // Print the summary.
+        let width = steps.iter().map(|step| step.name.len()).max().unwrap_or(0).max("step".len());
+        println!();
+        println!("{:width$}  {:26}  time", "step", "status");
+        for (name, status, time) in &results {
+            let time = time.map(ci::format_duration).unwrap_or_default();
+            println!("{name:width$}  {status:26}  {time}");
+        }
+        if let Some(name) = failed {
+            bail!("step `{name}` failed; once it is fixed, resume with `./miri ci --from {name}`");
+        }
+        Ok(())
+    }
+
+    fn completions(
+        shell: CompletionShell,
+        complete: bool,
+        words: Vec<String>,
+        global: &GlobalArgs,
+    ) -> Result<()> {
+        if !complete {
+            print!("{}", completions::script(shell));
+            return Ok(());
+        }
+        let words = completions::words(shell, words);
+        // Completing must not fail loudly; without a working toolchain, there just are no targets.
+        let targets =
+            || MiriEnv::new(global).and_then(|e| completions::target_list(&e)).unwrap_or_default();
+        let mut candidates = completions::complete(&words, targets);
+        if shell == CompletionShell::Bash {
+            candidates = completions::strip_for_bash(words.last().unwrap(), candidates);
+        }
+        for candidate in candidates {
+            println!("{candidate}");
+        }
+        Ok(())
+    }
+
+    fn ide_setup(editor: Editor, print: bool, global: &GlobalArgs) -> Result<()> {
+        let e = MiriEnv::new(global)?;
+        e.print_toolchain();
+        ide::setup(&e, editor, print)
+    }
+
+    fn doctor(json: bool, global: &GlobalArgs) -> Result<()> {
+        let results = doctor::run_checks(global);
+        if json {
+            println!("{}", doctor::json(&results));
+        } else {
+            print!("{}", doctor::human(&results));
+        }
+        let failures = results.iter().filter(|r| r.status == doctor::Status::Fail).count();
+        if failures > 0 {
+            bail!("{failures} check(s) failed");
+        }
+        Ok(())
+    }
+
     fn toolchain(flags: Vec<OsString>) -> Result<()> {
         // Make sure rustup-toolchain-install-master is installed.
         which::which("rustup-toolchain-install-master")
             .context("Please install rustup-toolchain-install-master by running 'cargo install rustup-toolchain-install-master'")?;
-        let sh = Shell::new()?;
-        sh.change_dir(miri_dir()?);
+        let sh = ScriptCtx::new()?.miri_dir_shell();
         let new_commit = sh.read_file("rust-version")?.trim().to_owned();
         let current_commit = {
             let rustc_info = cmd!(sh, "rustc +miri --version -v").read();
-            if rustc_info.is_err() {
-                None
-            } else {
-                let metadata = rustc_version::version_meta_for(&rustc_info.unwrap())?;
+            if let Ok(rustc_info) = rustc_info {
+                let metadata = rustc_version::version_meta_for(&rustc_info)?;
                 Some(
                     metadata
                         .commit_hash
                         .ok_or_else(|| anyhow!("rustc metadata did not contain commit hash"))?,
                 )
+            } else {
+                None
             }
         };
         // Check if we already are at that commit.
@@ -208,7 +556,15 @@ fn toolchain(flags: Vec<OsString>) -> Result<()> {
         // Install and setup new toolchain.
         cmd!(sh, "rustup toolchain uninstall miri").run()?;
 
-        cmd!(sh, "rustup-toolchain-install-master -n miri -c cargo -c rust-src -c rustc-dev -c llvm-tools -c rustfmt -c clippy {flags...} -- {new_commit}").run()?;
+        let install = cmd!(sh, "rustup-toolchain-install-master -n miri -c cargo -c rust-src -c rustc-dev -c llvm-tools -c rustfmt -c clippy {flags...} -- {new_commit}")
+            .label("install the toolchain");
+        retry(
+            DOWNLOAD_RETRIES,
+            DOWNLOAD_RETRY_DELAY,
+            "installing the toolchain",
+            is_download_hiccup,
+            || install.run_with_timeout(network_timeout()?),
+        )?;
         cmd!(sh, "rustup override set miri").run()?;
         // Cleanup.
         cmd!(sh, "cargo clean").run()?;
@@ -220,24 +576,68 @@ fn toolchain(flags: Vec<OsString>) -> Result<()> {
         Ok(())
     }
 
-    fn rustc_pull(commit: Option<String>) -> Result<()> {
-        let sh = Shell::new()?;
-        sh.change_dir(miri_dir()?);
-        let commit = commit.map(Result::Ok).unwrap_or_else(|| {
-            let rust_repo_head =
-                cmd!(sh, "git ls-remote https://github.com/rust-lang/rust/ HEAD").read()?;
-            rust_repo_head
-                .split_whitespace()
-                .next()
-                .map(|front| front.trim().to_owned())
-                .ok_or_else(|| anyhow!("Could not obtain Rust repo HEAD from remote."))
-        })?;
+    fn rustc_pull(
+        commit: Option<String>,
+        allow_downgrade: bool,
+        action: Option<PullAction>,
+        allow_any_josh: bool,
+        retries: u32,
+    ) -> Result<()> {
+        let ctx = ScriptCtx::new()?;
+        let sh = ctx.miri_dir_shell();
+        let state = PullState::load(&sh)?;
+        match (action, state) {
+            (None, None) => {}
+            (None, Some(_)) =>
+                bail!(
+                    "a previous `./miri rustc-pull` has been interrupted; use `--continue` or `--abort` first"
+                ),
+            (Some(_), None) => bail!("there is no interrupted `./miri rustc-pull` to resume"),
+            (Some(PullAction::Abort), Some(state)) => {
+                // This also gets rid of the unfinished merge.
+                let pre_pull_ref = &state.pre_pull_ref;
+                cmd!(sh, "git reset --hard {pre_pull_ref}").run()?;
+                PullState::clear(&sh)?;
+                println!("Aborted the pull; the repository is back at {pre_pull_ref}.");
+                return Ok(());
+            }
+            (Some(PullAction::Continue), Some(state)) => {
+                return Self::rustc_pull_finish(&sh, &state);
+            }
+        }
+
+        if let Some(commit) = &commit {
+            // Make sure the commit exists, and that we do not accidentally go back in time.
+            let current = sh.read_file("rust-version")?.trim().to_owned();
+            if rust_commit_time(&ctx.target_dir, commit, retries)?
+                < rust_commit_time(&ctx.target_dir, &current, retries)?
+            {
+                if !allow_downgrade {
+                    bail!(
+                        "{commit} is older than the current rust-version {current}; pulling it \
+                        rewinds the toolchain, use `--allow-downgrade` if you really want that"
+                    );
+                }
+                warning!(
+                    "{commit} is older than the current rust-version {current}.\n\
+                    `rust-version` (and thus the toolchain) goes back; Miri changes pulled since \
+                    then are kept.\n"
+                );
+            }
+        }
+        let commit = match commit {
+            Some(commit) => commit,
+            None => rust_head(&sh, retries)?,
+        };
         // Make sure the repo is clean.
         if cmd!(sh, "git status --untracked-files=no --porcelain").read()?.is_empty().not() {
             bail!("working directory must be clean before running `./miri rustc-pull`");
         }
         // Make sure josh is running.
-        let josh = Self::start_josh()?;
+        let josh = Josh::start(&ctx.target_dir, allow_any_josh)?;
+
+        // Remember where we started, so that we can go back there if there are conflicts.
+        let pre_pull_ref = cmd!(sh, "git rev-parse HEAD").read()?;
 
         // Update rust-version file. As a separate commit, since making it part of
         // the merge has confused the heck out of josh in the past.
@@ -245,58 +645,202 @@ fn rustc_pull(commit: Option<String>) -> Result<()> {
         // trigger auto-actions.
         // We do this before the merge so that if there are merge conflicts, we have
         // the right rust-version file while resolving them.
-        sh.write_file("rust-version", format!("{commit}\n"))?;
-        const PREPARING_COMMIT_MESSAGE: &str = "Preparing for merge from rustc";
+        if !skip_in_dry_run(format_args!("write {commit} to rust-version")) {
+            sh.write_file("rust-version", format!("{commit}\n"))?;
+        }
         cmd!(sh, "git commit rust-version --no-verify -m {PREPARING_COMMIT_MESSAGE}")
             .run()
             .context("FAILED to commit rust-version file, something went wrong")?;
 
         // Fetch given rustc commit.
-        cmd!(sh, "git fetch http://localhost:{JOSH_PORT}/rust-lang/rust.git@{commit}{JOSH_FILTER}.git")
-            .run()
-            .map_err(|e| {
+        let josh_url = josh.url("rust-lang/rust", Some(&commit));
+        with_retries(retries, "fetching through josh", || {
+            josh.check(fetch_with_progress(&sh, &josh_url, &[]))
+        })
+        .inspect_err(|_| {
                 // Try to un-do the previous `git commit`, to leave the repo in the state we found it it.
                 cmd!(sh, "git reset --hard HEAD^")
                     .run()
                     .expect("FAILED to clean up again after failed `git fetch`, sorry for that");
-                e
             })
             .context("FAILED to fetch new commits, something went wrong (committing the rust-version file has been undone)")?;
 
         // This should not add any new root commits. So count those before and after merging.
-        let num_roots = || -> Result<u32> {
-            Ok(cmd!(sh, "git rev-list HEAD --max-parents=0 --count")
-                .read()
-                .context("failed to determine the number of root commits")?
-                .parse::<u32>()?)
-        };
-        let num_roots_before = num_roots()?;
+        let state = PullState { pre_pull_ref, commit, num_roots_before: num_root_commits(&sh)? };
+        // Store the state before merging, so that recovery works even if we get killed.
+        state.store(&sh)?;
 
         // Merge the fetched commit.
-        const MERGE_COMMIT_MESSAGE: &str = "Merge from rustc";
-        cmd!(sh, "git merge FETCH_HEAD --no-verify --no-ff -m {MERGE_COMMIT_MESSAGE}")
-            .run()
-            .context("FAILED to merge new commits, something went wrong")?;
+        let merge =
+            cmd!(sh, "git merge FETCH_HEAD --no-verify --no-ff -m {MERGE_COMMIT_MESSAGE}").run();
+        drop(josh);
+        if let Err(err) = merge {
+            let conflicts = conflicted_files(&sh)?;
+            if conflicts.is_empty() {
+                return Err(err).context("FAILED to merge new commits, something went wrong");
+            }
+            print_conflicts(&sh, &conflicts);
+            bail!("merge conflicts need to be resolved");
+        }
+        Self::rustc_pull_finish(&sh, &state)
+    }
+
+    /// Completes a pull after the merge: commits the merge if it has not been committed yet (because
+    /// there were conflicts), and checks the result.
+    fn rustc_pull_finish(sh: &Shell, state: &PullState) -> Result<()> {
+        if cmd!(sh, "git rev-parse -q --verify MERGE_HEAD").quiet().read().is_ok() {
+            // We are resuming after conflicts. Make sure they have all been resolved.
+            let conflicts = conflicted_files(sh)?;
+            if !conflicts.is_empty() {
+                print_conflicts(sh, &conflicts);
+                bail!("there are still unresolved conflicts");
+            }
+            let changed = cmd!(sh, "git diff --cached --name-only --diff-filter=AM")
+                .quiet()
+                .read()?
+                .lines()
+                .map(str::to_owned)
+                .collect::<Vec<_>>();
+            let markers = files_with_markers(sh, &changed);
+            if !markers.is_empty() {
+                bail!(
+                    "these files still contain conflict markers, please resolve them: {}",
+                    markers.join(", ")
+                );
+            }
+            cmd!(sh, "git commit --no-verify -m {MERGE_COMMIT_MESSAGE}")
+                .run()
+                .context("FAILED to commit the merge")?;
+        }
 
         // Check that the number of roots did not increase.
-        if num_roots()? != num_roots_before {
+        if num_root_commits(sh)? != state.num_roots_before {
             bail!("Josh created a new root commit. This is probably not the history you want.");
         }
+        PullState::clear(sh)?;
+
+        let mut sync = SyncState::load(sh)?;
+        let merge = cmd!(sh, "git rev-parse HEAD").read()?;
+        sync.last_pull = Some(PullRecord { commit: state.commit.clone(), time: now(), merge });
+        sync.store(sh)?;
+        Ok(())
+    }
+
+    /// Checks that Miri builds with the toolchain we just pulled. If it does not, the pull itself
+    /// is still fine and is kept; Miri just needs some fixes for the new toolchain.
+    fn verify_pull() -> Result<()> {
+        status!("\nChecking that Miri builds with the new toolchain...");
+        Self::toolchain(vec![]).context(
+            "the pull itself succeeded, but installing the new toolchain for verification failed",
+        )?;
+        // Default global args, so that we use the toolchain we just installed.
+        let e = MiriEnv::new(&GlobalArgs::default())?;
+        let failed = [
+            ("miri", path!(e.miri_dir / "Cargo.toml")),
+            ("cargo-miri", path!(e.miri_dir / "cargo-miri" / "Cargo.toml")),
+        ]
+        .into_iter()
+        .filter(|(_, manifest)| e.check(manifest, &[]).is_err())
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+        if !failed.is_empty() {
+            bail!(
+                "the pull itself succeeded, but Miri needs fixes for the new toolchain; \
+                these crates do not build: {}",
+                failed.join(", ")
+            );
+        }
+        success!("Miri builds with the new toolchain.");
+        Ok(())
+    }
+
+    fn rustc_push_dry_run(
+        target: PushTarget,
+        branch: String,
+        force_with_lease: bool,
+        allow_any_josh: bool,
+        retries: u32,
+    ) -> Result<()> {
+        let ctx = ScriptCtx::new()?;
+        let sh = ctx.miri_dir_shell();
+        let base = sh.read_file("rust-version")?.trim().to_owned();
+        let mut blockers = Vec::new();
+        if cmd!(sh, "git status --untracked-files=no --porcelain").read()?.is_empty().not() {
+            blockers.push("the working directory is not clean".to_owned());
+        }
+        // Make sure josh is running.
+        let josh = Josh::start(&ctx.target_dir, allow_any_josh)?;
 
+        // Let josh compute what the base looks like in Miri's history; everything on top of that is
+        // what the push would transfer.
+        let base_url = josh.url("rust-lang/rust", Some(&base));
+        with_retries(retries, "fetching through josh", || {
+            josh.check(fetch_with_progress(&sh, &base_url, &[]))
+        })
+        .context("FAILED to fetch the base commit through josh")?;
+        let upstream = cmd!(sh, "git rev-parse FETCH_HEAD").read()?;
+        let push_url = josh.url(&target.repo, None);
         drop(josh);
+        if cmd!(sh, "git merge-base --is-ancestor {upstream} HEAD").quiet().run().is_err() {
+            blockers.push(format!(
+                "HEAD does not contain the rustc base {base}; run `./miri rustc-pull` first"
+            ));
+        }
+        if target.branch_commit(&sh, &branch, retries)?.is_some() {
+            if force_with_lease {
+                println!(
+                    "The branch '{branch}' already exists in '{}' and would be overwritten.\n",
+                    target.url
+                );
+            } else {
+                blockers.push(format!(
+                    "the branch '{branch}' already exists in '{}' (use `--force-with-lease` to overwrite it)",
+                    target.url
+                ));
+            }
+        }
+
+        println!("Commits that would be pushed (base: {base}):");
+        let log = cmd!(sh, "git log --oneline {upstream}..HEAD").read()?;
+        if log.is_empty() {
+            println!("    (none)");
+        }
+        for line in log.lines() {
+            println!("    {line}");
+        }
+        println!("\nChanges compared to the base:");
+        println!("{}", cmd!(sh, "git diff --stat {upstream} HEAD").read()?);
+        println!("\nThe branch would be pushed with:");
+        println!("    git push {push_url} HEAD:{branch}");
+        println!("which josh forwards to https://github.com/{}.", target.repo);
+
+        if !blockers.is_empty() {
+            println!("\nBlocking issues:");
+            for blocker in &blockers {
+                println!("    {blocker}");
+            }
+            bail!("the push would not succeed");
+        }
         Ok(())
     }
 
-    fn rustc_push(github_user: String, branch: String) -> Result<()> {
-        let sh = Shell::new()?;
-        sh.change_dir(miri_dir()?);
+    fn rustc_push(
+        target: PushTarget,
+        branch: String,
+        force_with_lease: bool,
+        allow_any_josh: bool,
+        retries: u32,
+    ) -> Result<()> {
+        let ctx = ScriptCtx::new()?;
+        let (sh, miri_dir) = (ctx.miri_dir_shell(), ctx.miri_dir);
         let base = sh.read_file("rust-version")?.trim().to_owned();
         // Make sure the repo is clean.
         if cmd!(sh, "git status --untracked-files=no --porcelain").read()?.is_empty().not() {
             bail!("working directory must be clean before running `./miri rustc-push`");
         }
         // Make sure josh is running.
-        let josh = Self::start_josh()?;
+        let josh = Josh::start(&ctx.target_dir, allow_any_josh)?;
+        let josh_url = josh.url(&target.repo, None);
 
         // Find a repo we can do our preparation in.
         if let Ok(rustc_git) = env::var("RUSTC_GIT") {
@@ -320,41 +864,43 @@ fn rustc_push(github_user: String, branch: String) -> Result<()> {
         // Prepare the branch. Pushing works much better if we use as base exactly
         // the commit that we pulled from last time, so we use the `rust-version`
         // file to find out which commit that would be.
-        println!("Preparing {github_user}/rust (base: {base})...");
-        if cmd!(sh, "git fetch https://github.com/{github_user}/rust {branch}")
-            .ignore_stderr()
-            .read()
-            .is_ok()
-        {
-            println!(
-                "The branch '{branch}' seems to already exist in 'https://github.com/{github_user}/rust'. Please delete it and try again."
-            );
-            std::process::exit(1);
-        }
-        cmd!(sh, "git fetch https://github.com/rust-lang/rust {base}").run()?;
-        cmd!(sh, "git push https://github.com/{github_user}/rust {base}:refs/heads/{branch}")
-            .ignore_stdout()
-            .ignore_stderr() // silence the "create GitHub PR" message
-            .run()?;
+        status!("Preparing {} (base: {base})...", target.repo);
+        // When overwriting, we make sure that the branch is still where we saw it just now.
+        let lease = match target.branch_commit(&sh, &branch, retries)? {
+            None => vec![],
+            Some(old) if force_with_lease =>
+                vec![format!("--force-with-lease=refs/heads/{branch}:{old}")],
+            Some(_) => {
+                println!(
+                    "The branch '{branch}' seems to already exist in '{}'. Please delete it and try again, or use `--force-with-lease`.",
+                    target.url
+                );
+                std::process::exit(1);
+            }
+        };
+        with_retries(retries, "fetching the base commit", || {
+            fetch_with_progress(&sh, "https://github.com/rust-lang/rust", &[&base])
+        })?;
+        // This captures the output, which also silences the "create GitHub PR" message. Pushes
+        // have no timeout, since git may have to ask for credentials.
+        let (url, lease) = (&target.url, &lease);
+        with_retries(retries, "pushing the base commit", || {
+            run_captured(cmd!(sh, "git push {lease...} {url} {base}:refs/heads/{branch}"), None)
+        })?;
         println!();
 
         // Do the actual push.
-        sh.change_dir(miri_dir()?);
-        println!("Pushing miri changes...");
-        cmd!(
-            sh,
-            "git push http://localhost:{JOSH_PORT}/{github_user}/rust.git{JOSH_FILTER}.git HEAD:{branch}"
-        )
-        .run()?;
+        sh.change_dir(&miri_dir);
+        status!("Pushing miri changes...");
+        with_retries(retries, "pushing through josh", || {
+            josh.check(run_captured(cmd!(sh, "git push {josh_url} HEAD:{branch}"), None))
+        })?;
         println!();
 
         // Do a round-trip check to make sure the push worked as expected.
-        cmd!(
-            sh,
-            "git fetch http://localhost:{JOSH_PORT}/{github_user}/rust.git{JOSH_FILTER}.git {branch}"
-        )
-        .ignore_stderr()
-        .read()?;
+        with_retries(retries, "fetching through josh", || {
+            josh.check(run_captured(cmd!(sh, "git fetch {josh_url} {branch}"), network_timeout()?))
+        })?;
         let head = cmd!(sh, "git rev-parse HEAD").read()?;
         let fetch_head = cmd!(sh, "git rev-parse FETCH_HEAD").read()?;
         if head != fetch_head {
@@ -363,17 +909,169 @@ fn rustc_push(github_user: String, branch: String) -> Result<()> {
         println!(
             "Confirmed that the push round-trips back to Miri properly. Please create a rustc PR:"
         );
+        println!("    {}", target.pr_url(&branch));
+
+        drop(josh);
+        let mut sync = SyncState::load(&sh)?;
+        sync.last_push =
+            Some(PushRecord { head, branch: format!("{}:{branch}", target.repo), time: now() });
+        sync.store(&sh)?;
+        Ok(())
+    }
+
+    fn rustc_status(json: bool, retries: u32) -> Result<()> {
+        let ctx = ScriptCtx::new()?;
+        let sh = ctx.miri_dir_shell();
+        let last_pulled_commit = sh.read_file("rust-version")?.trim().to_owned();
+        let sync = SyncState::load(&sh)?;
+        // Our record is only useful if nobody else pulled since. If it is missing (e.g., in a
+        // fresh clone) or outdated, we find the merge in the history instead.
+        let last_pull = sync.last_pull.filter(|pull| pull.commit == last_pulled_commit);
+        let last_merge = match &last_pull {
+            Some(pull) => Some(pull.merge.clone()),
+            None => {
+                let grep = format!("--grep=^{MERGE_COMMIT_MESSAGE}$");
+                let merge = cmd!(sh, "git rev-list -1 --first-parent --merges {grep} HEAD")
+                    .quiet()
+                    .read()?;
+                merge.is_empty().not().then_some(merge)
+            }
+        };
+
+        let upstream_head = rust_head(&sh, retries)?;
+        let upstream_commits_since =
+            count_rust_commits(&ctx.target_dir, &last_pulled_commit, &upstream_head, retries)?;
+        // Everything since the last merge from rustc is a Miri change that rustc does not have
+        // yet, unless it has been pushed since.
+        let exclude = last_merge
+            .into_iter()
+            .chain(sync.last_push.map(|push| push.head))
+            .map(|commit| format!("^{commit}"))
+            .collect::<Vec<_>>();
+        let unpushed_commits =
+            cmd!(sh, "git rev-list --count --no-merges HEAD {exclude...}").quiet().read()?;
+
+        let status = RustcStatus {
+            last_pulled_commit,
+            last_pulled_at: last_pull.map(|pull| pull.time),
+            upstream_head,
+            upstream_commits_since,
+            unpushed_commits: unpushed_commits.trim().parse()?,
+        };
+        if json {
+            println!("{}", serde_json::to_string_pretty(&status)?);
+        } else {
+            status.print_human();
+        }
+        Ok(())
+    }
+
+    fn squash(onto: Option<String>, allow_merges: bool) -> Result<()> {
+        use itertools::Itertools;
+
+        let sh = ScriptCtx::new()?.miri_dir_shell();
+        if !cmd!(sh, "git status --porcelain --untracked-files=no").quiet().read()?.is_empty() {
+            bail!("the working tree has uncommitted changes; commit or stash them first");
+        }
+        if cmd!(sh, "git symbolic-ref --quiet HEAD").quiet().ignore_stdout().run().is_err() {
+            bail!("HEAD is detached; check out the branch to squash first");
+        }
+        let base = match &onto {
+            Some(onto) => {
+                let spec = format!("{onto}^{{commit}}");
+                let base = cmd!(sh, "git rev-parse --verify --quiet {spec}")
+                    .quiet()
+                    .read()
+                    .with_context(|| format!("`{onto}` is not a commit"))?;
+                if cmd!(sh, "git merge-base --is-ancestor {base} HEAD").quiet().run().is_err() {
+                    bail!("`{onto}` is not an ancestor of the current branch");
+                }
+                base
+            }
+            None => squash::upstream_base(&sh)?,
+        };
+
+        let commits = squash::commits(&sh, &base)?;
+        let Some(first) = commits.first() else {
+            println!("There are no commits to squash.");
+            return Ok(());
+        };
+        if commits.len() == 1 {
+            println!(
+                "There is only one commit, {} {}; nothing to squash.",
+                first.hash, first.subject
+            );
+            return Ok(());
+        }
+        let merges = cmd!(sh, "git rev-list --merges {base}..HEAD").quiet().read()?;
+        if !merges.is_empty() && !allow_merges {
+            bail!(
+                "the commits to squash include merge commits ({}); use `--allow-merges` to squash them anyway",
+                merges.lines().map(|hash| &hash[..10]).join(", ")
+            );
+        }
+        println!("Squashing these {} commits on top of {}:", commits.len(), &base[..10]);
+        for commit in &commits {
+            println!("  {} {}", &commit.hash[..10], commit.subject);
+        }
+
+        let first_hash = &first.hash;
+        let first_message = cmd!(sh, "git log -1 --format=%B {first_hash}").quiet().read()?;
+        let message =
+            squash::edit_message(&sh, &squash::initial_message(&first_message, &commits))?;
+        if message.is_empty() {
+            bail!("aborting the squash due to an empty commit message");
+        }
+
+        // Build the squashed commit before touching the branch, so that nothing changes if this
+        // fails. Like `git rebase` does, we keep the author of the first commit.
+        let head = cmd!(sh, "git rev-parse HEAD").quiet().read()?;
+        let author = cmd!(sh, "git log -1 --format=%an%x00%ae%x00%ad --date=raw {first_hash}")
+            .quiet()
+            .read()?;
+        let [name, email, date] = author.splitn(3, '\0').collect::<Vec<_>>()[..] else {
+            bail!("unexpected output of `git log`: {author:?}");
+        };
+        let tree = format!("{head}^{{tree}}");
+        let squashed = cmd!(sh, "git commit-tree {tree} -p {base} -F -")
+            .env("GIT_AUTHOR_NAME", name)
+            .env("GIT_AUTHOR_EMAIL", email)
+            .env("GIT_AUTHOR_DATE", date)
+            .stdin(&message)
+            .quiet()
+            .read()?;
+        cmd!(sh, "git reset --quiet --soft {squashed}").run()?;
         println!(
-            // Open PR with `subtree update` title to silence the `no-merges` triagebot check
-            // See https://github.com/rust-lang/rust/pull/114157
-            "    https://github.com/rust-lang/rust/compare/{github_user}:{branch}?quick_pull=1&title=Miri+subtree+update&body=r?+@ghost"
+            "Squashed {} commits into {}. To undo this, run `git reset --soft {head}`.",
+            commits.len(),
+            &squashed[..10]
         );
+        Ok(())
+    }
 
-        drop(josh);
+    fn bench_history(
+        benches: Vec<OsString>,
+        n: usize,
+        threshold: f64,
+        global: &GlobalArgs,
+    ) -> Result<()> {
+        let e = MiriEnv::new(global)?;
+        let history = bench::load(&path!(e.target_dir / "bench-history.jsonl"))?;
+        let benches: Vec<String> =
+            benches.iter().map(|bench| bench.to_string_lossy().into_owned()).collect();
+        bench::print_history(&history, &benches, n, threshold);
         Ok(())
     }
 
-    fn bench(target: Option<OsString>, benches: Vec<OsString>) -> Result<()> {
+    fn bench(
+        target: Option<OsString>,
+        benches: Vec<OsString>,
+        against: Option<String>,
+        save_baseline: Option<String>,
+        threshold: f64,
+        fail_on_regression: bool,
+        global: &GlobalArgs,
+    ) -> Result<()> {
         // The hyperfine to use
         let hyperfine = env::var("HYPERFINE");
         let hyperfine = hyperfine.as_deref().unwrap_or("hyperfine -w 1 -m 5 --shell=none");
@@ -381,11 +1079,32 @@ fn bench(target: Option<OsString>, benches: Vec<OsString>) -> Result<()> {
         let Some((program_name, args)) = hyperfine.split_first() else {
             bail!("expected HYPERFINE environment variable to be non-empty");
         };
+        // Everything after `--` is passed to hyperfine.
+        let (benches, hyperfine_args) = split_args(benches);
+        let hyperfine_args = &hyperfine_args.unwrap_or_default();
         // Make sure we have an up-to-date Miri installed and selected the right toolchain.
-        Self::install(vec![])?;
+        Self::install(/* strip */ false, vec![], global)?;
 
-        let sh = Shell::new()?;
-        sh.change_dir(miri_dir()?);
+        let e = MiriEnv::new(global)?;
+        let history_path = path!(e.target_dir / "bench-history.jsonl");
+        let history = bench::load(&history_path)?;
+        if let Some(against) = &against {
+            if !bench::has_entry(&history, against) {
+                bail!("no benchmark results have been recorded for `{against}`");
+            }
+        }
+        let results_dir = path!(e.target_dir / "bench");
+        if !skip_in_dry_run(format_args!("create {}", results_dir.display())) {
+            fs::create_dir_all(&results_dir)?;
+        }
+        let toolchain = match (&e.toolchain, &e.rustc) {
+            (Some(toolchain), _) => toolchain.clone(),
+            (None, rustc) => rustc.as_ref().unwrap().display().to_string(),
+        };
+
+        let sh = e.build_sh()?.clone();
+        sh.change_dir(&e.miri_dir);
+        let commit = cmd!(sh, "git rev-parse HEAD").read()?;
         let benches_dir = "bench-cargo-miri";
         let benches = if benches.is_empty() {
             sh.read_dir(benches_dir)?
@@ -396,7 +1115,7 @@ fn bench(target: Option<OsString>, benches: Vec<OsString>) -> Result<()> {
         } else {
             benches.to_owned()
         };
-        let target_flag = if let Some(target) = target {
+        let target_flag = if let Some(target) = &target {
             let mut flag = OsString::from("--target=");
             flag.push(target);
             flag
@@ -404,167 +1123,1040 @@ fn bench(target: Option<OsString>, benches: Vec<OsString>) -> Result<()> {
             OsString::new()
         };
         let target_flag = &target_flag;
+        let perf = which::which("perf").is_ok();
         // Run the requested benchmarks
+        let mut results = Vec::new();
         for bench in benches {
             let current_bench = path!(benches_dir / bench / "Cargo.toml");
+            let name = path!(bench).file_name().unwrap().to_string_lossy().into_owned();
+            let export = path!(results_dir / format!("{name}.json"));
             // We don't attempt to escape `current_bench`, but we wrap it in quotes.
             // That seems to make Windows CI happy.
             cmd!(
                 sh,
-                "{program_name} {args...} 'cargo miri run '{target_flag}' --manifest-path \"'{current_bench}'\"'"
+                "{program_name} {args...} --export-json {export} {hyperfine_args...} 'cargo miri run '{target_flag}' --manifest-path \"'{current_bench}'\"'"
             )
+            .label(format!("benchmark {name}"))
             .run()?;
+            let median = bench::hyperfine_median(&sh.read_file(&export)?)
+                .with_context(|| format!("failed to read the results of `{name}`"))?;
+            // One more run to count instructions; unlike the time, that is not noisy.
+            let instructions = if perf {
+                let target = target.iter().map(|_| target_flag);
+                let output = cmd!(
+                    sh,
+                    "perf stat -x, -e instructions:u cargo miri run {target...} --manifest-path {current_bench}"
+                )
+                .label(format!("count instructions of {name}"))
+                .ignore_stdout()
+                .ignore_status()
+                .output()?;
+                bench::perf_instructions(&String::from_utf8_lossy(&output.stderr))
+            } else {
+                None
+            };
+            results.push(bench::BenchEntry {
+                bench: name,
+                time: now(),
+                commit: commit.clone(),
+                toolchain: toolchain.clone(),
+                target: target.as_ref().map(|t| t.to_string_lossy().into_owned()),
+                baseline: save_baseline.clone(),
+                median,
+                instructions,
+            });
+        }
+        bench::append(&history_path, &results)?;
+
+        let regressions =
+            bench::print_comparison(&results, &history, against.as_deref(), threshold);
+        if regressions > 0 && fail_on_regression {
+            bail!("{regressions} benchmark(s) got more than {threshold}% slower");
         }
         Ok(())
     }
 
-    fn install(flags: Vec<OsString>) -> Result<()> {
-        let e = MiriEnv::new()?;
-        e.install_to_sysroot(e.miri_dir.clone(), &flags)?;
-        e.install_to_sysroot(path!(e.miri_dir / "cargo-miri"), &flags)?;
+    fn bench_wall(
+        wall: WallBench,
+        target: Option<OsString>,
+        against: Option<String>,
+        save_baseline: Option<String>,
+        threshold: f64,
+        fail_on_regression: bool,
+        global: &GlobalArgs,
+    ) -> Result<()> {
+        let mut e = MiriEnv::new(global)?;
+        let history_path = path!(e.target_dir / "bench-history.jsonl");
+        let history = bench::load(&history_path)?;
+        // `--against` is another Miri binary if there is one of that name, and otherwise a commit
+        // or baseline in the history.
+        let against_miri = against.as_deref().map(PathBuf::from).filter(|path| path.is_file());
+        if let (Some(against), None) = (&against, &against_miri) {
+            if !bench::has_entry(&history, against) {
+                bail!(
+                    "no benchmark results have been recorded for `{against}`, and there is no \
+                    Miri binary of that name"
+                );
+            }
+        }
+        let target = target
+            .map(|target| target.into_string())
+            .transpose()
+            .map_err(|_| anyhow!("`--target` must be valid UTF-8"))?;
+        let mut build = BuildOptions::default();
+        build.target = target.clone();
+        let built = e.build_miri(&build)?;
+
+        // Keep what we can the same between the runs: the flags, and with them the seed.
+        let mut miri_flags = e.sh.var_os("MIRIFLAGS").unwrap_or_default();
+        if !miriflags::parse(&miri_flags).iter().any(|flag| flag.starts_with("-Zmiri-seed=")) {
+            miri_flags = miriflags::with_flag(&miri_flags, "-Zmiri-seed=0")?;
+        }
+        let flags = miriflags::parse(&miri_flags);
+        let isolation = if flags.iter().any(|flag| flag == "-Zmiri-disable-isolation") {
+            "disabled"
+        } else {
+            "enabled"
+        };
+        note!(
+            "{} runs after {} warmup, with MIRIFLAGS=`{}`; isolation is {isolation}",
+            wall.runs,
+            bench::WALL_WARMUP_RUNS,
+            miri_flags.to_string_lossy()
+        );
+        let target_flag: Vec<String> =
+            target.iter().map(|target| format!("--target={target}")).collect();
+        let (sysroot, program, target_flag, flags) =
+            (&built.sysroot, &wall.program, &target_flag, &flags);
+        let miri_cmd = |miri: &Path| {
+            cmd!(
+                e.sh,
+                "{miri} --sysroot {sysroot} --edition=2021 {target_flag...} {flags...} {program}"
+            )
+            .label("benchmark miri")
+            .quiet()
+            .ignore_stdout()
+        };
+        let mut binaries = vec![built.miri.clone()];
+        binaries.extend(against_miri.clone());
+        for miri in &binaries {
+            plain!("$ {}", miri_cmd(miri));
+        }
+        if is_dry_run() {
+            return Ok(());
+        }
+
+        // The binaries take turns, so that what else happens on the machine meanwhile affects
+        // them alike.
+        let mut times = vec![Vec::new(); binaries.len()];
+        for run in 0..bench::WALL_WARMUP_RUNS + wall.runs {
+            for (miri, times) in binaries.iter().zip(&mut times) {
+                let start = Instant::now();
+                miri_cmd(miri).run().with_context(|| format!("{} failed", miri.display()))?;
+                if run >= bench::WALL_WARMUP_RUNS {
+                    times.push(start.elapsed().as_secs_f64());
+                }
+            }
+        }
+
+        let stem = program.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        let name = format!("wall/{stem}");
+        let export = path!(e.target_dir / "bench" / format!("wall-{stem}.json"));
+        if !skip_in_dry_run(format_args!("write {}", export.display())) {
+            fs::create_dir_all(export.parent().unwrap())?;
+            fs::write(
+                &export,
+                bench::hyperfine_json(&miri_cmd(&built.miri).to_string(), &times[0]),
+            )
+            .with_context(|| format!("failed to write {}", export.display()))?;
+        }
+        let stats = bench::WallStats::of(&times[0]);
+        let entry = bench::BenchEntry {
+            bench: name.clone(),
+            time: now(),
+            commit: record::commit(&e.miri_dir),
+            toolchain: match (&e.toolchain, &e.rustc) {
+                (Some(toolchain), _) => toolchain.clone(),
+                (None, rustc) => rustc.as_ref().unwrap().display().to_string(),
+            },
+            target,
+            baseline: save_baseline,
+            median: stats.median,
+            instructions: None,
+        };
+        bench::append(&history_path, std::slice::from_ref(&entry))?;
+
+        println!("Wall-clock times of {} runs:", wall.runs);
+        println!("  {name}: {}", stats.describe());
+        let regressions = match &against_miri {
+            Some(against_miri) => {
+                let theirs = bench::WallStats::of(&times[1]);
+                let change = bench::change(theirs.mean, stats.mean);
+                println!("  {}: {}", against_miri.display(), theirs.describe());
+                println!("  change of the mean: {}", bench::describe_change(change, threshold));
+                usize::from(change > threshold)
+            }
+            None => bench::print_comparison(&[entry], &history, against.as_deref(), threshold),
+        };
+        if regressions > 0 && fail_on_regression {
+            bail!("`{name}` got more than {threshold}% slower");
+        }
+        Ok(())
+    }
+
+    fn bench_compare_rev(
+        compare: CompareRev,
+        benches: Vec<OsString>,
+        target: Option<OsString>,
+        threshold: f64,
+        fail_on_regression: bool,
+        global: &GlobalArgs,
+    ) -> Result<()> {
+        let (benches, rest) = split_args(benches);
+        if rest.is_some() {
+            bail!("`--compare-rev` does not use hyperfine, so it takes no flags after `--`");
+        }
+        let mut e = MiriEnv::new(global)?;
+        let sh = e.sh.clone();
+        sh.change_dir(&e.miri_dir);
+        let dirty = !cmd!(sh, "git status --porcelain -- .").read()?.trim().is_empty();
+        let current = if dirty { "HEAD (dirty)" } else { "HEAD" };
+        if dirty {
+            warning!("the working tree has uncommitted changes; they are part of the `HEAD` side");
+        }
+        let benches_dir = path!(e.miri_dir / "bench-cargo-miri");
+        let benches: Vec<String> = if benches.is_empty() {
+            let mut all: Vec<String> = sh
+                .read_dir(&benches_dir)?
+                .into_iter()
+                .filter(|path| path.is_dir())
+                .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+                .collect();
+            all.sort();
+            all
+        } else {
+            benches.iter().map(|bench| bench.to_string_lossy().into_owned()).collect()
+        };
+        let target = target
+            .map(|target| target.into_string())
+            .transpose()
+            .map_err(|_| anyhow!("`--target` must be valid UTF-8"))?;
+        let mut build = BuildOptions::default();
+        build.target = target.clone();
+        // Before building sets up our sysroot in it.
+        let mut theirs_env = e.clone();
+        let ours = e.build_miri(&build)?;
+
+        // The other side gets built from a checkout of its own, with its own target dir.
+        let compare_dir = path!(e.target_dir / "compare-rev");
+        let prefix = cmd!(sh, "git rev-parse --show-prefix").read()?;
+        let worktree = Worktree::add(&sh, path!(compare_dir / "worktree"), &compare.rev)?;
+        status!("$ (building Miri at `{}`)", compare.rev);
+        theirs_env.miri_dir = path!(worktree.dir / prefix);
+        theirs_env.set_target_dir(path!(compare_dir / "target"));
+        let theirs = theirs_env.build_miri(&build)?;
+
+        let target_flag: Vec<String> =
+            target.iter().map(|target| format!("--target={target}")).collect();
+        let target_flag = &target_flag;
+        let bench_cmd = |built: &BuildOutput, bench: &str| {
+            let cargo_miri = &built.cargo_miri;
+            let manifest = path!(benches_dir / bench / "Cargo.toml");
+            cmd!(sh, "{cargo_miri} miri run {target_flag...} --manifest-path {manifest}")
+                .label(format!("benchmark {bench}"))
+                .env("MIRI", &built.miri)
+                .env("MIRI_SYSROOT", &built.sysroot)
+                .quiet()
+                .ignore_stdout()
+        };
+        if let Some(bench) = benches.first() {
+            for built in [&ours, &theirs] {
+                plain!("$ {} ...", bench_cmd(built, bench));
+            }
+        }
+        if is_dry_run() {
+            return Ok(());
+        }
+
+        // The two sides take turns, so that what else happens on the machine meanwhile affects
+        // them alike.
+        let mut results = Vec::new();
+        for bench in &benches {
+            status!("Benchmarking {bench}...");
+            let mut times = [Vec::new(), Vec::new()];
+            for run in 0..bench::WALL_WARMUP_RUNS + compare.runs {
+                for (built, times) in [&ours, &theirs].into_iter().zip(&mut times) {
+                    let start = Instant::now();
+                    bench_cmd(built, bench).run()?;
+                    if run >= bench::WALL_WARMUP_RUNS {
+                        times.push(start.elapsed().as_secs_f64());
+                    }
+                }
+            }
+            results.push((bench, times.map(|times| bench::WallStats::of(&times))));
+        }
+        drop(worktree);
+
+        let width = benches.iter().map(|bench| bench.len()).max().unwrap_or(0).max(5);
+        let time = |stats: &bench::WallStats| {
+            format!("{} ± {}", bench::format_time(stats.mean), bench::format_time(stats.stddev))
+        };
+        println!();
+        println!("{:width$}  {:22}  {:22}  change", "bench", current, compare.rev);
+        let mut regressions = 0;
+        for (bench, [ours, theirs]) in &results {
+            let change = bench::change(theirs.mean, ours.mean);
+            let significant = ours.differs_from(theirs);
+            regressions += usize::from(significant && change > threshold);
+            let hint = if significant { "" } else { " (within noise)" };
+            println!(
+                "{bench:width$}  {:22}  {:22}  {}{hint}",
+                time(ours),
+                time(theirs),
+                bench::describe_change(change, threshold)
+            );
+        }
+        if regressions > 0 && fail_on_regression {
+            bail!(
+                "{regressions} benchmark(s) got more than {threshold}% slower than at `{}`",
+                compare.rev
+            );
+        }
+        Ok(())
+    }
+
+    fn install(strip: bool, mut flags: Vec<OsString>, global: &GlobalArgs) -> Result<()> {
+        let e = MiriEnv::new(global)?;
+        let target = e.foreign_target(&flags)?;
+        if let Some(target) = &target {
+            e.check_cross_target(target)?;
+            cross::announce(target);
+        }
+        if !strip {
+            e.install(&flags)?;
+            if let Some(target) = &target {
+                let bin = path!(e.install_root(&flags)? / "bin");
+                success!("installed Miri for `{target}` into {}", bin.display());
+            }
+            return Ok(());
+        }
+        let binaries = e.installed_binaries(&flags)?;
+        let size = |path: &Path| fs::metadata(path).map(|meta| meta.len()).ok();
+        // The sizes of the unstripped binaries of this build. When cargo strips them, it never
+        // writes those, so we only know them when we strip them ourselves.
+        let mut before: Vec<Option<u64>> = vec![None; binaries.len()];
+        let config = strip_config(&flags);
+        // Before installing, so that a missing component does not leave unstripped binaries.
+        let llvm_strip = match &config {
+            Some(config) => {
+                flags.extend(["--config".into(), config.into()]);
+                None
+            }
+            None => Some(e.llvm_tool("llvm-strip")?),
+        };
+        e.install(&flags)?;
+        if let Some(llvm_strip) = &llvm_strip {
+            note!("cargo cannot strip the binaries of this profile; running llvm-strip on them");
+            for (binary, before) in binaries.iter().zip(&mut before) {
+                *before = size(binary);
+                cmd!(e.sh, "{llvm_strip} --strip-all {binary}").run()?;
+            }
+        }
+        if is_dry_run() {
+            return Ok(());
+        }
+        // Binaries for another target do not run here.
+        if target.is_none() {
+            let [miri, cargo_miri] = &binaries;
+            let version = cmd!(e.sh, "{miri} --version").quiet().read();
+            let cargo_version = cmd!(e.sh, "{cargo_miri} miri --version").quiet().read();
+            for (binary, version) in [(miri, version), (cargo_miri, cargo_version)] {
+                version
+                    .with_context(|| format!("the stripped {} does not run", binary.display()))?;
+            }
+        }
+        for (binary, before) in binaries.iter().zip(before) {
+            let name = binary.file_name().unwrap().to_string_lossy();
+            let now = human_size(size(binary).unwrap_or_default());
+            match before {
+                Some(before) => success!("{name}: {now} (was {})", human_size(before)),
+                None => success!("{name}: {now}"),
+            }
+        }
         Ok(())
     }
 
-    fn build(flags: Vec<OsString>) -> Result<()> {
-        let e = MiriEnv::new()?;
-        e.build(path!(e.miri_dir / "Cargo.toml"), &flags, /* quiet */ false)?;
-        e.build(path!(e.miri_dir / "cargo-miri" / "Cargo.toml"), &flags, /* quiet */ false)?;
+    fn build(flags: Vec<OsString>, global: &GlobalArgs) -> Result<()> {
+        let e = MiriEnv::new(global)?;
+        let target = e.foreign_target(&flags)?;
+        if let Some(target) = &target {
+            e.check_cross_target(target)?;
+            cross::announce(target);
+        }
+        // These run one after the other: the tests expect cargo-miri next to Miri, so they share
+        // the target dir, and cargo would make the second build wait for the first anyway.
+        e.build(path!(e.miri_dir / "Cargo.toml"), &flags)?;
+        e.build(path!(e.miri_dir / "cargo-miri" / "Cargo.toml"), &flags)?;
+        if let Some(target) = &target {
+            let cargo_flags: Vec<String> = e
+                .cargo_extra_flags
+                .iter()
+                .cloned()
+                .chain(flags.iter().map(|flag| flag.to_string_lossy().into_owned()))
+                .collect();
+            for bin in ["miri", "cargo-miri"] {
+                let artifact = bin_artifact(&e.target_dir, &cargo_flags, bin);
+                success!("{bin} for `{target}`: {}", artifact.display());
+            }
+            note!(
+                "to run them there, put them into the `bin` dir of a toolchain for `{target}` of \
+                the same version, with the rustc-dev component"
+            );
+        }
         Ok(())
     }
 
-    fn check(flags: Vec<OsString>) -> Result<()> {
-        let e = MiriEnv::new()?;
+    fn check(flags: Vec<OsString>, global: &GlobalArgs) -> Result<()> {
+        let e = MiriEnv::new(global)?;
         e.check(path!(e.miri_dir / "Cargo.toml"), &flags)?;
         e.check(path!(e.miri_dir / "cargo-miri" / "Cargo.toml"), &flags)?;
+        let (helpers, skipped) = helper_crates(&e.miri_dir);
+        for manifest in &helpers {
+            e.check(manifest, &flags)?;
+        }
+        Self::helpers_summary("checked", &e, &helpers, &skipped);
         Ok(())
     }
 
-    fn clippy(flags: Vec<OsString>) -> Result<()> {
-        let e = MiriEnv::new()?;
-        e.clippy(path!(e.miri_dir / "Cargo.toml"), &flags)?;
-        e.clippy(path!(e.miri_dir / "cargo-miri" / "Cargo.toml"), &flags)?;
-        e.clippy(path!(e.miri_dir / "miri-script" / "Cargo.toml"), &flags)?;
-        Ok(())
+    fn clippy(flags: Vec<OsString>, global: &GlobalArgs) -> Result<()> {
+        let e = MiriEnv::new(global)?;
+        let (helpers, skipped) = helper_crates(&e.miri_dir);
+        let manifests = [
+            path!(e.miri_dir / "Cargo.toml"),
+            path!(e.miri_dir / "cargo-miri" / "Cargo.toml"),
+            path!(e.miri_dir / "miri-script" / "Cargo.toml"),
+        ]
+        .into_iter()
+        .chain(helpers.iter().cloned());
+        if !sarif::recording() {
+            for manifest in manifests {
+                e.clippy(manifest, &flags)?;
+            }
+            Self::helpers_summary("linted", &e, &helpers, &skipped);
+            return Ok(());
+        }
+        // The SARIF log should have what clippy finds in all crates, so go on after a failure.
+        let results: Vec<Result<()>> =
+            manifests.map(|manifest| e.clippy(manifest, &flags)).collect();
+        Self::helpers_summary("linted", &e, &helpers, &skipped);
+        results.into_iter().collect()
+    }
+
+    /// Says which of the helper crates of the test suite (see `helper_crates`) got `done` too.
+    fn helpers_summary(done: &str, e: &MiriEnv, helpers: &[PathBuf], skipped: &[PathBuf]) {
+        if e.verbosity == Verbosity::Quiet {
+            return;
+        }
+        let names = |manifests: &[PathBuf]| -> String {
+            let dirs = manifests.iter().map(|manifest| {
+                let dir = manifest.parent().unwrap();
+                dir.strip_prefix(&e.miri_dir).unwrap_or(dir).display().to_string()
+            });
+            dirs.collect::<Vec<_>>().join(", ")
+        };
+        if helpers.is_empty() {
+            note!("there are no test helper crates in {TEST_DEPS_DIR}");
+        } else {
+            note!("also {done} the test helper crates ({}): {}", helpers.len(), names(helpers));
+        }
+        if !skipped.is_empty() {
+            note!("skipped test helper crates that opt out: {}", names(skipped));
+        }
     }
 
-    fn cargo(flags: Vec<OsString>) -> Result<()> {
-        let e = MiriEnv::new()?;
-        let toolchain = &e.toolchain;
+    fn cargo(krate: Option<String>, mut flags: Vec<OsString>, global: &GlobalArgs) -> Result<()> {
+        let e = MiriEnv::new(global)?;
+        let toolchain = e.toolchain_flag();
+        if let Some(krate) = krate {
+            let manifest_path = match &*krate {
+                "miri" => path!(e.miri_dir / "Cargo.toml"),
+                _ => path!(e.miri_dir / krate / "Cargo.toml"),
+            };
+            // This has to go after the cargo subcommand.
+            let pos = flags.iter().position(|flag| !flag.to_string_lossy().starts_with('-'));
+            let pos = pos.map_or(flags.len(), |pos| pos + 1);
+            flags.splice(pos..pos, ["--manifest-path".into(), manifest_path.into()]);
+        }
         // We carefully kept the working dir intact, so this will run cargo *on the workspace in the
         // current working dir*, not on the main Miri workspace. That is exactly what RA needs.
-        cmd!(e.sh, "cargo +{toolchain} {flags...}").run()?;
-        Ok(())
+        // Before the subcommand, which cargo allows for `--locked`.
+        let locked =
+            (e.locked && !ArgQuery::flag(&["--locked"]).is_present(&flags)).then_some("--locked");
+        let sh = e.build_sh()?;
+        let mut cmd = cmd!(sh, "cargo {toolchain...} {locked...} {flags...}");
+        if let Some(rustflags) = e.merged_rustflags(&e.extra_rustflags)? {
+            cmd = with_rustflags(cmd, &rustflags);
+        }
+        eprintln!("$ {cmd}");
+        if skip_in_dry_run(format_args!("run `{cmd}`")) {
+            return Ok(());
+        }
+        let mut cargo = std::process::Command::from(cmd);
+        let status = tee::with_terminal(|| cargo.status()).context("failed to run cargo")?;
+        // Exit like cargo did, so that callers can tell what went wrong.
+        match status.code() {
+            Some(0) => Ok(()),
+            Some(code) => std::process::exit(code),
+            None => bail!("cargo was terminated ({status})"),
+        }
     }
 
-    fn test(bless: bool, mut flags: Vec<OsString>, target: Option<OsString>) -> Result<()> {
-        let mut e = MiriEnv::new()?;
+    fn test(
+        bless: bool,
+        doc: bool,
+        flags: Vec<OsString>,
+        target: Option<OsString>,
+        global: &GlobalArgs,
+    ) -> Result<()> {
+        let mut e = MiriEnv::new(global)?;
+        Self::test_in_env(&mut e, bless, doc, flags, target)
+    }
+
+    /// The target the test harness should test: `target` if it is one rustc knows, or else the
+    /// host. The harness would otherwise ask Miri for its host, which need not be what we know.
+    fn test_target(e: &MiriEnv, target: Option<&OsStr>) -> Result<OsString> {
+        let Some(target) = target else {
+            let host = e.host()?;
+            note!("testing for the host, {host}");
+            return Ok(host.into());
+        };
+        let name = target.to_str().context("`--target` must be valid UTF-8")?;
+        completions::check_target(name, &completions::target_list(e)?)?;
+        note!("testing for {name}");
+        Ok(target.to_owned())
+    }
+
+    fn test_in_env(
+        e: &mut MiriEnv,
+        bless: bool,
+        doc: bool,
+        flags: Vec<OsString>,
+        target: Option<OsString>,
+    ) -> Result<()> {
+        if doc {
+            if target.is_some() || bless || ArgQuery::new(&["--target"]).is_present(&flags) {
+                bail!(
+                    "the doctests do not run with Miri, so `--target` and `--bless` do not apply"
+                );
+            }
+            return Self::doctests(e, &flags);
+        }
+        let repro_args: Vec<OsString> = match &target {
+            Some(target) =>
+                ["--target".into(), target.clone()].into_iter().chain(flags.clone()).collect(),
+            None => flags.clone(),
+        };
+        let (targets, flags) = test_harness_args(flags);
+        let target = match (target, &targets[..]) {
+            (target, []) => target,
+            (None, [target]) => Some(target.clone()),
+            _ => bail!("`--target` must not be given more than once"),
+        };
+
+        let test_target = Self::test_target(e, target.as_deref())?;
 
         // Prepare a sysroot.
-        e.build_miri_sysroot(/* quiet */ false, target.as_deref())?;
+        e.build_miri_sysroot(target.as_deref())?;
 
         // Forward information to test harness.
         if bless {
             e.sh.set_var("RUSTC_BLESS", "Gesundheit");
         }
-        if let Some(target) = target {
-            // Tell the harness which target to test.
-            e.sh.set_var("MIRI_TEST_TARGET", target);
-        }
-
-        // Make sure the flags are going to the test harness, not cargo.
-        flags.insert(0, "--".into());
+        e.sh.set_var("MIRI_TEST_TARGET", test_target);
 
         // Then test, and let caller control flags.
         // Only in root project as `cargo-miri` has no tests.
-        e.test(path!(e.miri_dir / "Cargo.toml"), &flags)?;
+        let result = e.test(path!(e.miri_dir / "Cargo.toml"), &flags);
+        if result.is_err() {
+            let miri_flags = e.sh.var_os("MIRIFLAGS").unwrap_or_default();
+            match Repro::new(e, "test", &repro_args) {
+                Ok(repro) => repro.report(None, &Injected::none(&miri_flags)),
+                Err(err) => warning!("failed to write a script that reproduces this: {err:#}"),
+            }
+        }
+        result
+    }
+
+    /// `./miri test --doc`: the doctests of each crate, also when those of another one failed.
+    fn doctests(e: &MiriEnv, args: &[OsString]) -> Result<()> {
+        let mut failed = Vec::new();
+        let mut without = Vec::new();
+        for krate in ["miri", "cargo-miri"] {
+            let manifest_path = match krate {
+                "miri" => path!(e.miri_dir / "Cargo.toml"),
+                _ => path!(e.miri_dir / krate / "Cargo.toml"),
+            };
+            match e.doctest(manifest_path, args) {
+                Ok(Doctests::None) => without.push(krate),
+                Ok(Doctests::Passed(count)) => success!("{krate}: {count} doctests passed"),
+                Err(err) => {
+                    error!("{krate}: {err:#}");
+                    failed.push(krate);
+                }
+            }
+        }
+        if !without.is_empty() {
+            note!("no doctests in: {}", without.join(", "));
+        }
+        if !failed.is_empty() {
+            bail!("doctests failed in: {}", failed.join(", "));
+        }
         Ok(())
     }
 
-    fn run(
-        dep: bool,
-        verbose: bool,
-        many_seeds: Option<Range<u32>>,
-        mut flags: Vec<OsString>,
+    fn bless(
+        suites: Vec<String>,
+        target: Option<OsString>,
+        jobs: Option<NonZeroUsize>,
+        global: &GlobalArgs,
     ) -> Result<()> {
-        let mut e = MiriEnv::new()?;
-        let target = arg_flag_value(&flags, "--target");
-
-        // Scan for "--edition", set one ourselves if that flag is not present.
-        let have_edition = arg_flag_value(&flags, "--edition").is_some();
-        if !have_edition {
-            flags.push("--edition=2021".into()); // keep in sync with `tests/ui.rs`.`
-        }
-
-        // Prepare a sysroot, and add it to the flags.
-        let miri_sysroot = e.build_miri_sysroot(/* quiet */ !verbose, target.as_deref())?;
-        flags.push("--sysroot".into());
-        flags.push(miri_sysroot.into());
-
-        // Compute everything needed to run the actual command. Also add MIRIFLAGS.
-        let miri_manifest = path!(e.miri_dir / "Cargo.toml");
-        let miri_flags = e.sh.var("MIRIFLAGS").unwrap_or_default();
-        let miri_flags = flagsplit(&miri_flags);
-        let toolchain = &e.toolchain;
-        let extra_flags = &e.cargo_extra_flags;
-        let quiet_flag = if verbose { None } else { Some("--quiet") };
-        // This closure runs the command with the given `seed_flag` added between the MIRIFLAGS and
-        // the `flags` given on the command-line.
-        let run_miri = |sh: &Shell, seed_flag: Option<String>| -> Result<()> {
-            // The basic command that executes the Miri driver.
-            let mut cmd = if dep {
-                cmd!(
-                    sh,
-                    "cargo +{toolchain} {quiet_flag...} test {extra_flags...} --manifest-path {miri_manifest} --test ui -- --miri-run-dep-mode"
-                )
+        use itertools::Itertools;
+
+        let mut e = MiriEnv::new(global)?;
+        let suites: Vec<String> = if suites.is_empty() {
+            bless::default_suites()
+        } else {
+            suites.into_iter().unique().collect()
+        };
+
+        let test_target = Self::test_target(&e, target.as_deref())?;
+
+        // Prepare a sysroot and build the test harness up front, so that the suites do not all do
+        // that at the same time.
+        e.build_miri_sysroot(target.as_deref())?;
+        e.sh.set_var("MIRI_TEST_TARGET", test_target);
+        let manifest_path = path!(e.miri_dir / "Cargo.toml");
+        e.test(&manifest_path, &["--test".into(), "ui".into(), "--no-run".into()])?;
+
+        // Give each suite its own copy of the tests. The build output next to it is kept between
+        // runs, since building the test dependencies takes a while.
+        let tests_dir = path!(e.miri_dir / "tests");
+        let bless_dir = path!(e.target_dir / "bless");
+        let shard_tests = |suite: &str| path!(bless_dir / bless::shard_dir(suite) / "tests");
+        for suite in &suites {
+            let copy = shard_tests(suite);
+            if copy.exists() && !skip_in_dry_run(format_args!("delete {}", copy.display())) {
+                fs::remove_dir_all(&copy)
+                    .with_context(|| format!("failed to remove {}", copy.display()))?;
+            }
+            bless::copy_dir(&tests_dir, &copy)?;
+        }
+        let before = bless::reference_files(&tests_dir)?;
+
+        let (toolchain, cargo_flags) = (e.toolchain_flag(), e.shared_cargo_flags(&[]));
+        let label = |suite: &&String| format!("blessing {suite}");
+        let res = e.run_many(&suites, jobs.map(NonZeroUsize::get), None, label, |sh, suite| {
+            let root = path!(bless_dir / bless::shard_dir(suite));
+            let (toolchain, cargo_flags, manifest_path) =
+                (&toolchain, &cargo_flags, &manifest_path);
+            status!("Blessing {suite}...");
+            let output = cmd!(
+                sh,
+                "cargo {toolchain...} test {cargo_flags...} --manifest-path {manifest_path} --test ui"
+            )
+            .label("bless the tests")
+            .env("RUSTC_BLESS", "Gesundheit")
+            .env("MIRI_UI_ROOT", &root)
+            .env("MIRI_UI_SUITE", suite)
+            .quiet()
+            .ignore_status()
+            .output()?;
+            if !output.status.success() {
+                // Print everything at once, so that it does not get mixed up with the other suites.
+                let mut stderr = std::io::stderr().lock();
+                writeln!(stderr, "Blessing {suite} failed:")?;
+                stderr.write_all(&output.stdout)?;
+                stderr.write_all(&output.stderr)?;
+                bail!("`cargo test` failed");
+            }
+            success!("Blessed {suite}.");
+            Ok(())
+        });
+
+        // Even if a suite failed, the files that were blessed until then are fine to keep.
+        let shards = suites
+            .iter()
+            .map(|suite| {
+                let after = bless::reference_files(&shard_tests(suite))?;
+                Ok((suite.clone(), bless::changes(&before, &after)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let merged = bless::merge(shards);
+        merged.apply(&tests_dir)?;
+        for suite in &suites {
+            if !skip_in_dry_run(format_args!("delete {}", shard_tests(suite).display())) {
+                fs::remove_dir_all(shard_tests(suite))?;
+            }
+        }
+
+        let (stderr, stdout) = merged.counts();
+        eprintln!("{stderr} `.stderr` and {stdout} `.stdout` files changed.");
+        for (path, suites) in &merged.conflicts {
+            warning!(
+                "conflict: tests/{} was changed differently by {}",
+                path.display(),
+                suites.join(", ")
+            );
+        }
+        if !merged.changes.is_empty() {
+            eprintln!("Please review the changes with `git diff tests` before committing them.");
+        }
+        res?;
+        if !merged.conflicts.is_empty() {
+            bail!(
+                "{} files were changed differently by several suites and have been left unchanged",
+                merged.conflicts.len()
+            );
+        }
+        Ok(())
+    }
+
+    fn test_cargo_miri(
+        target: Option<OsString>,
+        bless: bool,
+        keep_tmp: bool,
+        filters: Vec<OsString>,
+        global: &GlobalArgs,
+    ) -> Result<()> {
+        let mut e = MiriEnv::new(global)?;
+        let miri = e.build_bin(path!(e.miri_dir / "Cargo.toml"), "miri")?;
+        let cargo_miri =
+            e.build_bin(path!(e.miri_dir / "cargo-miri" / "Cargo.toml"), "cargo-miri")?;
+        e.build_miri_sysroot(target.as_deref())?;
+
+        // Put the binaries we just built first in the PATH. They need to be next to each other so
+        // that cargo-miri finds this miri.
+        let tmp = TempDir::new("miri-test-cargo-miri", keep_tmp)?;
+        let bin_dir = path!(tmp.path / "bin");
+        if !skip_in_dry_run(format_args!("copy the binaries to {}", bin_dir.display())) {
+            fs::create_dir_all(&bin_dir)?;
+            for built in [miri, cargo_miri] {
+                fs::copy(&built, path!(bin_dir / built.file_name().unwrap()))
+                    .with_context(|| format!("failed to copy {}", built.display()))?;
+            }
+        }
+        let path = e.sh.var_os("PATH").unwrap_or_default();
+        e.sh.set_var(
+            "PATH",
+            env::join_paths([bin_dir].into_iter().chain(env::split_paths(&path)))?,
+        );
+
+        // Cargo looks for subcommands in `$CARGO_HOME/bin` before the PATH, so we need a fresh
+        // CARGO_HOME to make sure an installed cargo-miri does not get used. That also keeps the
+        // user's cargo config out of this. The downloaded crates can be shared, though.
+        let cargo_home = path!(tmp.path / "cargo-home");
+        let dry_run = skip_in_dry_run(format_args!("set up {}", cargo_home.display()));
+        if !dry_run {
+            fs::create_dir_all(&cargo_home)?;
+        }
+        #[cfg(unix)]
+        if !dry_run {
+            let real_cargo_home = e.sh.var_os("CARGO_HOME").map(PathBuf::from).or_else(|| {
+                directories::BaseDirs::new().map(|dirs| path!(dirs.home_dir() / ".cargo"))
+            });
+            for dir in ["registry", "git"] {
+                let Some(real) = real_cargo_home.as_ref().map(|home| path!(home / dir)) else {
+                    continue;
+                };
+                if real.exists() {
+                    std::os::unix::fs::symlink(&real, path!(cargo_home / dir))?;
+                }
+            }
+        }
+        e.sh.set_var("CARGO_HOME", &cargo_home);
+        if let Some(toolchain) = &e.toolchain {
+            e.sh.set_var("RUSTUP_TOOLCHAIN", toolchain);
+        }
+
+        // On Windows, there is always "python", not "python3" or "python2".
+        let python = if which::which("python3").is_ok() { "python3" } else { "python" };
+        let script = path!(e.miri_dir / "test-cargo-miri" / "run-test.py");
+        let target_flag = target.iter().flat_map(|target| [OsStr::new("--target"), target]);
+        let bless_flag = bless.then_some("--bless");
+        cmd!(e.sh, "{python} {script} {target_flag...} {bless_flag...} -- {filters...}")
+            // This would take precedence over the miri next to cargo-miri.
+            .env_remove("MIRI")
+            // Its output goes to a pipe, but we want to see the progress anyway.
+            .env("PYTHONUNBUFFERED", "1")
+            .label("test cargo-miri")
+            .tee()?;
+        Ok(())
+    }
+
+    fn test_matrix(
+        bless: bool,
+        doc: bool,
+        toolchains: Vec<String>,
+        flags: Vec<OsString>,
+        target: Option<OsString>,
+        global: &GlobalArgs,
+    ) -> Result<()> {
+        if local_rustc(global).is_some() {
+            bail!("`--toolchains` cannot be combined with a locally built rustc");
+        }
+        let step_result = |res: &Result<()>| if res.is_ok() { "ok" } else { "FAILED" };
+        let mut results = Vec::new();
+        let mut first: Option<MiriEnv> = None;
+        for toolchain in &toolchains {
+            status!("$ (testing with toolchain `{toolchain}`)");
+            // RUSTFLAGS and the libdir have to be computed for this toolchain, but the rest of the
+            // environment stays the same.
+            let e = match &first {
+                Some(first) => first.clone_for_toolchain(toolchain),
+                None => {
+                    let global =
+                        GlobalArgs { toolchain: Some(toolchain.clone()), ..global.clone() };
+                    MiriEnv::new(&global)
+                }
+            };
+            let mut e = match e {
+                Ok(e) => e,
+                Err(err) => {
+                    error!("failed to set up toolchain `{toolchain}`: {err:#}");
+                    results.push((toolchain, "FAILED", "skipped"));
+                    continue;
+                }
+            };
+            // The testing changes the environment, so keep a pristine copy.
+            first.get_or_insert_with(|| e.clone());
+            // Keep the build artifacts of the different toolchains apart.
+            e.set_target_dir(path!(e.target_dir / "toolchains" / toolchain));
+
+            let build = (|| {
+                e.build(path!(e.miri_dir / "Cargo.toml"), &[])?;
+                e.build(path!(e.miri_dir / "cargo-miri" / "Cargo.toml"), &[])
+            })();
+            let test = if build.is_ok() {
+                step_result(&Self::test_in_env(&mut e, bless, doc, flags.clone(), target.clone()))
             } else {
-                cmd!(
-                    sh,
-                    "cargo +{toolchain} {quiet_flag...} run {extra_flags...} --manifest-path {miri_manifest} --"
-                )
+                "skipped"
             };
-            cmd.set_quiet(!verbose);
-            // Add Miri flags
-            let cmd = cmd.args(&miri_flags).args(seed_flag).args(&flags);
-            // And run the thing.
-            Ok(cmd.run()?)
-        };
-        // Run the closure once or many times.
-        if let Some(seed_range) = many_seeds {
-            e.run_many_times(seed_range, |sh, seed| {
-                eprintln!("Trying seed: {seed}");
-                run_miri(sh, Some(format!("-Zmiri-seed={seed}"))).map_err(|err| {
-                    eprintln!("FAILING SEED: {seed}");
-                    err
-                })
-            })?;
-        } else {
-            run_miri(&e.sh, None)?;
+            results.push((toolchain, step_result(&build), test));
+        }
+
+        // Print the comparison.
+        let width = toolchains.iter().map(|t| t.len()).max().unwrap_or(0).max("toolchain".len());
+        println!();
+        println!("{:width$}  {:7} test", "toolchain", "build");
+        for (toolchain, build, test) in &results {
+            println!("{toolchain:width$}  {build:7} {test}");
+        }
+        if results.iter().any(|(_, build, test)| *build != "ok" || *test != "ok") {
+            bail!("some toolchains failed to build or test");
         }
         Ok(())
     }
 
-    fn fmt(flags: Vec<OsString>) -> Result<()> {
+    fn run(
+        options: RunOptions,
+        many_seeds: Option<Range<u32>>,
+        repro_for: Option<u32>,
+        global: &GlobalArgs,
+    ) -> Result<()> {
+        let mut e = MiriEnv::new(global)?;
+        if options.verbose {
+            e.print_toolchain();
+        }
+        let miri_flags = e.sh.var_os("MIRIFLAGS").unwrap_or_default();
+        // What a script that reproduces a failing seed runs again.
+        let repro_args: Vec<OsString> =
+            options.dep.then(|| "--dep".into()).into_iter().chain(options.flags.clone()).collect();
+        if let Some(seed) = repro_for {
+            let repro = Repro::new(&e, "run", &repro_args)?;
+            let injected = Injected::new(&options.inject, &miri_flags, seed)?;
+            println!("{}", repro.write(Some(seed), &injected)?.display());
+            return Ok(());
+        }
+        let Some(seed_range) = many_seeds else {
+            return e.run(&options);
+        };
+        let report = e.run_seeds(&options, seed_range)?;
+        if report.failed().next().is_some() {
+            let repro = Repro::new(&e, "run", &repro_args)?;
+            for seed in report.failed() {
+                repro.report(Some(seed), &Injected::new(&options.inject, &miri_flags, seed)?);
+            }
+        }
+        report.into_result()
+    }
+
+    fn run_remote(workers: Workers, seeds: Range<u32>, global: &GlobalArgs) -> Result<()> {
+        let e = MiriEnv::new(global)?;
+        let failures = remote::run_seeds(&e, &workers, seeds)?;
+        remote::report(&failures)
+    }
+
+    fn debug(debugger: Option<Debugger>, flags: Vec<OsString>, global: &GlobalArgs) -> Result<()> {
         use itertools::Itertools;
 
-        let e = MiriEnv::new()?;
-        let config_path = path!(e.miri_dir / "rustfmt.toml");
+        let mut e = MiriEnv::new(global)?;
+        let miri = e.build_bin(path!(e.miri_dir / "Cargo.toml"), "miri")?;
+        let (flags, program_args) = e.prepare_run(flags)?;
+        let miri_flags = e.sh.var_os("MIRIFLAGS").unwrap_or_default();
+        let mut args: Vec<OsString> =
+            miriflags::parse(&miri_flags).into_iter().map(Into::into).chain(flags).collect();
+        if let Some(program_args) = program_args {
+            args.push("--".into());
+            args.extend(program_args);
+        }
 
-        // Collect each rust file in the miri repo.
-        let files = WalkDir::new(&e.miri_dir)
-            .into_iter()
-            .filter_entry(|entry| {
-                let name = entry.file_name().to_string_lossy();
-                let ty = entry.file_type();
-                if ty.is_file() {
-                    name.ends_with(".rs")
-                } else {
-                    // dir or symlink. skip `target` and `.git`.
-                    &name != "target" && &name != ".git"
-                }
-            })
-            .filter_ok(|item| item.file_type().is_file())
-            .map_ok(|item| item.into_path());
+        // What `cargo run` would set up: the sysroot, and the library path so that the driver finds
+        // the rustc libraries (which the rpath should take care of, but better be safe).
+        let mut vars = vec![("MIRI_SYSROOT", miri_sysroot(&e.sh).unwrap_or_default())];
+        let libdir = path!(e.sysroot()? / if cfg!(windows) { "bin" } else { "lib" });
+        let dylib_path = env::var_os(debug::dylib_path_var()).unwrap_or_default();
+        let dylib_path =
+            env::join_paths([libdir].into_iter().chain(env::split_paths(&dylib_path)))?;
+        vars.push((debug::dylib_path_var(), dylib_path));
+        // The `rust-` debugger wrappers ask rustc for the pretty-printers.
+        if let Some(toolchain) = &e.toolchain {
+            vars.push(("RUSTUP_TOOLCHAIN", toolchain.into()));
+        }
+
+        let debugger = debugger.or_else(|| {
+            Debugger::defaults()
+                .iter()
+                .copied()
+                .find(|debugger| which::which(debugger.program()).is_ok())
+        });
+        let Some(debugger) = debugger else {
+            eprintln!("No debugger found; run this in the debugger of your choice:");
+            eprintln!("{}", debug::render_command(&vars, miri.as_os_str(), &args));
+            bail!("no debugger found, tried: {}", Debugger::defaults().iter().join(", "));
+        };
+        let args = debugger.args(&miri, &args);
+        eprintln!("$ {}", debug::render_command(&vars, debugger.program().as_ref(), &args));
+        if is_dry_run() {
+            return Ok(());
+        }
+        let mut cmd = std::process::Command::new(debugger.program());
+        cmd.args(&args).envs(vars);
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            // Let the debugger take over, so that Ctrl-C goes to it and not to us.
+            let err = cmd.exec();
+            Err(err).with_context(|| format!("failed to run {debugger}"))
+        }
+        #[cfg(not(unix))]
+        {
+            let status = tee::with_terminal(|| cmd.status())
+                .with_context(|| format!("failed to run {debugger}"))?;
+            std::process::exit(status.code().unwrap_or(1))
+        }
+    }
 
-        e.format_files(files, &e.toolchain[..], &config_path, &flags[..])
+    fn fuzz(
+        target: Option<String>,
+        time: Option<u64>,
+        list: bool,
+        global: &GlobalArgs,
+    ) -> Result<()> {
+        let e = MiriEnv::new(global)?;
+        let fuzz_dir = path!(e.miri_dir / "fuzz");
+        if !path!(fuzz_dir / "Cargo.toml").exists() {
+            bail!(
+                "there are no fuzz targets; `cargo fuzz init` creates them in {}",
+                fuzz_dir.display()
+            );
+        }
+        let targets = fuzz::targets(&fuzz_dir)?;
+        if list {
+            for target in &targets {
+                println!("{target}");
+            }
+            return Ok(());
+        }
+        let Some(target) = target else {
+            bail!("`./miri fuzz` needs a target, one of: {}", targets.join(", "));
+        };
+        fuzz::check_target(&target, &targets)?;
+
+        // Make sure we have what cargo-fuzz needs.
+        let toolchain = &e.toolchain_flag();
+        let sh = e.build_sh()?;
+        let have_cargo_fuzz = cmd!(sh, "cargo {toolchain...} fuzz --version")
+            .quiet()
+            .ignore_stdout()
+            .ignore_stderr()
+            .run()
+            .is_ok();
+        if !have_cargo_fuzz {
+            bail!("cargo-fuzz is not installed; install it with `cargo install cargo-fuzz`");
+        }
+        let meta = e.rustc_meta().context("cargo-fuzz needs a nightly toolchain")?;
+        if !matches!(meta.channel, rustc_version::Channel::Nightly | rustc_version::Channel::Dev) {
+            bail!(
+                "cargo-fuzz needs a nightly toolchain, but we are using rustc {}; \
+                run `./miri toolchain` to install the one Miri is pinned to",
+                meta.semver
+            );
+        }
+        if !fuzz::has_asan_runtime(e.sysroot()?, &meta.host) {
+            bail!(
+                "the toolchain in {} has no address sanitizer runtime for {}, which cargo-fuzz \
+                needs; use a nightly toolchain on a host with sanitizer support (like \
+                x86_64-unknown-linux-gnu)",
+                e.sysroot()?.display(),
+                meta.host
+            );
+        }
+
+        // Keep everything in the target dir. The fuzz build gets its own target dir since it uses
+        // different RUSTFLAGS.
+        let fuzz_target_dir = path!(e.target_dir / "fuzz");
+        let build_dir = path!(fuzz_target_dir / "target");
+        let corpus = path!(fuzz_target_dir / "corpus" / target);
+        let artifacts_dir = path!(fuzz_target_dir / "artifacts" / target);
+        if !skip_in_dry_run(format_args!("create {}", fuzz_target_dir.display())) {
+            fs::create_dir_all(&corpus)?;
+            fs::create_dir_all(&artifacts_dir)?;
+        }
+        let old_artifacts = fuzz::artifacts(&artifacts_dir)?;
+        // libFuzzer just prepends this to the file names.
+        let artifact_flag =
+            format!("-artifact_prefix={}{}", artifacts_dir.display(), std::path::MAIN_SEPARATOR);
+        let time_flag = time.map(|secs| format!("-max_total_time={secs}"));
+        let result = cmd!(
+            sh,
+            "cargo {toolchain...} fuzz run --fuzz-dir {fuzz_dir} --target-dir {build_dir} {target} {corpus} -- {artifact_flag} {time_flag...}"
+        )
+        .run();
+        if result.is_ok() {
+            return Ok(());
+        }
+
+        let new_artifacts = fuzz::artifacts(&artifacts_dir)?;
+        for artifact in new_artifacts.difference(&old_artifacts) {
+            let replay = cmd!(
+                sh,
+                "cargo {toolchain...} fuzz run --fuzz-dir {fuzz_dir} --target-dir {build_dir} {target} {artifact}"
+            );
+            eprintln!("Reproducer: {}", artifact.display());
+            eprintln!("Replay it with:\n    {replay}");
+        }
+        Ok(result?)
+    }
+
+    fn fmt(no_ignore: bool, flags: Vec<OsString>, global: &GlobalArgs) -> Result<()> {
+        let e = MiriEnv::new(global)?;
+        let mut options = FormatOptions::default();
+        options.no_ignore = no_ignore;
+        options.flags = flags;
+        let outcome = e.format(&options)?;
+        if outcome.skipped > 0 {
+            note!("left alone {} files that say `// no-rustfmt`", outcome.skipped);
+        }
+        Ok(())
     }
 }
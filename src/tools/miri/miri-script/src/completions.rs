@@ -0,0 +1,402 @@
+//! Shell completions for `./miri`.
+//!
+//! The scripts we generate are small: they hand the command line to
+//! `./miri completions --complete <shell>`, which figures out the candidates from `COMMANDS`.
+//! That way, the completions cannot get out of sync with the options we actually accept.
+
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use path_macro::path;
+
+use crate::args::{CommandSpec, OptValue};
+use crate::record::{cmd, skip_in_dry_run};
+use crate::util::MiriEnv;
+use crate::{bless, COMMANDS, GLOBAL};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompletionShell {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+}
+
+impl FromStr for CompletionShell {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "bash" => CompletionShell::Bash,
+            "zsh" => CompletionShell::Zsh,
+            "fish" => CompletionShell::Fish,
+            "powershell" | "pwsh" => CompletionShell::Powershell,
+            _ => bail!("unknown shell `{s}`, expected one of: bash, zsh, fish, powershell"),
+        })
+    }
+}
+
+impl fmt::Display for CompletionShell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CompletionShell::Bash => "bash",
+            CompletionShell::Zsh => "zsh",
+            CompletionShell::Fish => "fish",
+            CompletionShell::Powershell => "powershell",
+        })
+    }
+}
+
+const BASH: &str = r#"# Completions for ./miri; load with `source <(./miri completions bash)`.
+_miri() {
+    local IFS=$'\n'
+    COMPREPLY=($("${COMP_WORDS[0]}" completions --complete bash -- "${COMP_LINE:0:COMP_POINT}" 2>/dev/null))
+}
+complete -o default -F _miri ./miri miri
+"#;
+
+const ZSH: &str = r#"# Completions for ./miri; load with `source <(./miri completions zsh)`.
+_miri() {
+    local -a candidates
+    candidates=("${(@f)$("${words[1]}" completions --complete zsh -- "${(@)words[1,CURRENT]}" 2>/dev/null)}")
+    if (( ${#candidates[@]} )) && [[ -n "${candidates[1]}" ]]; then
+        compadd -- "${candidates[@]}"
+    else
+        _files
+    fi
+}
+compdef _miri ./miri miri
+"#;
+
+const FISH: &str = r#"# Completions for ./miri; load with `./miri completions fish | source`.
+function __miri_complete
+    set -l words (commandline -opc) (commandline -ct)
+    set -l candidates ($words[1] completions --complete fish -- $words 2>/dev/null)
+    if test (count $candidates) -gt 0
+        printf '%s\n' $candidates
+    else
+        __fish_complete_path (commandline -ct)
+    end
+end
+complete -c miri -f -a '(__miri_complete)'
+complete -p '*/miri' -f -a '(__miri_complete)'
+"#;
+
+const POWERSHELL: &str = r#"# Completions for ./miri; load with `./miri completions powershell | Out-String | Invoke-Expression`.
+Register-ArgumentCompleter -Native -CommandName 'miri', './miri' -ScriptBlock {
+    param($wordToComplete, $commandAst, $cursorPosition)
+    $line = $commandAst.Extent.Text
+    $line = $line.Substring(0, [Math]::Min($line.Length, $cursorPosition - $commandAst.Extent.StartOffset))
+    if ($wordToComplete -eq '' -and -not $line.EndsWith(' ')) { $line += ' ' }
+    $program = $commandAst.CommandElements[0].ToString()
+    & $program completions --complete powershell -- $line 2>$null | ForEach-Object {
+        [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)
+    }
+}
+"#;
+
+/// The completion script for `shell`.
+pub fn script(shell: CompletionShell) -> &'static str {
+    match shell {
+        CompletionShell::Bash => BASH,
+        CompletionShell::Zsh => ZSH,
+        CompletionShell::Fish => FISH,
+        CompletionShell::Powershell => POWERSHELL,
+    }
+}
+
+/// Turns what the completion script passed us into the words on the command line, the last of
+/// which is the one being completed. Bash and PowerShell give us the line up to the cursor, since
+/// they split words differently than we do (bash splits at `=`, and PowerShell cannot pass empty
+/// arguments in older versions).
+pub fn words(shell: CompletionShell, args: Vec<String>) -> Vec<String> {
+    match shell {
+        CompletionShell::Bash | CompletionShell::Powershell => {
+            let line = args.concat();
+            let mut words = shell_words::split(&line)
+                .unwrap_or_else(|_| line.split_whitespace().map(str::to_owned).collect());
+            if line.is_empty() || line.ends_with(char::is_whitespace) {
+                words.push(String::new());
+            }
+            words
+        }
+        CompletionShell::Zsh | CompletionShell::Fish => args,
+    }
+}
+
+/// Where the candidates for an option value or the other arguments come from.
+#[derive(Debug, PartialEq, Eq)]
+enum Values {
+    Choices(Vec<String>),
+    Targets,
+    /// Let the shell complete file names.
+    Files,
+}
+
+/// Figures out the candidates from how the value is described in the help.
+fn values(placeholder: &str) -> Values {
+    match placeholder {
+        "<target>" => Values::Targets,
+        "[<suite>...]" =>
+            Values::Choices(
+                bless::SUITES
+                    .iter()
+                    .map(|suite| suite.trim_start_matches("tests/").into())
+                    .collect(),
+            ),
+        // The argument of `./miri help`.
+        "[<command>]" => Values::Choices(COMMANDS.iter().map(|spec| spec.name.into()).collect()),
+        choices if choices.contains('|') && !choices.contains(['<', '[']) =>
+            Values::Choices(choices.split('|').map(Into::into).collect()),
+        _ => Values::Files,
+    }
+}
+
+/// If `word` is an option of `spec`, returns whether the next word is its value, and how that
+/// value is described.
+fn takes_next(spec: &CommandSpec, word: &str) -> Option<Option<&'static str>> {
+    let (name, attached) = match word.split_once('=') {
+        Some((name, _)) if name.starts_with("--") => (name, true),
+        _ => (word, false),
+    };
+    let negated = name.strip_prefix("--no-").map(|flag| format!("--{flag}"));
+    let opt = spec.opts.iter().find(|opt| {
+        opt.names.contains(&name) || negated.as_deref().is_some_and(|n| opt.names.contains(&n))
+    })?;
+    Some(match opt.value {
+        OptValue::Required(value) if !attached => Some(value),
+        _ => None,
+    })
+}
+
+fn options(spec: &CommandSpec) -> Vec<String> {
+    spec.opts
+        .iter()
+        .flat_map(|opt| opt.names)
+        .map(|name| name.to_string())
+        .chain(["--help".into()])
+        .collect()
+}
+
+/// The candidates for the last of `words` (the first being `./miri` itself). `targets` is only
+/// called if we need the list of targets.
+pub fn complete(words: &[String], targets: impl FnOnce() -> Vec<String>) -> Vec<String> {
+    let Some((cur, words)) = words.split_last() else {
+        return Vec::new();
+    };
+    let mut words = words.iter().skip(1);
+    // Find the command, skipping the global options.
+    let mut command = None;
+    let mut pending = None;
+    while let Some(word) = words.next() {
+        if word.starts_with('+') {
+            continue;
+        }
+        if !word.starts_with('-') {
+            command = COMMANDS.iter().find(|spec| spec.name == word);
+            if command.is_none() {
+                return Vec::new();
+            }
+            break;
+        }
+        if let Some(Some(value)) = takes_next(&GLOBAL, word) {
+            if words.next().is_none() {
+                pending = Some(value);
+            }
+        }
+    }
+    let Some(spec) = command else {
+        let candidates = match pending {
+            Some(value) => return filter(cur, candidates(values(value), targets)),
+            None if cur.starts_with('-') => options(&GLOBAL),
+            None => COMMANDS.iter().map(|spec| spec.name.to_string()).collect(),
+        };
+        return filter(cur, candidates);
+    };
+
+    // Go through the options of the command, to see whether we are still among them.
+    let mut in_rest = false;
+    while let Some(word) = words.next() {
+        if word == "--" || !word.starts_with('-') {
+            in_rest = true;
+            break;
+        }
+        // Note that the guard skips over the value of the option.
+        match takes_next(spec, word) {
+            Some(Some(value)) if words.next().is_none() =>
+                return filter(cur, candidates(values(value), targets)),
+            Some(_) => {}
+            None if spec.forwards_flags => {
+                in_rest = true;
+                break;
+            }
+            None => {}
+        }
+    }
+    if in_rest {
+        return filter(cur, candidates(values(spec.rest), targets));
+    }
+    if let Some((name, _)) = cur.split_once('=').filter(|(name, _)| name.starts_with("--")) {
+        return match spec.opts.iter().find(|opt| opt.names.contains(&name)).map(|opt| opt.value) {
+            Some(OptValue::Required(value) | OptValue::Optional(value)) =>
+                filter(
+                    cur,
+                    candidates(values(value), targets)
+                        .into_iter()
+                        .map(|value| format!("{name}={value}"))
+                        .collect(),
+                ),
+            _ => Vec::new(),
+        };
+    }
+    if cur.starts_with('-') {
+        return filter(cur, options(spec));
+    }
+    filter(cur, candidates(values(spec.rest), targets))
+}
+
+fn candidates(values: Values, targets: impl FnOnce() -> Vec<String>) -> Vec<String> {
+    match values {
+        Values::Choices(choices) => choices,
+        Values::Targets => targets(),
+        Values::Files => Vec::new(),
+    }
+}
+
+fn filter(cur: &str, candidates: Vec<String>) -> Vec<String> {
+    candidates.into_iter().filter(|candidate| candidate.starts_with(cur)).collect()
+}
+
+/// Bash replaces only what comes after the last `=` or `:` in the word being completed, so the
+/// candidates must not repeat what comes before.
+pub fn strip_for_bash(cur: &str, candidates: Vec<String>) -> Vec<String> {
+    match cur.rfind(['=', ':']) {
+        Some(pos) => candidates.into_iter().map(|c| c[pos + 1..].to_owned()).collect(),
+        None => candidates,
+    }
+}
+
+/// The targets the rustc of `e` supports. Cached in the target dir, since completions should be
+/// quick.
+pub fn target_list(e: &MiriEnv) -> Result<Vec<String>> {
+    let cache = path!(e.target_dir / "completions" / "targets");
+    let sysroot = e.sysroot()?.to_string_lossy();
+    // The cache is stale if it is for another sysroot, or the compiler changed since.
+    let compiler_changed = fs::metadata(path!(e.sysroot()? / "bin")).and_then(|m| m.modified());
+    let cache_changed = fs::metadata(&cache).and_then(|m| m.modified());
+    let fresh = match (compiler_changed, cache_changed) {
+        (Ok(compiler), Ok(cache)) => cache >= compiler,
+        _ => false,
+    };
+    if fresh {
+        let contents = fs::read_to_string(&cache)?;
+        let mut lines = contents.lines();
+        if lines.next() == Some(&*sysroot) {
+            return Ok(lines.map(Into::into).collect());
+        }
+    }
+
+    let rustc = e.rustc.clone().unwrap_or_else(|| "rustc".into());
+    let toolchain = e.toolchain_flag();
+    let targets = cmd!(e.sh, "{rustc} {toolchain...} --print target-list").quiet().read()?;
+    if !skip_in_dry_run(format_args!("write {}", cache.display())) {
+        fs::create_dir_all(cache.parent().unwrap())?;
+        fs::write(&cache, format!("{sysroot}\n{targets}\n"))?;
+    }
+    Ok(targets.lines().map(Into::into).collect())
+}
+
+/// Fails unless `target` is one of `known` (from `target_list`), suggesting the closest one. A path
+/// to a target spec is fine as well.
+pub fn check_target(target: &str, known: &[String]) -> Result<()> {
+    if target.ends_with(".json") || known.iter().any(|known| known == target) {
+        return Ok(());
+    }
+    let known: Vec<&str> = known.iter().map(|known| &**known).collect();
+    match crate::args::nearest(target, &known) {
+        Some(suggestion) => bail!("unknown target `{target}`; did you mean `{suggestion}`?"),
+        None => bail!("unknown target `{target}`; `rustc --print target-list` lists all of them"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    fn complete_line(line: &str) -> Vec<String> {
+        let words = words(CompletionShell::Bash, vec![line.into()]);
+        complete(&words, || vec!["aarch64-apple-darwin".into(), "x86_64-unknown-linux-gnu".into()])
+    }
+
+    #[test]
+    fn checks_targets() {
+        let known = ["i686-pc-windows-gnu".to_owned(), "x86_64-unknown-linux-gnu".to_owned()];
+        check_target("i686-pc-windows-gnu", &known).unwrap();
+        check_target("../my-target.json", &known).unwrap();
+        assert_eq!(
+            check_target("x86_64-unknwon-linux-gnu", &known).unwrap_err().to_string(),
+            "unknown target `x86_64-unknwon-linux-gnu`; did you mean `x86_64-unknown-linux-gnu`?"
+        );
+        assert!(check_target("wasm32", &known).unwrap_err().to_string().contains("target-list"));
+    }
+
+    #[test]
+    fn candidates() {
+        assert!(complete_line("./miri ").contains(&"test".to_owned()));
+        assert_eq!(complete_line("./miri rustc-p"), ["rustc-pull", "rustc-push"]);
+        assert_eq!(complete_line("./miri +nightly --rustc foo cl"), ["clippy", "clean"]);
+        assert_eq!(complete_line("./miri test --t"), ["--target", "--toolchains"]);
+        assert_eq!(complete_line("./miri test --target x8"), ["x86_64-unknown-linux-gnu"]);
+        assert_eq!(complete_line("./miri clean --only=s"), ["--only=sysroot"]);
+        assert_eq!(complete_line("./miri env --shell f"), ["fish"]);
+        assert_eq!(complete_line("./miri test --target=foo --bless --no-v"), Vec::<String>::new());
+        assert_eq!(complete_line("./miri run --no-verbose --d"), ["--dep"]);
+        assert_eq!(complete_line("./miri bless native"), ["native-lib/pass", "native-lib/fail"]);
+        assert_eq!(complete_line("./miri help ca"), ["cargo"]);
+        assert_eq!(complete_line("./miri clean --h"), ["--help"]);
+        assert_eq!(complete_line("./miri cargo --h"), ["--help"]);
+        // Everything after the options is forwarded, so we leave it to the shell.
+        assert!(complete_line("./miri test foo --t").is_empty());
+        assert!(complete_line("./miri run -- --t").is_empty());
+        assert!(complete_line("./miri frobnicate --").is_empty());
+        assert_eq!(strip_for_bash("--only=s", complete_line("./miri clean --only=s")), ["sysroot"]);
+    }
+
+    /// Checks that the scripts at least parse, for the shells that are installed.
+    #[test]
+    fn scripts_parse() {
+        let shells = [
+            (CompletionShell::Bash, "bash", &["-n"][..]),
+            (CompletionShell::Zsh, "zsh", &["-n"]),
+            (CompletionShell::Fish, "fish", &["--no-execute"]),
+        ];
+        let dir = std::env::temp_dir();
+        for (shell, program, args) in shells {
+            if which::which(program).is_err() {
+                continue;
+            }
+            let file = path!(dir / format!("miri-completions-{}.{shell}", std::process::id()));
+            fs::write(&file, script(shell)).unwrap();
+            let status = Command::new(program).args(args).arg(&file).status().unwrap();
+            fs::remove_file(&file).unwrap();
+            assert!(status.success(), "the {shell} completions do not parse");
+        }
+        if which::which("pwsh").is_ok() {
+            let file = path!(dir / format!("miri-completions-{}.ps1", std::process::id()));
+            fs::write(&file, script(CompletionShell::Powershell)).unwrap();
+            let check = format!(
+                "$errors = $null; \
+                [System.Management.Automation.Language.Parser]::ParseFile('{}', [ref]$null, [ref]$errors) | Out-Null; \
+                if ($errors) {{ exit 1 }}",
+                file.display()
+            );
+            let status = Command::new("pwsh").args(["-NoProfile", "-Command", &check]).status();
+            fs::remove_file(&file).unwrap();
+            assert!(status.unwrap().success(), "the powershell completions do not parse");
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! Building Miri for another target than the host (`--target` for `./miri build` and
+//! `./miri install`), to run it on a machine of that target. Only building is supported: the test
+//! suite runs Miri on the host.
+
+use std::path::Path;
+
+use path_macro::path;
+
+use crate::output::{note, status, warning};
+
+/// What the toolchain needs to have for a target to build Miri for it: the rustup component, and
+/// the start of the name of one of its files in the library dir of the target.
+const COMPONENTS: &[(&str, &str)] = &[("rust-std", "libstd-"), ("rustc-dev", "librustc_middle-")];
+
+/// The components the toolchain with `sysroot` lacks for building Miri for `target`.
+pub fn missing_components(sysroot: &Path, target: &str) -> Vec<&'static str> {
+    let files: Vec<String> = std::fs::read_dir(path!(sysroot / "lib" / "rustlib" / target / "lib"))
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .collect()
+        })
+        .unwrap_or_default();
+    COMPONENTS
+        .iter()
+        .filter(|(_, prefix)| !files.iter().any(|file| file.starts_with(prefix)))
+        .map(|&(component, _)| component)
+        .collect()
+}
+
+/// The rpath of Miri built for `target`, instead of the rustc library dir of the toolchain here,
+/// which means nothing on another machine: the `lib` dir next to the `bin` dir the binary is put
+/// in, which is where the rustc libraries are in a toolchain (and how rustc itself finds them).
+/// `None` for targets whose linker takes no rpath.
+pub fn rpath(target: &str) -> Option<&'static str> {
+    if target.contains("-windows") || target.starts_with("wasm") {
+        None
+    } else if target.contains("-apple-") {
+        Some("@loader_path/../lib")
+    } else {
+        Some("$ORIGIN/../lib")
+    }
+}
+
+/// What executables end in on `target`.
+pub fn exe_suffix(target: &str) -> &'static str {
+    if target.contains("-windows") {
+        ".exe"
+    } else {
+        ""
+    }
+}
+
+/// The env var that tells cargo which linker to use for `target`.
+fn linker_var(target: &str) -> String {
+    format!("CARGO_TARGET_{}_LINKER", target.to_uppercase().replace(['-', '.'], "_"))
+}
+
+/// Says that Miri gets built for `target`, and what that means.
+pub fn announce(target: &str) {
+    status!("$ (building Miri for `{target}`)");
+    if rpath(target).is_none() {
+        warning!(
+            "the binaries for `{target}` get no rpath, so the rustc libraries have to be found \
+            some other way there, like in the same dir"
+        );
+    }
+    let linker_var = linker_var(target);
+    if std::env::var_os(&linker_var).is_none() {
+        note!(
+            "if linking fails, set {linker_var} (or `target.{target}.linker` in a cargo config) \
+            to a linker for `{target}`"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn targets() {
+        assert_eq!(rpath("aarch64-unknown-linux-gnu"), Some("$ORIGIN/../lib"));
+        assert_eq!(rpath("x86_64-apple-darwin"), Some("@loader_path/../lib"));
+        assert_eq!(rpath("x86_64-pc-windows-gnu"), None);
+        assert_eq!(exe_suffix("x86_64-pc-windows-msvc"), ".exe");
+        assert_eq!(exe_suffix("aarch64-unknown-linux-gnu"), "");
+        assert_eq!(
+            linker_var("armv7-unknown-linux-gnueabihf"),
+            "CARGO_TARGET_ARMV7_UNKNOWN_LINUX_GNUEABIHF_LINKER"
+        );
+
+        let sysroot = TempDir::new("miri-script-cross-test", false).unwrap();
+        let target = "aarch64-unknown-linux-gnu";
+        assert_eq!(missing_components(&sysroot.path, target), ["rust-std", "rustc-dev"]);
+        let libdir = path!(sysroot.path / "lib" / "rustlib" / target / "lib");
+        std::fs::create_dir_all(&libdir).unwrap();
+        std::fs::write(path!(libdir / "libstd-0123abcd.rlib"), "").unwrap();
+        assert_eq!(missing_components(&sysroot.path, target), ["rustc-dev"]);
+        std::fs::write(path!(libdir / "librustc_middle-0123abcd.rlib"), "").unwrap();
+        assert!(missing_components(&sysroot.path, target).is_empty());
+    }
+}
@@ -0,0 +1,127 @@
+//! `./miri debug`: running the Miri driver under a debugger.
+
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+
+use crate::cmdline;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Debugger {
+    Gdb,
+    RustGdb,
+    Lldb,
+    RustLldb,
+    Windbg,
+    /// The Visual Studio debugger.
+    Devenv,
+}
+
+impl FromStr for Debugger {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "gdb" => Debugger::Gdb,
+            "rust-gdb" => Debugger::RustGdb,
+            "lldb" => Debugger::Lldb,
+            "rust-lldb" => Debugger::RustLldb,
+            "windbg" => Debugger::Windbg,
+            "devenv" => Debugger::Devenv,
+            _ =>
+                bail!(
+                    "unknown debugger `{s}`, expected one of: gdb, rust-gdb, lldb, rust-lldb, windbg, devenv"
+                ),
+        })
+    }
+}
+
+impl fmt::Display for Debugger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.program())
+    }
+}
+
+impl Debugger {
+    pub fn program(self) -> &'static str {
+        match self {
+            Debugger::Gdb => "gdb",
+            Debugger::RustGdb => "rust-gdb",
+            Debugger::Lldb => "lldb",
+            Debugger::RustLldb => "rust-lldb",
+            Debugger::Windbg => "windbg",
+            Debugger::Devenv => "devenv",
+        }
+    }
+
+    /// The debuggers to try if none was given, in order of preference. The `rust-` wrappers come
+    /// first since they know how to print Rust values.
+    pub fn defaults() -> &'static [Debugger] {
+        if cfg!(windows) {
+            &[Debugger::Windbg, Debugger::Devenv]
+        } else if cfg!(target_os = "macos") {
+            &[Debugger::RustLldb, Debugger::Lldb]
+        } else {
+            &[Debugger::RustGdb, Debugger::Gdb, Debugger::RustLldb, Debugger::Lldb]
+        }
+    }
+
+    /// The arguments that make the debugger load `program` with `args`, so that `run` (or
+    /// whatever the debugger calls it) starts it.
+    pub fn args(self, program: &Path, args: &[OsString]) -> Vec<OsString> {
+        let mut all: Vec<OsString> = match self {
+            Debugger::Gdb | Debugger::RustGdb => vec!["--args".into()],
+            Debugger::Lldb | Debugger::RustLldb => vec!["--".into()],
+            Debugger::Windbg => vec![],
+            Debugger::Devenv => vec!["/debugexe".into()],
+        };
+        all.push(program.into());
+        all.extend(args.iter().cloned());
+        all
+    }
+}
+
+/// The variable that tells the dynamic linker where to find libraries.
+pub fn dylib_path_var() -> &'static str {
+    if cfg!(windows) {
+        "PATH"
+    } else if cfg!(target_os = "macos") {
+        "DYLD_FALLBACK_LIBRARY_PATH"
+    } else {
+        "LD_LIBRARY_PATH"
+    }
+}
+
+/// Renders a command with its environment, ready to be pasted into the user's shell.
+pub fn render_command(env: &[(&str, OsString)], program: &OsStr, args: &[OsString]) -> String {
+    let argv: Vec<&OsStr> =
+        [program].into_iter().chain(args.iter().map(OsString::as_os_str)).collect();
+    let env: Vec<_> = env.iter().map(|(var, value)| (*var, Some(value))).collect();
+    cmdline::display_command(&argv, None, &env)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debuggers() {
+        for debugger in Debugger::defaults() {
+            assert_eq!(debugger.to_string().parse::<Debugger>().unwrap(), *debugger);
+        }
+        assert!("valgrind".parse::<Debugger>().is_err());
+        let args = ["tests/pass/hello.rs".into(), "--".into(), "a b".into()];
+        let miri = Path::new("target/debug/miri");
+        let gdb = Debugger::RustGdb.args(miri, &args);
+        assert_eq!(gdb, ["--args", "target/debug/miri", "tests/pass/hello.rs", "--", "a b"]);
+        assert_eq!(Debugger::Lldb.args(miri, &args)[..2], ["--", "target/debug/miri"]);
+        assert_eq!(Debugger::Windbg.args(miri, &[])[..], ["target/debug/miri"]);
+        assert_eq!(
+            render_command(&[("MIRI_SYSROOT", "/tmp/my sysroot".into())], "gdb".as_ref(), &gdb),
+            "MIRI_SYSROOT='/tmp/my sysroot' gdb --args target/debug/miri tests/pass/hello.rs -- 'a b'"
+        );
+    }
+}
@@ -0,0 +1,603 @@
+//! Diagnostics for common misconfigurations of a Miri development setup.
+
+use std::env;
+use std::fmt;
+use std::path::Path;
+
+use path_macro::path;
+use serde::Serialize;
+use xshell::Shell;
+
+use crate::record::{cmd, Cmd};
+use crate::rustup::RustupInfo;
+use crate::sync::{installed_josh, josh_install_command, josh_version, JoshVersion, JOSH_VERSION};
+use crate::util::*;
+use crate::GlobalArgs;
+
+/// Below this much free space in the target dir, builds are likely to fail.
+const MIN_FREE_DISK_MIB: u64 = 1024;
+/// Below this much free space in the target dir, we warn.
+const LOW_FREE_DISK_MIB: u64 = 10 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// What a check looks at. Its `id` is the `check` of `./miri doctor --json`, which scripts rely
+/// on: never change one, only add new checks (and to `doctor.schema.json`, too).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Check {
+    /// Whether we run in a Miri checkout, and can tell where the target dir is.
+    MiriDir,
+    /// The `--version` of the locally built rustc, if one is used.
+    LocalRustc,
+    /// Whether the toolchain is installed, at the commit `rust-version` says.
+    Toolchain,
+    /// The rustup components Miri needs, and the `miri` one that gets in the way.
+    Components,
+    /// The library dir of the toolchain, which Miri links against.
+    Libdir,
+    /// Whether `./miri install` can write to the sysroot of the toolchain.
+    SysrootWritable,
+    /// The `--version` of the tools of the toolchain.
+    Cargo,
+    Rustfmt,
+    Clippy,
+    /// RUSTFLAGS that conflict with ours.
+    Rustflags,
+    /// The env vars that change how Miri is built.
+    BuildSettings,
+    /// A MIRI_SYSROOT that keeps the sysroot from being rebuilt.
+    MiriSysroot,
+    /// The josh-proxy that `rustc-pull` and `rustc-push` need.
+    Josh,
+    /// The free space in the target dir.
+    DiskSpace,
+}
+
+impl Check {
+    /// For checking that the schema knows all of them.
+    #[cfg(test)]
+    const ALL: &[Check] = &[
+        Check::MiriDir,
+        Check::LocalRustc,
+        Check::Toolchain,
+        Check::Components,
+        Check::Libdir,
+        Check::SysrootWritable,
+        Check::Cargo,
+        Check::Rustfmt,
+        Check::Clippy,
+        Check::Rustflags,
+        Check::BuildSettings,
+        Check::MiriSysroot,
+        Check::Josh,
+        Check::DiskSpace,
+    ];
+
+    pub fn id(self) -> &'static str {
+        match self {
+            Check::MiriDir => "miri-dir",
+            Check::LocalRustc => "local-rustc",
+            Check::Toolchain => "toolchain",
+            Check::Components => "components",
+            Check::Libdir => "libdir",
+            Check::SysrootWritable => "sysroot-writable",
+            Check::Cargo => "cargo",
+            Check::Rustfmt => "rustfmt",
+            Check::Clippy => "clippy",
+            Check::Rustflags => "rustflags",
+            Check::BuildSettings => "build-settings",
+            Check::MiriSysroot => "miri-sysroot",
+            Check::Josh => "josh",
+            Check::DiskSpace => "disk-space",
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.id())
+    }
+}
+
+impl Serialize for Check {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.id())
+    }
+}
+
+/// How to fix a problem: what we tell the user, and the command that does it, if there is one.
+pub struct Fix {
+    advice: String,
+    command: Option<String>,
+}
+
+impl Fix {
+    /// Running `command` fixes the problem.
+    fn command(command: impl Into<String>) -> Fix {
+        let command = command.into();
+        Fix { advice: command.clone(), command: Some(command) }
+    }
+
+    /// Running `command` fixes the problem; it does `what`.
+    fn run(command: &str, what: &str) -> Fix {
+        Fix { advice: format!("run `{command}` to {what}"), command: Some(command.to_owned()) }
+    }
+
+    /// There is no one command that fixes the problem.
+    fn advice(advice: impl Into<String>) -> Fix {
+        Fix { advice: advice.into(), command: None }
+    }
+}
+
+/// The outcome of a single diagnostic check. Both the human output and `--json` show these.
+#[derive(Debug, Serialize)]
+pub struct CheckResult {
+    pub check: Check,
+    pub status: Status,
+    pub detail: String,
+    /// A suggestion for how to fix the problem, if the check did not pass.
+    pub fix: Option<String>,
+    /// The command that fixes the problem, if there is one.
+    pub fix_command: Option<String>,
+}
+
+impl CheckResult {
+    fn new(check: Check, status: Status, detail: impl Into<String>, fix: Option<Fix>) -> Self {
+        let (fix, fix_command) = match fix {
+            Some(Fix { advice, command }) => (Some(advice), command),
+            None => (None, None),
+        };
+        CheckResult { check, status, detail: detail.into(), fix, fix_command }
+    }
+
+    fn pass(check: Check, detail: impl Into<String>) -> Self {
+        CheckResult::new(check, Status::Pass, detail, None)
+    }
+
+    fn warn(check: Check, detail: impl Into<String>, fix: Fix) -> Self {
+        CheckResult::new(check, Status::Warn, detail, Some(fix))
+    }
+
+    fn fail(check: Check, detail: impl Into<String>, fix: Fix) -> Self {
+        CheckResult::new(check, Status::Fail, detail, Some(fix))
+    }
+}
+
+/// Runs all checks. This never fails; problems are reported as failed checks instead.
+pub fn run_checks(global: &GlobalArgs) -> Vec<CheckResult> {
+    let mut results = Vec::new();
+    let ctx = match ScriptCtx::new() {
+        Ok(ctx) => ctx,
+        Err(err) => {
+            results.push(CheckResult::fail(
+                Check::MiriDir,
+                format!("could not determine the Miri checkout and target dir: {err:#}"),
+                Fix::advice(
+                    "run the script via `./miri` from a Miri checkout, and check CARGO_EXTRA_FLAGS",
+                ),
+            ));
+            return results;
+        }
+    };
+    let sh = ctx.miri_dir_shell();
+
+    if let Some(rustc) = local_rustc(global) {
+        results.push(check_tool_version(Check::LocalRustc, cmd!(sh, "{rustc} --version")));
+    }
+    match resolve_toolchain(global.toolchain.as_deref()) {
+        Ok((toolchain, source)) => {
+            // One query for both checks, so that rustup (which is slow to start) runs only twice.
+            let rustup = RustupInfo::query(&toolchain);
+            results.push(check_toolchain(&sh, &toolchain, source, rustup.as_ref().ok()));
+            results.push(check_components(&toolchain, &rustup));
+            results.push(check_libdir(&sh, &toolchain));
+            results.push(check_sysroot_writable(&sh, &toolchain));
+            results
+                .push(check_tool_version(Check::Cargo, cmd!(sh, "cargo +{toolchain} --version")));
+            results.push(check_tool_version(
+                Check::Rustfmt,
+                cmd!(sh, "rustfmt +{toolchain} --version"),
+            ));
+            results.push(check_tool_version(
+                Check::Clippy,
+                cmd!(sh, "cargo +{toolchain} clippy --version"),
+            ));
+        }
+        Err(err) =>
+            results.push(CheckResult::fail(
+                Check::Toolchain,
+                format!("could not determine the toolchain: {err:#}"),
+                Fix::advice("install rustup and run `./miri toolchain`"),
+            )),
+    }
+    results.push(check_rustflags());
+    results.push(check_build_settings());
+    results.push(check_miri_sysroot());
+    results.push(check_josh());
+    results.push(check_disk_space(&sh, &ctx.target_dir));
+    results
+}
+
+fn check_toolchain(
+    sh: &Shell,
+    toolchain: &str,
+    source: ToolchainSource,
+    rustup: Option<&RustupInfo>,
+) -> CheckResult {
+    const CHECK: Check = Check::Toolchain;
+    let source = match rustup.and_then(|info| info.active_reason.as_deref()) {
+        Some(reason) if source == ToolchainSource::Rustup => format!("{source}, {reason}"),
+        _ => source.to_string(),
+    };
+    if rustup.is_some_and(|info| !info.is_installed(toolchain)) {
+        return CheckResult::fail(
+            CHECK,
+            format!("toolchain `{toolchain}` (from {source}) is not installed"),
+            Fix::run("./miri toolchain", "install the pinned toolchain"),
+        );
+    }
+    let Ok(expected) = sh.read_file("rust-version") else {
+        return CheckResult::fail(
+            CHECK,
+            "could not read the `rust-version` file",
+            Fix::advice("make sure you are in a complete Miri checkout"),
+        );
+    };
+    let expected = expected.trim();
+    let actual = cmd!(sh, "rustc +{toolchain} --version --verbose")
+        .quiet()
+        .ignore_stderr()
+        .read()
+        .ok()
+        .and_then(|out| rustc_version::version_meta_for(&out).ok())
+        .and_then(|meta| meta.commit_hash);
+    match actual {
+        None =>
+            CheckResult::fail(
+                CHECK,
+                format!("could not query rustc of toolchain `{toolchain}` (from {source})"),
+                Fix::run("./miri toolchain", "install the pinned toolchain"),
+            ),
+        Some(actual) if actual == expected =>
+            CheckResult::pass(CHECK, format!("`{toolchain}` (from {source}) is at {expected}")),
+        Some(actual) =>
+            CheckResult::warn(
+                CHECK,
+                format!(
+                    "`{toolchain}` (from {source}) is at {actual}, \
+                    but `rust-version` says {expected}"
+                ),
+                Fix::run("./miri toolchain", "install the pinned toolchain"),
+            ),
+    }
+}
+
+fn check_components(toolchain: &str, rustup: &anyhow::Result<RustupInfo>) -> CheckResult {
+    const CHECK: Check = Check::Components;
+    const REQUIRED: &[&str] = &["rust-src", "rustc-dev", "llvm-tools"];
+    let installed = match rustup {
+        Ok(installed) => installed,
+        Err(err) =>
+            return CheckResult::warn(
+                CHECK,
+                format!("could not list the installed rustup components: {err:#}"),
+                Fix::advice("make sure `rustup` is installed and on your PATH"),
+            ),
+    };
+    let missing: Vec<&str> =
+        REQUIRED.iter().copied().filter(|c| !installed.has_component(c)).collect();
+    if !missing.is_empty() {
+        let missing = missing.join(" ");
+        return CheckResult::fail(
+            CHECK,
+            format!("missing components: {missing}"),
+            Fix::command(format!("rustup component add --toolchain {toolchain} {missing}")),
+        );
+    }
+    if installed.has_component("miri") {
+        return CheckResult::warn(
+            CHECK,
+            "the rustup `miri` component is installed and may shadow the locally built Miri",
+            Fix::command(format!("rustup component remove --toolchain {toolchain} miri")),
+        );
+    }
+    CheckResult::pass(CHECK, format!("{} are installed", REQUIRED.join(", ")))
+}
+
+fn check_libdir(sh: &Shell, toolchain: &str) -> CheckResult {
+    const CHECK: Check = Check::Libdir;
+    match rustc_sysroot_and_libdir(sh, "rustc".as_ref(), Some(toolchain)) {
+        Ok((_, libdir)) if libdir.exists() =>
+            CheckResult::pass(CHECK, libdir.display().to_string()),
+        Ok((_, libdir)) =>
+            CheckResult::fail(
+                CHECK,
+                format!("{} does not exist", libdir.display()),
+                Fix::run("./miri toolchain", "reinstall the toolchain"),
+            ),
+        Err(err) =>
+            CheckResult::fail(
+                CHECK,
+                format!("could not determine the library dir: {err}"),
+                Fix::run("./miri toolchain", "reinstall the toolchain"),
+            ),
+    }
+}
+
+fn check_sysroot_writable(sh: &Shell, toolchain: &str) -> CheckResult {
+    const CHECK: Check = Check::SysrootWritable;
+    let Ok((sysroot, _)) = rustc_sysroot_and_libdir(sh, "rustc".as_ref(), Some(toolchain)) else {
+        // `libdir` already says so.
+        return CheckResult::warn(
+            CHECK,
+            "could not determine the sysroot",
+            Fix::run("./miri toolchain", "reinstall the toolchain"),
+        );
+    };
+    let bin = path!(sysroot / "bin");
+    match check_writable(&bin) {
+        Ok(()) => CheckResult::pass(CHECK, format!("{} is writable", bin.display())),
+        Err(err) =>
+            CheckResult::warn(
+                CHECK,
+                format!("{err:#}, so `./miri install` cannot install there"),
+                Fix::advice(
+                    "run `./miri install --root <dir>` to install into `<dir>/bin` instead",
+                ),
+            ),
+    }
+}
+
+fn check_tool_version(check: Check, cmd: Cmd<'_>) -> CheckResult {
+    match cmd.quiet().ignore_stderr().read() {
+        Ok(version) => CheckResult::pass(check, version.trim().to_owned()),
+        Err(_) =>
+            CheckResult::fail(
+                check,
+                format!("`{check} --version` failed"),
+                Fix::run("./miri toolchain", "install the pinned toolchain with all components"),
+            ),
+    }
+}
+
+fn check_rustflags() -> CheckResult {
+    const CHECK: Check = Check::Rustflags;
+    if env::var_os("CARGO_ENCODED_RUSTFLAGS").is_some() {
+        return CheckResult::warn(
+            CHECK,
+            "CARGO_ENCODED_RUSTFLAGS is set, so cargo will ignore the RUSTFLAGS computed by `./miri`",
+            Fix::advice("unset CARGO_ENCODED_RUSTFLAGS and use RUSTFLAGS instead"),
+        );
+    }
+    match env::var("RUSTFLAGS") {
+        Ok(flags) if flags.contains("link-args") || flags.contains("rpath") =>
+            CheckResult::warn(
+                CHECK,
+                format!("RUSTFLAGS contains linker arguments that may conflict with ours: {flags}"),
+                Fix::advice("remove the `link-args`/`rpath` flags from RUSTFLAGS"),
+            ),
+        Ok(flags) => CheckResult::pass(CHECK, format!("user RUSTFLAGS: {flags}")),
+        Err(_) => CheckResult::pass(CHECK, "no user RUSTFLAGS"),
+    }
+}
+
+fn check_build_settings() -> CheckResult {
+    const CHECK: Check = Check::BuildSettings;
+    let overrides = Ambient::get().build_setting_overrides();
+    if overrides.is_empty() {
+        return CheckResult::pass(CHECK, "no build settings in the environment");
+    }
+    CheckResult::warn(
+        CHECK,
+        format!("the environment changes how Miri is built: {}", overrides.join(" ")),
+        Fix::advice(
+            "unset these to build like CI, or use `./miri --hermetic` to ignore them for a run",
+        ),
+    )
+}
+
+fn check_miri_sysroot() -> CheckResult {
+    const CHECK: Check = Check::MiriSysroot;
+    match Ambient::get().miri_sysroot() {
+        Some(sysroot) => CheckResult::warn(
+            CHECK,
+            format!(
+                "MIRI_SYSROOT is set to {}, so the sysroot will not be rebuilt and may be stale",
+                Path::new(&sysroot).display()
+            ),
+            Fix::advice(
+                "unset MIRI_SYSROOT unless you know you need it, or pass `--ignore-miri-sysroot`",
+            ),
+        ),
+        None => CheckResult::pass(CHECK, "MIRI_SYSROOT is not set"),
+    }
+}
+
+fn check_josh() -> CheckResult {
+    const CHECK: Check = Check::Josh;
+    let installed = installed_josh();
+    if let Some(path) = installed.iter().find(|p| josh_version(p) == Some(JoshVersion::pinned())) {
+        return CheckResult::pass(CHECK, format!("{} ({JOSH_VERSION})", path.display()));
+    }
+    let Some(path) = installed.first() else {
+        return CheckResult::warn(
+            CHECK,
+            "josh-proxy is not installed (only needed for `rustc-pull`/`rustc-push`)",
+            Fix::command(josh_install_command()),
+        );
+    };
+    let version = josh_version(path)
+        .map_or_else(|| "of an unknown version".to_owned(), |v| format!("version {v}"));
+    CheckResult::warn(
+        CHECK,
+        format!("{} is {version}, but the sync needs {JOSH_VERSION}", path.display()),
+        Fix::command(josh_install_command()),
+    )
+}
+
+fn check_disk_space(sh: &Shell, target_dir: &Path) -> CheckResult {
+    const CHECK: Check = Check::DiskSpace;
+    const DISK_SPACE_FIX: &str = "free up disk space or point CARGO_TARGET_DIR elsewhere";
+    // The target dir might not exist yet; use the closest ancestor that does.
+    let Some(existing) = target_dir.ancestors().find(|p| p.exists()) else {
+        return CheckResult::warn(
+            CHECK,
+            format!("{} does not exist", target_dir.display()),
+            Fix::advice("check your CARGO_TARGET_DIR"),
+        );
+    };
+    let Some(free_mib) = free_disk_space_mib(sh, existing) else {
+        return CheckResult::warn(
+            CHECK,
+            format!("could not determine the free disk space at {}", existing.display()),
+            Fix::advice(format!("make sure there are at least {LOW_FREE_DISK_MIB} MiB free")),
+        );
+    };
+    let detail = format!("{free_mib} MiB free at {}", target_dir.display());
+    if free_mib < MIN_FREE_DISK_MIB {
+        CheckResult::fail(CHECK, detail, Fix::advice(DISK_SPACE_FIX))
+    } else if free_mib < LOW_FREE_DISK_MIB {
+        CheckResult::warn(CHECK, detail, Fix::advice(DISK_SPACE_FIX))
+    } else {
+        CheckResult::pass(CHECK, detail)
+    }
+}
+
+/// Uses `df` to determine the free disk space. Returns `None` if that is not possible.
+fn free_disk_space_mib(sh: &Shell, path: &Path) -> Option<u64> {
+    let output = cmd!(sh, "df -Pk {path}").quiet().ignore_stderr().read().ok()?;
+    // The second line contains the data; the 4th column is the available space in KiB.
+    let kib: u64 = output.lines().nth(1)?.split_whitespace().nth(3)?.parse().ok()?;
+    Some(kib / 1024)
+}
+
+/// The results in human-readable form.
+pub fn human(results: &[CheckResult]) -> String {
+    let mut out = String::new();
+    for result in results {
+        let status = match result.status {
+            Status::Pass => "pass",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        };
+        out.push_str(&format!("[{status}] {}: {}\n", result.check, result.detail));
+        if let Some(fix) = &result.fix {
+            out.push_str(&format!("       fix: {fix}\n"));
+        }
+    }
+    out
+}
+
+/// The results as a JSON array, as described by `doctor.schema.json`.
+pub fn json(results: &[CheckResult]) -> String {
+    serde_json::to_string_pretty(results).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    /// Checks `value` against the parts of JSON Schema that `doctor.schema.json` uses.
+    fn validate(value: &Value, schema: &Value, path: &str) -> Result<(), String> {
+        let fail = |what: &str| Err(format!("{path}: {what}"));
+        if let Some(types) = schema.get("type") {
+            let types: Vec<&str> = match types {
+                Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                ty => vec![ty.as_str().unwrap()],
+            };
+            let ty = match value {
+                Value::Null => "null",
+                Value::Bool(_) => "boolean",
+                Value::Number(_) => "number",
+                Value::String(_) => "string",
+                Value::Array(_) => "array",
+                Value::Object(_) => "object",
+            };
+            if !types.contains(&ty) {
+                return fail(&format!("{ty} is not one of {types:?}"));
+            }
+        }
+        if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+            if !allowed.contains(value) {
+                return fail(&format!("{value} is not one of {allowed:?}"));
+            }
+        }
+        if let (Some(items), Value::Array(values)) = (schema.get("items"), value) {
+            for (i, value) in values.iter().enumerate() {
+                validate(value, items, &format!("{path}[{i}]"))?;
+            }
+        }
+        if let Value::Object(fields) = value {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for required in schema.get("required").and_then(Value::as_array).into_iter().flatten() {
+                if !fields.contains_key(required.as_str().unwrap()) {
+                    return fail(&format!("`{required}` is missing"));
+                }
+            }
+            for (key, value) in fields {
+                match properties.and_then(|properties| properties.get(key)) {
+                    Some(schema) => validate(value, schema, &format!("{path}.{key}"))?,
+                    None if schema["additionalProperties"] == false =>
+                        return fail(&format!("`{key}` is not allowed")),
+                    None => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn json_matches_schema() {
+        let schema: Value = serde_json::from_str(include_str!("doctor.schema.json")).unwrap();
+        let results: Vec<CheckResult> = Check::ALL
+            .iter()
+            .enumerate()
+            .map(|(i, &check)| {
+                match i % 3 {
+                    0 => CheckResult::pass(check, "fine"),
+                    1 => CheckResult::warn(check, "hmm", Fix::advice("look into it")),
+                    _ => CheckResult::fail(check, "broken", Fix::run("./miri toolchain", "fix it")),
+                }
+            })
+            .collect();
+        let json: Value = serde_json::from_str(&json(&results)).unwrap();
+        validate(&json, &schema, "$").unwrap();
+        // The schema lists exactly our checks.
+        let ids: Vec<&str> = schema["items"]["properties"]["check"]["enum"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|id| id.as_str().unwrap())
+            .collect();
+        assert_eq!(ids, Check::ALL.iter().map(|check| check.id()).collect::<Vec<_>>());
+        // And it does catch mistakes.
+        let mut wrong = json.clone();
+        wrong[0]["check"] = "miri_dir".into();
+        assert!(validate(&wrong, &schema, "$").is_err());
+        wrong = json.clone();
+        wrong[1].as_object_mut().unwrap().remove("fix_command");
+        assert!(validate(&wrong, &schema, "$").is_err());
+
+        // The human output shows the same results, in the same order.
+        let human = human(&results);
+        let checks: Vec<&str> = human
+            .lines()
+            .filter(|line| line.starts_with('['))
+            .map(|line| line.split_once("] ").unwrap().1.split_once(':').unwrap().0)
+            .collect();
+        assert_eq!(checks, ids);
+        assert!(human.contains(
+            "[FAIL] toolchain: broken\n       fix: run `./miri toolchain` to fix it\n[pass]"
+        ));
+        assert_eq!(json[2]["fix_command"], "./miri toolchain");
+        assert_eq!(json[0]["fix"], Value::Null);
+    }
+}
@@ -0,0 +1,168 @@
+//! `--message-format json`: what `./miri` is doing, as one JSON object per line on stdout, for
+//! tools that drive it. Everything else we (and the commands we run) print goes to stderr then.
+//!
+//! The events are only defined here. Consumers should check `version`, which goes up whenever an
+//! event changes in a way that could break them (not when one is added).
+
+use std::fs::File;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock, PoisonError};
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+/// The version of the events below.
+pub const VERSION: u32 = 1;
+
+/// The `--message-format` option.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageFormat {
+    Human,
+    Json,
+}
+
+impl FromStr for MessageFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "human" => MessageFormat::Human,
+            "json" => MessageFormat::Json,
+            _ => bail!("invalid value `{s}` for `--message-format`, expected human or json"),
+        })
+    }
+}
+
+/// How a step or a seed went.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Status {
+    Ok,
+    Failed,
+    /// The command got killed because it took too long.
+    TimedOut,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// A step began: a `./miri ci` step, or a command that says what it does (like `build miri`).
+    StepStarted {
+        name: &'a str,
+    },
+    StepFinished {
+        name: &'a str,
+        status: Status,
+        duration_secs: f64,
+    },
+    /// One run of `./miri run --many-seeds` finished.
+    SeedResult {
+        seed: u32,
+        status: Status,
+        duration_secs: f64,
+    },
+    /// A warning we printed (without the `warning: `).
+    Warning {
+        message: &'a str,
+    },
+    /// An error we printed, like the one `./miri` fails with.
+    Error {
+        message: &'a str,
+    },
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    version: u32,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// Where the events go: what stdout was before we pointed it at stderr.
+static EVENTS: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Turns the events on for `--message-format json`: stdout is kept for them, and everything else
+/// that would go there (including the output of the commands we run) goes to stderr from now on.
+/// This has to run before anything is printed, and before any threads are spawned.
+pub fn init(format: Option<MessageFormat>) -> Result<()> {
+    if format != Some(MessageFormat::Json) {
+        return Ok(());
+    }
+    let stdout = redirect_stdout()?;
+    EVENTS.set(Mutex::new(stdout)).unwrap();
+    Ok(())
+}
+
+#[cfg(unix)]
+fn redirect_stdout() -> Result<File> {
+    use std::os::fd::FromRawFd;
+    // SAFETY: these only duplicate file descriptors; the one `fcntl` returns is ours alone.
+    unsafe {
+        let stdout = libc::fcntl(libc::STDOUT_FILENO, libc::F_DUPFD_CLOEXEC, 3);
+        if stdout < 0 || libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            bail!("failed to redirect stdout: {}", std::io::Error::last_os_error());
+        }
+        Ok(File::from_raw_fd(stdout))
+    }
+}
+
+#[cfg(windows)]
+fn redirect_stdout() -> Result<File> {
+    use std::os::windows::io::FromRawHandle;
+    use windows_sys::Win32::System::Console::{
+        GetStdHandle, SetStdHandle, STD_ERROR_HANDLE, STD_OUTPUT_HANDLE,
+    };
+    // SAFETY: the standard handles stay open; we only change which one is stdout. Both std and the
+    // commands we spawn look it up each time.
+    unsafe {
+        let stdout = GetStdHandle(STD_OUTPUT_HANDLE);
+        if SetStdHandle(STD_OUTPUT_HANDLE, GetStdHandle(STD_ERROR_HANDLE)) == 0 {
+            bail!("failed to redirect stdout: {}", std::io::Error::last_os_error());
+        }
+        Ok(File::from_raw_handle(stdout as _))
+    }
+}
+
+/// Prints `event` on a line of its own, if the events are on.
+pub fn emit(event: &Event<'_>) {
+    let Some(events) = EVENTS.get() else {
+        return;
+    };
+    let mut line = serde_json::to_string(&Line { version: VERSION, event }).unwrap();
+    line.push('\n');
+    // If the consumer went away, there is nobody to tell about it.
+    let _ = events.lock().unwrap_or_else(PoisonError::into_inner).write_all(line.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_events() {
+        let line =
+            |event| serde_json::to_string(&Line { version: VERSION, event: &event }).unwrap();
+        assert_eq!(
+            line(Event::StepStarted { name: "build miri" }),
+            r#"{"version":1,"event":"step_started","name":"build miri"}"#
+        );
+        assert_eq!(
+            line(Event::StepFinished {
+                name: "build miri",
+                status: Status::TimedOut,
+                duration_secs: 1.5
+            }),
+            r#"{"version":1,"event":"step_finished","name":"build miri","status":"timed_out","duration_secs":1.5}"#
+        );
+        assert_eq!(
+            line(Event::SeedResult { seed: 7, status: Status::Failed, duration_secs: 0.25 }),
+            r#"{"version":1,"event":"seed_result","seed":7,"status":"failed","duration_secs":0.25}"#
+        );
+        assert_eq!(
+            line(Event::Warning { message: "a \"b\"" }),
+            r#"{"version":1,"event":"warning","message":"a \"b\""}"#
+        );
+    }
+}
@@ -0,0 +1,98 @@
+//! `./miri fuzz`: running the cargo-fuzz targets in `fuzz/`.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+/// The kinds of files libFuzzer writes when it finds a problem.
+const ARTIFACT_PREFIXES: &[&str] = &["crash-", "leak-", "timeout-", "oom-", "slow-unit-"];
+
+/// The fuzz targets in the cargo-fuzz directory `fuzz_dir`, by name.
+pub fn targets(fuzz_dir: &Path) -> Result<Vec<String>> {
+    let targets_dir = fuzz_dir.join("fuzz_targets");
+    let mut targets = Vec::new();
+    for entry in fs::read_dir(&targets_dir)
+        .with_context(|| format!("failed to read {}", targets_dir.display()))?
+    {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "rs") {
+            targets.push(path.file_stem().unwrap().to_string_lossy().into_owned());
+        }
+    }
+    targets.sort();
+    Ok(targets)
+}
+
+/// The files libFuzzer wrote to `dir` to reproduce a problem.
+pub fn artifacts(dir: &Path) -> Result<BTreeSet<PathBuf>> {
+    if !dir.exists() {
+        return Ok(BTreeSet::new());
+    }
+    let mut artifacts = BTreeSet::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy();
+        if ARTIFACT_PREFIXES.iter().any(|prefix| name.starts_with(prefix)) {
+            artifacts.insert(path);
+        }
+    }
+    Ok(artifacts)
+}
+
+/// Whether the toolchain in `sysroot` has the address sanitizer runtime for `host`, which cargo
+/// fuzz needs by default.
+pub fn has_asan_runtime(sysroot: &Path, host: &str) -> bool {
+    let libdir = sysroot.join("lib").join("rustlib").join(host).join("lib");
+    fs::read_dir(libdir).is_ok_and(|entries| {
+        entries.filter_map(|entry| entry.ok()).any(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name.contains("_rt.asan.")
+        })
+    })
+}
+
+/// Checks that `target` is one of `targets`.
+pub fn check_target(target: &str, targets: &[String]) -> Result<()> {
+    if targets.iter().any(|t| t == target) {
+        return Ok(());
+    }
+    match crate::args::nearest(
+        target,
+        targets.iter().map(String::as_str).collect::<Vec<_>>().iter(),
+    ) {
+        Some(suggestion) => bail!("unknown fuzz target `{target}`; did you mean `{suggestion}`?"),
+        None => bail!("unknown fuzz target `{target}`; see `./miri fuzz --list`"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuzz_dir() {
+        let dir = std::env::temp_dir().join(format!("miri-fuzz-{}", std::process::id()));
+        fs::create_dir_all(dir.join("fuzz_targets")).unwrap();
+        fs::create_dir_all(dir.join("artifacts")).unwrap();
+        for file in ["fuzz_targets/miriflags.rs", "fuzz_targets/layout.rs", "fuzz_targets/README"] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        for file in ["crash-da39a3ee", "oom-1234", "input.bin"] {
+            fs::write(dir.join("artifacts").join(file), "").unwrap();
+        }
+
+        let targets = targets(&dir).unwrap();
+        assert_eq!(targets, ["layout", "miriflags"]);
+        assert!(check_target("layout", &targets).is_ok());
+        let err = check_target("miriflag", &targets).unwrap_err().to_string();
+        assert_eq!(err, "unknown fuzz target `miriflag`; did you mean `miriflags`?");
+        let artifacts = artifacts(&dir.join("artifacts")).unwrap();
+        let names: Vec<_> = artifacts.iter().map(|a| a.file_name().unwrap()).collect();
+        assert_eq!(names, ["crash-da39a3ee", "oom-1234"]);
+        assert!(!has_asan_runtime(&dir, "x86_64-unknown-linux-gnu"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,481 @@
+//! `./miri ide-setup`: the rust-analyzer settings for working on Miri, written into the config of
+//! an editor (merged with what is there already), or printed.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use path_macro::path;
+use serde_json::{json, Map, Value};
+
+use crate::output::{status, success, warning};
+use crate::record::skip_in_dry_run;
+use crate::util::MiriEnv;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Editor {
+    Vscode,
+    Neovim,
+    Helix,
+}
+
+impl FromStr for Editor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "vscode" | "code" => Editor::Vscode,
+            "neovim" | "nvim" => Editor::Neovim,
+            "helix" | "hx" => Editor::Helix,
+            _ => bail!("unknown editor `{s}`, expected one of: vscode, neovim, helix"),
+        })
+    }
+}
+
+impl fmt::Display for Editor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Editor::Vscode => "vscode",
+            Editor::Neovim => "neovim",
+            Editor::Helix => "helix",
+        })
+    }
+}
+
+impl Editor {
+    /// Where the editor looks for settings of the project, relative to the Miri dir. For Neovim,
+    /// that is the file of neoconf.nvim (which also passes the settings to rust-analyzer).
+    fn config_file(self) -> &'static str {
+        match self {
+            Editor::Vscode => ".vscode/settings.json",
+            Editor::Neovim => ".neoconf.json",
+            Editor::Helix => ".helix/languages.toml",
+        }
+    }
+}
+
+/// The rust-analyzer settings for working on Miri the way `e` builds it, by their names (without
+/// the `rust-analyzer.` that VS Code puts in front). The checks go through `./miri cargo`, which
+/// sets the RUSTFLAGS (and finds the rustc libraries) like every other `./miri` command does, so
+/// that they share the build artifacts; rust-analyzer gets the toolchain (or the local rustc) to
+/// use, and its sysroot.
+fn settings(e: &MiriEnv) -> Result<Vec<(&'static str, Value)>> {
+    let miri = if cfg!(windows) { "./miri.bat" } else { "./miri" };
+    // A locally built rustc usually does not come with clippy.
+    let check = if e.rustc.is_some() { "check" } else { "clippy" };
+    let command =
+        |subcommand| json!([miri, "cargo", subcommand, "--message-format=json", "--all-targets"]);
+    let mut env = Map::new();
+    env.insert("MIRI_AUTO_OPS".into(), "no".into());
+    if let Some(toolchain) = &e.toolchain {
+        env.insert("RUSTUP_TOOLCHAIN".into(), toolchain.as_str().into());
+    }
+    if let Some(rustc) = &e.rustc {
+        env.insert("MIRI_SCRIPT_RUSTC".into(), rustc.to_string_lossy().into());
+    }
+    let sysroot = e.sysroot()?.to_string_lossy().into_owned();
+    Ok(vec![
+        ("rustc.source", rustc_source(e.rustc.as_deref()).into()),
+        (
+            "linkedProjects",
+            json!(["Cargo.toml", "cargo-miri/Cargo.toml", "miri-script/Cargo.toml"]),
+        ),
+        ("check.overrideCommand", command(check)),
+        // Contrary to what the name suggests, this also affects proc macros.
+        ("cargo.buildScripts.overrideCommand", command("check")),
+        ("cargo.extraEnv", env.into()),
+        ("cargo.sysroot", sysroot.into()),
+    ])
+}
+
+/// Where rust-analyzer finds the sources of the rustc crates: for a locally built rustc, the
+/// workspace of the checkout it was built in; otherwise in the `rustc-dev` component.
+fn rustc_source(rustc: Option<&Path>) -> String {
+    rustc
+        .and_then(|rustc| {
+            rustc
+                .ancestors()
+                .find(|dir| dir.join("compiler").is_dir() && dir.join("Cargo.toml").is_file())
+        })
+        .map_or_else(
+            || "discover".to_owned(),
+            |dir| dir.join("Cargo.toml").to_string_lossy().into_owned(),
+        )
+}
+
+/// The settings with the names VS Code (and neoconf.nvim) know them by.
+fn prefixed(settings: &[(&str, Value)]) -> Map<String, Value> {
+    settings.iter().map(|(name, value)| (format!("rust-analyzer.{name}"), value.clone())).collect()
+}
+
+/// The object the settings go in, in the settings file of `editor` (for the JSON ones).
+fn settings_object(editor: Editor, root: &mut Value) -> Result<&mut Map<String, Value>> {
+    let mut object = root;
+    if editor == Editor::Neovim {
+        for key in ["lspconfig", "rust_analyzer"] {
+            object = object
+                .as_object_mut()
+                .context("the settings are not a JSON object")?
+                .entry(key)
+                .or_insert_with(|| json!({}));
+        }
+    }
+    object.as_object_mut().context("the settings are not a JSON object")
+}
+
+/// JSON with 4 spaces of indentation, like VS Code writes it.
+fn to_json(value: &Value) -> String {
+    use serde::Serialize;
+    let mut out = Vec::new();
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(b"    ");
+    let mut ser = serde_json::Serializer::with_formatter(&mut out, formatter);
+    value.serialize(&mut ser).unwrap();
+    String::from_utf8(out).unwrap() + "\n"
+}
+
+/// `value` as a TOML value; the settings are only strings, arrays of strings and tables of them.
+fn toml_value(value: &Value) -> String {
+    match value {
+        Value::Array(items) =>
+            format!("[{}]", items.iter().map(toml_value).collect::<Vec<_>>().join(", ")),
+        Value::Object(map) => {
+            let entries: Vec<String> =
+                map.iter().map(|(key, value)| format!("{key} = {}", toml_value(value))).collect();
+            format!("{{ {} }}", entries.join(", "))
+        }
+        // JSON strings are valid TOML strings.
+        value => value.to_string(),
+    }
+}
+
+/// The header of the table in `languages.toml` that the settings go in.
+const HELIX_TABLE: &str = "[language-server.rust-analyzer.config]";
+
+/// The settings as they would be put into the config of `editor`, or printed.
+fn fragment(editor: Editor, settings: &[(&str, Value)]) -> String {
+    match editor {
+        Editor::Vscode => to_json(&prefixed(settings).into()),
+        Editor::Neovim => to_json(&json!({ "lspconfig": { "rust_analyzer": prefixed(settings) } })),
+        Editor::Helix => {
+            let mut table = format!("{HELIX_TABLE}\n");
+            for (name, value) in settings {
+                table.push_str(&format!("{name} = {}\n", toml_value(value)));
+            }
+            table
+        }
+    }
+}
+
+/// Removes the comments and trailing commas that VS Code allows in its JSON files, and says
+/// whether there were comments.
+fn strip_jsonc(s: &str) -> (String, bool) {
+    let mut out = String::with_capacity(s.len());
+    let mut comments = false;
+    let mut chars = s.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            out.push(c);
+            match c {
+                '\\' => out.extend(chars.next()),
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                out.push(c);
+            }
+            ('/', Some('/')) => {
+                comments = true;
+                // Keep the newline.
+                while chars.next_if(|&c| c != '\n').is_some() {}
+            }
+            ('/', Some('*')) => {
+                comments = true;
+                chars.next();
+                let mut last = ' ';
+                for c in chars.by_ref() {
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+            }
+            (',', _) => {
+                let rest = chars.clone().find(|c| !c.is_whitespace());
+                if !matches!(rest, Some('}' | ']')) {
+                    out.push(c);
+                }
+            }
+            _ => out.push(c),
+        }
+    }
+    (out, comments)
+}
+
+/// `existing` (the contents of `languages.toml`, if any) with the settings in the rust-analyzer
+/// table: the lines that set them are replaced, and the others are kept as they are.
+fn merge_toml(existing: &str, settings: &[(&str, Value)]) -> String {
+    let mut lines: Vec<String> = existing.lines().map(ToOwned::to_owned).collect();
+    let start = match lines.iter().position(|line| line.trim() == HELIX_TABLE) {
+        Some(pos) => pos + 1,
+        None => {
+            if lines.last().is_some_and(|line| !line.trim().is_empty()) {
+                lines.push(String::new());
+            }
+            lines.push(HELIX_TABLE.to_owned());
+            lines.len()
+        }
+    };
+    let end = lines[start..]
+        .iter()
+        .position(|line| line.trim_start().starts_with('['))
+        .map_or(lines.len(), |pos| start + pos);
+    let mut table: Vec<String> = lines[start..end].to_vec();
+    for (name, value) in settings {
+        let line = format!("{name} = {}", toml_value(value));
+        let is_setting = |l: &String| {
+            l.split_once('=').is_some_and(|(key, _)| key.trim().trim_matches('"') == *name)
+        };
+        match table.iter().position(is_setting) {
+            Some(pos) => {
+                // A value can go on over several lines, like an array with one item per line.
+                let open = |l: &String| l.matches('[').count() + l.matches('{').count();
+                let close = |l: &String| l.matches(']').count() + l.matches('}').count();
+                let (mut depth, mut last) = (0isize, pos);
+                for (i, l) in table.iter().enumerate().skip(pos) {
+                    depth += open(l) as isize - close(l) as isize;
+                    last = i;
+                    if depth <= 0 {
+                        break;
+                    }
+                }
+                table.splice(pos..=last, [line]);
+            }
+            None => {
+                // After the last setting, before the empty lines that separate the next table.
+                let pos = table.iter().rposition(|l| !l.trim().is_empty()).map_or(0, |pos| pos + 1);
+                table.insert(pos, line);
+            }
+        }
+    }
+    lines.splice(start..end, table);
+    lines.join("\n") + "\n"
+}
+
+/// `existing` (the contents of the config file of `editor`, if any) with the settings in it. Also
+/// says whether comments were dropped.
+fn merge(
+    editor: Editor,
+    existing: Option<&str>,
+    settings: &[(&str, Value)],
+) -> Result<(String, bool)> {
+    if editor == Editor::Helix {
+        return Ok((merge_toml(existing.unwrap_or_default(), settings), false));
+    }
+    let (json, comments) = strip_jsonc(existing.unwrap_or_default());
+    let mut root: Value =
+        if json.trim().is_empty() { json!({}) } else { serde_json::from_str(&json)? };
+    settings_object(editor, &mut root)?.extend(prefixed(settings));
+    Ok((to_json(&root), comments))
+}
+
+/// A diff of the lines of `old` and `new`, with `-` and `+` in front of the lines that went and
+/// came, and some unchanged lines around them.
+fn diff(old: &str, new: &str) -> String {
+    const CONTEXT: usize = 2;
+    let (old, new): (Vec<&str>, Vec<&str>) = (old.lines().collect(), new.lines().collect());
+    // `lcs[i][j]`: how many lines `old[i..]` and `new[j..]` have in common.
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push((' ', old[i]));
+            (i, j) = (i + 1, j + 1);
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(('-', old[i]));
+            i += 1;
+        } else {
+            lines.push(('+', new[j]));
+            j += 1;
+        }
+    }
+    let changed: Vec<usize> = (0..lines.len()).filter(|&k| lines[k].0 != ' ').collect();
+    let near = |k: usize| changed.iter().any(|&c| c.abs_diff(k) <= CONTEXT);
+    let mut out = String::new();
+    let mut skipped = false;
+    for (k, (mark, line)) in lines.iter().enumerate() {
+        if !near(k) {
+            skipped = true;
+            continue;
+        }
+        if skipped && !out.is_empty() {
+            out.push_str("...\n");
+        }
+        skipped = false;
+        out.push_str(&format!("{mark} {line}\n"));
+    }
+    out
+}
+
+/// Writes (or with `print`, prints) the rust-analyzer settings for working on Miri with `e` into
+/// the config of `editor`.
+pub fn setup(e: &MiriEnv, editor: Editor, print: bool) -> Result<()> {
+    let settings = settings(e)?;
+    if print {
+        print!("{}", fragment(editor, &settings));
+        return Ok(());
+    }
+    let file = path!(e.miri_dir / editor.config_file());
+    let existing = match fs::read_to_string(&file) {
+        Ok(existing) => Some(existing),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err).with_context(|| format!("failed to read {}", file.display())),
+    };
+    let (merged, comments) = merge(editor, existing.as_deref(), &settings)
+        .with_context(|| format!("failed to understand {}; see `--print`", file.display()))?;
+    let old = existing.as_deref().unwrap_or_default();
+    if merged == old {
+        success!("{} is up to date.", file.display());
+        return Ok(());
+    }
+    status!("Changes to {}:", file.display());
+    print!("{}", diff(old, &merged));
+    if skip_in_dry_run(format_args!("write {}", file.display())) {
+        return Ok(());
+    }
+    if comments {
+        let backup = PathBuf::from(format!("{}.bak", file.display()));
+        fs::copy(&file, &backup)
+            .with_context(|| format!("failed to back up {}", file.display()))?;
+        warning!(
+            "the comments in {} are not kept; the old file is in {}",
+            file.display(),
+            backup.display()
+        );
+    }
+    fs::create_dir_all(file.parent().unwrap())?;
+    fs::write(&file, merged).with_context(|| format!("failed to write {}", file.display()))?;
+    success!("Updated {}.", file.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> Vec<(&'static str, Value)> {
+        vec![
+            ("rustc.source", "discover".into()),
+            ("check.overrideCommand", json!(["./miri", "cargo", "clippy"])),
+            ("cargo.extraEnv", json!({ "MIRI_AUTO_OPS": "no" })),
+        ]
+    }
+
+    #[test]
+    fn merges_vscode_settings() {
+        let existing = r#"{
+    // Mine.
+    "editor.formatOnSave": true,
+    "rust-analyzer.rustc.source": "../rust/Cargo.toml", /* stale */
+    "files.exclude": { "a,b": true, },
+}"#;
+        let (merged, comments) = merge(Editor::Vscode, Some(existing), &settings()).unwrap();
+        assert!(comments);
+        assert_eq!(
+            merged,
+            r#"{
+    "editor.formatOnSave": true,
+    "rust-analyzer.rustc.source": "discover",
+    "files.exclude": {
+        "a,b": true
+    },
+    "rust-analyzer.check.overrideCommand": [
+        "./miri",
+        "cargo",
+        "clippy"
+    ],
+    "rust-analyzer.cargo.extraEnv": {
+        "MIRI_AUTO_OPS": "no"
+    }
+}
+"#
+        );
+        // Merging again changes nothing.
+        assert_eq!(merge(Editor::Vscode, Some(&merged), &settings()).unwrap(), (merged, false));
+
+        let (merged, _) = merge(Editor::Neovim, None, &settings()).unwrap();
+        let merged: Value = serde_json::from_str(&merged).unwrap();
+        assert_eq!(merged["lspconfig"]["rust_analyzer"]["rust-analyzer.rustc.source"], "discover");
+    }
+
+    #[test]
+    fn strips_jsonc() {
+        let (json, comments) = strip_jsonc(r#"{"a": "// not a comment", "b": [1, 2,], }"#);
+        assert_eq!(json, r#"{"a": "// not a comment", "b": [1, 2] }"#);
+        assert!(!comments);
+        let (json, comments) = strip_jsonc("{\"a\\\"//\": 1 // c\n}");
+        assert_eq!(json, "{\"a\\\"//\": 1 \n}");
+        assert!(comments);
+    }
+
+    #[test]
+    fn merges_helix_settings() {
+        let existing = "\
+[[language]]
+name = \"rust\"
+
+[language-server.rust-analyzer.config]
+cargo.features = \"all\"
+check.overrideCommand = [
+    \"cargo\",
+    \"check\",
+]
+
+[language-server.other]
+command = \"x\"
+";
+        let merged = merge_toml(existing, &settings());
+        assert_eq!(
+            merged,
+            "\
+[[language]]
+name = \"rust\"
+
+[language-server.rust-analyzer.config]
+cargo.features = \"all\"
+check.overrideCommand = [\"./miri\", \"cargo\", \"clippy\"]
+rustc.source = \"discover\"
+cargo.extraEnv = { MIRI_AUTO_OPS = \"no\" }
+
+[language-server.other]
+command = \"x\"
+"
+        );
+        assert_eq!(merge_toml(&merged, &settings()), merged);
+        assert_eq!(merge_toml("", &settings()), fragment(Editor::Helix, &settings()));
+    }
+
+    #[test]
+    fn diffs_lines() {
+        let old = "a\nb\nc\nd\ne\nf\ng\n";
+        let new = "a\nB\nc\nd\ne\nf\ng\nh\n";
+        assert_eq!(diff(old, new), "  a\n- b\n+ B\n  c\n  d\n...\n  f\n  g\n+ h\n");
+        assert_eq!(diff("", "x\n"), "+ x\n");
+    }
+}
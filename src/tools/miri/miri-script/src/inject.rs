@@ -0,0 +1,154 @@
+//! How `./miri run --many-seeds` hands each seed to a run: by default as `-Zmiri-seed=<seed>` in
+//! the MIRIFLAGS, but also (with `--inject`) in an environment variable or as an argument of the
+//! program, for programs that have their own randomness.
+
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+
+use crate::util::miriflags;
+
+/// Where a run gets the seed (or another value it is run with). Parsed from what `--inject` is
+/// given: `MIRIFLAGS+=<flag>`, `ENV:<var>=<value>`, or `ARG:<arg>`, where `{}` is replaced by the
+/// seed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Injection {
+    /// A flag added to the MIRIFLAGS, replacing one of the same name.
+    MiriFlag(String),
+    /// An environment variable for the driver.
+    Env { var: String, value: String },
+    /// An argument appended to those of the program.
+    Arg(String),
+}
+
+impl Default for Injection {
+    fn default() -> Self {
+        Injection::MiriFlag("-Zmiri-seed={}".into())
+    }
+}
+
+impl FromStr for Injection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let injection = if let Some(flag) = s.strip_prefix("MIRIFLAGS+=") {
+            // cargo-miri splits the MIRIFLAGS at spaces, so a flag cannot contain any.
+            if flag.contains(char::is_whitespace) {
+                bail!("`{s}` contains whitespace, which cannot be part of a flag in the MIRIFLAGS");
+            }
+            Injection::MiriFlag(flag.into())
+        } else if let Some(env) = s.strip_prefix("ENV:") {
+            let Some((var, value)) = env.split_once('=').filter(|(var, _)| !var.is_empty()) else {
+                bail!("expected `ENV:<var>=<value>`");
+            };
+            if var == "MIRIFLAGS" {
+                bail!("use `MIRIFLAGS+=<flag>` to add to the MIRIFLAGS");
+            }
+            Injection::Env { var: var.into(), value: value.into() }
+        } else if let Some(arg) = s.strip_prefix("ARG:") {
+            Injection::Arg(arg.into())
+        } else {
+            bail!("expected `MIRIFLAGS+=<flag>`, `ENV:<var>=<value>` or `ARG:<arg>`");
+        };
+        if !s.contains("{}") {
+            bail!("`{s}` does not contain `{{}}`, which is replaced by the seed");
+        }
+        Ok(injection)
+    }
+}
+
+impl fmt::Display for Injection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Injection::MiriFlag(flag) => write!(f, "MIRIFLAGS+={flag}"),
+            Injection::Env { var, value } => write!(f, "ENV:{var}={value}"),
+            Injection::Arg(arg) => write!(f, "ARG:{arg}"),
+        }
+    }
+}
+
+/// What one run gets with the injections for one value.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Injected {
+    /// All of the MIRIFLAGS.
+    pub miri_flags: OsString,
+    pub env: Vec<(String, String)>,
+    /// What goes after the arguments of the program.
+    pub args: Vec<String>,
+}
+
+impl Injected {
+    /// A run with `miri_flags`, and nothing injected.
+    pub fn none(miri_flags: &OsStr) -> Injected {
+        Injected { miri_flags: miri_flags.to_owned(), ..Injected::default() }
+    }
+
+    /// A run with `miri_flags` and `injections` for `value`; no injections mean the default one.
+    pub fn new(injections: &[Injection], miri_flags: &OsStr, value: u32) -> Result<Injected> {
+        let default = [Injection::default()];
+        let injections = if injections.is_empty() { &default[..] } else { injections };
+        let value = value.to_string();
+        let mut injected = Injected::none(miri_flags);
+        for injection in injections {
+            match injection {
+                Injection::MiriFlag(flag) => {
+                    let flag = flag.replace("{}", &value);
+                    if flag.contains(char::is_whitespace) {
+                        bail!("`{flag}` contains whitespace, so it cannot go into the MIRIFLAGS");
+                    }
+                    injected.miri_flags = miriflags::with_flag(&injected.miri_flags, &flag)?;
+                }
+                Injection::Env { var, value: template } =>
+                    injected.env.push((var.clone(), template.replace("{}", &value))),
+                Injection::Arg(arg) => injected.args.push(arg.replace("{}", &value)),
+            }
+        }
+        Ok(injected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn injections() {
+        let parse = |s: &str| s.parse::<Injection>();
+        let injections = [
+            parse("MIRIFLAGS+=-Zmiri-seed={}").unwrap(),
+            parse("ENV:MY_SEED=0x{}").unwrap(),
+            parse("ARG:--seed={}").unwrap(),
+        ];
+        assert_eq!(injections[1], Injection::Env { var: "MY_SEED".into(), value: "0x{}".into() });
+        assert_eq!(injections[2].to_string(), "ARG:--seed={}");
+        assert_eq!(
+            Injected::new(&injections, OsStr::new("-Zmiri-seed=1 -Zmiri-tree-borrows"), 7).unwrap(),
+            Injected {
+                miri_flags: "-Zmiri-seed=7 -Zmiri-tree-borrows".into(),
+                env: vec![("MY_SEED".into(), "0x7".into())],
+                args: vec!["--seed=7".into()],
+            }
+        );
+        assert_eq!(Injected::new(&[], OsStr::new(""), 3).unwrap().miri_flags, "-Zmiri-seed=3");
+        for bad in ["ENV:=1{}", "ENV:X", "ENV:MIRIFLAGS={}", "ARG:--seed", "SEED={}"] {
+            assert!(parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn whitespace_in_miri_flags() {
+        let err = "MIRIFLAGS+=-Zfoo {}".parse::<Injection>().unwrap_err().to_string();
+        assert!(err.contains("whitespace"), "{err}");
+        assert!("MIRIFLAGS+=-Zfoo=\t{}".parse::<Injection>().is_err());
+        // Other injections can have spaces.
+        assert!("ARG:--seed {}".parse::<Injection>().is_ok());
+        // In case an injection gets made without the parser: refused too, instead of ending up as
+        // two flags.
+        let injection = Injection::MiriFlag("-Zfoo {}".into());
+        let err = Injected::new(&[injection], OsStr::new(""), 1).unwrap_err().to_string();
+        assert!(err.contains("whitespace"), "{err}");
+    }
+}
@@ -0,0 +1,408 @@
+//! JUnit XML reports of test runs, for `./miri test --junit <path>`: dashboards read those. With a
+//! report, `MiriEnv::test` runs every test target on its own, so that it knows which harness prints
+//! what: libtest suites print JSON (with `--format json`, which needs a nightly), and ui_test
+//! suites print a line per test, and a block per failure.
+
+use std::fmt::Write as _;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::annotations;
+use crate::output;
+use crate::util::write_report;
+
+/// The suites that ran so far, if we are making a report.
+static REPORT: Mutex<Option<Vec<Suite>>> = Mutex::new(None);
+
+/// The test targets of one manifest, like `ui` or the unit tests of the lib, under one toolchain.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Suite {
+    /// Like `miri/ui`: the crate, and then `lib`, `doc` or the name of the target.
+    pub name: String,
+    pub toolchain: Option<String>,
+    /// How long `cargo test` took for it.
+    pub time: Duration,
+    pub cases: Vec<Case>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Case {
+    pub name: String,
+    /// In seconds; ui_test does not say.
+    pub time: Option<f64>,
+    pub outcome: Outcome,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    Failed {
+        message: String,
+        output: String,
+    },
+    Skipped {
+        message: Option<String>,
+    },
+    /// The suite failed without saying which test failed, like when it did not build.
+    Error {
+        message: String,
+        output: String,
+    },
+}
+
+/// Starts collecting the suites that `MiriEnv::test` runs.
+pub fn start() {
+    *REPORT.lock().unwrap() = Some(Vec::new());
+}
+
+/// Whether `MiriEnv::test` has to report its suites.
+pub fn recording() -> bool {
+    REPORT.lock().unwrap().is_some()
+}
+
+pub fn add(suite: Suite) {
+    if let Some(suites) = &mut *REPORT.lock().unwrap() {
+        suites.push(suite);
+    }
+}
+
+/// Writes the report of the suites that ran to `path`, also when the run (which went like
+/// `result`) failed: the report should show which tests did.
+pub fn finish(path: &Path, result: Result<()>) -> Result<()> {
+    let suites = REPORT.lock().unwrap().take().unwrap_or_default();
+    write_report(path, "the JUnit report", to_xml(&suites), result)
+}
+
+/// Whether the target `name` of the given kind (like `test` or `lib`) uses libtest, according to
+/// the Cargo.toml in `manifest`. Only `harness = false` in the `[lib]` or in a `[[test]]` (or
+/// `[[bin]]`, ...) with that name says otherwise.
+pub fn uses_libtest(manifest: &str, kind: &str, name: &str) -> bool {
+    let mut section = "";
+    let (mut target, mut harness) = (None, true);
+    let mut custom = false;
+    for line in manifest.lines().map(str::trim).chain(["[end]"]) {
+        if line.starts_with('[') {
+            let matches = match section {
+                "[lib]" => kind == "lib",
+                // Like `[[test]]`.
+                _ => section.trim_matches(['[', ']']) == kind && target == Some(name),
+            };
+            custom |= matches && !harness;
+            section = line;
+            (target, harness) = (None, true);
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let value = value.split('#').next().unwrap().trim();
+        match key.trim() {
+            "name" => target = Some(value.trim_matches('"')),
+            "harness" => harness = value != "false",
+            _ => {}
+        }
+    }
+    !custom
+}
+
+/// The outcome of a test that libtest reported as failed, with what it printed.
+fn failure(output: &str) -> Outcome {
+    // The line after the location of the panic says what went wrong.
+    let message = output
+        .lines()
+        .skip_while(|line| !line.contains("' panicked at "))
+        .nth(1)
+        .filter(|line| !line.trim().is_empty())
+        .unwrap_or("test failed");
+    Outcome::Failed { message: message.to_owned(), output: output.to_owned() }
+}
+
+/// The tests in what libtest printed with `--format json --report-time`.
+pub fn parse_libtest(stdout: &str) -> Vec<Case> {
+    let mut cases = Vec::new();
+    for event in stdout.lines().filter_map(|l| serde_json::from_str::<serde_json::Value>(l).ok()) {
+        if event["type"] != "test" {
+            continue;
+        }
+        let outcome = match event["event"].as_str() {
+            Some("ok") => Outcome::Passed,
+            Some("failed" | "timeout") => failure(event["stdout"].as_str().unwrap_or_default()),
+            Some("ignored") =>
+                Outcome::Skipped { message: event["message"].as_str().map(ToOwned::to_owned) },
+            _ => continue,
+        };
+        cases.push(Case {
+            name: event["name"].as_str().unwrap_or_default().to_owned(),
+            time: event["exec_time"].as_f64(),
+            outcome,
+        });
+    }
+    cases
+}
+
+/// The tests in what ui_test printed: `tests/pass/foo.rs ... ok` for each of them (or `FAILED`, or
+/// `ignored (...)`), and a `FAILED TEST: ...` block for each failure. libtest lines in its default
+/// format (`test foo ... ok`) are understood as well.
+pub fn parse_text(output: &str) -> Vec<Case> {
+    let output = output::strip_colors(output);
+    let failures = annotations::test_failures(&output);
+    let mut cases = Vec::new();
+    for line in output.lines() {
+        let Some((name, result)) = line.trim().split_once(" ... ") else {
+            continue;
+        };
+        let name = name.strip_prefix("test ").unwrap_or(name);
+        let outcome = if result == "ok" {
+            Outcome::Passed
+        } else if result == "FAILED" {
+            match failures.iter().find(|f| f.header.strip_prefix("FAILED TEST: ") == Some(name)) {
+                Some(f) =>
+                    Outcome::Failed { message: f.message.clone(), output: f.lines.join("\n") },
+                None => Outcome::Failed { message: "test failed".into(), output: String::new() },
+            }
+        } else if let Some(reason) = result.strip_prefix("ignored") {
+            let reason = reason.trim().trim_start_matches(',').trim();
+            let reason =
+                reason.strip_prefix('(').and_then(|r| r.strip_suffix(')')).unwrap_or(reason);
+            Outcome::Skipped { message: (!reason.is_empty()).then(|| reason.to_owned()) }
+        } else {
+            continue;
+        };
+        cases.push(Case { name: name.to_owned(), time: None, outcome });
+    }
+    cases
+}
+
+/// What libtest prints at the end of its default format: the output of the failed tests, and the
+/// counts. We show that for the suites that print JSON.
+pub fn summary(cases: &[Case]) -> String {
+    let mut out = String::new();
+    let count = |f: fn(&Outcome) -> bool| cases.iter().filter(|c| f(&c.outcome)).count();
+    let failed = count(|o| matches!(o, Outcome::Failed { .. } | Outcome::Error { .. }));
+    if failed > 0 {
+        out.push_str("\nfailures:\n");
+        for case in cases {
+            if let Outcome::Failed { output, .. } = &case.outcome {
+                let _ = write!(out, "\n---- {} stdout ----\n{output}", case.name);
+            }
+        }
+        out.push_str("\nfailures:\n");
+        for case in cases.iter().filter(|c| matches!(c.outcome, Outcome::Failed { .. })) {
+            let _ = writeln!(out, "    {}", case.name);
+        }
+    }
+    let _ = writeln!(
+        out,
+        "\ntest result: {}. {} passed; {failed} failed; {} ignored\n",
+        if failed > 0 { "FAILED" } else { "ok" },
+        count(|o| matches!(o, Outcome::Passed)),
+        count(|o| matches!(o, Outcome::Skipped { .. })),
+    );
+    out
+}
+
+/// Escapes `s` for XML text and attributes. The output of tests can contain anything: what is not
+/// valid UTF-8 was replaced when reading it, and what XML does not allow (most control characters,
+/// like those that color the output) is replaced here.
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in output::strip_colors(s).chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            '\t' | '\n' | '\r' => out.push(c),
+            c if c < ' ' || c == '\u{fffe}' || c == '\u{ffff}' => out.push('\u{fffd}'),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// The report: one `<testsuite>` per suite, named by it; suites that ran under several toolchains
+/// (`--toolchains`) get the toolchain added to their names, so that they stay apart.
+fn to_xml(suites: &[Suite]) -> String {
+    let count = |suite: &Suite, f: fn(&Outcome) -> bool| {
+        suite.cases.iter().filter(|c| f(&c.outcome)).count()
+    };
+    let failures = |s: &Suite| count(s, |o| matches!(o, Outcome::Failed { .. }));
+    let errors = |s: &Suite| count(s, |o| matches!(o, Outcome::Error { .. }));
+    let skipped = |s: &Suite| count(s, |o| matches!(o, Outcome::Skipped { .. }));
+    let total = |f: &dyn Fn(&Suite) -> usize| suites.iter().map(f).sum::<usize>();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(
+        xml,
+        "<testsuites tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{:.3}\">",
+        total(&|s| s.cases.len()),
+        total(&failures),
+        total(&errors),
+        total(&skipped),
+        suites.iter().map(|s| s.time.as_secs_f64()).sum::<f64>(),
+    );
+    for suite in suites {
+        let shared = suites.iter().filter(|s| s.name == suite.name).count() > 1;
+        let name = match &suite.toolchain {
+            Some(toolchain) if shared => format!("{} ({toolchain})", suite.name),
+            _ => suite.name.clone(),
+        };
+        let name = escape(&name);
+        let _ = writeln!(
+            xml,
+            "  <testsuite name=\"{name}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{:.3}\">",
+            suite.cases.len(),
+            failures(suite),
+            errors(suite),
+            skipped(suite),
+            suite.time.as_secs_f64(),
+        );
+        if let Some(toolchain) = &suite.toolchain {
+            let _ = writeln!(
+                xml,
+                "    <properties><property name=\"toolchain\" value=\"{}\"/></properties>",
+                escape(toolchain)
+            );
+        }
+        for case in &suite.cases {
+            let time = case.time.map(|t| format!(" time=\"{t:.3}\"")).unwrap_or_default();
+            let _ = write!(
+                xml,
+                "    <testcase name=\"{}\" classname=\"{name}\"{time}",
+                escape(&case.name)
+            );
+            match &case.outcome {
+                Outcome::Passed => xml.push_str("/>\n"),
+                Outcome::Skipped { message } => {
+                    let message = message.as_ref().map(|m| format!(" message=\"{}\"", escape(m)));
+                    let _ = writeln!(xml, "><skipped{}/></testcase>", message.unwrap_or_default());
+                }
+                Outcome::Failed { message, output } | Outcome::Error { message, output } => {
+                    let tag = if matches!(case.outcome, Outcome::Error { .. }) {
+                        "error"
+                    } else {
+                        "failure"
+                    };
+                    let _ = writeln!(
+                        xml,
+                        "><{tag} message=\"{}\">{}</{tag}></testcase>",
+                        escape(message.trim()),
+                        escape(output)
+                    );
+                }
+            }
+        }
+        xml.push_str("  </testsuite>\n");
+    }
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_libtest() {
+        let stdout = r#"{ "type": "suite", "event": "started", "test_count": 3 }
+{ "type": "test", "event": "started", "name": "a::works" }
+{ "type": "test", "name": "a::works", "event": "ok", "exec_time": 0.25 }
+{ "type": "test", "name": "a::skipped", "event": "ignored", "message": "slow" }
+{ "type": "test", "name": "a::breaks", "event": "failed", "exec_time": 0.5, "stdout": "\nthread 'a::breaks' panicked at src/a.rs:3:5:\nassertion failed: ok\n" }
+{ "type": "suite", "event": "failed", "passed": 1, "failed": 1, "ignored": 1 }"#;
+        let cases = parse_libtest(stdout);
+        assert_eq!(cases.len(), 3);
+        assert_eq!(
+            cases[0],
+            Case { name: "a::works".into(), time: Some(0.25), outcome: Outcome::Passed }
+        );
+        assert_eq!(cases[1].outcome, Outcome::Skipped { message: Some("slow".into()) });
+        assert!(matches!(
+            &cases[2].outcome,
+            Outcome::Failed { message, .. } if message == "assertion failed: ok"
+        ));
+        assert!(summary(&cases).contains("---- a::breaks stdout ----"));
+        assert!(summary(&cases).contains("test result: FAILED. 1 passed; 1 failed; 1 ignored"));
+    }
+
+    #[test]
+    fn parses_ui_test() {
+        let output = "\
+tests/pass/ok.rs ... ok
+tests/pass/skip.rs ... ignored (in-test comment)
+tests/fail/bad.rs (revision `stack`) ... FAILED
+
+FAILED TEST: tests/fail/bad.rs (revision `stack`)
+command: \"miri\"
+
+error: actual output differed from expected
+
+FAILURES:
+    tests/fail/bad.rs (revision `stack`)
+";
+        let cases = parse_text(output);
+        assert_eq!(cases.len(), 3);
+        assert_eq!(cases[0].outcome, Outcome::Passed);
+        assert_eq!(cases[1].outcome, Outcome::Skipped { message: Some("in-test comment".into()) });
+        assert_eq!(cases[2].name, "tests/fail/bad.rs (revision `stack`)");
+        assert!(matches!(
+            &cases[2].outcome,
+            Outcome::Failed { output, .. } if output.contains("actual output differed")
+        ));
+    }
+
+    #[test]
+    fn finds_custom_harnesses() {
+        let manifest = "\
+[package]
+name = \"miri\"
+
+[lib]
+test = true
+
+[[test]]
+name = \"ui\"
+harness = false # we use ui_test
+
+[[test]]
+name = \"other\" # libtest
+";
+        assert!(uses_libtest(manifest, "lib", "miri"));
+        assert!(!uses_libtest(manifest, "test", "ui"));
+        assert!(uses_libtest(manifest, "test", "other"));
+        assert!(uses_libtest(manifest, "bin", "ui"));
+    }
+
+    #[test]
+    fn writes_xml() {
+        let suite = |toolchain: &str, outcome| {
+            Suite {
+                name: "miri/ui".into(),
+                toolchain: Some(toolchain.into()),
+                time: Duration::from_millis(1500),
+                cases: vec![Case { name: "tests/a<b>.rs".into(), time: None, outcome }],
+            }
+        };
+        let failed = Outcome::Failed {
+            message: "it \"broke\"".into(),
+            output: "\x1b[31mred\x1b[0m & \u{7} \u{fffd}".into(),
+        };
+        let xml = to_xml(&[suite("nightly", Outcome::Passed), suite("beta", failed)]);
+        assert!(xml.contains("<testsuites tests=\"2\" failures=\"1\" errors=\"0\" skipped=\"0\""));
+        assert!(xml.contains("<testsuite name=\"miri/ui (nightly)\""));
+        assert!(
+            xml.contains("<testcase name=\"tests/a&lt;b&gt;.rs\" classname=\"miri/ui (beta)\">")
+        );
+        assert!(xml.contains(
+            "<failure message=\"it &quot;broke&quot;\">red &amp; \u{fffd} \u{fffd}</failure>"
+        ));
+
+        let xml = to_xml(&[suite("nightly", Outcome::Skipped { message: None })]);
+        assert!(xml.contains("<testsuite name=\"miri/ui\""));
+        assert!(xml.contains("<skipped/></testcase>"));
+    }
+}
@@ -0,0 +1,118 @@
+//! What `./miri`, the script for working on Miri, does, as a library for tools that would rather
+//! drive it from Rust than from the command line: set up an environment for a toolchain, build
+//! Miri and a sysroot, install it, run programs with many seeds, and format the sources.
+//!
+//! The items re-exported here are the API of this crate. Changes that break code using them bump
+//! the minor version. Everything else is public only for the `./miri` binary: it is hidden from
+//! the docs, and changes whenever the binary needs it to.
+//!
+//! This works on the Miri checkout the crate is part of, and (like `./miri`) prints what it does
+//! and the commands it runs on stderr.
+//!
+//! ```no_run
+//! use miri_script::{BuildOptions, MiriEnv, RunOptions};
+//!
+//! let mut e = MiriEnv::builder().toolchain("miri").build()?;
+//! let build = e.build_miri(&BuildOptions::default())?;
+//! eprintln!("built {}", build.miri.display());
+//!
+//! let mut options = RunOptions::default();
+//! options.flags.push("tests/pass/hello.rs".into());
+//! let report = e.run_seeds(&options, 0..16)?;
+//! eprintln!("{} of {} seeds failed", report.failed().count(), report.seeds.len());
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+#![allow(clippy::needless_question_mark)]
+
+use std::path::PathBuf;
+
+#[doc(hidden)]
+pub mod annotations;
+mod api;
+#[doc(hidden)]
+pub mod args;
+#[doc(hidden)]
+pub mod bench;
+#[doc(hidden)]
+pub mod bless;
+#[doc(hidden)]
+pub mod ci;
+#[doc(hidden)]
+pub mod clean;
+#[doc(hidden)]
+pub mod cmdline;
+#[doc(hidden)]
+pub mod cross;
+#[doc(hidden)]
+pub mod debug;
+#[doc(hidden)]
+pub mod doctor;
+#[doc(hidden)]
+pub mod events;
+#[doc(hidden)]
+pub mod fuzz;
+#[doc(hidden)]
+pub mod ide;
+#[doc(hidden)]
+pub mod inject;
+#[doc(hidden)]
+pub mod junit;
+#[doc(hidden)]
+pub mod load;
+#[doc(hidden)]
+pub mod logfile;
+#[doc(hidden)]
+pub mod metrics;
+#[doc(hidden)]
+pub mod output;
+#[doc(hidden)]
+pub mod record;
+#[doc(hidden)]
+pub mod remote;
+#[doc(hidden)]
+pub mod repro;
+#[doc(hidden)]
+pub mod rustup;
+#[doc(hidden)]
+pub mod sarif;
+#[doc(hidden)]
+pub mod squash;
+#[doc(hidden)]
+pub mod sync;
+#[doc(hidden)]
+pub mod tee;
+#[doc(hidden)]
+pub mod timings;
+#[doc(hidden)]
+pub mod util;
+#[doc(hidden)]
+pub mod watch;
+#[doc(hidden)]
+pub mod wrapper;
+
+pub use crate::api::{
+    BuildOptions, BuildOutput, FormatOptions, FormatOutcome, ManySeedsReport, MiriEnvBuilder,
+    RunOptions, SeedResult,
+};
+pub use crate::events::Status;
+pub use crate::inject::Injection;
+pub use crate::util::MiriEnv;
+
+/// Options that apply to all commands. They are given before the command name.
+#[doc(hidden)]
+#[derive(Clone, Debug, Default)]
+pub struct GlobalArgs {
+    /// The toolchain given as `+toolchain`, if any.
+    pub toolchain: Option<String>,
+    /// A locally built rustc to use instead of a rustup toolchain, given as `--rustc <path>`.
+    pub rustc: Option<PathBuf>,
+    /// Always run `cargo build`, even when the sources did not change (`--no-skip-build`).
+    pub no_skip_build: bool,
+    /// Pass `--locked` to every cargo command (`--locked`).
+    pub locked: bool,
+    /// rustc flags for the cargo commands, on top of `RUSTFLAGS` (`--rustflags '<flags>'`).
+    pub rustflags: Vec<String>,
+    /// How much cargo and we say (`--quiet` or `--verbose`).
+    pub verbosity: util::Verbosity,
+}
@@ -0,0 +1,110 @@
+//! `--max-load`, for `./miri run --many-seeds` on a machine that others use as well: before a
+//! worker of the parallel driver (`MiriEnv::run_many_times`) starts its next item, it waits while
+//! the load is above the limit, so that we run fewer at the same time while the machine is busy.
+//! The load is the 1-minute load average on Unix, and how many cores are busy on Windows.
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::output::{note, status};
+use crate::tee;
+
+/// How long to wait before looking at the load again.
+const RETRY: Duration = Duration::from_secs(5);
+
+/// The load of the machine now, if we can tell.
+#[cfg(unix)]
+pub fn current() -> Option<f64> {
+    let mut load = [0.0];
+    // SAFETY: `load` has room for the one value we ask for.
+    let n = unsafe { libc::getloadavg(load.as_mut_ptr(), 1) };
+    (n == 1).then_some(load[0])
+}
+
+/// The load of the machine now, if we can tell: Windows has no load average, so this is how
+/// many cores were busy over a short while.
+#[cfg(windows)]
+pub fn current() -> Option<f64> {
+    use windows_sys::Win32::Foundation::FILETIME;
+    use windows_sys::Win32::System::Threading::GetSystemTimes;
+
+    // Idle, and all the time (the kernel time includes the idle time).
+    let times = || -> Option<(u64, u64)> {
+        let mut t = [FILETIME { dwLowDateTime: 0, dwHighDateTime: 0 }; 3];
+        let [idle, kernel, user] = &mut t;
+        // SAFETY: The pointers are to `FILETIME`s we own.
+        if unsafe { GetSystemTimes(idle, kernel, user) } == 0 {
+            return None;
+        }
+        let [idle, kernel, user] =
+            t.map(|t| u64::from(t.dwHighDateTime) << 32 | u64::from(t.dwLowDateTime));
+        Some((idle, kernel + user))
+    };
+    let (idle_before, total_before) = times()?;
+    thread::sleep(Duration::from_millis(500));
+    let (idle, total) = times()?;
+    let cores = thread::available_parallelism().ok()?.get();
+    Some(busy_cores(idle - idle_before, total - total_before, cores))
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn current() -> Option<f64> {
+    None
+}
+
+/// How many of `cores` were busy, given how much of the `total` time of all of them was `idle`.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn busy_cores(idle: u64, total: u64, cores: usize) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    (total.saturating_sub(idle)) as f64 / total as f64 * cores as f64
+}
+
+/// Waits until the load is at most `max_load`, or we cannot tell it, or we are shutting down.
+/// The workers wait one at a time, and the one that waits says so.
+pub fn wait_below(max_load: f64) {
+    static WAITING: Mutex<()> = Mutex::new(());
+    let _waiting = WAITING.lock().unwrap_or_else(|err| err.into_inner());
+    let mut throttled = false;
+    loop {
+        let load = match current() {
+            Some(load) if load > max_load => load,
+            Some(load) if throttled => {
+                note!("no longer throttled: load {load:.1}");
+                return;
+            }
+            _ => return,
+        };
+        if !throttled {
+            status!("throttled: load {load:.1} > {max_load:.1}");
+            throttled = true;
+        }
+        let start = Instant::now();
+        while start.elapsed() < RETRY {
+            if tee::interrupted() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load() {
+        assert_eq!(busy_cores(0, 0, 8), 0.0);
+        assert_eq!(busy_cores(750, 1000, 8), 2.0);
+        assert_eq!(busy_cores(1000, 1000, 8), 0.0);
+        #[cfg(any(unix, windows))]
+        assert!(current().is_some_and(|load| load >= 0.0));
+        // Not throttled.
+        let start = Instant::now();
+        wait_below(f64::MAX);
+        assert!(start.elapsed() < RETRY);
+    }
+}
@@ -0,0 +1,181 @@
+//! The log in `target/miri-script.log`: what `./miri` ran and printed, for figuring out what went
+//! wrong after the fact.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+
+use crate::output;
+use crate::sync::now;
+use crate::util::move_file;
+
+/// The log gets moved to `miri-script.log.old` when it gets larger than this.
+const MAX_SIZE: u64 = 4 << 20;
+
+/// How many lines at the start and at the end of a command's output we log.
+const EXCERPT_LINES: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Off,
+    /// Only our own warnings and errors.
+    Warn,
+    /// Also the commands we run, and everything else we print (the default).
+    Info,
+    /// Also the env vars the commands are run with, and all of their output.
+    Debug,
+}
+
+impl FromStr for LogLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "off" => LogLevel::Off,
+            "warn" => LogLevel::Warn,
+            "info" => LogLevel::Info,
+            "debug" => LogLevel::Debug,
+            _ => bail!("invalid log level `{s}`, expected one of: off, warn, info, debug"),
+        })
+    }
+}
+
+struct Log {
+    path: PathBuf,
+    file: fs::File,
+    level: LogLevel,
+}
+
+static LOG: Mutex<Option<Log>> = Mutex::new(None);
+
+fn open(path: &Path) -> Result<fs::File> {
+    if fs::metadata(path).is_ok_and(|meta| meta.len() > MAX_SIZE) {
+        move_file(path, &path.with_extension("log.old"))?;
+    }
+    Ok(fs::File::options().create(true).append(true).open(path)?)
+}
+
+/// Starts logging to `miri-script.log` in `target_dir`, with the given level.
+pub fn start(level: LogLevel, target_dir: &Path) -> Result<()> {
+    if level == LogLevel::Off {
+        return Ok(());
+    }
+    fs::create_dir_all(target_dir)?;
+    let path = target_dir.join("miri-script.log");
+    let file = open(&path)?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    *LOG.lock().unwrap() = Some(Log { path, file, level });
+    write(
+        LogLevel::Warn,
+        &format!(
+            "\n======== {} ({}) ======== ./miri {}",
+            format_time(now()),
+            std::process::id(),
+            shell_words::join(&args)
+        ),
+    );
+    Ok(())
+}
+
+/// Where we log to, if we do.
+pub fn path() -> Option<PathBuf> {
+    LOG.lock().unwrap().as_ref().map(|log| log.path.clone())
+}
+
+pub fn enabled(level: LogLevel) -> bool {
+    LOG.lock().unwrap().as_ref().is_some_and(|log| log.level >= level)
+}
+
+/// Appends `text` to the log, if we log at `level`.
+pub fn write(level: LogLevel, text: &str) {
+    let mut log = LOG.lock().unwrap();
+    let Some(Log { path, file, level: log_level }) = log.as_mut() else {
+        return;
+    };
+    if *log_level < level {
+        return;
+    }
+    // Commands color their output if we do, but the log is for reading in an editor.
+    let text = output::strip_colors(text);
+    let result = writeln!(file, "{text}").map_err(anyhow::Error::from).and_then(|()| {
+        if file.metadata()?.len() > MAX_SIZE {
+            *file = open(path)?;
+        }
+        Ok(())
+    });
+    if let Err(err) = result {
+        // Going through `output` would try to log this again.
+        eprintln!("warning: failed to write to {}, not logging any more: {err:#}", path.display());
+        *log = None;
+    }
+}
+
+/// Logs a command we ran, how long it took and how it went, and what it printed (if we captured
+/// that).
+pub fn command(command: &str, duration: Duration, status: &str, output: Option<&str>) {
+    let mut entry = format!("[{:.1}s] $ {command}\n        {status}", duration.as_secs_f64());
+    let lines = if enabled(LogLevel::Debug) { usize::MAX } else { EXCERPT_LINES };
+    match output {
+        Some(output) if !output.trim().is_empty() =>
+            for line in excerpt(output, lines) {
+                entry.push_str(&format!("\n        | {line}"));
+            },
+        Some(_) => entry.push_str("\n        (no output)"),
+        None => entry.push_str("\n        (the output went to the terminal)"),
+    }
+    write(LogLevel::Info, &entry);
+}
+
+/// The first and last `n` lines of `output`.
+fn excerpt(output: &str, n: usize) -> Vec<String> {
+    let lines: Vec<&str> = output.trim_end().lines().collect();
+    if lines.len() <= n.saturating_mul(2) {
+        return lines.into_iter().map(Into::into).collect();
+    }
+    let mut excerpt: Vec<String> = lines[..n].iter().map(|&line| line.into()).collect();
+    excerpt.push(format!("... ({} more lines) ...", lines.len() - 2 * n));
+    excerpt.extend(lines[lines.len() - n..].iter().map(|&line| line.into()));
+    excerpt
+}
+
+/// Formats seconds since the Unix epoch as a UTC date and time.
+fn format_time(time: u64) -> String {
+    // See http://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+    let days = time / 86400 + 719468;
+    let (era, day_of_era) = (days / 146097, days % 146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    let (h, m, s) = (time / 3600 % 24, time / 60 % 60, time % 60);
+    format!("{year}-{month:02}-{day:02} {h:02}:{m:02}:{s:02} UTC")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn excerpts() {
+        let output: String = (1..=30).map(|i| format!("line {i}\n")).collect();
+        let excerpt = excerpt(&output, 2);
+        assert_eq!(excerpt, ["line 1", "line 2", "... (26 more lines) ...", "line 29", "line 30"]);
+        assert_eq!(super::excerpt("a\nb\n", 2), ["a", "b"]);
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_time(951782400 + 3723), "2000-02-29 01:02:03 UTC");
+        assert_eq!(format_time(1791979200), "2026-10-14 12:00:00 UTC");
+        assert!("verbose".parse::<LogLevel>().is_err());
+    }
+}
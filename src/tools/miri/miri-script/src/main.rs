@@ -1,13 +1,41 @@
 #![allow(clippy::needless_question_mark)]
 
 mod commands;
-mod util;
+mod completions;
 
 use std::ffi::OsString;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::{env, ops::Range};
 
 use anyhow::{anyhow, bail, Context, Result};
 
+use miri_script::{
+    annotations, args, bench, bless, ci, clean, cross, debug, doctor, events, fuzz, ide, inject,
+    junit, logfile, metrics, output, record, remote, repro, sarif, squash, sync, tee, timings,
+    util, watch, wrapper, GlobalArgs,
+};
+
+use crate::args::{CommandSpec, Matches, Opt, OptValue};
+use crate::bench::{CompareRev, WallBench};
+use crate::clean::ArtifactKind;
+use crate::completions::CompletionShell;
+use crate::debug::Debugger;
+use crate::ide::Editor;
+use crate::inject::Injection;
+use crate::remote::Workers;
+use crate::util::{arg_flag_value, ShellKind};
+
+/// How to resume a `rustc-pull` that stopped due to merge conflicts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PullAction {
+    /// Commit the merge once all conflicts are resolved.
+    Continue,
+    /// Go back to the state before the pull.
+    Abort,
+}
+
 #[derive(Clone, Debug)]
 pub enum Command {
     /// Installs the miri driver and cargo-miri.
@@ -15,6 +43,8 @@ pub enum Command {
     /// working directory. Note that the binaries are placed in the `miri` toolchain
     /// sysroot, to prevent conflicts with other toolchains.
     Install {
+        /// Strip the symbols from the binaries.
+        strip: bool,
         /// Flags that are passed through to `cargo install`.
         flags: Vec<OsString>,
     },
@@ -31,11 +61,38 @@ pub enum Command {
     /// Build miri, set up a sysroot and then run the test suite.
     Test {
         bless: bool,
+        /// Only run the doctests.
+        doc: bool,
+        /// If non-empty, run the test suite once for each of these toolchains and compare.
+        toolchains: Vec<String>,
         /// The cross-interpretation target.
         /// If none then the host is the target.
         target: Option<OsString>,
         /// Flags that are passed through to the test harness.
         flags: Vec<OsString>,
+        /// Where to write a JUnit XML report of the tests.
+        junit: Option<PathBuf>,
+    },
+    /// Build miri and cargo-miri, and run the cargo-miri test suite with them.
+    TestCargoMiri {
+        /// The cross-interpretation target.
+        /// If none then the host is the target.
+        target: Option<OsString>,
+        bless: bool,
+        /// Do not delete the temporary directories afterwards.
+        keep_tmp: bool,
+        /// Only run the tests whose name contains one of these.
+        filters: Vec<OsString>,
+    },
+    /// Update the expected output of the ui test suites, running the suites in parallel.
+    Bless {
+        /// The suites to bless; by default, all of them.
+        suites: Vec<String>,
+        /// The cross-interpretation target.
+        /// If none then the host is the target.
+        target: Option<OsString>,
+        /// How many suites to bless in parallel; by default, one per core.
+        jobs: Option<NonZeroUsize>,
     },
     /// Build miri, set up a sysroot and then run the driver with the given <flags>.
     /// (Also respects MIRIFLAGS environment variable.)
@@ -43,11 +100,38 @@ pub enum Command {
         dep: bool,
         verbose: bool,
         many_seeds: Option<Range<u32>>,
+        /// How many seeds to run in parallel; by default, one per core.
+        jobs: Option<NonZeroUsize>,
+        /// Start no further seeds while the load of the machine is above this.
+        max_load: Option<f64>,
+        /// Only write the script that reproduces a run with this seed.
+        repro_for: Option<u32>,
+        /// Where the seed goes; by default, `-Zmiri-seed` in the MIRIFLAGS.
+        inject: Vec<Injection>,
+        /// Run the seeds on these hosts instead.
+        workers: Option<Workers>,
         /// Flags that are passed through to `miri`.
         flags: Vec<OsString>,
     },
+    /// Build miri, set up a sysroot and then run the driver with the given <flags> in a debugger.
+    Debug {
+        /// The debugger to use; by default, the first one we find.
+        debugger: Option<Debugger>,
+        /// Flags that are passed through to `miri`, like for `Run`.
+        flags: Vec<OsString>,
+    },
+    /// Run a cargo-fuzz target from `fuzz/`.
+    Fuzz {
+        target: Option<String>,
+        /// For how many seconds to fuzz; by default, until interrupted.
+        time: Option<u64>,
+        /// Only list the fuzz targets.
+        list: bool,
+    },
     /// Format all sources and tests.
     Fmt {
+        /// Also format the files that git ignores.
+        no_ignore: bool,
         /// Flags that are passed through to `rustfmt`.
         flags: Vec<OsString>,
     },
@@ -55,15 +139,37 @@ pub enum Command {
     Clippy {
         /// Flags that are passed through to `cargo clippy`.
         flags: Vec<OsString>,
+        /// Where to write a SARIF log of what clippy found.
+        sarif: Option<PathBuf>,
     },
     /// Runs just `cargo <flags>` with the Miri-specific environment variables.
     /// Mainly meant to be invoked by rust-analyzer.
-    Cargo { flags: Vec<OsString> },
+    Cargo {
+        /// Run cargo on the manifest of this crate.
+        krate: Option<String>,
+        flags: Vec<OsString>,
+    },
     /// Runs the benchmarks from bench-cargo-miri in hyperfine. hyperfine needs to be installed.
     Bench {
         target: Option<OsString>,
-        /// List of benchmarks to run. By default all benchmarks are run.
+        /// List of benchmarks to run. By default all benchmarks are run. Flags after `--` are
+        /// passed to hyperfine.
         benches: Vec<OsString>,
+        /// Instead of running the benchmarks, print the last this many results of each.
+        history: Option<usize>,
+        /// Instead of the benchmarks, time the Miri driver running this program.
+        wall: Option<WallBench>,
+        /// Compare the benchmarks with those of Miri built at another revision.
+        compare_rev: Option<CompareRev>,
+        /// The commit (prefix) or baseline name to compare the results with; with `wall`, this
+        /// may also be another Miri binary.
+        against: Option<String>,
+        /// Record the results under this baseline name.
+        save_baseline: Option<String>,
+        /// By how many percent a benchmark must get slower to count as a regression.
+        threshold: f64,
+        /// Fail if there are regressions.
+        fail_on_regression: bool,
     },
     /// Update and activate the rustup toolchain 'miri' to the commit given in the
     /// `rust-version` file.
@@ -73,194 +179,1572 @@ pub enum Command {
     /// Pull and merge Miri changes from the rustc repo. Defaults to fetching the latest
     /// rustc commit. The fetched commit is stored in the `rust-version` file, so the
     /// next `./miri toolchain` will install the rustc that just got pulled.
-    RustcPull { commit: Option<String> },
+    RustcPull {
+        commit: Option<String>,
+        /// Allow pulling a commit that is older than the one in `rust-version`.
+        allow_downgrade: bool,
+        /// After the pull, check that Miri builds with the new toolchain.
+        verify: bool,
+        /// Resume a pull that stopped due to merge conflicts.
+        action: Option<PullAction>,
+        /// Only warn if josh-proxy is not the pinned version.
+        allow_any_josh: bool,
+        /// How often to retry network operations that failed in a way that looks transient.
+        retries: u32,
+    },
     /// Push Miri changes back to the rustc repo. This will pull a copy of the rustc
     /// history into the Miri repo, unless you set the RUSTC_GIT env var to an existing
     /// clone of the rustc repo.
-    RustcPush { github_user: String, branch: String },
+    RustcPush {
+        /// Push to `https://github.com/<github_user>/rust`. Ignored if `remote` is set.
+        github_user: Option<String>,
+        /// The git remote (name or URL) to push to.
+        remote: Option<String>,
+        branch: String,
+        /// Allow overwriting an existing branch.
+        force_with_lease: bool,
+        /// Only show what would be pushed.
+        dry_run: bool,
+        /// Only warn if josh-proxy is not the pinned version.
+        allow_any_josh: bool,
+        /// How often to retry network operations that failed in a way that looks transient.
+        retries: u32,
+    },
+    /// Show how far the Miri subtree is behind rustc, and whether there are changes to push.
+    RustcStatus {
+        /// Emit the status as JSON instead of human-readable text.
+        json: bool,
+        /// How often to retry network operations that failed in a way that looks transient.
+        retries: u32,
+    },
+    /// Squash the commits of the current branch into one.
+    Squash {
+        /// Only squash the commits after this one, instead of all commits that are not upstream.
+        onto: Option<String>,
+        /// Allow squashing merge commits.
+        allow_merges: bool,
+    },
+    /// Delete the build output and caches `./miri` created.
+    Clean {
+        /// Only delete this kind of artifact.
+        only: Option<ArtifactKind>,
+        /// Do not ask for confirmation.
+        yes: bool,
+    },
+    /// Print the environment `./miri` sets up for cargo and rustc.
+    Env {
+        /// The shell to print the variables for.
+        shell: ShellKind,
+        /// Emit the variables as JSON instead.
+        json: bool,
+    },
+    /// Check the environment for common misconfigurations.
+    Doctor {
+        /// Emit the results as JSON instead of human-readable text.
+        json: bool,
+    },
+    /// Set up rust-analyzer for working on Miri in an editor.
+    IdeSetup {
+        editor: Editor,
+        /// Print the settings instead of writing them into the config of the editor.
+        print: bool,
+    },
+    /// Write the scripts that run `./miri` on this platform into the Miri dir.
+    SelfInstallWrapper,
+    /// Run another `./miri` command, and run it again whenever the sources change.
+    Watch {
+        /// The command and its arguments.
+        command: Vec<OsString>,
+    },
+    /// Run the steps CI runs.
+    Ci {
+        /// Skip the steps that test foreign targets.
+        host_only: bool,
+        /// Skip the style checks.
+        skip_style: bool,
+        /// Start at this step.
+        from: Option<String>,
+        /// Only run this step.
+        only: Option<String>,
+        /// Only list the steps, instead of running them.
+        list: Option<ci::ListFormat>,
+    },
+    /// Summarize the recorded durations of the commands.
+    Metrics {
+        /// How many of the latest runs of each command to look at.
+        last: usize,
+        /// Only this command.
+        command: Option<String>,
+        /// Also summarize the steps of the commands.
+        steps: bool,
+    },
+    /// Print a completion script for the given shell.
+    Completions {
+        shell: CompletionShell,
+        /// Used by the completion scripts: print the candidates for the command line in `words`.
+        complete: bool,
+        words: Vec<String>,
+    },
 }
 
-const HELP: &str = r#"  COMMANDS
+/// The options that come before the command.
+const GLOBAL: CommandSpec = CommandSpec {
+    name: "",
+    opts: &[
+        Opt {
+            names: &["--rustc"],
+            value: OptValue::Required("<path>"),
+            help: "Build with a locally built rustc instead of a rustup toolchain.",
+        },
+        Opt {
+            names: &["--record"],
+            value: OptValue::Required("<path>"),
+            help: "Append the commands that are run to the shell script at <path>.",
+        },
+        Opt {
+            names: &["--dry-run"],
+            value: OptValue::None,
+            help: "Print the commands that would be run (and the files that would change) instead.",
+        },
+        Opt {
+            names: &["--no-skip-build"],
+            value: OptValue::None,
+            help: "Always build Miri before using it, even when nothing changed.",
+        },
+        Opt {
+            names: &["-q", "--quiet"],
+            value: OptValue::None,
+            help: "Show the output of cargo only if it fails, and only what it says with `--quiet`.",
+        },
+        Opt {
+            names: &["-v", "--verbose"],
+            value: OptValue::None,
+            help: "Pass `-v` to cargo.",
+        },
+        Opt {
+            names: &["--locked"],
+            value: OptValue::None,
+            help: "Pass `--locked` to all cargo commands, so that no lock file gets updated.",
+        },
+        Opt {
+            names: &["--rustflags"],
+            value: OptValue::Required("<flags>"),
+            help: "Build with these rustc flags as well, without changing RUSTFLAGS.",
+        },
+        Opt {
+            names: &["--ignore-miri-sysroot"],
+            value: OptValue::None,
+            help: "Build a sysroot as usual, instead of using the one in MIRI_SYSROOT.",
+        },
+        Opt {
+            names: &["--hermetic"],
+            value: OptValue::None,
+            help: "Ignore the build settings in the environment (like CARGO_INCREMENTAL), to build like CI.",
+        },
+        Opt {
+            names: &["--github-annotations"],
+            value: OptValue::None,
+            help: "Report failures as GitHub Actions annotations (the default when `GITHUB_ACTIONS=true`).",
+        },
+        Opt {
+            names: &["--log-level"],
+            value: OptValue::Required("off|warn|info|debug"),
+            help: "How much to write to `target/miri-script.log` (default: `info`).",
+        },
+        Opt {
+            names: &["--timings-summary"],
+            value: OptValue::None,
+            help: "Print how long the commands took at the end, even if that was quick.",
+        },
+        Opt {
+            names: &["--message-format"],
+            value: OptValue::Required("human|json"),
+            help: "With `json`, print what `./miri` does as JSON events on stdout (and all else on stderr).",
+        },
+        Opt {
+            names: &["--color"],
+            value: OptValue::Required("auto|always|never"),
+            help: "Whether to color the output (also of cargo); the default is `auto`.",
+        },
+    ],
+    rest: "<command> <args>",
+    forwards_flags: false,
+    about: "\
+All commands accept a leading `+<toolchain>` (e.g. `./miri +nightly test`) to override the
+toolchain. Otherwise `RUSTUP_TOOLCHAIN` is used if set, and the active rustup toolchain else.
+With a leading `--verbose`, every command says which toolchain it uses, and which of these it
+came from.
 
-./miri build <flags>:
-Just build miri. <flags> are passed to `cargo build`.
+All commands also accept a leading `--rustc <path>` to build with a locally built rustc (e.g. a
+stage1 compiler) instead of a rustup toolchain; see `MIRI_SCRIPT_RUSTC` below.
 
-./miri check <flags>:
-Just check miri. <flags> are passed to `cargo check`.
+With a leading `--record <path>`, every command that gets run is appended to the shell script at
+<path>, which replays them; see `MIRI_SCRIPT_RECORD` below.
+
+With a leading `--dry-run`, the commands that would be run are printed instead, with their
+working dir and env vars, and no files are changed. Commands that only query something (like
+`rustc --print sysroot`) still run, since what comes next depends on them; those are marked as
+`[probe]`. The output of the other commands is taken to be empty.
+
+Before building the sysroot (for `test`, `run`, ...), Miri and cargo-miri are only built if one
+of their tracked files, the toolchain or the build settings changed since `./miri` last built
+them, if the tree has uncommitted changes, or if the binary is gone; even a cargo that has
+nothing to do takes a few seconds. `--no-skip-build` always builds them.
+
+With a leading `--quiet`, cargo (for `build`, `check`, `clippy`, `test`, `install`, ...) gets
+`--quiet`, and its output and the commands we run are only shown if it fails; `./miri ci` gets
+much shorter that way. With a leading `--verbose`, cargo gets `-v`. Either way, the cargo flags
+after the command win, and a `-q` or `-v` there is not passed twice.
+
+With a leading `--locked`, all the cargo commands (`build`, `check`, `clippy`, `test`, `install`,
+`./miri cargo`, ...) get `--locked`, so that they fail instead of updating a lock file.
+
+With a leading `--rustflags '<flags>'`, the cargo commands of this run get these rustc flags on
+top of ours and those in `RUSTFLAGS`, like `./miri --rustflags -Zrandomize-layout test`; the
+environment does not change, so the commands `./miri` runs for itself and later runs do not get
+them. A flag that only matters once (unlike `-L` or `--cfg`) is only passed once; `--verbose`
+shows all the flags each command gets.
+
+If `MIRI_SYSROOT` is set, that sysroot is used instead of building one. Since a leftover one
+makes Miri fail in confusing ways, there is a warning when it is missing, has no libraries for
+the target, or was built by `./miri` with another toolchain; `--ignore-miri-sysroot` ignores it.
+
+The env vars that change how cargo builds (`CARGO_INCREMENTAL`, `CARGO_PROFILE_DEV_*`, ...) are
+passed on, and `./miri doctor` lists those that are set. With a leading `--hermetic`, they are
+ignored, so that the build is the same as in CI.
+
+With `--github-annotations` (the default when `GITHUB_ACTIONS=true`), `check` and `clippy` also
+print their errors and warnings as GitHub Actions annotations on stdout, so that they show up on
+the lines they are about, and `test` prints the failed tests folded in groups, annotated with the
+file that failed. The paths are relative to `GITHUB_WORKSPACE` (or else the Miri dir).
+
+What `./miri` runs and prints is logged to `target/miri-script.log` (moved to
+`miri-script.log.old` once it gets large), so that failures can be looked into later. Set the
+amount of detail with `--log-level` or `MIRI_SCRIPT_LOG`: `warn` logs only warnings and errors,
+`info` (the default) also the commands with their duration, exit status and an excerpt of their
+output (if it did not go to the terminal), and `debug` also their env vars and all of their
+output. `off` disables the log.
+
+When a command takes more than a few seconds, a summary of where the time went (building Miri,
+building the sysroot, running the tests, ...) is printed at the end; `--timings-summary` prints
+it in any case.
 
-./miri test [--bless] [--target <target>] <flags>:
+The output is colored if stderr is a terminal, unless `NO_COLOR` is set; `--color always|never`
+overrides that. The commands we run are told the same (via `CARGO_TERM_COLOR`, and `NO_COLOR` or
+`CLICOLOR_FORCE` for other tools), also when we capture their output to show it later, so that all
+output agrees. The log, annotations, JSON events and JUnit reports are never colored.
+
+With `--message-format json`, stdout only gets events, one JSON object per line with a
+`version` (which goes up when the events change) and an `event`: `step_started` and
+`step_finished` (with `status` and `duration_secs`) for the steps of `./miri ci` and for
+building Miri, the sysroot, ...; `seed_result` (with `seed`, `status` and `duration_secs`) for
+each run of `./miri run --many-seeds`; and `warning` and `error` (with `message`) for what we
+warn about or fail with. Everything that would go to stdout otherwise goes to stderr, including
+the output of the commands that get run.
+
+Use `./miri help <command>` for details on a command.",
+};
+
+const DEFAULT_RETRIES: u32 = 3;
+
+/// Shared by the commands that go through josh.
+const ALLOW_ANY_JOSH: Opt = Opt {
+    names: &["--allow-any-josh"],
+    value: OptValue::None,
+    help: "Only warn if josh-proxy is not the pinned version, instead of stopping.",
+};
+
+const RETRIES: Opt = Opt {
+    names: &["--retries"],
+    value: OptValue::Required("<n>"),
+    help: "Retry network operations that fail transiently up to <n> times (default: 3).",
+};
+
+const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "build",
+        opts: &[],
+        rest: "<flags>",
+        forwards_flags: true,
+        about: "\
+Just build miri. <flags> are passed to `cargo build`.
+With `--target <target>` among them, Miri and cargo-miri are built to run on that target (which
+needs its `rust-std` and `rustc-dev` components), and where they are is printed. They find the
+rustc libraries in the `lib` dir next to the one they are in, as in a toolchain.",
+    },
+    CommandSpec {
+        name: "check",
+        opts: &[],
+        rest: "<flags>",
+        forwards_flags: true,
+        about: "\
+Just check miri. <flags> are passed to `cargo check`.
+The helper crates of the test suite in `test_dependencies` are checked as well, unless their
+Cargo.toml has `skip-lints = true` in `[package.metadata.miri-script]`.",
+    },
+    CommandSpec {
+        name: "test",
+        opts: &[
+            Opt {
+                names: &["--bless"],
+                value: OptValue::None,
+                help: "Update the expected output of the tests.",
+            },
+            Opt {
+                names: &["--doc"],
+                value: OptValue::None,
+                help: "Only run the doctests of miri and cargo-miri.",
+            },
+            Opt {
+                names: &["--target"],
+                value: OptValue::Required("<target>"),
+                help: "Run the tests for this target instead of the host.",
+            },
+            Opt {
+                names: &["--toolchains"],
+                value: OptValue::Required("<a>,<b>,..."),
+                help: "Run the tests once for each of these toolchains.",
+            },
+            Opt {
+                names: &["--junit"],
+                value: OptValue::Required("<path>"),
+                help: "Write a JUnit XML report of the tests to <path>.",
+            },
+        ],
+        rest: "<flags>",
+        forwards_flags: true,
+        about: "\
 Build miri, set up a sysroot and then run the test suite.
 <flags> are passed to the test harness.
-
-./miri run [--dep] [-v|--verbose] [--many-seeds|--many-seeds=..to|--many-seeds=from..to] <flags>:
+If `--toolchains` is present, the build and test suite are run once for each of the given
+toolchains (each with its own target dir), and the results are compared at the end.
+With `--junit`, the test targets run one by one (so that the report can tell what each harness
+said, and so that one failing does not keep the others from running), and the report is written
+even if tests failed. It has a test suite per crate and target (and toolchain), with the time
+and the output of each failed test; ui_test does not say how long each test took. This needs a
+nightly toolchain, since libtest only prints JSON there.
+If the tests fail, a script that runs them again with the same toolchain, MIRIFLAGS and <flags>
+is written to `target/repro/test-<filters>.sh`, for bug reports.
+With `--doc`, only the doctests run, with `cargo test --doc` in each crate (and with the RUSTDOCFLAGS
+they need to find the rustc libraries); <flags> go to cargo, and after a `--` to the harness. A
+crate failing does not keep the others from running, and at the end it says which crates have no
+doctests and which failed. They are not run with Miri, so `--target` and `--bless` do not apply.",
+    },
+    CommandSpec {
+        name: "test-cargo-miri",
+        opts: &[
+            Opt {
+                names: &["--target"],
+                value: OptValue::Required("<target>"),
+                help: "Run the tests for this target instead of the host.",
+            },
+            Opt {
+                names: &["--bless"],
+                value: OptValue::None,
+                help: "Update the expected output of the tests.",
+            },
+            Opt {
+                names: &["--keep-tmp"],
+                value: OptValue::None,
+                help: "Keep the temporary directories, for debugging.",
+            },
+        ],
+        rest: "[<filter>...]",
+        forwards_flags: false,
+        about: "\
+Build miri and cargo-miri, set up a sysroot and then run the cargo-miri test suite
+(`test-cargo-miri/run-test.py`) with them, in a fresh CARGO_HOME. Only the tests whose name
+contains one of the <filter>s are run, if any are given.",
+    },
+    CommandSpec {
+        name: "bless",
+        opts: &[
+            Opt {
+                names: &["--target"],
+                value: OptValue::Required("<target>"),
+                help: "Bless the tests for this target instead of the host.",
+            },
+            Opt {
+                names: &["-j", "--jobs"],
+                value: OptValue::Required("<n>"),
+                help: "Bless this many suites in parallel (default: one per core).",
+            },
+        ],
+        rest: "[<suite>...]",
+        forwards_flags: false,
+        about: "\
+Build miri, set up a sysroot and then update the expected output of the given ui test suites
+(like `pass` or `native-lib/fail`; default: all of them), running the suites in parallel.
+Each suite is blessed in its own copy of `tests`; the changes are then merged back.",
+    },
+    CommandSpec {
+        name: "run",
+        opts: &[
+            Opt {
+                names: &["--dep"],
+                value: OptValue::None,
+                help: "Run the program with the test dependencies available.",
+            },
+            Opt {
+                names: &["-v", "--verbose"],
+                value: OptValue::None,
+                help: "Show the commands that are run.",
+            },
+            Opt {
+                names: &["--many-seeds"],
+                value: OptValue::Optional("[<from>]..<to>"),
+                help: "Run Miri with each seed in the range (default: `0..256`).",
+            },
+            Opt {
+                names: &["-j", "--jobs"],
+                value: OptValue::Required("<n>"),
+                help:
+                    "With `--many-seeds`, run this many seeds in parallel (default: one per core).",
+            },
+            Opt {
+                names: &["--max-load"],
+                value: OptValue::Required("<load>"),
+                help: "With `--many-seeds`, start no further seeds while the load is above <load>.",
+            },
+            Opt {
+                names: &["--repro-for"],
+                value: OptValue::Required("<seed>"),
+                help: "Only write the script that reproduces the run with <seed> (see below).",
+            },
+            Opt {
+                names: &["--inject"],
+                value: OptValue::Required("<template>"),
+                help: "Where each seed goes, instead of `-Zmiri-seed` (see below; repeatable).",
+            },
+            Opt {
+                names: &["--workers"],
+                value: OptValue::Required("<host>,..."),
+                help: "Experimental: run the seeds on these hosts over SSH (see below).",
+            },
+            Opt {
+                names: &["--remote-command"],
+                value: OptValue::Required("<command>"),
+                help: "With `--workers`, what to run on them for each seed `{}`.",
+            },
+        ],
+        rest: "<flags>",
+        forwards_flags: true,
+        about: "\
 Build miri, set up a sysroot and then run the driver with the given <flags>.
 (Also respects MIRIFLAGS environment variable.)
+Flags after `--` are passed to the interpreted program.
 If `--many-seeds` is present, Miri is run many times in parallel with different seeds.
-The range defaults to `0..256`.
-
-./miri fmt <flags>:
-Format all sources and tests. <flags> are passed to `rustfmt`.
-
-./miri clippy <flags>:
+The range defaults to `0..256`. Seeds can also be given in hex, like `0x10..0x20`.
+For a failing seed, a script that reproduces the failure from a fresh checkout (installing
+the toolchain, and with the same MIRIFLAGS and arguments) is written to
+`target/repro/run-<program>-seed-<seed>.sh`, for bug reports; `--repro-for <seed>` writes it
+without running anything. `./miri test` does the same for failing tests.
+`--inject` says where the seed goes, with `{}` replaced by it: `MIRIFLAGS+=-Zmiri-seed={}` (the
+default) adds a flag to the MIRIFLAGS, `ENV:MY_SEED={}` sets an environment variable, and
+`ARG:{}` appends an argument for the program. Several of them all apply.
+`--max-load` is for machines that others use as well: while the load (the 1-minute load average;
+on Windows, how many cores are busy) is above it, no further seeds are started, so that fewer run
+at the same time.
+`--workers` (experimental) runs the seeds of `--many-seeds` on other machines over SSH instead,
+a chunk at a time: each runs the shell command of `--remote-command` for each seed, with `{}`
+replaced by it, like `~/miri/target/release/miri --sysroot ~/sysroot prog.rs -Zmiri-seed={}`.
+Building Miri there is up to you; the hosts are checked with the command's `<program> --version`
+first. The seeds of a host that becomes unreachable go to the others.",
+    },
+    CommandSpec {
+        name: "debug",
+        opts: &[Opt {
+            names: &["--debugger"],
+            value: OptValue::Required("gdb|rust-gdb|lldb|rust-lldb|windbg|devenv"),
+            help: "The debugger to use. By default, the first of these that is installed.",
+        }],
+        rest: "[--] <flags>",
+        forwards_flags: true,
+        about: "\
+Build miri, set up a sysroot and then start a debugger on the driver, with the same environment and
+arguments as `./miri run <flags>` would use; `run` inside the debugger then just works. If no
+debugger can be found, print the command to run in a debugger instead.",
+    },
+    CommandSpec {
+        name: "fuzz",
+        opts: &[
+            Opt {
+                names: &["--time"],
+                value: OptValue::Required("<secs>"),
+                help: "Stop after this many seconds, instead of when interrupted.",
+            },
+            Opt {
+                names: &["--list"],
+                value: OptValue::None,
+                help: "List the fuzz targets instead.",
+            },
+        ],
+        rest: "[<target>]",
+        forwards_flags: false,
+        about: "\
+Run the given cargo-fuzz target from the `fuzz` directory with `cargo fuzz run`, using the toolchain
+Miri is pinned to (which must come with sanitizer support). The corpus and the reproducers of
+crashes are kept in `target/fuzz`. This needs cargo-fuzz (`cargo install cargo-fuzz`).",
+    },
+    CommandSpec {
+        name: "fmt",
+        opts: &[Opt {
+            names: &["--no-ignore"],
+            value: OptValue::None,
+            help: "Also format the files ignored by git (like those in `target`).",
+        }],
+        rest: "<flags>",
+        forwards_flags: true,
+        about: "\
+Format all sources and tests (all the `.rs` files in the Miri checkout that git does not ignore).
+<flags> are passed to `rustfmt`.
+The files in `tests` are formatted with `tests/rustfmt.toml`, which moves less code around, since
+the stderr files point into them. Files with a `// no-rustfmt` line among their first five lines
+are left alone.",
+    },
+    CommandSpec {
+        name: "clippy",
+        opts: &[Opt {
+            names: &["--sarif"],
+            value: OptValue::Required("<path>"),
+            help: "Also write what clippy found to <path>, as a SARIF log.",
+        }],
+        rest: "<flags>",
+        forwards_flags: true,
+        about: "\
 Runs clippy on all sources. <flags> are passed to `cargo clippy`.
-
-./miri cargo <flags>:
-Runs just `cargo <flags>` with the Miri-specific environment variables.
-Mainly meant to be invoked by rust-analyzer.
-
-./miri install <flags>:
+Like `./miri check`, this covers the helper crates of the test suite in `test_dependencies`.
+With `--sarif`, the findings of all crates are written to one SARIF 2.1.0 log (also when clippy
+fails), for code scanning tools: each with its lint as the rule, its location relative to
+`GITHUB_WORKSPACE` (or else the Miri dir), and its suggestions as fixes.",
+    },
+    CommandSpec {
+        name: "cargo",
+        opts: &[Opt {
+            names: &["--crate"],
+            value: OptValue::Required("miri|cargo-miri|miri-script"),
+            help: "Pass the `--manifest-path` of this crate to cargo.",
+        }],
+        rest: "<flags>",
+        forwards_flags: true,
+        about: "\
+Runs just `cargo <flags>` with the Miri-specific environment variables and toolchain, in the
+current directory, and exits with the same status as cargo (e.g. `./miri cargo --crate
+cargo-miri tree`). Mainly meant to be invoked by rust-analyzer.",
+    },
+    CommandSpec {
+        name: "install",
+        opts: &[Opt {
+            names: &["--strip"],
+            value: OptValue::None,
+            help: "Strip the symbols (and debug info) from the binaries, to make them smaller.",
+        }],
+        rest: "<flags>",
+        forwards_flags: true,
+        about: "\
 Installs the miri driver and cargo-miri. <flags> are passed to `cargo
 install`. Sets up the rpath such that the installed binary should work in any
 working directory. Note that the binaries are placed in the `miri` toolchain
-sysroot, to prevent conflicts with other toolchains.
-
-./miri bench [--target <target>] <benches>:
+sysroot, to prevent conflicts with other toolchains; if that is not writable (say, since the
+toolchain is shared), `--root <dir>` installs them into `<dir>/bin` instead.
+With `--strip`, cargo strips the binaries (or, for a custom `--profile`, llvm-strip from the
+`llvm-tools` component does); then their sizes are printed (along with the unstripped sizes, when
+llvm-strip does the stripping), and they are checked to still run.
+With `--target <target>`, the binaries are built for that target as `./miri build` does, and go
+into `<target dir>/<target>/install/bin` unless `--root` is given.",
+    },
+    CommandSpec {
+        name: "bench",
+        opts: &[
+            Opt {
+                names: &["--target"],
+                value: OptValue::Required("<target>"),
+                help: "Run the benchmarks for this target instead of the host.",
+            },
+            Opt {
+                names: &["--history"],
+                value: OptValue::Optional("<n>"),
+                help: "Do not run anything; print the last <n> results (default: 10) of each.",
+            },
+            Opt {
+                names: &["--wall"],
+                value: OptValue::Required("<program.rs>"),
+                help: "Instead of the benchmarks, time how long Miri takes to run this program.",
+            },
+            Opt {
+                names: &["--compare-rev"],
+                value: OptValue::Required("<rev>"),
+                help: "Compare with Miri built from this git revision, taking turns.",
+            },
+            Opt {
+                names: &["--runs"],
+                value: OptValue::Required("<n>"),
+                help: "With `--wall` or `--compare-rev`, how many runs count (default: 10).",
+            },
+            Opt {
+                names: &["--against"],
+                value: OptValue::Required("<commit-or-baseline>"),
+                help: "Compare with the results recorded for this commit or baseline.",
+            },
+            Opt {
+                names: &["--save-baseline"],
+                value: OptValue::Required("<name>"),
+                help: "Record the results under this name, for use with `--against`.",
+            },
+            Opt {
+                names: &["--threshold"],
+                value: OptValue::Required("<percent>"),
+                help: "Count slowdowns above this many percent as regressions (default: 5).",
+            },
+            Opt {
+                names: &["--fail-on-regression"],
+                value: OptValue::None,
+                help: "Exit with an error if there are regressions.",
+            },
+        ],
+        rest: "<benches> [-- <hyperfine flags>]",
+        forwards_flags: false,
+        about: "\
 Runs the benchmarks from bench-cargo-miri in hyperfine. hyperfine needs to be installed.
 <benches> can explicitly list the benchmarks to run; by default, all of them are run.
-
-./miri toolchain <flags>:
+Flags after `--` are passed to hyperfine.
+The results are appended to `bench-history.jsonl` in the target dir, and compared with the
+previous results (or those given with `--against`). If `perf` is installed, the number of
+instructions is recorded as well.
+With `--wall`, this builds Miri and the sysroot, and then runs the Miri driver on <program.rs>
+directly, without cargo, `--runs` times after a warmup run, and prints the mean, standard deviation
+and minimum of the wall-clock times. All runs get the same MIRIFLAGS, with `-Zmiri-seed=0` unless
+those pick a seed. With `--against <path>` to another Miri binary (built with the same toolchain,
+maybe in another worktree), that one runs as well, taking turns with ours, and the two are
+compared. The results go into the history as `wall/<program>`, and into the target dir as
+`bench/wall-<program>.json`, in hyperfine's format.
+With `--compare-rev <rev>`, <rev> is checked out in a git worktree in the target dir (which is
+removed again afterwards), and Miri and cargo-miri are built there as well, with the same
+toolchain and a target dir of their own. Then each of <benches> runs `--runs` times with each of
+the two builds, taking turns, and a table says how the means compare and whether the difference
+is more than the noise would explain. Uncommitted changes are part of the current side; that is
+noted at the top. This does not use hyperfine, nor the history.",
+    },
+    CommandSpec {
+        name: "toolchain",
+        opts: &[],
+        rest: "<flags>",
+        forwards_flags: true,
+        about: "\
 Update and activate the rustup toolchain 'miri' to the commit given in the
 `rust-version` file.
 `rustup-toolchain-install-master` must be installed for this to work. Any extra
-flags are passed to `rustup-toolchain-install-master`.
-
-./miri rustc-pull <commit>:
+flags are passed to `rustup-toolchain-install-master`.",
+    },
+    CommandSpec {
+        name: "rustc-pull",
+        opts: &[
+            Opt {
+                names: &["--commit"],
+                value: OptValue::Required("<sha>"),
+                help: "Pull the given rust-lang/rust commit instead of the latest one.",
+            },
+            Opt {
+                names: &["--allow-downgrade"],
+                value: OptValue::None,
+                help: "Allow pulling a commit that is older than the current `rust-version`.",
+            },
+            Opt {
+                names: &["--verify"],
+                value: OptValue::None,
+                help: "Check that Miri builds with the new toolchain (default if `CI` is set).",
+            },
+            Opt {
+                names: &["--continue"],
+                value: OptValue::None,
+                help: "Finish a pull that stopped due to merge conflicts, once they are resolved.",
+            },
+            Opt {
+                names: &["--abort"],
+                value: OptValue::None,
+                help: "Undo a pull that stopped due to merge conflicts.",
+            },
+            ALLOW_ANY_JOSH,
+            RETRIES,
+        ],
+        rest: "[<sha>]",
+        forwards_flags: false,
+        about: "\
 Pull and merge Miri changes from the rustc repo. Defaults to fetching the latest
 rustc commit. The fetched commit is stored in the `rust-version` file, so the
 next `./miri toolchain` will install the rustc that just got pulled.
-
-./miri rustc-push <github user> [<branch>]:
+The commit can also be given as the (only) positional argument. Going back to an older commit than
+the one in `rust-version` rewinds the toolchain and requires `--allow-downgrade`.
+If the merge has conflicts, the pull stops and lists them. Resolve them and use `--continue`,
+or go back to where you started with `--abort`.
+josh-proxy must be the pinned version; if it is not, this offers to install that version.
+With `--verify`, the new toolchain is installed after the pull and Miri is checked with it. Use
+`--no-verify` to skip that in CI.",
+    },
+    CommandSpec {
+        name: "rustc-push",
+        opts: &[
+            Opt {
+                names: &["--remote"],
+                value: OptValue::Required("<name-or-url>"),
+                help: "Push to this GitHub repository instead of <github user>/rust.",
+            },
+            Opt {
+                names: &["--branch"],
+                value: OptValue::Required("<name>"),
+                help: "Push to this branch (default: `miri-sync`).",
+            },
+            Opt {
+                names: &["--force-with-lease"],
+                value: OptValue::None,
+                help: "Overwrite the branch if it already exists.",
+            },
+            Opt {
+                names: &["--dry-run"],
+                value: OptValue::None,
+                help: "Show what would be pushed, without pushing anything.",
+            },
+            ALLOW_ANY_JOSH,
+            RETRIES,
+        ],
+        rest: "[<github user>] [<branch>]",
+        forwards_flags: false,
+        about: "\
 Push Miri changes back to the rustc repo. This will pull a copy of the rustc
 history into the Miri repo, unless you set the RUSTC_GIT env var to an existing
 clone of the rustc repo. The branch defaults to `miri-sync`.
+The changes are pushed to `https://github.com/<github user>/rust`, or to the GitHub repository
+given with `--remote` (a remote name or URL). Either way, a rustc PR can be opened from there.
+With `--dry-run`, this shows the commits and changes that would be pushed, and the push command
+that would be used, and checks that the push can be done.
+josh-proxy must be the pinned version; if it is not, this offers to install that version.",
+    },
+    CommandSpec {
+        name: "rustc-status",
+        opts: &[
+            Opt {
+                names: &["--json"],
+                value: OptValue::None,
+                help: "Emit the status as JSON instead of human-readable text.",
+            },
+            RETRIES,
+        ],
+        rest: "",
+        forwards_flags: false,
+        about: "\
+Show which rustc commit was last pulled, how many commits have landed in rustc since then, and
+how many Miri commits have not been pushed to rustc yet.",
+    },
+    CommandSpec {
+        name: "squash",
+        opts: &[
+            Opt {
+                names: &["--onto"],
+                value: OptValue::Required("<ref>"),
+                help: "Only squash the commits after <ref>.",
+            },
+            Opt {
+                names: &["--allow-merges"],
+                value: OptValue::None,
+                help: "Squash even if some of the commits are merge commits.",
+            },
+        ],
+        rest: "",
+        forwards_flags: false,
+        about: "\
+Squash the commits of the current branch that are not in rust-lang/miri's master yet (fetching it as
+needed) into one. This shows the commits and opens your editor (`$EDITOR`) with the message of the
+first one, for the message of the squashed commit. The working tree must be clean.",
+    },
+    CommandSpec {
+        name: "clean",
+        opts: &[
+            Opt {
+                names: &["--only"],
+                value: OptValue::Required("target|sysroot|caches"),
+                help: "Only delete this kind of artifact.",
+            },
+            Opt {
+                names: &["-y", "--yes"],
+                value: OptValue::None,
+                help: "Delete without asking for confirmation.",
+            },
+        ],
+        rest: "",
+        forwards_flags: false,
+        about: "\
+List the build output (including the per-toolchain target dirs of `./miri test --toolchains`),
+the Miri sysroot, and the caches of the rustc sync, with their sizes, and delete them.
+Only things inside the Miri checkout are ever deleted, and of a target dir elsewhere (which may
+be shared with other projects) only what `./miri` itself puts there, not the build output of
+cargo. The sysroot usually lives elsewhere and is only listed.",
+    },
+    CommandSpec {
+        name: "env",
+        opts: &[
+            Opt {
+                names: &["--shell"],
+                value: OptValue::Required("sh|fish|powershell|cmd"),
+                help: "Print commands for this shell (default: sh).",
+            },
+            Opt {
+                names: &["--json"],
+                value: OptValue::None,
+                help: "Print the variables as a JSON object instead.",
+            },
+        ],
+        rest: "",
+        forwards_flags: false,
+        about: "\
+Print the environment variables `./miri` sets for cargo and rustc (including RUSTFLAGS and
+CARGO_TARGET_DIR), so that you can run commands by hand in the same environment, e.g. with
+`eval \"$(./miri env)\"`. The toolchain is included as MIRI_SCRIPT_TOOLCHAIN, for use with
+`cargo +$MIRI_SCRIPT_TOOLCHAIN`.",
+    },
+    CommandSpec {
+        name: "doctor",
+        opts: &[Opt {
+            names: &["--json"],
+            value: OptValue::None,
+            help: "Emit the results as JSON instead of human-readable text.",
+        }],
+        rest: "",
+        forwards_flags: false,
+        about: "\
+Check the environment for common misconfigurations (toolchain, components, disk space, ...)
+and suggest fixes. Exits with a non-zero status only if a check failed hard. With `--json`, the
+same results are printed as an array of `{check, status, detail, fix, fix_command}` objects, as
+described by `miri-script/src/doctor.schema.json`; the `check` identifiers do not change.",
+    },
+    CommandSpec {
+        name: "ide-setup",
+        opts: &[
+            Opt {
+                names: &["--editor"],
+                value: OptValue::Required("vscode|neovim|helix"),
+                help: "Set up this editor (default: vscode).",
+            },
+            Opt {
+                names: &["--print"],
+                value: OptValue::None,
+                help: "Print the settings instead of writing them.",
+            },
+        ],
+        rest: "",
+        forwards_flags: false,
+        about: "\
+Configure rust-analyzer for working on Miri with the current toolchain (or `--rustc`): the rustc
+sources, the Miri workspaces, checks through `./miri cargo`, and the sysroot. The settings are
+merged into `.vscode/settings.json`, `.neoconf.json` (for neoconf.nvim) or
+`.helix/languages.toml`, keeping what is there already, and the changes are shown. The RUSTFLAGS
+are left to `./miri cargo`, so that rust-analyzer and `./miri` share their build artifacts. Run
+it again after switching toolchains.",
+    },
+    CommandSpec {
+        name: "self-install-wrapper",
+        opts: &[],
+        rest: "",
+        forwards_flags: false,
+        about: "\
+Write the scripts that build and run `./miri` into the Miri dir, unless they are up to date: on
+Windows, `miri.bat` for cmd and `miri.ps1` for PowerShell (which passes arguments on without cmd
+interpreting them), and elsewhere `./miri` itself. They work from any dir, also with spaces in
+the path. Which one to use is shown for the shell you run this from.",
+    },
+    CommandSpec {
+        name: "watch",
+        opts: &[],
+        rest: "[--] <command> <args>",
+        forwards_flags: true,
+        about: "\
+Run `./miri <command> <args>`, and run it again whenever a file in `src`, `cargo-miri/src` or
+`tests` changes (e.g. `./miri watch -- test shims`). A run that is still going when files change
+is stopped first. The auto-actions (see `.auto-everything` below) only happen once, at the start.",
+    },
+    CommandSpec {
+        name: "ci",
+        opts: &[
+            Opt {
+                names: &["--host-only"],
+                value: OptValue::None,
+                help: "Skip the steps that test foreign targets.",
+            },
+            Opt {
+                names: &["--skip-style"],
+                value: OptValue::None,
+                help: "Skip the style checks (CI runs them in a separate job).",
+            },
+            Opt {
+                names: &["--from"],
+                value: OptValue::Required("<step>"),
+                help: "Start at this step, e.g. to resume after fixing a failure.",
+            },
+            Opt {
+                names: &["--only"],
+                value: OptValue::Required("<step>"),
+                help: "Only run this step.",
+            },
+            Opt {
+                names: &["--list"],
+                value: OptValue::None,
+                help: "List the steps (and whether they do anything on this host) instead.",
+            },
+            Opt { names: &["--json"], value: OptValue::None, help: "With `--list`, emit JSON." },
+            Opt {
+                names: &["--names"],
+                value: OptValue::None,
+                help: "With `--list`, only print the names of the steps that would run, one per line.",
+            },
+        ],
+        rest: "",
+        forwards_flags: false,
+        about: "\
+Run the steps CI runs for this host, in the same order and with the same settings (like
+`RUSTFLAGS=-D warnings`), and print how long each step took. This stops at the first failing step.
+CI itself runs the steps via `./miri ci --only <step>`, so this is always what CI does.",
+    },
+    CommandSpec {
+        name: "metrics",
+        opts: &[
+            Opt {
+                names: &["--last"],
+                value: OptValue::Required("<n>"),
+                help: "Look at the last <n> runs of each command (default: 20).",
+            },
+            Opt {
+                names: &["--command"],
+                value: OptValue::Required("<name>"),
+                help: "Only summarize this command.",
+            },
+            Opt {
+                names: &["--steps"],
+                value: OptValue::None,
+                help: "Also summarize the steps (building Miri, the sysroot, ...) of the commands.",
+            },
+        ],
+        rest: "",
+        forwards_flags: false,
+        about: "\
+Summarize how long the commands took on this machine, as recorded with `MIRI_SCRIPT_METRICS=1`
+(see below): the median duration of the successful runs, the last one, and the trend (how the
+median of the newer half of the runs compares to that of the older half).",
+    },
+    CommandSpec {
+        name: "completions",
+        opts: &[Opt {
+            names: &["--complete"],
+            value: OptValue::Required("bash|zsh|fish|powershell"),
+            help: "Used by the completion scripts: print the candidates for the words after `--`.",
+        }],
+        rest: "bash|zsh|fish|powershell",
+        forwards_flags: false,
+        about: "\
+Print a completion script for the given shell. Load it with, e.g., `source <(./miri completions
+bash)` (or `./miri completions fish | source`), or save it where your shell looks for completions.
+The script calls back into `./miri` to complete the commands and their options, as well as
+targets (cached in the target dir) and test suites.",
+    },
+    CommandSpec {
+        name: "help",
+        opts: &[],
+        rest: "[<command>]",
+        forwards_flags: false,
+        about: "\
+Print the options of <command>, like `./miri <command> --help`, or this help without one. The
+commands that forward their flags to the tool they wrap (like `./miri test` and `./miri cargo`)
+forward `--help` only when it comes after `--`.",
+    },
+];
 
-  ENVIRONMENT VARIABLES
-
-MIRI_SYSROOT:
+const ENV_HELP: &str = r#"MIRI_SYSROOT:
 If already set, the "sysroot setup" step is skipped.
 
 CARGO_EXTRA_FLAGS:
-Pass extra flags to all cargo invocations. (Ignored by `./miri cargo`.)"#;
+Pass extra flags to all cargo invocations. (Ignored by `./miri cargo`.)
+Flags are separated by spaces; use shell-style quotes for flags that themselves contain spaces.
 
-fn main() -> Result<()> {
-    // We are hand-rolling our own argument parser, since `clap` can't express what we need
-    // (https://github.com/clap-rs/clap/issues/5055).
-    let mut args = env::args_os().peekable();
-    args.next().unwrap(); // skip program name
-    let command = match args.next().and_then(|s| s.into_string().ok()).as_deref() {
-        Some("build") => Command::Build { flags: args.collect() },
-        Some("check") => Command::Check { flags: args.collect() },
-        Some("test") => {
-            let mut target = None;
-            let mut bless = false;
-
-            while let Some(arg) = args.peek().and_then(|s| s.to_str()) {
-                match arg {
-                    "--bless" => bless = true,
-                    "--target" => {
-                        // Skip "--target"
-                        args.next().unwrap();
-                        // Next argument is the target triple.
-                        let val = args.peek().ok_or_else(|| {
-                            anyhow!("`--target` must be followed by target triple")
-                        })?;
-                        target = Some(val.to_owned());
-                    }
-                    // Only parse the leading flags.
-                    _ => break,
-                }
+MIRI_SCRIPT_RUSTC:
+Path to a locally built rustc to use instead of a rustup toolchain (like `--rustc`). Since such
+builds usually lack rustfmt and clippy, those fall back to the rustup toolchain.
+
+MIRI_SCRIPT_RECORD:
+Append every command that gets run (with its working dir and the env vars set for it) to the shell
+script at this path (like `--record`), to replay the session from a fresh checkout. The values of
+env vars that look like secrets (e.g. `GITHUB_TOKEN`) are not recorded.
+
+MIRI_SCRIPT_LOG:
+How much to write to `target/miri-script.log` (like `--log-level`): off, warn, info or debug.
+
+MIRI_SCRIPT_METRICS:
+If set to 1, append the duration of every command (and of its steps, like building Miri), with
+the commit and the toolchain, to `target/.metrics.jsonl`, for `./miri metrics`. The file is kept
+to about a megabyte (the older entries move to `.metrics.jsonl.old`), and it stays on this
+machine.
 
-                // Consume the flag, look at the next one.
-                args.next().unwrap();
+MIRI_SCRIPT_NETWORK_TIMEOUT, MIRI_SCRIPT_SYSROOT_TIMEOUT:
+How many seconds a network command (fetching, installing a toolchain) and building the sysroot
+may take before they are killed. Both default to an hour; 0 means no limit."#;
+
+/// The overview of all commands.
+fn help() -> String {
+    let mut help = format!("  COMMANDS\n\n{}\n\n", GLOBAL.about);
+    for spec in COMMANDS {
+        help.push_str(&format!("{}:\n{}\n\n", spec.usage(), spec.about));
+    }
+    help.push_str("  ENVIRONMENT VARIABLES\n\n");
+    help.push_str(ENV_HELP);
+    help
+}
+
+fn parse_toolchain_list(list: &str) -> Result<Vec<String>> {
+    let toolchains: Vec<String> =
+        list.split(',').map(str::trim).filter(|s| !s.is_empty()).map(str::to_owned).collect();
+    if toolchains.is_empty() {
+        bail!("`--toolchains` must be followed by a non-empty list of toolchains");
+    }
+    Ok(toolchains)
+}
+
+/// The hosts of `--workers`, comma-separated, and what `--remote-command` runs on them.
+fn parse_workers(hosts: OsString, command: OsString) -> Result<Workers> {
+    let hosts = hosts.to_str().context("`--workers` must be valid UTF-8")?;
+    let hosts: Vec<String> =
+        hosts.split(',').map(str::trim).filter(|host| !host.is_empty()).map(Into::into).collect();
+    if hosts.is_empty() {
+        bail!("`--workers` must be followed by a non-empty list of hosts");
+    }
+    let command =
+        command.into_string().map_err(|_| anyhow!("`--remote-command` must be valid UTF-8"))?;
+    if !command.contains("{}") {
+        bail!("`--remote-command` must contain `{{}}`, which is replaced by the seed");
+    }
+    Ok(Workers { hosts, command })
+}
+
+/// A range of seeds for `--many-seeds`, given as `from..to` (where `from` defaults to 0). Seeds can
+/// be decimal or hexadecimal.
+struct SeedRange(Range<u32>);
+
+impl FromStr for SeedRange {
+    type Err = anyhow::Error;
+
+    fn from_str(range: &str) -> Result<Self> {
+        let parse_seed = |seed: &str| {
+            match seed.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => seed.parse(),
             }
+        };
+        let (from, to) = range.split_once("..").ok_or_else(|| anyhow!("expected `from..to`"))?;
+        let from =
+            if from.is_empty() { 0 } else { parse_seed(from).context("invalid `from` seed")? };
+        let to = parse_seed(to).context("invalid `to` seed")?;
+        Ok(SeedRange(from..to))
+    }
+}
 
-            Command::Test { bless, flags: args.collect(), target }
-        }
-        Some("run") => {
-            let mut dep = false;
-            let mut verbose = false;
-            let mut many_seeds = None;
-            while let Some(arg) = args.peek().and_then(|s| s.to_str()) {
-                if arg == "--dep" {
-                    dep = true;
-                } else if arg == "-v" || arg == "--verbose" {
-                    verbose = true;
-                } else if arg == "--many-seeds" {
-                    many_seeds = Some(0..256);
-                } else if let Some(val) = arg.strip_prefix("--many-seeds=") {
-                    let (from, to) = val.split_once("..").ok_or_else(|| {
-                        anyhow!("invalid format for `--many-seeds`: expected `from..to`")
-                    })?;
-                    let from: u32 = if from.is_empty() {
-                        0
-                    } else {
-                        from.parse().context("invalid `from` in `--many-seeds=from..to")?
-                    };
-                    let to: u32 = to.parse().context("invalid `to` in `--many-seeds=from..to")?;
-                    many_seeds = Some(from..to);
-                } else {
-                    break; // not for us
+impl Command {
+    /// Builds the command from the arguments parsed according to its entry in `COMMANDS`.
+    fn from_matches(name: &str, m: Matches) -> Result<Self> {
+        Ok(match name {
+            "build" => Command::Build { flags: m.rest },
+            "check" => Command::Check { flags: m.rest },
+            "test" => {
+                let toolchains = match m.parse::<String>("--toolchains")? {
+                    Some(list) => parse_toolchain_list(&list)?,
+                    None => Vec::new(),
+                };
+                Command::Test {
+                    bless: m.flag("--bless"),
+                    doc: m.flag("--doc"),
+                    toolchains,
+                    target: m.value("--target"),
+                    junit: m.value("--junit").map(Into::into),
+                    flags: m.rest,
                 }
-                // Consume the flag, look at the next one.
-                args.next().unwrap();
             }
-            Command::Run { dep, verbose, many_seeds, flags: args.collect() }
-        }
-        Some("fmt") => Command::Fmt { flags: args.collect() },
-        Some("clippy") => Command::Clippy { flags: args.collect() },
-        Some("cargo") => Command::Cargo { flags: args.collect() },
-        Some("install") => Command::Install { flags: args.collect() },
-        Some("bench") => {
-            let mut target = None;
-            while let Some(arg) = args.peek().and_then(|s| s.to_str()) {
-                match arg {
-                    "--target" => {
-                        // Skip "--target"
-                        args.next().unwrap();
-                        // Next argument is the target triple.
-                        let val = args.peek().ok_or_else(|| {
-                            anyhow!("`--target` must be followed by target triple")
-                        })?;
-                        target = Some(val.to_owned());
+            "test-cargo-miri" =>
+                Command::TestCargoMiri {
+                    target: m.value("--target"),
+                    bless: m.flag("--bless"),
+                    keep_tmp: m.flag("--keep-tmp"),
+                    filters: m.rest,
+                },
+            "bless" => {
+                let suites = m
+                    .rest
+                    .iter()
+                    .map(|suite| {
+                        let suite = suite.to_str().context("test suites must be valid UTF-8")?;
+                        bless::parse_suite(suite)
+                    })
+                    .collect::<Result<_>>()?;
+                Command::Bless { suites, target: m.value("--target"), jobs: m.parse("--jobs")? }
+            }
+            "run" => {
+                let many_seeds = match m.parse::<SeedRange>("--many-seeds")? {
+                    Some(SeedRange(range)) => Some(range),
+                    None => m.flag("--many-seeds").then_some(0..256),
+                };
+                let inject = m
+                    .values("--inject")
+                    .iter()
+                    .map(|inject| {
+                        let inject = inject.to_str().context("`--inject` must be valid UTF-8")?;
+                        inject
+                            .parse()
+                            .with_context(|| format!("invalid value `{inject}` for `--inject`"))
+                    })
+                    .collect::<Result<Vec<Injection>>>()?;
+                if !inject.is_empty() && many_seeds.is_none() && m.value("--repro-for").is_none() {
+                    bail!("`--inject` only makes sense with `--many-seeds` or `--repro-for`");
+                }
+                let workers = match (m.value("--workers"), m.value("--remote-command")) {
+                    (None, None) => None,
+                    (Some(_), None) => bail!("`--workers` needs `--remote-command`"),
+                    (None, Some(_)) =>
+                        bail!("`--remote-command` only makes sense with `--workers`"),
+                    (Some(hosts), Some(command)) => Some(parse_workers(hosts, command)?),
+                };
+                let max_load: Option<f64> = m.parse("--max-load")?;
+                if let Some(max_load) = max_load {
+                    if !(max_load.is_finite() && max_load > 0.0) {
+                        bail!("`--max-load` must be a positive number, not `{max_load}`");
+                    }
+                    if many_seeds.is_none() || m.value("--workers").is_some() {
+                        bail!("`--max-load` only makes sense with `--many-seeds` on this machine");
                     }
-                    // Only parse the leading flags.
-                    _ => break,
                 }
-
-                // Consume the flag, look at the next one.
-                args.next().unwrap();
+                if workers.is_some() {
+                    if many_seeds.is_none() {
+                        bail!("`--workers` only makes sense with `--many-seeds`");
+                    }
+                    if m.value("--repro-for").is_some() || !inject.is_empty() || m.flag("--dep") {
+                        bail!("`--workers` conflicts with `--repro-for`, `--inject` and `--dep`");
+                    }
+                    if !m.rest.is_empty() {
+                        bail!(
+                            "with `--workers`, the program and its flags go in `--remote-command`"
+                        );
+                    }
+                }
+                Command::Run {
+                    dep: m.flag("--dep"),
+                    verbose: m.flag("-v"),
+                    many_seeds,
+                    jobs: m.parse("--jobs")?,
+                    max_load,
+                    repro_for: m.parse("--repro-for")?,
+                    inject,
+                    workers,
+                    flags: m.rest,
+                }
             }
-
-            Command::Bench { target, benches: args.collect() }
-        }
-        Some("toolchain") => Command::Toolchain { flags: args.collect() },
-        Some("rustc-pull") => {
-            let commit = args.next().map(|a| a.to_string_lossy().into_owned());
-            if args.next().is_some() {
-                bail!("Too many arguments for `./miri rustc-pull`");
+            "debug" => {
+                let debugger = m.parse("--debugger")?;
+                let mut flags = m.rest;
+                if flags.first().is_some_and(|arg| arg == "--") {
+                    flags.remove(0);
+                }
+                Command::Debug { debugger, flags }
             }
-            Command::RustcPull { commit }
-        }
-        Some("rustc-push") => {
-            let github_user = args
-                .next()
-                .ok_or_else(|| {
-                    anyhow!("Missing first argument for `./miri rustc-push GITHUB_USER [BRANCH]`")
-                })?
-                .to_string_lossy()
-                .into_owned();
-            let branch =
-                args.next().unwrap_or_else(|| "miri-sync".into()).to_string_lossy().into_owned();
-            if args.next().is_some() {
-                bail!("Too many arguments for `./miri rustc-push GITHUB_USER BRANCH`");
+            "fuzz" => {
+                let mut rest = m.rest.iter();
+                let target = rest
+                    .next()
+                    .map(|target| target.to_str().context("fuzz targets must be valid UTF-8"))
+                    .transpose()?
+                    .map(str::to_owned);
+                if rest.next().is_some() {
+                    bail!("`./miri fuzz` takes only one target");
+                }
+                Command::Fuzz { target, time: m.parse("--time")?, list: m.flag("--list") }
+            }
+            "fmt" => Command::Fmt { no_ignore: m.flag("--no-ignore"), flags: m.rest },
+            "clippy" =>
+                Command::Clippy { sarif: m.value("--sarif").map(Into::into), flags: m.rest },
+            "cargo" => {
+                let krate = m.parse::<String>("--crate")?;
+                if let Some(krate) = &krate {
+                    if !["miri", "cargo-miri", "miri-script"].contains(&&**krate) {
+                        bail!("unknown crate `{krate}`, expected one of: miri, cargo-miri, miri-script");
+                    }
+                    if arg_flag_value(&m.rest, "--manifest-path").is_some() {
+                        bail!("`--crate` cannot be combined with `--manifest-path`");
+                    }
+                }
+                Command::Cargo { krate, flags: m.rest }
             }
-            Command::RustcPush { github_user, branch }
+            "install" => Command::Install { strip: m.flag("--strip"), flags: m.rest },
+            "bench" => {
+                let history = m.parse("--history")?.or_else(|| m.flag("--history").then_some(10));
+                let runs = m.parse("--runs")?;
+                let compare_rev: Option<String> = m.parse("--compare-rev")?;
+                if runs == Some(0) {
+                    bail!("`--runs` must be at least 1");
+                }
+                let wall = match m.value("--wall") {
+                    Some(program) => {
+                        if history.is_some() || compare_rev.is_some() || !m.rest.is_empty() {
+                            bail!(
+                                "`--wall` cannot be combined with `--history`, `--compare-rev` or <benches>"
+                            );
+                        }
+                        Some(WallBench { program: program.into(), runs: runs.unwrap_or(10) })
+                    }
+                    None => None,
+                };
+                let compare_rev = match compare_rev {
+                    Some(_)
+                        if history.is_some()
+                            || m.value("--against").is_some()
+                            || m.value("--save-baseline").is_some() =>
+                        bail!(
+                            "`--compare-rev` cannot be combined with `--history`, `--against` or `--save-baseline`"
+                        ),
+                    Some(rev) => Some(CompareRev { rev, runs: runs.unwrap_or(10) }),
+                    None if wall.is_none() && runs.is_some() =>
+                        bail!("`--runs` only makes sense with `--wall` or `--compare-rev`"),
+                    None => None,
+                };
+                Command::Bench {
+                    target: m.value("--target"),
+                    history,
+                    wall,
+                    compare_rev,
+                    against: m.parse("--against")?,
+                    save_baseline: m.parse("--save-baseline")?,
+                    threshold: m.parse("--threshold")?.unwrap_or(5.0),
+                    fail_on_regression: m.flag("--fail-on-regression"),
+                    benches: m.rest,
+                }
+            }
+            "toolchain" => Command::Toolchain { flags: m.rest },
+            "rustc-pull" => {
+                let action = match (m.flag("--continue"), m.flag("--abort")) {
+                    (false, false) => None,
+                    (true, false) => Some(PullAction::Continue),
+                    (false, true) => Some(PullAction::Abort),
+                    (true, true) => bail!("`--continue` and `--abort` cannot be used together"),
+                };
+                let allow_any_josh = m.flag("--allow-any-josh");
+                let retries = m.parse("--retries")?.unwrap_or(DEFAULT_RETRIES);
+                let allow_downgrade = m.flag("--allow-downgrade");
+                let verify = m.parse("--verify")?.unwrap_or_else(|| env::var_os("CI").is_some());
+                let commit = m.value("--commit").map(|c| c.to_string_lossy().into_owned());
+                let mut rest = m.rest.into_iter();
+                let positional = rest.next().map(|a| a.to_string_lossy().into_owned());
+                if rest.next().is_some() {
+                    bail!("Too many arguments for `./miri rustc-pull`");
+                }
+                if commit.is_some() && positional.is_some() {
+                    bail!("the commit must be given either with `--commit` or positionally");
+                }
+                let commit = commit.or(positional);
+                if action.is_some() && commit.is_some() {
+                    bail!("`--continue` and `--abort` do not take a commit");
+                }
+                Command::RustcPull {
+                    commit,
+                    allow_downgrade,
+                    verify,
+                    action,
+                    allow_any_josh,
+                    retries,
+                }
+            }
+            "rustc-push" => {
+                let dry_run = m.flag("--dry-run");
+                let allow_any_josh = m.flag("--allow-any-josh");
+                let force_with_lease = m.flag("--force-with-lease");
+                let retries = m.parse("--retries")?.unwrap_or(DEFAULT_RETRIES);
+                let remote = m.value("--remote").map(|r| r.to_string_lossy().into_owned());
+                let branch = m.value("--branch").map(|b| b.to_string_lossy().into_owned());
+                let mut rest = m.rest.into_iter().map(|a| a.to_string_lossy().into_owned());
+                let github_user = if remote.is_some() { None } else { rest.next() };
+                if remote.is_none() && github_user.is_none() {
+                    bail!(
+                        "Missing first argument for `./miri rustc-push GITHUB_USER [BRANCH]` \
+                        (or use `--remote`)"
+                    );
+                }
+                let branch = match (branch, rest.next()) {
+                    (Some(_), Some(_)) =>
+                        bail!("the branch must be given either with `--branch` or positionally"),
+                    (branch, positional) =>
+                        branch.or(positional).unwrap_or_else(|| "miri-sync".into()),
+                };
+                if rest.next().is_some() {
+                    bail!("Too many arguments for `./miri rustc-push GITHUB_USER BRANCH`");
+                }
+                Command::RustcPush {
+                    github_user,
+                    remote,
+                    branch,
+                    force_with_lease,
+                    dry_run,
+                    allow_any_josh,
+                    retries,
+                }
+            }
+            "rustc-status" =>
+                Command::RustcStatus {
+                    json: m.flag("--json"),
+                    retries: m.parse("--retries")?.unwrap_or(DEFAULT_RETRIES),
+                },
+            "squash" =>
+                Command::Squash { onto: m.parse("--onto")?, allow_merges: m.flag("--allow-merges") },
+            "clean" => Command::Clean { only: m.parse("--only")?, yes: m.flag("--yes") },
+            "env" =>
+                Command::Env {
+                    shell: m.parse("--shell")?.unwrap_or(ShellKind::Sh),
+                    json: m.flag("--json"),
+                },
+            "doctor" => Command::Doctor { json: m.flag("--json") },
+            "ide-setup" =>
+                Command::IdeSetup {
+                    editor: m.parse("--editor")?.unwrap_or(Editor::Vscode),
+                    print: m.flag("--print"),
+                },
+            "self-install-wrapper" => Command::SelfInstallWrapper,
+            "watch" => {
+                let mut command = m.rest;
+                if command.first().is_some_and(|arg| arg == "--") {
+                    command.remove(0);
+                }
+                match command.first().and_then(|c| c.to_str()) {
+                    None =>
+                        bail!("`./miri watch` needs a command, like `./miri watch -- test shims`"),
+                    Some("watch") => bail!("`./miri watch` cannot watch itself"),
+                    Some(_) => Command::Watch { command },
+                }
+            }
+            "ci" => {
+                let list = match (m.flag("--list"), m.flag("--json"), m.flag("--names")) {
+                    (_, true, true) => bail!("`--json` and `--names` cannot be used together"),
+                    (false, true, _) | (false, _, true) =>
+                        bail!("`--json` and `--names` only work together with `--list`"),
+                    (true, true, _) => Some(ci::ListFormat::Json),
+                    (true, _, true) => Some(ci::ListFormat::Names),
+                    (true, false, false) => Some(ci::ListFormat::Table),
+                    (false, false, false) => None,
+                };
+                Command::Ci {
+                    host_only: m.flag("--host-only"),
+                    skip_style: m.flag("--skip-style"),
+                    from: m.parse("--from")?,
+                    only: m.parse("--only")?,
+                    list,
+                }
+            }
+            "metrics" =>
+                Command::Metrics {
+                    last: m.parse("--last")?.unwrap_or(20),
+                    command: m.parse("--command")?,
+                    steps: m.flag("--steps"),
+                },
+            "completions" => {
+                let (complete, shell) = match m.parse::<CompletionShell>("--complete")? {
+                    Some(shell) => (true, shell),
+                    None => {
+                        let [shell] = &m.rest[..] else {
+                            bail!("`./miri completions` needs exactly one shell");
+                        };
+                        (false, shell.to_str().unwrap_or_default().parse()?)
+                    }
+                };
+                let words = if complete {
+                    // Skip the `--` that separates the words from our options.
+                    m.rest
+                        .iter()
+                        .skip_while(|word| *word == "--")
+                        .map(|word| word.to_string_lossy().into_owned())
+                        .collect()
+                } else {
+                    Vec::new()
+                };
+                Command::Completions { shell, complete, words }
+            }
+            _ => unreachable!("`COMMANDS` contains a command without a parser: {name}"),
+        })
+    }
+}
+
+fn main() {
+    let start = std::time::Instant::now();
+    tee::handle_interrupts();
+    let result = run();
+    // Whatever failed because of Ctrl-C does not need explaining.
+    tee::exit_if_interrupted();
+    timings::print_summary(start.elapsed());
+    metrics::finish(start.elapsed(), result.is_ok());
+    if let Err(err) = result {
+        output::error!("{err:?}");
+        if let Some(path) = logfile::path() {
+            output::note!("the log in {} has more details", path.display());
         }
-        _ => {
-            eprintln!("Unknown or missing command. Usage:\n\n{HELP}");
+        std::process::exit(tee::exit_code(&err));
+    }
+}
+
+fn run() -> Result<()> {
+    let mut args = env::args_os().peekable();
+    args.next().unwrap(); // skip program name
+
+    // Like cargo, we accept a leading `+toolchain` to override the toolchain.
+    let toolchain = util::strip_toolchain_arg(&mut args)?;
+    let Some(global_matches) = GLOBAL.parse(args)? else {
+        println!("{}", help());
+        return Ok(());
+    };
+    output::init(global_matches.parse("--color")?);
+    events::init(global_matches.parse("--message-format")?)?;
+    let global = GlobalArgs {
+        toolchain,
+        rustc: global_matches.value("--rustc").map(Into::into),
+        no_skip_build: global_matches.flag("--no-skip-build"),
+        locked: global_matches.flag("--locked"),
+        rustflags: match global_matches.value("--rustflags") {
+            Some(flags) => {
+                let flags = flags.to_str().context("invalid `--rustflags`: not UTF-8")?;
+                util::flagsplit(flags).context("invalid `--rustflags`")?
+            }
+            None => Vec::new(),
+        },
+        verbosity: match (global_matches.flag("--quiet"), global_matches.flag("--verbose")) {
+            (true, true) => bail!("`--quiet` and `--verbose` cannot be given together"),
+            (true, false) => util::Verbosity::Quiet,
+            (false, true) => util::Verbosity::Verbose,
+            (false, false) => util::Verbosity::Normal,
+        },
+    };
+    let log_level = match global_matches.parse("--log-level")? {
+        Some(level) => level,
+        None =>
+            match env::var("MIRI_SCRIPT_LOG") {
+                Ok(level) if !level.is_empty() =>
+                    level.parse().context("invalid MIRI_SCRIPT_LOG")?,
+                _ => logfile::LogLevel::Info,
+            },
+    };
+    // Before anything reads what the environment says about building.
+    let ambient = util::Ambient::init(
+        global_matches.flag("--hermetic"),
+        global_matches.flag("--ignore-miri-sysroot"),
+    );
+    if !ambient.ignored.is_empty() {
+        output::note!("ignoring {} (`--hermetic`)", ambient.ignored.join(", "));
+    }
+    annotations::init(global_matches.flag("--github-annotations"));
+    if global_matches.flag("--timings-summary") {
+        timings::always_summarize();
+    }
+    if global_matches.flag("--dry-run") {
+        // A dry run must not change any files, and that includes the log.
+        record::start_dry_run();
+    } else if let Err(err) = logfile::start(log_level, &util::ScriptCtx::new()?.target_dir) {
+        output::warning!("failed to open the log file, not logging: {err:#}");
+    }
+    if let Some(path) = record::record_path(global_matches.value("--record")) {
+        record::start(&path, &global, &util::miri_dir()?)?;
+    }
+
+    let mut args = global_matches.rest.into_iter();
+    let command = args.next();
+    let Some(spec) = command.as_ref().and_then(|c| COMMANDS.iter().find(|spec| c == spec.name))
+    else {
+        if let Some(suggestion) = command
+            .as_ref()
+            .and_then(|c| c.to_str())
+            .and_then(|c| args::nearest(c, COMMANDS.iter().map(|spec| &spec.name)))
+        {
+            output::error!("unknown command; did you mean `{suggestion}`?");
             std::process::exit(1);
         }
+        output::error!("unknown or missing command. Usage:\n\n{}", help());
+        std::process::exit(1);
+    };
+    let Some(matches) = spec.parse(args)? else {
+        println!("{}", spec.help());
+        return Ok(());
     };
-    command.exec()?;
+    if spec.name == "help" {
+        let help = match &matches.rest[..] {
+            [] => help(),
+            [command] =>
+                match COMMANDS.iter().find(|spec| command == spec.name) {
+                    Some(spec) => spec.help(),
+                    None => {
+                        let command = command.to_string_lossy();
+                        match args::nearest(&command, COMMANDS.iter().map(|spec| &spec.name)) {
+                            Some(suggestion) =>
+                                bail!("unknown command `{command}`; did you mean `{suggestion}`?"),
+                            None => bail!("unknown command `{command}`"),
+                        }
+                    }
+                },
+            _ => bail!("`./miri help` takes at most one command"),
+        };
+        println!("{help}");
+        return Ok(());
+    }
+    let command = Command::from_matches(spec.name, matches)?;
+    if !matches!(command, Command::Metrics { .. } | Command::Completions { .. }) {
+        let util::ScriptCtx { target_dir, miri_dir, .. } = util::ScriptCtx::new()?;
+        metrics::start(spec.name, &target_dir, &miri_dir);
+    }
+    command.exec(&global)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seeds(range: &str) -> Result<Range<u32>> {
+        range.parse::<SeedRange>().map(|r| r.0)
+    }
+
+    #[test]
+    fn seed_ranges() {
+        assert_eq!(seeds("0..256").unwrap(), 0..256);
+        assert_eq!(seeds("..16").unwrap(), 0..16);
+        assert_eq!(seeds("0x10..0x20").unwrap(), 16..32);
+        assert_eq!(seeds("8..0xff").unwrap(), 8..255);
+        assert!(seeds("-1..4").is_err());
+        assert!(seeds("0..-4").is_err());
+        assert!(seeds("0x..4").is_err());
+        assert!(seeds("4").is_err());
+    }
+
+    #[test]
+    fn seed_range_flag() {
+        let spec = COMMANDS.iter().find(|spec| spec.name == "run").unwrap();
+        let parse = |args: &[&str]| {
+            let m = spec.parse(args.iter().map(OsString::from)).unwrap().unwrap();
+            Command::from_matches("run", m)
+        };
+        let Command::Run { many_seeds, jobs, .. } =
+            parse(&["--many-seeds=0x0..0x4", "-j", "2"]).unwrap()
+        else {
+            unreachable!()
+        };
+        assert_eq!(many_seeds, Some(0..4));
+        assert_eq!(jobs, NonZeroUsize::new(2));
+        let err = format!("{:#}", parse(&["--many-seeds=-1..4"]).unwrap_err());
+        assert!(err.starts_with("invalid value `-1..4` for `--many-seeds`"), "{err}");
+        let err = format!("{:#}", parse(&["--jobs=-2"]).unwrap_err());
+        assert!(err.starts_with("invalid value `-2` for `--jobs`"), "{err}");
+        assert!(parse(&["--jobs=0"]).is_err());
+        assert!(parse(&["--many-seeds="]).is_err());
+        let Command::Run { max_load, .. } = parse(&["--many-seeds", "--max-load=24"]).unwrap()
+        else {
+            unreachable!()
+        };
+        assert_eq!(max_load, Some(24.0));
+        for bad in ["--max-load=0", "--max-load=-1", "--max-load=NaN", "--max-load=x"] {
+            assert!(parse(&["--many-seeds", bad]).is_err(), "{bad}");
+        }
+        assert!(parse(&["--max-load=24"]).is_err());
+    }
+}
@@ -0,0 +1,335 @@
+//! The opt-in history of how long `./miri` commands took on this machine (`MIRI_SCRIPT_METRICS`),
+//! in `target/.metrics.jsonl`, and `./miri metrics` to look at it. Nothing of this leaves the
+//! machine.
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::bench::change;
+use crate::ci::format_duration;
+use crate::record::{self, is_dry_run};
+use crate::sync::now;
+use crate::util::move_file;
+use crate::{output::warning, timings};
+
+/// The env var that turns recording the metrics on.
+pub const METRICS_VAR: &str = "MIRI_SCRIPT_METRICS";
+
+/// The metrics get moved to `.metrics.jsonl.old` when they get larger than this.
+const MAX_SIZE: u64 = 1 << 20;
+
+/// A median that changed by less than this (in percent) is shown as not having changed.
+const TREND_THRESHOLD: f64 = 5.0;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Step {
+    /// What the commands did, as in the timings summary (e.g. `build miri`).
+    pub name: String,
+    /// How long the commands that did it took, added up.
+    pub duration_secs: f64,
+}
+
+/// One `./miri` invocation. The metrics file has one of these per line.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Run {
+    pub command: String,
+    /// When the command finished, in seconds since the Unix epoch.
+    pub time: u64,
+    pub commit: String,
+    /// The toolchain (or the path of the local rustc) the command used, if it used one.
+    pub toolchain: Option<String>,
+    pub duration_secs: f64,
+    pub success: bool,
+    /// By how long they took, longest first.
+    pub steps: Vec<Step>,
+}
+
+struct Recording {
+    command: &'static str,
+    path: PathBuf,
+    miri_dir: PathBuf,
+    toolchain: Option<String>,
+}
+
+static RECORDING: Mutex<Option<Recording>> = Mutex::new(None);
+
+fn metrics_path(target_dir: &Path) -> PathBuf {
+    target_dir.join(".metrics.jsonl")
+}
+
+/// Starts recording the metrics of `command`, if `MIRI_SCRIPT_METRICS` asks for it. Dry runs
+/// do not count.
+pub fn start(command: &'static str, target_dir: &Path, miri_dir: &Path) {
+    let enabled = env::var(METRICS_VAR).is_ok_and(|v| !v.is_empty() && v != "0");
+    if !enabled || is_dry_run() {
+        return;
+    }
+    *RECORDING.lock().unwrap() = Some(Recording {
+        command,
+        path: metrics_path(target_dir),
+        miri_dir: miri_dir.to_owned(),
+        toolchain: None,
+    });
+}
+
+pub fn is_recording() -> bool {
+    RECORDING.lock().unwrap().is_some()
+}
+
+/// Notes the toolchain the command uses.
+pub fn set_toolchain(toolchain: String) {
+    if let Some(recording) = &mut *RECORDING.lock().unwrap() {
+        recording.toolchain = Some(toolchain);
+    }
+}
+
+/// Appends the metrics of the command, which took `duration`, if they are being recorded.
+pub fn finish(duration: Duration, success: bool) {
+    let Some(recording) = RECORDING.lock().unwrap().take() else {
+        return;
+    };
+    let steps = timings::totals()
+        .into_iter()
+        .map(|(name, duration)| Step { name, duration_secs: duration.as_secs_f64() })
+        .collect();
+    let run = Run {
+        command: recording.command.to_owned(),
+        time: now(),
+        commit: record::commit(&recording.miri_dir),
+        toolchain: recording.toolchain,
+        duration_secs: duration.as_secs_f64(),
+        success,
+        steps,
+    };
+    if let Err(err) = append(&recording.path, &run) {
+        warning!("failed to record the metrics in {}: {err:#}", recording.path.display());
+    }
+}
+
+fn append(path: &Path, run: &Run) -> Result<()> {
+    if fs::metadata(path).is_ok_and(|meta| meta.len() > MAX_SIZE) {
+        move_file(path, &path.with_extension("jsonl.old"))?;
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::File::options().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(run)?)?;
+    Ok(())
+}
+
+/// Reads the metrics, the older ones first. Lines that do not parse (like one that was cut
+/// short) are skipped.
+pub fn load(target_dir: &Path) -> Result<Vec<Run>> {
+    let path = metrics_path(target_dir);
+    let mut runs = Vec::new();
+    for path in [path.with_extension("jsonl.old"), path] {
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) =>
+                return Err(err).with_context(|| format!("failed to read {}", path.display())),
+        };
+        runs.extend(contents.lines().filter_map(|line| serde_json::from_str::<Run>(line).ok()));
+    }
+    Ok(runs)
+}
+
+fn median(values: &mut [f64]) -> Option<f64> {
+    values.sort_by(f64::total_cmp);
+    let n = values.len();
+    match n {
+        0 => None,
+        _ if n % 2 == 1 => Some(values[n / 2]),
+        _ => Some((values[n / 2 - 1] + values[n / 2]) / 2.0),
+    }
+}
+
+/// How the newer half of `durations` compares to the older half: an arrow, and the change of the
+/// median in percent. Nothing for fewer than 4 durations.
+fn trend(durations: &[f64]) -> Option<String> {
+    if durations.len() < 4 {
+        return None;
+    }
+    let (older, newer) = durations.split_at(durations.len() / 2);
+    let change = change(median(&mut older.to_vec())?, median(&mut newer.to_vec())?);
+    let arrow = if change > TREND_THRESHOLD {
+        "↑"
+    } else if change < -TREND_THRESHOLD {
+        "↓"
+    } else {
+        "→"
+    };
+    Some(format!("{arrow} {change:+.0}%"))
+}
+
+/// One line of the summary.
+#[derive(Debug, PartialEq)]
+struct Row {
+    name: String,
+    runs: usize,
+    failed: usize,
+    median: f64,
+    last: f64,
+    trend: Option<String>,
+}
+
+impl Row {
+    /// The line for `name`, from the durations of its successful runs (older ones first).
+    fn new(name: String, durations: &[f64], failed: usize) -> Row {
+        Row {
+            name,
+            runs: durations.len(),
+            failed,
+            median: median(&mut durations.to_vec()).unwrap_or_default(),
+            last: durations.last().copied().unwrap_or_default(),
+            trend: trend(durations),
+        }
+    }
+}
+
+/// Summarizes the last `last` runs of each command (the successful ones; the others probably
+/// stopped early), and with `steps` the steps of those runs as well.
+fn summarize(runs: &[Run], last: usize, command: Option<&str>, steps: bool) -> Vec<Row> {
+    let mut commands: Vec<&str> = runs.iter().map(|run| &*run.command).collect();
+    commands.sort();
+    commands.dedup();
+    commands.retain(|name| command.is_none_or(|command| command == *name));
+    let mut rows = Vec::new();
+    for name in commands {
+        let runs: Vec<&Run> = runs.iter().filter(|run| run.command == name).collect();
+        let runs = &runs[runs.len().saturating_sub(last)..];
+        let succeeded: Vec<&Run> = runs.iter().copied().filter(|run| run.success).collect();
+        let durations: Vec<f64> = succeeded.iter().map(|run| run.duration_secs).collect();
+        rows.push(Row::new(name.to_owned(), &durations, runs.len() - succeeded.len()));
+        if !steps {
+            continue;
+        }
+        let mut step_names: Vec<&str> =
+            succeeded.iter().flat_map(|run| &run.steps).map(|step| &*step.name).collect();
+        step_names.sort();
+        step_names.dedup();
+        let mut step_rows: Vec<Row> = step_names
+            .into_iter()
+            .map(|step| {
+                let durations: Vec<f64> = succeeded
+                    .iter()
+                    .filter_map(|run| run.steps.iter().find(|s| s.name == step))
+                    .map(|s| s.duration_secs)
+                    .collect();
+                Row::new(format!("  {step}"), &durations, 0)
+            })
+            .collect();
+        step_rows.sort_by(|a, b| b.median.total_cmp(&a.median));
+        rows.extend(step_rows);
+    }
+    rows
+}
+
+/// Prints the median duration of each command over its last `last` runs, and how that changed.
+pub fn print(target_dir: &Path, last: usize, command: Option<&str>, steps: bool) -> Result<()> {
+    let runs = load(target_dir)?;
+    let rows = summarize(&runs, last, command, steps);
+    if rows.is_empty() {
+        println!("No metrics have been recorded yet; set {METRICS_VAR}=1 to record them.");
+        return Ok(());
+    }
+    let width = rows.iter().map(|row| row.name.chars().count()).max().unwrap().max(7);
+    println!("{:width$}  {:>4}  {:>8}  {:>8}  trend", "command", "runs", "median", "last");
+    for row in rows {
+        let secs = Duration::from_secs_f64;
+        let (median, last) = if row.runs == 0 {
+            ("-".to_owned(), "-".to_owned())
+        } else {
+            (format_duration(secs(row.median)), format_duration(secs(row.last)))
+        };
+        let mut trend = row.trend.unwrap_or_default();
+        if row.failed > 0 {
+            if !trend.is_empty() {
+                trend.push(' ');
+            }
+            trend.push_str(&format!("({} failed)", row.failed));
+        }
+        println!("{:width$}  {:>4}  {median:>8}  {last:>8}  {trend}", row.name, row.runs);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(command: &str, duration_secs: f64, success: bool) -> Run {
+        Run {
+            command: command.into(),
+            time: 0,
+            commit: "abc".into(),
+            toolchain: Some("miri".into()),
+            duration_secs,
+            success,
+            steps: vec![Step { name: "build miri".into(), duration_secs: duration_secs / 2.0 }],
+        }
+    }
+
+    #[test]
+    fn metrics_file() {
+        let dir = std::env::temp_dir().join(format!("miri-metrics-{}", std::process::id()));
+        assert!(load(&dir).unwrap().is_empty());
+        let path = metrics_path(&dir);
+        append(&path, &run("test", 2.0, true)).unwrap();
+        // A line that got cut short.
+        fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"com\n").unwrap();
+        append(&path, &run("build", 1.0, false)).unwrap();
+        assert_eq!(load(&dir).unwrap(), [run("test", 2.0, true), run("build", 1.0, false)]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn summaries() {
+        assert_eq!(median(&mut [3.0, 1.0, 2.0]), Some(2.0));
+        assert_eq!(median(&mut [4.0, 1.0, 2.0, 3.0]), Some(2.5));
+        assert_eq!(trend(&[1.0, 1.0, 1.0]), None);
+        assert_eq!(trend(&[1.0, 1.0, 1.2, 1.2]).unwrap(), "↑ +20%");
+        assert_eq!(trend(&[1.0, 1.0, 0.98, 1.0, 1.01]).unwrap(), "→ +0%");
+
+        let runs = [
+            run("test", 10.0, true),
+            run("build", 3.0, true),
+            run("test", 20.0, true),
+            run("test", 1.0, false),
+            run("test", 30.0, true),
+        ];
+        let rows = summarize(&runs, 3, None, false);
+        assert_eq!(
+            rows,
+            [
+                Row {
+                    name: "build".into(),
+                    runs: 1,
+                    failed: 0,
+                    median: 3.0,
+                    last: 3.0,
+                    trend: None
+                },
+                Row {
+                    name: "test".into(),
+                    runs: 2,
+                    failed: 1,
+                    median: 25.0,
+                    last: 30.0,
+                    trend: None
+                },
+            ]
+        );
+        let rows = summarize(&runs, 10, Some("test"), true);
+        assert_eq!(rows.len(), 2);
+        assert_eq!((&*rows[1].name, rows[1].median), ("  build miri", 10.0));
+    }
+}
@@ -0,0 +1,338 @@
+//! Status lines, warnings and errors on stderr, colored if the terminal supports it and the user
+//! did not ask us not to. Everything we print while other threads might print as well goes
+//! through [`write`], so that lines do not tear.
+
+use std::env;
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+use anyhow::{bail, Result};
+
+use crate::events::{self, Event};
+use crate::logfile::{self, LogLevel};
+
+/// The `--color` option.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// The value for `--color`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ColorChoice::Auto => "auto",
+            ColorChoice::Always => "always",
+            ColorChoice::Never => "never",
+        }
+    }
+}
+
+impl FromStr for ColorChoice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "auto" => ColorChoice::Auto,
+            "always" => ColorChoice::Always,
+            "never" => ColorChoice::Never,
+            _ => bail!("invalid value `{s}` for `--color`, expected auto, always or never"),
+        })
+    }
+}
+
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+/// Decides whether to use colors. `--color` wins over `NO_COLOR`, which wins over what the user
+/// told cargo with `CARGO_TERM_COLOR`; otherwise we color if stderr is a terminal.
+fn use_color(
+    choice: Option<ColorChoice>,
+    no_color: bool,
+    cargo_term_color: Option<&str>,
+    terminal: bool,
+) -> bool {
+    match choice {
+        Some(ColorChoice::Always) => true,
+        Some(ColorChoice::Never) => false,
+        _ if no_color => false,
+        _ =>
+            match cargo_term_color {
+                Some("always") => true,
+                Some("never") => false,
+                _ => terminal,
+            },
+    }
+}
+
+/// What tells the commands we run whether to color: cargo (and nested `./miri`s) look at
+/// `CARGO_TERM_COLOR`, most other tools at `NO_COLOR` and `CLICOLOR_FORCE`. Their output often
+/// goes through us (when we capture it to show it later, or with a prefix), so they cannot tell
+/// on their own whether it ends up on a terminal. `ScriptCtx` sets these in its shell.
+pub fn child_env(color: bool) -> [(&'static str, Option<&'static str>); 3] {
+    if color {
+        [("CARGO_TERM_COLOR", Some("always")), ("CLICOLOR_FORCE", Some("1")), ("NO_COLOR", None)]
+    } else {
+        [("CARGO_TERM_COLOR", Some("never")), ("CLICOLOR_FORCE", None), ("NO_COLOR", Some("1"))]
+    }
+}
+
+/// Decides whether to use colors. The commands we run get told the same (see `child_env`), so that
+/// their output agrees with ours; this has to run before the first `ScriptCtx` is made.
+pub fn init(choice: Option<ColorChoice>) {
+    let no_color = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    let cargo_term_color = env::var("CARGO_TERM_COLOR").ok();
+    let terminal = io::stderr().is_terminal() && env::var_os("TERM").is_none_or(|t| t != "dumb");
+    let color = use_color(choice, no_color, cargo_term_color.as_deref(), terminal);
+    COLOR.store(color, Ordering::Relaxed);
+}
+
+/// Whether we color the output (see [`init`]).
+pub fn color() -> bool {
+    COLOR.load(Ordering::Relaxed)
+}
+
+/// What [`init`] decided, as `Always` or `Never`.
+pub fn choice() -> ColorChoice {
+    if color() {
+        ColorChoice::Always
+    } else {
+        ColorChoice::Never
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Style {
+    /// What we are doing right now, e.g. `Running 256 seeds...`.
+    Status,
+    /// Something finished successfully.
+    Success,
+    Note,
+    Warning,
+    Error,
+}
+
+impl Style {
+    fn prefix(self) -> &'static str {
+        match self {
+            Style::Status | Style::Success => "",
+            Style::Note => "note: ",
+            Style::Warning => "warning: ",
+            Style::Error => "error: ",
+        }
+    }
+
+    /// The SGR parameters for the prefix (or the whole line, if there is no prefix).
+    fn sgr(self) -> &'static str {
+        match self {
+            Style::Status => "1",
+            Style::Success => "1;32",
+            Style::Note => "1;36",
+            Style::Warning => "1;33",
+            Style::Error => "1;31",
+        }
+    }
+}
+
+fn paint(style: Style, msg: fmt::Arguments<'_>, color: bool) -> String {
+    let prefix = style.prefix();
+    if !color {
+        format!("{prefix}{msg}")
+    } else if prefix.is_empty() {
+        format!("\x1b[{}m{msg}\x1b[0m", style.sgr())
+    } else {
+        format!("\x1b[{}m{prefix}\x1b[0m{msg}", style.sgr())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Moves the cursor to the start of the line and clears it.
+const CLEAR_LINE: &[u8] = b"\r\x1b[K";
+
+/// The progress line at the bottom of stderr, if one is shown. Holding the lock is what makes
+/// a write whole.
+static PROGRESS: Mutex<Option<String>> = Mutex::new(None);
+
+/// Writes `bytes` (usually whole lines) to `stream`, without anything else we print ending up in
+/// the middle. A progress line gets cleared first, and shown again below.
+pub fn write(stream: Stream, bytes: &[u8]) {
+    // A thread that panicked while printing should not keep the others from printing.
+    let progress = PROGRESS.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut stderr = io::stderr().lock();
+    // If the terminal goes away, there is nobody to tell about it.
+    if progress.is_some() {
+        let _ = stderr.write_all(CLEAR_LINE);
+    }
+    let _ = match stream {
+        Stream::Stdout => {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(bytes).and_then(|()| stdout.flush())
+        }
+        Stream::Stderr => stderr.write_all(bytes),
+    };
+    if let Some(progress) = &*progress {
+        let _ = stderr.write_all(progress.as_bytes());
+    }
+    let _ = stderr.flush();
+}
+
+/// Replaces the progress line at the bottom of stderr, or removes it. This is only for when stderr
+/// is a terminal; otherwise, nothing takes the line back.
+pub fn set_progress(line: Option<String>) {
+    let mut progress = PROGRESS.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut stderr = io::stderr().lock();
+    if progress.is_some() {
+        let _ = stderr.write_all(CLEAR_LINE);
+    }
+    if let Some(line) = &line {
+        let _ = stderr.write_all(line.as_bytes());
+    }
+    let _ = stderr.flush();
+    *progress = line;
+}
+
+/// Removes the escape sequences that color the output.
+pub fn strip_colors(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // `ESC [ ... <letter>`
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if c.is_ascii_alphabetic() {
+                        break;
+                    }
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Prints `msg` in the given style, and logs it as well.
+pub fn print(style: Style, msg: fmt::Arguments<'_>) {
+    write(Stream::Stderr, format!("{}\n", paint(style, msg, color())).as_bytes());
+    // What we print may have the colored output of a command in it.
+    match style {
+        Style::Warning =>
+            events::emit(&Event::Warning { message: &strip_colors(&msg.to_string()) }),
+        Style::Error => events::emit(&Event::Error { message: &strip_colors(&msg.to_string()) }),
+        Style::Status | Style::Success | Style::Note => {}
+    }
+    let level = match style {
+        Style::Warning | Style::Error => LogLevel::Warn,
+        Style::Status | Style::Success | Style::Note => LogLevel::Info,
+    };
+    logfile::write(level, &paint(style, msg, false));
+}
+
+/// Prints a line on stderr like `eprintln!`, but through [`write`], and without logging it.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! plain {
+    ($($arg:tt)*) => {
+        $crate::output::write(
+            $crate::output::Stream::Stderr,
+            format!("{}\n", format_args!($($arg)*)).as_bytes(),
+        )
+    };
+}
+pub use plain;
+
+/// Prints a status line.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! status {
+    ($($arg:tt)*) => {
+        $crate::output::print($crate::output::Style::Status, format_args!($($arg)*))
+    };
+}
+pub use status;
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! success {
+    ($($arg:tt)*) => {
+        $crate::output::print($crate::output::Style::Success, format_args!($($arg)*))
+    };
+}
+pub use success;
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! note {
+    ($($arg:tt)*) => {
+        $crate::output::print($crate::output::Style::Note, format_args!($($arg)*))
+    };
+}
+pub use note;
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::output::print($crate::output::Style::Warning, format_args!($($arg)*))
+    };
+}
+pub use warning;
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::output::print($crate::output::Style::Error, format_args!($($arg)*))
+    };
+}
+pub use error;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_choice() {
+        use ColorChoice::*;
+        assert!(use_color(Some(Always), true, Some("never"), false));
+        assert!(!use_color(Some(Never), false, None, true));
+        assert!(!use_color(Some(Auto), true, None, true));
+        assert!(!use_color(None, false, Some("never"), true));
+        assert!(use_color(None, false, Some("always"), false));
+        assert!(use_color(None, false, Some("auto"), true));
+        assert!("sometimes".parse::<ColorChoice>().is_err());
+
+        // Whatever the user told the commands we run, they get told what we decided.
+        for color in [true, false] {
+            let env = child_env(color);
+            let value = |var| env.iter().find(|(v, _)| *v == var).unwrap().1;
+            assert_eq!(value("CARGO_TERM_COLOR"), Some(if color { "always" } else { "never" }));
+            assert_eq!(value("CLICOLOR_FORCE").is_some(), color);
+            assert_eq!(value("NO_COLOR").is_some(), !color);
+        }
+    }
+
+    #[test]
+    fn painting() {
+        let seeds = 256;
+        assert_eq!(
+            paint(Style::Status, format_args!("Running {seeds} seeds..."), true),
+            "\x1b[1mRunning 256 seeds...\x1b[0m"
+        );
+        assert_eq!(
+            paint(Style::Warning, format_args!("careful"), true),
+            "\x1b[1;33mwarning: \x1b[0mcareful"
+        );
+        assert_eq!(paint(Style::Error, format_args!("oops"), false), "error: oops");
+    }
+}
@@ -0,0 +1,799 @@
+//! Recording the commands a `./miri` invocation runs into a shell script that replays them (see
+//! `MIRI_SCRIPT_RECORD`), and printing them instead of running them (`--dry-run`).
+
+use std::cell::Cell;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use xshell::Shell;
+
+use crate::cmdline;
+use crate::events::{self, Event};
+use crate::logfile::{self, LogLevel};
+use crate::metrics;
+use crate::output::{self, plain, warning, Stream};
+use crate::tee::{self, CommandFailed, TimedOut};
+use crate::timings;
+use crate::util::{local_rustc, miri_sysroot, Ambient};
+use crate::GlobalArgs;
+
+/// The variable to set to the script to record to (like `--record`).
+pub const RECORD_VAR: &str = "MIRI_SCRIPT_RECORD";
+
+/// Parts of env var names that suggest the value should not end up in a file.
+const SECRET_PARTS: &[&str] =
+    &["TOKEN", "SECRET", "PASSWORD", "PASSWD", "KEY", "AUTH", "CREDENTIAL"];
+
+struct Recording {
+    file: fs::File,
+    /// The Miri dir, which is replaced by `$MIRI_DIR` in the script.
+    root: String,
+}
+
+static RECORDING: Mutex<Option<Recording>> = Mutex::new(None);
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// The seed the commands on this thread run with, if any (see `with_seed`).
+    static SEED: Cell<Option<u32>> = const { Cell::new(None) };
+}
+
+/// Starts appending the commands that are run to the script at `path`. The script is created if
+/// it does not exist yet; otherwise, this invocation is added at the end.
+pub fn start(path: &Path, global: &GlobalArgs, miri_dir: &Path) -> Result<()> {
+    let fresh = fs::metadata(path).map_or(true, |meta| meta.len() == 0);
+    let mut file = fs::File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let mut header = String::new();
+    if fresh {
+        header.push_str(&format!(
+            "\
+#!/bin/sh
+# Commands recorded by `./miri` (see {RECORD_VAR}). To replay them, run this from the
+# Miri dir of a checkout of the commit below (or set MIRI_DIR to it). Secrets in env vars are
+# not recorded; those vars are taken from the environment of the replay instead. Commands that
+# ran in parallel were recorded in the order they started.
+MIRI_DIR=\"${{MIRI_DIR:-$(pwd)}}\"
+"
+        ));
+    }
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    header.push_str(&format!("\n# ./miri {}\n", shell_words::join(&args)));
+    header.push_str(&format!("# toolchain: {}\n", toolchain(global)));
+    header.push_str(&format!("# commit: {}\n", commit(miri_dir)));
+    file.write_all(header.as_bytes())?;
+    let root = miri_dir.to_string_lossy().into_owned();
+    *RECORDING.lock().unwrap() = Some(Recording { file, root });
+    Ok(())
+}
+
+/// Describes the toolchain for the header. This does not go through `resolve_toolchain`, to not
+/// print its notes twice.
+fn toolchain(global: &GlobalArgs) -> String {
+    if let Some(rustc) = local_rustc(global) {
+        return format!("local rustc {}", rustc.display());
+    }
+    global
+        .toolchain
+        .clone()
+        .or_else(|| std::env::var("RUSTUP_TOOLCHAIN").ok().filter(|t| !t.is_empty()))
+        .or_else(|| crate::util::active_toolchain().ok())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+/// The Miri commit, for the header (and the metrics). This does not run through `Cmd`, which
+/// would record it.
+pub fn commit(miri_dir: &Path) -> String {
+    let output =
+        process::Command::new("git").args(["rev-parse", "HEAD"]).current_dir(miri_dir).output();
+    match output {
+        Ok(output) if output.status.success() =>
+            String::from_utf8_lossy(&output.stdout).trim().to_owned(),
+        _ => "unknown".to_owned(),
+    }
+}
+
+fn is_recording() -> bool {
+    RECORDING.lock().unwrap().is_some()
+}
+
+pub fn start_dry_run() {
+    DRY_RUN.store(true, Ordering::Relaxed);
+}
+
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+/// For everything that changes files or starts processes without going through `Cmd`: in a dry
+/// run, prints what we would do, and returns `true` to tell the caller to not do it.
+pub fn skip_in_dry_run(action: impl fmt::Display) -> bool {
+    if is_dry_run() {
+        plain!("[dry run] {action}");
+    }
+    is_dry_run()
+}
+
+/// Whether a command only queries something. In a dry run, those still run (marked as `[probe]`),
+/// since what we do next usually depends on their output.
+fn is_probe(command: &process::Command) -> bool {
+    let program = Path::new(command.get_program()).file_stem().unwrap_or_default();
+    let args: Vec<_> = command.get_args().map(|arg| arg.to_string_lossy()).collect();
+    // Skip the `+toolchain`.
+    let args: Vec<&str> =
+        args.iter().map(|arg| &**arg).filter(|arg| !arg.starts_with('+')).collect();
+    match (&*program.to_string_lossy(), &args[..]) {
+        (_, ["--version" | "-V" | "-vV"] | ["--version", "--verbose" | "-v"]) => true,
+        // E.g. `cargo clippy --version`.
+        ("cargo", [_, "--version"]) => true,
+        ("rustc", args) => args.iter().any(|arg| arg.starts_with("--print")),
+        ("rustup", ["show", ..] | ["component", "list", ..] | ["toolchain", "list", ..]) => true,
+        // Skip a `-C <dir>`.
+        ("git", ["-C", _, subcommand, rest @ ..] | [subcommand, rest @ ..]) =>
+            match *subcommand {
+                "rev-parse" | "rev-list" | "log" | "show" | "status" | "diff" | "cat-file"
+                | "var" | "merge-base" | "ls-files" => true,
+                "remote" => matches!(rest, [] | ["-v"] | ["get-url", _]),
+                _ => false,
+            },
+        ("df", _) => true,
+        _ => false,
+    }
+}
+
+/// Runs `f` with the commands it runs annotated with `seed` in the script.
+pub fn with_seed<T>(seed: u32, f: impl FnOnce() -> T) -> T {
+    let outer = SEED.replace(Some(seed));
+    let result = f();
+    SEED.set(outer);
+    result
+}
+
+/// Appends `command` to the script, if we are recording.
+fn record(command: &process::Command, stdin: Option<&[u8]>) {
+    let mut recording = RECORDING.lock().unwrap();
+    let Some(Recording { file, root }) = recording.as_mut() else {
+        return;
+    };
+    let mut script = String::new();
+    if let Some(seed) = SEED.get() {
+        script.push_str(&format!("# seed {seed}\n"));
+    }
+    script.push_str(&render(command, stdin, root));
+    script.push('\n');
+    if let Err(err) = file.write_all(script.as_bytes()) {
+        warning!("failed to record a command, not recording any more: {err}");
+        *recording = None;
+    }
+}
+
+fn is_secret(var: &str) -> bool {
+    let var = var.to_ascii_uppercase();
+    SECRET_PARTS.iter().any(|part| var.contains(part))
+}
+
+/// Quotes `value` for the shell, with the occurrences of `root` replaced by `$MIRI_DIR`.
+fn quote(value: &OsStr, root: &str) -> String {
+    let value = value.to_string_lossy();
+    if root.is_empty() || !value.contains(root) {
+        return shell_words::quote(&value).into_owned();
+    }
+    let pieces = value.split(root).map(|piece| {
+        match piece {
+            "" => String::new(),
+            piece => shell_words::quote(piece).into_owned(),
+        }
+    });
+    pieces.collect::<Vec<_>>().join("\"$MIRI_DIR\"")
+}
+
+/// Renders `command` as a line of shell script that runs it in its working dir with the env vars
+/// it sets.
+fn render(command: &process::Command, stdin: Option<&[u8]>, root: &str) -> String {
+    let mut removed = Vec::new();
+    let mut set = Vec::new();
+    for (var, value) in command.get_envs() {
+        let name = var.to_string_lossy();
+        if name == RECORD_VAR {
+            continue;
+        }
+        match value {
+            None => removed.push(format!("-u {}", shell_words::quote(&name))),
+            Some(_) if is_secret(&name) => set.push(format!("{name}=\"${name}\"")),
+            Some(value) => set.push(format!("{name}={}", quote(value, root))),
+        }
+    }
+    let mut line = String::from("(");
+    if let Some(dir) = command.get_current_dir() {
+        line.push_str(&format!("cd {} && ", quote(dir.as_os_str(), root)));
+    }
+    if !removed.is_empty() || !set.is_empty() {
+        line.push_str("env ");
+        for word in removed.iter().chain(&set) {
+            line.push_str(word);
+            line.push(' ');
+        }
+    }
+    let program = [command.get_program()].into_iter().chain(command.get_args());
+    line.push_str(&program.map(|word| quote(word, root)).collect::<Vec<_>>().join(" "));
+    match stdin {
+        Some(stdin) => {
+            let stdin = String::from_utf8_lossy(stdin);
+            line.push_str(&format!(
+                " <<'MIRI_SCRIPT_STDIN'\n{}\nMIRI_SCRIPT_STDIN\n)",
+                stdin.trim_end_matches('\n')
+            ));
+        }
+        None => line.push(')'),
+    }
+    line
+}
+
+/// Like `xshell::cmd!`, but the command is recorded when it runs.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! cmd {
+    ($sh:expr, $cmd:literal) => {
+        $crate::record::Cmd::new(&$sh, ::xshell::cmd!($sh, $cmd))
+    };
+}
+pub use cmd;
+
+/// An `xshell::Cmd` that gets recorded when it runs. Since those cannot be inspected, this keeps
+/// what it needs to build a new one each time it runs.
+#[derive(Clone, Debug)]
+pub struct Cmd<'a> {
+    sh: &'a Shell,
+    program: OsString,
+    args: Vec<OsString>,
+    env: Vec<(OsString, Option<OsString>)>,
+    stdin: Option<Vec<u8>>,
+    /// What the command does, for the timings summary.
+    label: Option<String>,
+    quiet: bool,
+    /// Whether the command uses the terminal itself, like an editor does.
+    interactive: bool,
+    ignore_status: bool,
+    ignore_stdout: bool,
+    ignore_stderr: bool,
+}
+
+impl<'a> Cmd<'a> {
+    pub fn new(sh: &'a Shell, cmd: xshell::Cmd<'a>) -> Cmd<'a> {
+        // The shell's env vars and working dir are not taken from here: they are applied when the
+        // command runs, which is what `xshell` does as well.
+        let command = process::Command::from(cmd);
+        Cmd {
+            sh,
+            program: command.get_program().to_owned(),
+            args: command.get_args().map(ToOwned::to_owned).collect(),
+            env: Vec::new(),
+            stdin: None,
+            label: None,
+            quiet: false,
+            interactive: false,
+            ignore_status: false,
+            ignore_stdout: false,
+            ignore_stderr: false,
+        }
+    }
+
+    pub fn arg(mut self, arg: impl AsRef<OsStr>) -> Cmd<'a> {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    pub fn args<I>(mut self, args: I) -> Cmd<'a>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<OsStr>,
+    {
+        self.args.extend(args.into_iter().map(|arg| arg.as_ref().to_owned()));
+        self
+    }
+
+    pub fn env(mut self, key: impl AsRef<OsStr>, val: impl AsRef<OsStr>) -> Cmd<'a> {
+        self.env.push((key.as_ref().to_owned(), Some(val.as_ref().to_owned())));
+        self
+    }
+
+    pub fn env_remove(mut self, key: impl AsRef<OsStr>) -> Cmd<'a> {
+        self.env.push((key.as_ref().to_owned(), None));
+        self
+    }
+
+    pub fn stdin(mut self, stdin: impl AsRef<[u8]>) -> Cmd<'a> {
+        self.stdin = Some(stdin.as_ref().to_owned());
+        self
+    }
+
+    /// Says what the command does (e.g. `build miri`), for the timings summary.
+    pub fn label(mut self, label: impl Into<String>) -> Cmd<'a> {
+        self.label = Some(label.into());
+        self
+    }
+
+    pub fn quiet(mut self) -> Cmd<'a> {
+        self.quiet = true;
+        self
+    }
+
+    pub fn set_quiet(&mut self, yes: bool) {
+        self.quiet = yes;
+    }
+
+    /// Lets the command read from the terminal, and leaves handling Ctrl-C to it. `run` is the
+    /// only way to run such a command.
+    pub fn interactive(mut self) -> Cmd<'a> {
+        self.interactive = true;
+        self
+    }
+
+    pub fn ignore_status(mut self) -> Cmd<'a> {
+        self.ignore_status = true;
+        self
+    }
+
+    pub fn ignore_stdout(mut self) -> Cmd<'a> {
+        self.ignore_stdout = true;
+        self
+    }
+
+    pub fn ignore_stderr(mut self) -> Cmd<'a> {
+        self.ignore_stderr = true;
+        self
+    }
+
+    /// The `xshell::Cmd` to run.
+    fn build(&self) -> xshell::Cmd<'a> {
+        let mut cmd = self.sh.cmd(&self.program).args(&self.args);
+        // Before the command's own env vars, which may set them again.
+        for var in Ambient::get().unset_for_commands() {
+            cmd = cmd.env_remove(var);
+        }
+        // The user's `MIRI_SYSROOT`, if `--ignore-miri-sysroot` ignores it.
+        if self.sh.var_os("MIRI_SYSROOT").is_some() && miri_sysroot(self.sh).is_none() {
+            cmd = cmd.env_remove("MIRI_SYSROOT");
+        }
+        // The shell sets the others (see `ScriptCtx::sh`).
+        for (var, value) in output::child_env(output::color()) {
+            if value.is_none() {
+                cmd = cmd.env_remove(var);
+            }
+        }
+        for (key, val) in &self.env {
+            cmd = match val {
+                Some(val) => cmd.env(key, val),
+                None => cmd.env_remove(key),
+            };
+        }
+        if let Some(stdin) = &self.stdin {
+            cmd = cmd.stdin(stdin);
+        }
+        cmd.set_quiet(self.quiet);
+        cmd.set_ignore_status(self.ignore_status);
+        cmd.set_ignore_stdout(self.ignore_stdout);
+        cmd.set_ignore_stderr(self.ignore_stderr);
+        if is_recording() {
+            // A nested `./miri` should not record its commands as well; we record it instead.
+            cmd = cmd.env_remove(RECORD_VAR);
+        }
+        if metrics::is_recording() {
+            // A nested `./miri` (like the ones `./miri ci` runs) is part of our run.
+            cmd = cmd.env_remove(metrics::METRICS_VAR);
+        }
+        cmd
+    }
+
+    /// Records the command, and returns it unless this is a dry run: then it is printed instead
+    /// (or printed and returned, if it is a probe).
+    fn prepare(&self) -> Option<xshell::Cmd<'a>> {
+        if is_recording() || is_dry_run() {
+            let command = process::Command::from(self.build());
+            record(&command, self.stdin.as_deref());
+            if is_dry_run() {
+                let probe = is_probe(&command);
+                let marker = if probe { "probe" } else { "dry run" };
+                plain!("[{marker}] {}", render(&command, self.stdin.as_deref(), ""));
+                if !probe {
+                    return None;
+                }
+            }
+        }
+        Some(self.build())
+    }
+
+    /// For `--message-format json`: a command that says what it does is a step.
+    fn step_started(&self) {
+        if let Some(name) = &self.label {
+            events::emit(&Event::StepStarted { name });
+        }
+    }
+
+    /// Records how long the command took (which ended with `step`), and logs how running it went;
+    /// `describe` gives the status and the output for a successful run.
+    fn finish<T, E: fmt::Display>(
+        &self,
+        start: Instant,
+        result: &Result<T, E>,
+        step: events::Status,
+        describe: impl FnOnce(&T) -> (String, Option<String>),
+    ) {
+        timings::add(self.label.as_deref().unwrap_or(timings::OTHER), start.elapsed());
+        if let Some(name) = &self.label {
+            let duration_secs = start.elapsed().as_secs_f64();
+            events::emit(&Event::StepFinished { name, status: step, duration_secs });
+        }
+        if !logfile::enabled(LogLevel::Info) {
+            return;
+        }
+        let command = if logfile::enabled(LogLevel::Debug) {
+            render(&process::Command::from(self.build()), self.stdin.as_deref(), "")
+        } else {
+            cmdline::display_command(&self.argv(), Some(&self.sh.current_dir()), &self.env)
+        };
+        let (status, output) = match result {
+            Ok(value) => describe(value),
+            Err(err) => (err.to_string(), None),
+        };
+        logfile::command(&command, start.elapsed(), &status, output.as_deref());
+    }
+
+    /// The program and its arguments.
+    fn argv(&self) -> Vec<&OsStr> {
+        [&self.program].into_iter().chain(&self.args).map(OsString::as_os_str).collect()
+    }
+
+    /// The error for when the command could not be started.
+    fn spawn_error(&self, err: io::Error) -> anyhow::Error {
+        if err.kind() == io::ErrorKind::NotFound {
+            anyhow!("command not found: `{}`", self.program.to_string_lossy())
+        } else {
+            err.into()
+        }
+    }
+
+    /// What we add to every error of the command: the command line, run in the dir it ran in, and
+    /// the names of the env vars we set (or unset) for it. Their values could be secrets, and most
+    /// of them are long.
+    fn failure_context(&self) -> String {
+        let command = process::Command::from(self.build());
+        let (mut set, mut unset) = (Vec::new(), Vec::new());
+        // Whether the command colors its output does not explain why it failed.
+        let color_vars = output::child_env(output::color()).map(|(var, _)| var);
+        for (var, value) in command.get_envs() {
+            if var == RECORD_VAR || color_vars.iter().any(|color_var| var == *color_var) {
+                continue;
+            }
+            let var = var.to_string_lossy().into_owned();
+            if value.is_some() {
+                set.push(var);
+            } else {
+                unset.push(var);
+            }
+        }
+        let no_env: &[(&str, Option<&str>)] = &[];
+        let line = cmdline::display_command(&self.argv(), command.get_current_dir(), no_env);
+        let mut changes = Vec::new();
+        if !set.is_empty() {
+            changes.push(format!("{} set", set.join(", ")));
+        }
+        if !unset.is_empty() {
+            changes.push(format!("{} unset", unset.join(", ")));
+        }
+        if changes.is_empty() {
+            format!("`{line}` failed")
+        } else {
+            format!("`{line}` failed, with {}", changes.join(" and "))
+        }
+    }
+
+    /// Fails with a [`CommandFailed`] if the command did not succeed (and that is not ignored).
+    fn check_status(
+        &self,
+        status: process::ExitStatus,
+        output: impl FnOnce() -> String,
+    ) -> Result<()> {
+        if status.success() || self.ignore_status {
+            return Ok(());
+        }
+        Err(CommandFailed { command: self.to_string(), status, output: output() }.into())
+    }
+
+    /// Runs the command like `xshell` does, capturing stdout and stderr as requested; otherwise
+    /// they go to the terminal (unless they are ignored).
+    fn execute(&self, cmd: xshell::Cmd<'_>, capture: (bool, bool)) -> Result<process::Output> {
+        self.execute_unannotated(cmd, capture).with_context(|| self.failure_context())
+    }
+
+    fn execute_unannotated(
+        &self,
+        cmd: xshell::Cmd<'_>,
+        capture: (bool, bool),
+    ) -> Result<process::Output> {
+        if !self.quiet {
+            plain!("$ {self}");
+        }
+        let mut command = process::Command::from(cmd);
+        if capture.0 && !self.ignore_stdout {
+            command.stdout(Stdio::piped());
+        }
+        if capture.1 && !self.ignore_stderr {
+            command.stderr(Stdio::piped());
+        }
+        self.step_started();
+        let start = Instant::now();
+        let result = tee::run_plain(command, self.stdin.as_deref(), self.interactive)
+            .map_err(|err| self.spawn_error(err));
+        let lossy = |output: &process::Output| {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            format!("{stdout}{stderr}")
+        };
+        let step = match &result {
+            Ok(output) if output.status.success() => events::Status::Ok,
+            _ => events::Status::Failed,
+        };
+        self.finish(start, &result, step, |output| {
+            (output.status.to_string(), (capture.0 || capture.1).then(|| lossy(output)))
+        });
+        let output = result?;
+        self.check_status(output.status, || lossy(&output))?;
+        Ok(output)
+    }
+
+    pub fn run(&self) -> Result<()> {
+        let Some(cmd) = self.prepare() else {
+            return Ok(());
+        };
+        self.execute(cmd, (false, false))?;
+        Ok(())
+    }
+
+    /// In a dry run, the output of commands that do not get run is empty.
+    pub fn read(&self) -> Result<String> {
+        let Some(cmd) = self.prepare() else {
+            return Ok(String::new());
+        };
+        let output = self.execute(cmd, (true, false))?;
+        let stdout = String::from_utf8(output.stdout)
+            .with_context(|| format!("`{self}` printed invalid UTF-8"))?;
+        Ok(trim_newline(stdout))
+    }
+
+    pub fn output(&self) -> Result<process::Output> {
+        let Some(cmd) = self.prepare() else {
+            return Ok(process::Output {
+                status: Default::default(),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            });
+        };
+        self.execute(cmd, (true, true))
+    }
+
+    /// Like `run`, but shows the output of the command only if it fails, all at once (stdout
+    /// first): for `--quiet`, which leaves out everything but what went wrong.
+    pub fn run_hushed(&self) -> Result<()> {
+        let Err(err) = self.output() else {
+            return Ok(());
+        };
+        if let Some(failed) = err.chain().find_map(|e| e.downcast_ref::<CommandFailed>()) {
+            output::write(Stream::Stderr, failed.output.as_bytes());
+        }
+        Err(err)
+    }
+
+    /// Runs the command ourselves, showing the output we are asked to, with the given timeout.
+    fn run_teed(&self, show: (bool, bool), timeout: Option<Duration>) -> Result<Teed> {
+        let Some(cmd) = self.prepare() else {
+            return Ok(Teed::default());
+        };
+        self.run_teed_unannotated(cmd, show, timeout).with_context(|| self.failure_context())
+    }
+
+    fn run_teed_unannotated(
+        &self,
+        cmd: xshell::Cmd<'_>,
+        show: (bool, bool),
+        timeout: Option<Duration>,
+    ) -> Result<Teed> {
+        let context = tee::context();
+        if !self.quiet {
+            plain!("{}$ {self}", context.prefix);
+        }
+        self.step_started();
+        let start = Instant::now();
+        let result = tee::run(process::Command::from(cmd), self.stdin.as_deref(), show, timeout)
+            .map_err(|err| self.spawn_error(err));
+        let step = match &result {
+            Ok(captured) =>
+                match captured.status {
+                    Some(status) if status.success() => events::Status::Ok,
+                    // Without a status, it got killed because it took too long (or was stopped).
+                    None if timeout.is_some() => events::Status::TimedOut,
+                    _ => events::Status::Failed,
+                },
+            Err(_) => events::Status::Failed,
+        };
+        self.finish(start, &result, step, |captured| {
+            let status = captured.status.map_or("killed".to_owned(), |s| s.to_string());
+            (status, Some(format!("{}{}", captured.stdout, captured.output)))
+        });
+        let tee::Captured { status, output, stdout } = result?;
+        let Some(status) = status else {
+            if context.stop.is_some_and(|stop| stop.load(Ordering::Relaxed)) {
+                bail!("`{self}` was stopped");
+            }
+            let timeout = timeout.unwrap();
+            return Err(TimedOut { command: self.to_string(), timeout, output }.into());
+        };
+        self.check_status(status, || output.clone())?;
+        Ok(Teed { status, output, stdout })
+    }
+
+    /// Runs the command like `run`, but shows its output as it happens *and* returns it. Unlike
+    /// with `run`, the output does not go to a terminal, so there are no progress bars.
+    /// `ignore_stdout` and `ignore_stderr` do not apply; in a dry run, the output is empty.
+    pub fn tee(&self) -> Result<Teed> {
+        self.run_teed((true, true), None)
+    }
+
+    /// Like `tee`, but kills the command (and everything it started) if it does not finish within
+    /// `timeout`; the error is a [`TimedOut`] then. The command cannot read from the terminal.
+    pub fn run_with_timeout(&self, timeout: Option<Duration>) -> Result<Teed> {
+        self.run_teed((true, true), timeout)
+    }
+
+    /// Like `tee`, but returns stdout (as `Teed::stdout`) instead of showing it; for commands that
+    /// print something for us on stdout, and something for the user on stderr.
+    pub fn tee_stderr(&self) -> Result<Teed> {
+        self.run_teed((false, true), None)
+    }
+
+    /// Like `run_with_timeout`, but returns stdout like `read` instead of showing it.
+    pub fn read_with_timeout(&self, timeout: Option<Duration>) -> Result<String> {
+        Ok(trim_newline(self.run_teed((false, true), timeout)?.stdout))
+    }
+
+    /// Like `run_with_timeout`, but shows nothing, like `output`.
+    pub fn output_with_timeout(&self, timeout: Option<Duration>) -> Result<Teed> {
+        self.run_teed((false, false), timeout)
+    }
+}
+
+/// Trims the trailing newline of the output of `read`, like `xshell` does.
+fn trim_newline(mut stdout: String) -> String {
+    if stdout.ends_with('\n') {
+        stdout.pop();
+    }
+    if stdout.ends_with('\r') {
+        stdout.pop();
+    }
+    stdout
+}
+
+/// How a command run with [`Cmd::tee`] or with a timeout went.
+#[derive(Debug, Default)]
+pub struct Teed {
+    pub status: process::ExitStatus,
+    /// What it printed to stderr, and to stdout if that was shown, interleaved. Very long output
+    /// is cut at the start.
+    pub output: String,
+    /// What it printed to stdout, if that was not shown.
+    pub stdout: String,
+}
+
+impl fmt::Display for Cmd<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let no_env: &[(&str, Option<&str>)] = &[];
+        f.write_str(&cmdline::display_command(&self.argv(), None, no_env))
+    }
+}
+
+/// For running the command some other way. This records it, but in a dry run, the caller has to
+/// make sure it does not actually run.
+impl From<Cmd<'_>> for process::Command {
+    fn from(cmd: Cmd<'_>) -> process::Command {
+        let command = process::Command::from(cmd.build());
+        record(&command, cmd.stdin.as_deref());
+        command
+    }
+}
+
+/// The path given with `--record`, or else in `MIRI_SCRIPT_RECORD`.
+pub fn record_path(flag: Option<OsString>) -> Option<PathBuf> {
+    flag.map(PathBuf::from)
+        .or_else(|| std::env::var_os(RECORD_VAR).filter(|path| !path.is_empty()).map(Into::into))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rendering() {
+        let root = "/home/me/miri";
+        let mut command = process::Command::new("cargo");
+        command
+            .args(["build", "--manifest-path", "/home/me/miri/cargo-miri/Cargo.toml", "a b"])
+            .current_dir(root)
+            .env("MIRIFLAGS", "-Zmiri-seed=3")
+            .env("GITHUB_TOKEN", "hunter2")
+            .env(RECORD_VAR, "/tmp/session.sh")
+            .env_remove("MIRI");
+        assert_eq!(
+            render(&command, None, root),
+            "(cd \"$MIRI_DIR\" && env -u MIRI GITHUB_TOKEN=\"$GITHUB_TOKEN\" MIRIFLAGS='-Zmiri-seed=3' \
+             cargo build --manifest-path \"$MIRI_DIR\"/cargo-miri/Cargo.toml 'a b')"
+        );
+
+        let mut command = process::Command::new("git");
+        command.args(["commit", "-F", "-"]).current_dir("/tmp/x y");
+        assert_eq!(
+            render(&command, Some(b"Subject\n"), root),
+            "(cd '/tmp/x y' && git commit -F - <<'MIRI_SCRIPT_STDIN'\nSubject\nMIRI_SCRIPT_STDIN\n)"
+        );
+        assert!(is_secret("CARGO_REGISTRY_TOKEN") && is_secret("ssh_auth_sock"));
+        assert!(!is_secret("MIRIFLAGS"));
+    }
+
+    #[test]
+    fn failure_context() {
+        let sh = crate::util::ScriptCtx::new().unwrap().miri_dir_shell();
+        let cmd = cmd!(sh, "cargo --no-such-flag")
+            .env("MIRIFLAGS", "-Zmiri-seed=3")
+            .env_remove("RUSTC")
+            .quiet()
+            .ignore_stderr();
+        let err = cmd.read().unwrap_err();
+        // Callers can still tell why it failed.
+        assert!(err.is::<CommandFailed>());
+        let no_env: &[(&str, Option<&str>)] = &[];
+        let argv = ["cargo", "--no-such-flag"];
+        let line = cmdline::display_command(&argv, Some(&sh.current_dir()), no_env);
+        assert_eq!(
+            err.to_string(),
+            format!("`{line}` failed, with CARGO_TARGET_DIR, MIRIFLAGS set and RUSTC unset")
+        );
+        let err = cmd.run().unwrap_err();
+        assert!(format!("{err:#}").contains("command exited with non-zero code"), "{err:#}");
+        assert!(!format!("{err:#}").contains("-Zmiri-seed=3"), "{err:#}");
+
+        // What it printed is kept, to show it.
+        let err = cmd!(sh, "cargo --no-such-flag").quiet().run_hushed().unwrap_err();
+        let failed = err.downcast_ref::<CommandFailed>().unwrap();
+        assert!(failed.output.contains("--no-such-flag"), "{}", failed.output);
+    }
+
+    #[test]
+    fn probes() {
+        let probe = |program: &str, args: &[&str]| {
+            let mut command = process::Command::new(program);
+            command.args(args);
+            is_probe(&command)
+        };
+        assert!(probe("rustc", &["+nightly", "--print", "sysroot"]));
+        assert!(probe("/home/me/rust/build/stage1/bin/rustc", &["--version", "--verbose"]));
+        assert!(probe("cargo", &["+miri", "clippy", "--version"]));
+        assert!(probe("git", &["rev-parse", "HEAD"]));
+        assert!(probe("git", &["remote", "get-url", "origin"]));
+        assert!(probe("git", &["-C", "/src/miri", "ls-files", "-s", "--", "."]));
+        assert!(!probe("git", &["remote", "add", "me", "https://github.com/me/miri"]));
+        assert!(!probe("git", &["commit", "-m", "msg"]));
+        assert!(!probe("cargo", &["build", "--manifest-path", "Cargo.toml"]));
+        assert!(!probe("rustc", &["-o", "out", "main.rs"]));
+    }
+}
@@ -0,0 +1,293 @@
+//! `./miri run --many-seeds --workers`: runs the seeds on other machines, over SSH, for sweeps too
+//! big for one. Each worker runs a command the user gives (getting Miri built there is up to them)
+//! for a chunk of the seeds at a time, and says how each seed went; the chunks of a worker that
+//! becomes unreachable go to the others. This is experimental.
+
+use std::ops::Range;
+use std::sync::Mutex;
+
+use anyhow::{bail, Result};
+use xshell::Shell;
+
+use crate::output::{error, note, plain, status, success, warning};
+use crate::record::{cmd, is_dry_run};
+use crate::util::MiriEnv;
+
+/// What `--workers` and `--remote-command` say.
+#[derive(Clone, Debug)]
+pub struct Workers {
+    pub hosts: Vec<String>,
+    /// A shell command run on each host for each seed, with `{}` replaced by the seed.
+    pub command: String,
+}
+
+/// How a failing seed went, and where.
+#[derive(Debug, PartialEq)]
+pub struct RemoteFailure {
+    pub seed: u32,
+    pub host: String,
+    /// What the command printed (stdout and stderr), and its exit code.
+    pub output: String,
+}
+
+/// The line each seed ends with in the output of a chunk.
+const MARKER: &str = "miri-script-seed";
+
+/// SSH, such that it does not ask for anything and gives up on hosts that stop answering (with
+/// exit code 255).
+const SSH_OPTIONS: &[&str] = &[
+    "-o",
+    "BatchMode=yes",
+    "-o",
+    "ConnectTimeout=10",
+    "-o",
+    "ServerAliveInterval=15",
+    "-o",
+    "ServerAliveCountMax=3",
+];
+
+/// The program of the command, which the preflight check asks for its `--version`: the first word
+/// that is not a variable assignment.
+fn program(command: &str) -> Option<String> {
+    let words = shell_words::split(command).ok()?;
+    words.into_iter().find(|word| {
+        !word
+            .split_once('=')
+            .is_some_and(|(var, _)| var.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+    })
+}
+
+/// The script that runs `command` for each of `seeds` on a host. For a failing seed, it prints
+/// what the command printed before the marker line.
+fn chunk_script(command: &str, seeds: Range<u32>) -> String {
+    let command = command.replace("{}", "$seed");
+    format!(
+        "for seed in $(seq {} {}); do \
+            if out=$( ( {command} ) 2>&1 ); then echo \"{MARKER} $seed ok\"; \
+            else code=$?; printf '%s\\n' \"$out\"; echo \"{MARKER} $seed failed $code\"; fi; \
+        done",
+        seeds.start,
+        seeds.end - 1,
+    )
+}
+
+/// The seeds a chunk script reported on, in order, with the output of those that failed.
+fn parse_chunk(stdout: &str) -> Vec<(u32, Option<String>)> {
+    let mut seeds = Vec::new();
+    let mut output = String::new();
+    for line in stdout.lines() {
+        let Some(report) = line.strip_prefix(MARKER).and_then(|rest| rest.strip_prefix(' ')) else {
+            output.push_str(line);
+            output.push('\n');
+            continue;
+        };
+        let mut words = report.split(' ');
+        let (Some(Ok(seed)), Some(outcome)) = (words.next().map(str::parse), words.next()) else {
+            output.push_str(line);
+            output.push('\n');
+            continue;
+        };
+        let output = std::mem::take(&mut output);
+        let failure = (outcome != "ok").then(|| {
+            match words.next() {
+                Some(code) => format!("{output}(exit code {code})"),
+                None => output,
+            }
+        });
+        seeds.push((seed, failure));
+    }
+    seeds
+}
+
+/// How many seeds a worker gets at a time: enough that SSH does not take longer than running them,
+/// few enough that the work is spread out and not much is left to do again when a host is lost.
+fn chunk_size(seeds: usize, slots: usize) -> usize {
+    (seeds / (slots * 4)).clamp(1, 100)
+}
+
+struct Host {
+    name: String,
+    /// How many chunks it runs at the same time: one per core.
+    slots: usize,
+    running: usize,
+    lost: bool,
+}
+
+/// The hosts, and how busy they are.
+struct Pool(Mutex<Vec<Host>>);
+
+impl Pool {
+    /// The host the least busy for its size, unless all of them were lost.
+    fn take(&self) -> Option<String> {
+        let mut hosts = self.0.lock().unwrap();
+        let host = hosts
+            .iter_mut()
+            .filter(|host| !host.lost)
+            .min_by_key(|host| host.running * 1024 / host.slots)?;
+        host.running += 1;
+        Some(host.name.clone())
+    }
+
+    fn give_back(&self, name: &str, lost: bool) {
+        let mut hosts = self.0.lock().unwrap();
+        let host = hosts.iter_mut().find(|host| host.name == name).unwrap();
+        host.running -= 1;
+        host.lost |= lost;
+    }
+}
+
+/// Checks that each host can run the command's program, and returns how many cores it has.
+fn preflight(sh: &Shell, workers: &Workers) -> Result<Vec<Host>> {
+    let Some(program) = program(&workers.command) else {
+        bail!("cannot tell which program `--remote-command` runs");
+    };
+    // Quoted, but such that a `~/` still means the home dir.
+    let quoted = match program.strip_prefix("~/") {
+        Some(path) => format!("~/{}", shell_words::quote(path)),
+        None => shell_words::quote(&program).into_owned(),
+    };
+    let script =
+        format!("nproc 2>/dev/null || sysctl -n hw.ncpu 2>/dev/null || echo 1; {quoted} --version");
+    let mut hosts = Vec::new();
+    let mut versions: Vec<(String, String)> = Vec::new();
+    for host in &workers.hosts {
+        let ssh = cmd!(sh, "ssh {SSH_OPTIONS...} {host} {script}");
+        let output = ssh.quiet().ignore_status().output()?;
+        if is_dry_run() {
+            continue;
+        }
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let stderr = stderr.trim_end();
+            bail!("preflight check failed on `{host}`: `{program} --version` failed\n{stderr}");
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut lines = stdout.lines();
+        let slots = lines.next().and_then(|cores| cores.trim().parse().ok()).unwrap_or(1).max(1);
+        let version = lines.next().unwrap_or_default().trim().to_owned();
+        note!("{host}: {version}, {slots} cores");
+        if let Some((other, other_version)) = versions.first() {
+            if *other_version != version {
+                warning!("`{host}` runs {version}, but `{other}` runs {other_version}");
+            }
+        }
+        versions.push((host.clone(), version));
+        hosts.push(Host { name: host.clone(), slots, running: 0, lost: false });
+    }
+    Ok(hosts)
+}
+
+/// Runs `seeds` on the workers, and returns the seeds that failed. Once one did, no further chunks
+/// are started, like `--many-seeds` does not start further seeds.
+pub fn run_seeds(e: &MiriEnv, workers: &Workers, seeds: Range<u32>) -> Result<Vec<RemoteFailure>> {
+    let hosts = preflight(&e.sh, workers)?;
+    if is_dry_run() {
+        return Ok(Vec::new());
+    }
+    let slots = hosts.iter().map(|host| host.slots).sum();
+    let chunk = chunk_size(seeds.len(), slots) as u32;
+    let chunks = seeds.len().div_ceil(chunk as usize) as u32;
+    status!("Running {} seeds on {} workers, {chunk} at a time...", seeds.len(), hosts.len());
+    let pool = Pool(Mutex::new(hosts));
+    let failures = Mutex::new(Vec::new());
+    let source = seeds.clone();
+    let res = e.run_many_times(0..chunks, Some(slots), None, |sh, i| {
+        let start = source.start + i * chunk;
+        let mut todo = start..source.end.min(start + chunk);
+        while !todo.is_empty() {
+            let Some(host) = pool.take() else {
+                bail!("all workers were lost; seeds {todo:?} did not run");
+            };
+            let script = chunk_script(&workers.command, todo.clone());
+            let output = cmd!(sh, "ssh {SSH_OPTIONS...} {host} {script}")
+                .quiet()
+                .ignore_status()
+                .output()?;
+            let lost = !output.status.success();
+            pool.give_back(&host, lost);
+            let mut failed = false;
+            for (seed, failure) in parse_chunk(&String::from_utf8_lossy(&output.stdout)) {
+                if !todo.contains(&seed) {
+                    continue;
+                }
+                todo.start = seed + 1;
+                if let Some(output) = failure {
+                    error!("FAILING SEED: {seed} (on {host})");
+                    let failure = RemoteFailure { seed, host: host.clone(), output };
+                    failures.lock().unwrap().push(failure);
+                    failed = true;
+                }
+            }
+            if lost && !todo.is_empty() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                let stderr = stderr.trim_end();
+                warning!("lost worker `{host}` ({stderr}); seeds {todo:?} go to the others");
+            } else if !todo.is_empty() {
+                bail!("`{host}` did not say how seeds {todo:?} went");
+            }
+            if failed {
+                bail!("seeds failed");
+            }
+        }
+        Ok(())
+    });
+    let mut failures = failures.into_inner().unwrap();
+    failures.sort_by_key(|failure| failure.seed);
+    match res {
+        Err(err) if failures.is_empty() => Err(err),
+        _ => Ok(failures),
+    }
+}
+
+/// Says which seeds failed on which host, and what they printed.
+pub fn report(failures: &[RemoteFailure]) -> Result<()> {
+    if failures.is_empty() {
+        success!("all seeds passed");
+        return Ok(());
+    }
+    for failure in failures {
+        error!("seed {} failed on {}:", failure.seed, failure.host);
+        plain!("{}", failure.output.trim_end());
+    }
+    bail!("{} of the seeds failed", failures.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks() {
+        assert_eq!(
+            program("MIRI_SYSROOT=~/sysroot '~/my miri/miri' tests/pass/a.rs -Zmiri-seed={}"),
+            Some("~/my miri/miri".into())
+        );
+        assert_eq!(program("A=1"), None);
+        let script = chunk_script("miri -Zmiri-seed={}", 10..20);
+        assert!(script.starts_with("for seed in $(seq 10 19); do"), "{script}");
+        assert!(script.contains("if out=$( ( miri -Zmiri-seed=$seed ) 2>&1 ); then"), "{script}");
+        let stdout =
+            format!("{MARKER} 10 ok\nerror: UB\n\n{MARKER} 11 failed 1\n{MARKER} 12 ok\nhalf");
+        assert_eq!(
+            parse_chunk(&stdout),
+            [(10, None), (11, Some("error: UB\n\n(exit code 1)".into())), (12, None)]
+        );
+        assert_eq!(chunk_size(1_000_000, 64), 100);
+        assert_eq!(chunk_size(256, 16), 4);
+        assert_eq!(chunk_size(3, 16), 1);
+    }
+
+    #[test]
+    fn pool() {
+        let host = |name: &str, slots| Host { name: name.into(), slots, running: 0, lost: false };
+        let pool = Pool(Mutex::new(vec![host("a", 1), host("b", 2)]));
+        let taken: Vec<_> = (0..3).map(|_| pool.take().unwrap()).collect();
+        assert_eq!(taken, ["a", "b", "b"]);
+        pool.give_back("a", /* lost */ true);
+        pool.give_back("b", /* lost */ false);
+        assert_eq!(pool.take().as_deref(), Some("b"));
+        pool.give_back("b", true);
+        pool.give_back("b", true);
+        assert_eq!(pool.take(), None);
+    }
+}
@@ -0,0 +1,237 @@
+//! Scripts in `target/repro` that reproduce a failing `./miri run --many-seeds` seed or `./miri
+//! test`, for bug reports: they install the toolchain that was used and run the same command with
+//! the same MIRIFLAGS (and whatever else the seed was injected into), and need nothing from this
+//! machine but a checkout of the same commit.
+
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use path_macro::path;
+
+use crate::inject::Injected;
+use crate::output::{note, warning};
+use crate::record::{self, skip_in_dry_run};
+use crate::util::MiriEnv;
+
+/// What the scripts for one command have in common; everything but the seed and what depends on
+/// it.
+/// This does not hold on to the `MiriEnv`, so that the runs of the seeds can share it.
+pub struct Repro {
+    dir: PathBuf,
+    commit: String,
+    /// The lines that install the toolchain (or say which rustc to build).
+    toolchain: String,
+    /// `+toolchain`, or `--rustc <path>`.
+    compiler_args: Vec<String>,
+    /// The `./miri` command, like `run`, and the arguments the user gave it.
+    command: &'static str,
+    args: Vec<String>,
+}
+
+/// What is safe in a file name.
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "-_.".contains(c) { c } else { '_' })
+        .collect()
+}
+
+impl Repro {
+    pub fn new(e: &MiriEnv, command: &'static str, args: &[OsString]) -> Result<Repro> {
+        let version = e.rustc_version()?;
+        let (toolchain, compiler_args) = match (&e.toolchain, &e.rustc) {
+            (Some(toolchain), _) => {
+                let install = match e.rustc_meta().ok().and_then(|meta| meta.commit_hash) {
+                    // The toolchain `./miri toolchain` installs; the commit is what counts.
+                    Some(commit) if toolchain == "miri" =>
+                        format!(
+                            "rustup-toolchain-install-master -n miri -c cargo -c rust-src -c rustc-dev -c llvm-tools -- {commit}"
+                        ),
+                    _ =>
+                        format!(
+                            "rustup toolchain install {} --profile minimal --component rust-src,rustc-dev,llvm-tools",
+                            shell_words::quote(toolchain)
+                        ),
+                };
+                (format!("# {version}\n{install}\n"), vec![format!("+{toolchain}")])
+            }
+            (None, rustc) => {
+                let rustc = rustc.as_ref().unwrap().to_string_lossy().into_owned();
+                let toolchain = format!(
+                    "# This used a locally built rustc: build {version} yourself, and point\n\
+                    # MIRI_SCRIPT_RUSTC at it.\n\
+                    MIRI_SCRIPT_RUSTC=\"${{MIRI_SCRIPT_RUSTC:-{rustc}}}\"\n"
+                );
+                (toolchain, vec!["--rustc".into(), "\"$MIRI_SCRIPT_RUSTC\"".into()])
+            }
+        };
+        Ok(Repro {
+            dir: path!(e.target_dir / "repro"),
+            commit: record::commit(&e.miri_dir),
+            toolchain,
+            compiler_args,
+            command,
+            args: args.iter().map(|arg| arg.to_string_lossy().into_owned()).collect(),
+        })
+    }
+
+    /// The name of the script: the command, the program (or the test filters), and the seed.
+    fn id(&self, seed: Option<u32>) -> String {
+        let mut id = self.command.to_owned();
+        let what: Vec<&str> = match self.command {
+            "run" =>
+                self.args
+                    .iter()
+                    .filter(|arg| arg.ends_with(".rs"))
+                    .take(1)
+                    .map(|arg| &**arg)
+                    .collect(),
+            // The test filters, not the value of `--target`.
+            _ => {
+                let mut after_target = false;
+                let filters = self.args.iter().filter(|arg| {
+                    let is_target = std::mem::replace(&mut after_target, *arg == "--target");
+                    !is_target && !arg.starts_with('-')
+                });
+                filters.take(3).map(|arg| &**arg).collect()
+            }
+        };
+        for what in what {
+            let stem = what.rsplit(['/', '\\']).next().unwrap().trim_end_matches(".rs");
+            id.push('-');
+            id.push_str(&sanitize(stem));
+        }
+        if let Some(seed) = seed {
+            id.push_str(&format!("-seed-{seed}"));
+        }
+        id
+    }
+
+    fn script(&self, seed: Option<u32>, injected: &Injected) -> String {
+        let mut args = self.args.clone();
+        if !injected.args.is_empty() {
+            if !args.iter().any(|arg| arg == "--") {
+                args.push("--".into());
+            }
+            args.extend(injected.args.iter().cloned());
+        }
+        let line = shell_words::join([self.command].into_iter().chain(args.iter().map(|a| &**a)));
+        let args: Vec<String> = args.iter().map(|arg| shell_words::quote(arg).into()).collect();
+        let env: String = injected
+            .env
+            .iter()
+            .map(|(var, value)| format!("{var}={}\nexport {var}\n", shell_words::quote(value)))
+            .collect();
+        let seed = seed.map(|seed| format!(" with seed {seed}")).unwrap_or_default();
+        format!(
+            "\
+#!/bin/sh
+# Reproduces a failure of `./miri {line}`{seed}, at Miri commit {commit}.
+# Run this from the Miri dir of a checkout of that commit.
+set -e
+
+{toolchain}
+MIRIFLAGS={miri_flags}
+export MIRIFLAGS
+{env}MIRI_AUTO_OPS=no ./miri {compiler} {command} {args}
+",
+            commit = self.commit,
+            toolchain = self.toolchain,
+            miri_flags = shell_words::quote(&injected.miri_flags.to_string_lossy()),
+            compiler = self.compiler_args.join(" "),
+            command = self.command,
+            args = args.join(" "),
+        )
+    }
+
+    /// Writes the script for a run with what is `injected` (which includes the seed, if there is
+    /// one), and returns where it went.
+    pub fn write(&self, seed: Option<u32>, injected: &Injected) -> Result<PathBuf> {
+        let path = path!(self.dir / format!("{}.sh", self.id(seed)));
+        if skip_in_dry_run(format_args!("write {}", path.display())) {
+            return Ok(path);
+        }
+        fs::create_dir_all(&self.dir)?;
+        fs::write(&path, self.script(seed, injected))
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+        }
+        Ok(path)
+    }
+
+    /// Writes the script after a failure, and says where it is; not being able to is no reason
+    /// to fail differently.
+    pub fn report(&self, seed: Option<u32>, injected: &Injected) {
+        match self.write(seed, injected) {
+            Ok(path) => note!("to reproduce this elsewhere, run {}", path.display()),
+            Err(err) => warning!("failed to write a script that reproduces this: {err:#}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use super::*;
+
+    fn repro(command: &'static str, args: &[&str]) -> Repro {
+        Repro {
+            dir: PathBuf::from("target/repro"),
+            commit: "abc".into(),
+            toolchain: "# rustc 1.80.0-nightly (8679004 2024-05-03)\nrustup-toolchain-install-master -n miri -- 8679004\n".into(),
+            compiler_args: vec!["+miri".into()],
+            command,
+            args: args.iter().map(|&arg| arg.to_owned()).collect(),
+        }
+    }
+
+    #[test]
+    fn scripts() {
+        let run = repro("run", &["--dep", "tests/pass/my test.rs", "--", "arg"]);
+        assert_eq!(run.id(Some(17)), "run-my_test-seed-17");
+        assert_eq!(
+            run.script(Some(17), &Injected::none(OsStr::new("-Zmiri-seed=17 -Zmiri-tree-borrows"))),
+            "\
+#!/bin/sh
+# Reproduces a failure of `./miri run --dep 'tests/pass/my test.rs' -- arg` with seed 17, at Miri commit abc.
+# Run this from the Miri dir of a checkout of that commit.
+set -e
+
+# rustc 1.80.0-nightly (8679004 2024-05-03)
+rustup-toolchain-install-master -n miri -- 8679004
+
+MIRIFLAGS='-Zmiri-seed=17 -Zmiri-tree-borrows'
+export MIRIFLAGS
+MIRI_AUTO_OPS=no ./miri +miri run --dep 'tests/pass/my test.rs' -- arg
+"
+        );
+        let injected = Injected {
+            miri_flags: "-Zmiri-tree-borrows".into(),
+            env: vec![("MY_SEED".into(), "17 0".into())],
+            args: vec!["17".into()],
+        };
+        let script = repro("run", &["tests/pass/rand.rs"]).script(Some(17), &injected);
+        assert!(
+            script.contains(
+                "\
+MIRIFLAGS=-Zmiri-tree-borrows
+export MIRIFLAGS
+MY_SEED='17 0'
+export MY_SEED
+MIRI_AUTO_OPS=no ./miri +miri run tests/pass/rand.rs -- 17
+"
+            ),
+            "{script}"
+        );
+        assert_eq!(
+            repro("test", &["--target", "i686-pc-windows-gnu", "alloc", "shims/env"]).id(None),
+            "test-alloc-env"
+        );
+        assert_eq!(repro("test", &[]).id(None), "test");
+    }
+}
@@ -0,0 +1,245 @@
+//! What rustup knows about our toolchains. Every rustup invocation takes a while (it is a proxy
+//! that resolves the toolchain first), so we ask it everything at once, and only once.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use anyhow::{bail, Result};
+use xshell::Shell;
+
+use crate::record::cmd;
+use crate::util::ScriptCtx;
+
+/// What `rustup show` and `rustup component list --installed` say.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RustupInfo {
+    /// The toolchain that is active in the Miri dir.
+    pub active_toolchain: String,
+    /// Why it is active, like `directory override for '/src/miri'`, if rustup says.
+    pub active_reason: Option<String>,
+    /// The installed toolchains, with their full names (like `stable-x86_64-unknown-linux-gnu`).
+    pub toolchains: Vec<String>,
+    /// The components installed for the toolchain we asked about, like
+    /// `rustc-dev-x86_64-unknown-linux-gnu` (with the target) or `rust-src`.
+    pub components: Vec<String>,
+}
+
+/// What `rustup show` said (without the components), and what `rustup component list
+/// --installed` said for each toolchain.
+static SHOW: Mutex<Option<RustupInfo>> = Mutex::new(None);
+static COMPONENTS: Mutex<BTreeMap<String, Vec<String>>> = Mutex::new(BTreeMap::new());
+
+impl RustupInfo {
+    /// Asks rustup about the toolchains, and about the components of `toolchain`. This runs
+    /// `rustup show` at most once per process, and `rustup component list` at most once per
+    /// toolchain.
+    pub fn query(toolchain: &str) -> Result<RustupInfo> {
+        let mut info = show()?;
+        let cached = COMPONENTS.lock().unwrap().get(toolchain).cloned();
+        info.components = match cached {
+            Some(components) => components,
+            None => {
+                let sh = shell()?;
+                let out = cmd!(sh, "rustup component list --installed --toolchain {toolchain}")
+                    .label("query the toolchain")
+                    .quiet()
+                    .ignore_stderr()
+                    .read()?;
+                let components = parse_components(&out);
+                COMPONENTS.lock().unwrap().insert(toolchain.to_owned(), components.clone());
+                components
+            }
+        };
+        Ok(info)
+    }
+
+    /// Whether `component` is installed, for any target.
+    pub fn has_component(&self, component: &str) -> bool {
+        self.components.iter().any(|c| {
+            c == component || c.strip_prefix(component).is_some_and(|s| s.starts_with('-'))
+        })
+    }
+
+    /// Whether `toolchain` is installed. Like rustup, this accepts names without the host, like
+    /// `stable`.
+    pub fn is_installed(&self, toolchain: &str) -> bool {
+        self.toolchains.iter().any(|t| {
+            t == toolchain || t.strip_prefix(toolchain).is_some_and(|s| s.starts_with('-'))
+        })
+    }
+}
+
+/// What `rustup show` says about the toolchains, when asked in the Miri dir; the components are
+/// left empty.
+pub fn show() -> Result<RustupInfo> {
+    if let Some(info) = &*SHOW.lock().unwrap() {
+        return Ok(info.clone());
+    }
+    // Not holding the lock while asking: another thread asking at the same time just does the
+    // same work.
+    let sh = shell()?;
+    let out = cmd!(sh, "rustup show").label("query the toolchain").read()?;
+    let info = parse_show(&out)?;
+    *SHOW.lock().unwrap() = Some(info.clone());
+    Ok(info)
+}
+
+fn shell() -> Result<Shell> {
+    Ok(ScriptCtx::new()?.miri_dir_shell())
+}
+
+/// Parses the output of `rustup show`. Since rustup 1.28, the active toolchain is given as
+/// `name: ...` and `active because: ...`; before, as `name (reason)`, followed by the version of
+/// its rustc (and with only one toolchain, without any section headers).
+fn parse_show(out: &str) -> Result<RustupInfo> {
+    let mut info = RustupInfo::default();
+    let mut active = None;
+    let mut section = "";
+    let mut lines = out.lines().map(str::trim).peekable();
+    while let Some(line) = lines.next() {
+        if lines.next_if(|next| !next.is_empty() && next.chars().all(|c| c == '-')).is_some() {
+            section = line;
+            continue;
+        }
+        if line.is_empty() {
+            continue;
+        }
+        match section {
+            "installed toolchains" =>
+                info.toolchains.extend(line.split_whitespace().next().map(ToOwned::to_owned)),
+            // The lines before the first section are `Default host: ...` and the like.
+            "active toolchain" | "" =>
+                if let Some(name) = line.strip_prefix("name: ") {
+                    active = Some(name.to_owned());
+                } else if let Some(reason) = line.strip_prefix("active because: ") {
+                    info.active_reason = Some(reason.to_owned());
+                } else if active.is_none() && !(section.is_empty() && line.contains(": ")) {
+                    if line.starts_with("no active toolchain") {
+                        break;
+                    }
+                    let (name, reason) = line.split_once(' ').unwrap_or((line, ""));
+                    active = Some(name.to_owned());
+                    info.active_reason = reason
+                        .strip_prefix('(')
+                        .and_then(|r| r.strip_suffix(')'))
+                        .map(ToOwned::to_owned);
+                },
+            _ => {}
+        }
+    }
+    let Some(active) = active else {
+        bail!("`rustup show` did not name an active toolchain");
+    };
+    // Older rustup does not list the toolchains when there is only one.
+    if info.toolchains.is_empty() {
+        info.toolchains.push(active.clone());
+    }
+    info.active_toolchain = active;
+    Ok(info)
+}
+
+fn parse_components(out: &str) -> Vec<String> {
+    out.lines().map(str::trim).filter(|line| !line.is_empty()).map(ToOwned::to_owned).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHOW_1_28: &str = "\
+Default host: x86_64-unknown-linux-gnu
+rustup home:  /home/ferris/.rustup
+
+installed toolchains
+--------------------
+stable-x86_64-unknown-linux-gnu (default)
+nightly-x86_64-unknown-linux-gnu
+miri (active)
+
+active toolchain
+----------------
+name: miri
+active because: overridden by '/src/miri/rust-toolchain.toml'
+installed targets:
+  x86_64-unknown-linux-gnu
+";
+
+    const SHOW_1_27: &str = "\
+Default host: x86_64-unknown-linux-gnu
+rustup home:  /home/ferris/.rustup
+
+installed toolchains
+--------------------
+
+stable-x86_64-unknown-linux-gnu (default)
+miri
+
+active toolchain
+----------------
+
+miri (directory override for '/src/miri')
+rustc 1.80.0-nightly (8679004 2024-05-03)
+";
+
+    const SHOW_1_27_SINGLE: &str = "\
+Default host: x86_64-unknown-linux-gnu
+rustup home:  /home/ferris/.rustup
+
+stable-x86_64-unknown-linux-gnu (default)
+rustc 1.78.0 (9b00956e5 2024-04-29)
+";
+
+    const SHOW_NONE: &str = "\
+Default host: x86_64-unknown-linux-gnu
+rustup home:  /home/ferris/.rustup
+
+no active toolchain
+";
+
+    const COMPONENTS: &str = "\
+cargo-x86_64-unknown-linux-gnu
+llvm-tools-x86_64-unknown-linux-gnu
+rust-src
+rustc-dev-x86_64-unknown-linux-gnu
+rustc-x86_64-unknown-linux-gnu
+";
+
+    #[test]
+    fn parses_show() {
+        let info = parse_show(SHOW_1_28).unwrap();
+        assert_eq!(info.active_toolchain, "miri");
+        assert_eq!(
+            info.active_reason.as_deref(),
+            Some("overridden by '/src/miri/rust-toolchain.toml'")
+        );
+        assert_eq!(
+            info.toolchains,
+            ["stable-x86_64-unknown-linux-gnu", "nightly-x86_64-unknown-linux-gnu", "miri"]
+        );
+        assert!(info.is_installed("stable") && info.is_installed("miri"));
+        assert!(!info.is_installed("beta") && !info.is_installed("mir"));
+
+        let info = parse_show(SHOW_1_27).unwrap();
+        assert_eq!(info.active_toolchain, "miri");
+        assert_eq!(info.active_reason.as_deref(), Some("directory override for '/src/miri'"));
+        assert_eq!(info.toolchains, ["stable-x86_64-unknown-linux-gnu", "miri"]);
+
+        let info = parse_show(SHOW_1_27_SINGLE).unwrap();
+        assert_eq!(info.active_toolchain, "stable-x86_64-unknown-linux-gnu");
+        assert_eq!(info.active_reason.as_deref(), Some("default"));
+        assert!(info.is_installed("stable"));
+
+        assert!(parse_show(SHOW_NONE).is_err());
+    }
+
+    #[test]
+    fn parses_components() {
+        let info = RustupInfo { components: parse_components(COMPONENTS), ..Default::default() };
+        assert_eq!(info.components.len(), 5);
+        assert!(info.has_component("rust-src"));
+        assert!(info.has_component("rustc-dev"));
+        assert!(info.has_component("llvm-tools"));
+        assert!(!info.has_component("miri"));
+        assert!(!info.has_component("rustc-d"));
+    }
+}
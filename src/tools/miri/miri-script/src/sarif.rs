@@ -0,0 +1,275 @@
+//! SARIF logs of what clippy found, for `./miri clippy --sarif <path>`: code scanning tools (like
+//! GitHub's, which shows the findings on the lines of the PR) read those. Every diagnostic is a
+//! result, with its lint as the rule, and its suggestions as fixes.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::annotations;
+use crate::util::write_report;
+
+/// What the clippy runs so far found, if we are making a log.
+static LOG: Mutex<Option<Log>> = Mutex::new(None);
+
+#[derive(Debug, Default)]
+struct Log {
+    /// The lints that the results are about, with where to read up on them.
+    rules: BTreeMap<String, Option<String>>,
+    results: Vec<Value>,
+}
+
+/// Starts collecting what `MiriEnv::clippy` finds.
+pub fn start() {
+    *LOG.lock().unwrap() = Some(Log::default());
+}
+
+/// Whether `MiriEnv::clippy` has to add what it finds to the log.
+pub fn recording() -> bool {
+    LOG.lock().unwrap().is_some()
+}
+
+/// Adds the diagnostics in `messages` (the stdout of a `cargo clippy --message-format=json`, for
+/// the crate in `base`) to the log, with their paths relative to `root`. With `--all-targets`,
+/// clippy reports the same thing for the lib and its tests; that is only added once.
+pub fn add(messages: &str, base: &Path, root: &Path) {
+    let mut log = LOG.lock().unwrap();
+    let Some(log) = &mut *log else {
+        return;
+    };
+    for msg in messages.lines().filter_map(|line| serde_json::from_str::<Value>(line).ok()) {
+        if msg["reason"] != "compiler-message" {
+            continue;
+        }
+        let Some((rule, help, result)) = result(&msg["message"], base, root) else {
+            continue;
+        };
+        log.rules.entry(rule).or_insert(help);
+        if !log.results.contains(&result) {
+            log.results.push(result);
+        }
+    }
+}
+
+/// Writes the log to `path`, also when clippy (which went like `result`) failed: with
+/// `-D warnings`, that is when there is something in it.
+pub fn finish(path: &Path, result: Result<()>) -> Result<()> {
+    let log = LOG.lock().unwrap().take().unwrap_or_default();
+    let contents = serde_json::to_string_pretty(&to_sarif(&log)).unwrap();
+    write_report(path, "the SARIF log", contents, result)
+}
+
+/// Where a span of rustc is, as a SARIF region. The columns of rustc count chars, from 1.
+fn region(span: &Value) -> Value {
+    json!({
+        "startLine": span["line_start"],
+        "startColumn": span["column_start"],
+        "endLine": span["line_end"],
+        "endColumn": span["column_end"],
+    })
+}
+
+/// `path` (relative to the root, with `/`) as a relative URI.
+fn uri(path: &str) -> String {
+    let mut uri = String::with_capacity(path.len());
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' =>
+                uri.push(b as char),
+            _ => uri.push_str(&format!("%{b:02X}")),
+        }
+    }
+    uri
+}
+
+fn artifact_location(span: &Value, base: &Path, root: &Path) -> Value {
+    let file =
+        annotations::relative_to_root(span["file_name"].as_str().unwrap_or_default(), base, root);
+    json!({ "uri": uri(&file) })
+}
+
+fn location(span: &Value, base: &Path, root: &Path) -> Value {
+    let mut location = json!({
+        "physicalLocation": {
+            "artifactLocation": artifact_location(span, base, root),
+            "region": region(span),
+        }
+    });
+    if let Some(label) = span["label"].as_str() {
+        location["message"] = json!({ "text": label });
+    }
+    location
+}
+
+/// The suggestion in `child` (a `help` of a diagnostic) as a SARIF fix, if it has one. A
+/// suggestion can replace several spans, also in several files.
+fn fix(child: &Value, base: &Path, root: &Path) -> Option<Value> {
+    let mut changes: BTreeMap<String, (Value, Vec<Value>)> = BTreeMap::new();
+    for span in child["spans"].as_array().into_iter().flatten() {
+        let Some(replacement) = span["suggested_replacement"].as_str() else {
+            continue;
+        };
+        let location = artifact_location(span, base, root);
+        let uri = location["uri"].as_str().unwrap_or_default().to_owned();
+        changes.entry(uri).or_insert_with(|| (location, Vec::new())).1.push(json!({
+            "deletedRegion": region(span),
+            "insertedContent": { "text": replacement },
+        }));
+    }
+    if changes.is_empty() {
+        return None;
+    }
+    let changes: Vec<Value> = changes
+        .into_values()
+        .map(|(location, replacements)| {
+            json!({ "artifactLocation": location, "replacements": replacements })
+        })
+        .collect();
+    Some(json!({ "description": { "text": child["message"] }, "artifactChanges": changes }))
+}
+
+/// The diagnostic `diag` of rustc (or clippy) as a SARIF result, with the rule it is about and
+/// where to read up on that; `None` for the notes that only sum up, like `aborting due to ...`.
+fn result(diag: &Value, base: &Path, root: &Path) -> Option<(String, Option<String>, Value)> {
+    let level = match diag["level"].as_str()? {
+        // A lint at `deny` is an error.
+        "error" | "error: internal compiler error" => "error",
+        "warning" => "warning",
+        _ => "note",
+    };
+    let spans = diag["spans"].as_array().map(Vec::as_slice).unwrap_or_default();
+    let rule = diag["code"]["code"].as_str();
+    if spans.is_empty() && rule.is_none() {
+        return None;
+    }
+    let children = diag["children"].as_array().map(Vec::as_slice).unwrap_or_default();
+    // Clippy links to the docs of the lint in a `help`.
+    let help = children.iter().find_map(|child| {
+        child["message"]
+            .as_str()?
+            .strip_prefix("for further information visit ")
+            .map(ToOwned::to_owned)
+    });
+    let (primary, related): (Vec<&Value>, Vec<&Value>) =
+        spans.iter().partition(|span| span["is_primary"] == true);
+    let mut result = json!({
+        "ruleId": rule.unwrap_or("rustc"),
+        "level": level,
+        "message": { "text": diag["message"] },
+        "locations": primary.iter().map(|span| location(span, base, root)).collect::<Vec<_>>(),
+    });
+    if !related.is_empty() {
+        result["relatedLocations"] =
+            related.iter().map(|span| location(span, base, root)).collect::<Vec<_>>().into();
+    }
+    let fixes: Vec<Value> = children.iter().filter_map(|child| fix(child, base, root)).collect();
+    if !fixes.is_empty() {
+        result["fixes"] = fixes.into();
+    }
+    Some((rule.unwrap_or("rustc").to_owned(), help, result))
+}
+
+fn to_sarif(log: &Log) -> Value {
+    let rules: Vec<Value> = log
+        .rules
+        .iter()
+        .map(|(id, help)| {
+            let mut rule = json!({ "id": id, "shortDescription": { "text": id } });
+            if let Some(help) = help {
+                rule["helpUri"] = help.as_str().into();
+            }
+            rule
+        })
+        .collect();
+    let results: Vec<Value> = log
+        .results
+        .iter()
+        .map(|result| {
+            let mut result = result.clone();
+            let id = result["ruleId"].as_str().unwrap_or_default();
+            result["ruleIndex"] = log.rules.keys().position(|rule| rule == id).into();
+            result
+        })
+        .collect();
+    json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "clippy",
+                    "informationUri": "https://github.com/rust-lang/rust-clippy",
+                    "rules": rules,
+                }
+            },
+            "columnKind": "unicodeCodePoints",
+            "results": results,
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What clippy said about a `for i in 0..v.len()` in `src/lib.rs`: its suggestion replaces
+    /// two spans.
+    const NEEDLESS_RANGE_LOOP: &str = r#"{"reason": "compiler-message", "manifest_path": "/src/sar/Cargo.toml", "target": {"name": "sar", "kind": ["lib"]}, "message": {"rendered": "warning: the loop variable `i` is only used to index `v`\n --> src/lib.rs:3:14\n  |\n3 |     for i in 0..v.len() {\n  |              ^^^^^^^^^^\n  |\n  = help: for further information visit https://rust-lang.github.io/rust-clippy/rust-1.95.0/index.html#needless_range_loop\n  = note: `#[warn(clippy::needless_range_loop)]` on by default\nhelp: consider using an iterator\n  |\n3 -     for i in 0..v.len() {\n3 +     for <item> in &v {\n  |\n\n", "$message_type": "diagnostic", "children": [{"children": [], "code": null, "level": "help", "message": "for further information visit https://rust-lang.github.io/rust-clippy/rust-1.95.0/index.html#needless_range_loop", "rendered": null, "spans": []}, {"children": [], "code": null, "level": "note", "message": "`#[warn(clippy::needless_range_loop)]` on by default", "rendered": null, "spans": []}, {"children": [], "code": null, "level": "help", "message": "consider using an iterator", "rendered": null, "spans": [{"byte_end": 61, "byte_start": 60, "column_end": 10, "column_start": 9, "expansion": null, "file_name": "src/lib.rs", "is_primary": true, "label": null, "line_end": 3, "line_start": 3, "suggested_replacement": "<item>", "suggestion_applicability": "HasPlaceholders", "text": [{"highlight_end": 10, "highlight_start": 9, "text": "    for i in 0..v.len() {"}]}, {"byte_end": 75, "byte_start": 65, "column_end": 24, "column_start": 14, "expansion": null, "file_name": "src/lib.rs", "is_primary": true, "label": null, "line_end": 3, "line_start": 3, "suggested_replacement": "&v", "suggestion_applicability": "HasPlaceholders", "text": [{"highlight_end": 24, "highlight_start": 14, "text": "    for i in 0..v.len() {"}]}]}], "level": "warning", "message": "the loop variable `i` is only used to index `v`", "spans": [{"byte_end": 75, "byte_start": 65, "column_end": 24, "column_start": 14, "expansion": null, "file_name": "src/lib.rs", "is_primary": true, "label": null, "line_end": 3, "line_start": 3, "suggested_replacement": null, "suggestion_applicability": null, "text": [{"highlight_end": 24, "highlight_start": 14, "text": "    for i in 0..v.len() {"}]}], "code": {"code": "clippy::needless_range_loop", "explanation": null}}}"#;
+
+    #[test]
+    fn converts_multi_span_suggestions() {
+        let (base, root) = (Path::new("/src/sar"), Path::new("/src"));
+        let msg: Value = serde_json::from_str(NEEDLESS_RANGE_LOOP).unwrap();
+        let (rule, help, result) = result(&msg["message"], base, root).unwrap();
+        assert_eq!(rule, "clippy::needless_range_loop");
+        assert_eq!(
+            help.as_deref(),
+            Some("https://rust-lang.github.io/rust-clippy/rust-1.95.0/index.html#needless_range_loop")
+        );
+        assert_eq!(result["level"], "warning");
+        assert_eq!(result["message"]["text"], "the loop variable `i` is only used to index `v`");
+        let location = &result["locations"][0]["physicalLocation"];
+        assert_eq!(location["artifactLocation"]["uri"], "sar/src/lib.rs");
+        assert_eq!(
+            location["region"],
+            json!({ "startLine": 3, "startColumn": 14, "endLine": 3, "endColumn": 24 })
+        );
+        let fixes = result["fixes"].as_array().unwrap();
+        assert_eq!(fixes.len(), 1);
+        assert_eq!(fixes[0]["description"]["text"], "consider using an iterator");
+        let changes = fixes[0]["artifactChanges"].as_array().unwrap();
+        assert_eq!(changes.len(), 1);
+        let replacements = changes[0]["replacements"].as_array().unwrap();
+        let inserted: Vec<&Value> =
+            replacements.iter().map(|r| &r["insertedContent"]["text"]).collect();
+        assert_eq!(inserted, ["<item>", "&v"]);
+        assert_eq!(replacements[0]["deletedRegion"]["startColumn"], 9);
+    }
+
+    #[test]
+    fn maps_levels_and_merges() {
+        let (base, root) = (Path::new("/src/sar"), Path::new("/src/sar"));
+        let denied = NEEDLESS_RANGE_LOOP.replace(r#""level": "warning""#, r#""level": "error""#);
+        let summary = r#"{"reason": "compiler-message", "message": {"level": "error", "message": "aborting due to 1 previous error", "code": null, "spans": [], "children": []}}"#;
+        *LOG.lock().unwrap() = Some(Log::default());
+        // Like with `--all-targets`: once for the lib, once for its tests.
+        add(&format!("{denied}\n{denied}\n{summary}\n"), base, root);
+        let log = LOG.lock().unwrap().take().unwrap();
+        let sarif = to_sarif(&log);
+        let run = &sarif["runs"][0];
+        assert_eq!(sarif["version"], "2.1.0");
+        assert_eq!(run["tool"]["driver"]["rules"][0]["id"], "clippy::needless_range_loop");
+        let results = run["results"].as_array().unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["level"], "error");
+        assert_eq!(results[0]["ruleIndex"], 0);
+        assert_eq!(
+            results[0]["locations"][0]["physicalLocation"]["artifactLocation"]["uri"],
+            "src/lib.rs"
+        );
+        assert_eq!(uri("tests/pass/a b%.rs"), "tests/pass/a%20b%25.rs");
+    }
+}
@@ -0,0 +1,149 @@
+//! `./miri squash`: squashing the commits of the current branch into one.
+
+use std::fs;
+
+use anyhow::{bail, Context, Result};
+use xshell::Shell;
+
+use crate::output::warning;
+use crate::record::{cmd, skip_in_dry_run};
+use crate::sync::github_repo;
+
+/// The repository and branch that PRs are merged into.
+const UPSTREAM_REPO: &str = "rust-lang/miri";
+const UPSTREAM_BRANCH: &str = "master";
+
+pub struct Commit {
+    pub hash: String,
+    pub subject: String,
+}
+
+/// Finds the remote that points to the upstream repository, given the output of `git remote -v`.
+fn upstream_remote(remotes: &str) -> Option<&str> {
+    remotes.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        let (name, url) = (parts.next()?, parts.next()?);
+        (github_repo(url).as_deref() == Some(UPSTREAM_REPO)).then_some(name)
+    })
+}
+
+/// Determines where the current branch forked off the upstream branch. We fetch the upstream
+/// branch first, since with an outdated one, commits that are upstream already would still look
+/// like they are only on this branch.
+pub fn upstream_base(sh: &Shell) -> Result<String> {
+    let remotes = cmd!(sh, "git remote -v").quiet().read()?;
+    let upstream = match upstream_remote(&remotes) {
+        Some(remote) => {
+            let refspec = format!("{UPSTREAM_BRANCH}:refs/remotes/{remote}/{UPSTREAM_BRANCH}");
+            let fetched = cmd!(sh, "git fetch --quiet {remote} {refspec}").run();
+            let upstream = format!("{remote}/{UPSTREAM_BRANCH}");
+            if let Err(err) = fetched {
+                let have_ref = cmd!(sh, "git rev-parse --verify --quiet {upstream}")
+                    .quiet()
+                    .ignore_stdout()
+                    .run()
+                    .is_ok();
+                if !have_ref {
+                    return Err(err).context(format!("failed to fetch {upstream}"));
+                }
+                warning!("failed to fetch {upstream}, using what we have ({err})");
+            }
+            upstream
+        }
+        None => {
+            let url = format!("https://github.com/{UPSTREAM_REPO}");
+            cmd!(sh, "git fetch --quiet {url} {UPSTREAM_BRANCH}")
+                .run()
+                .with_context(|| format!("failed to fetch {UPSTREAM_BRANCH} of {url}"))?;
+            "FETCH_HEAD".to_owned()
+        }
+    };
+    Ok(cmd!(sh, "git merge-base {upstream} HEAD").quiet().read()?)
+}
+
+/// The commits after `base`, oldest first.
+pub fn commits(sh: &Shell, base: &str) -> Result<Vec<Commit>> {
+    let log = cmd!(sh, "git log --reverse --format=%H%x00%s {base}..HEAD").quiet().read()?;
+    Ok(log
+        .lines()
+        .filter_map(|line| {
+            let (hash, subject) = line.split_once('\0')?;
+            Some(Commit { hash: hash.to_owned(), subject: subject.to_owned() })
+        })
+        .collect())
+}
+
+/// The message we let the user edit: the one of the first commit, and a list of all the commits.
+pub fn initial_message(first_message: &str, commits: &[Commit]) -> String {
+    let mut message = format!("{}\n\n", first_message.trim_end());
+    message
+        .push_str("# Please enter the message for the squashed commit. Lines starting with '#'\n");
+    message.push_str("# will be ignored, and an empty message aborts the squash.\n#\n");
+    message.push_str(&format!("# Squashing these {} commits:\n", commits.len()));
+    for commit in commits {
+        message.push_str(&format!("#   {} {}\n", &commit.hash[..10], commit.subject));
+    }
+    message
+}
+
+/// Removes the comments from an edited message, like git does.
+pub fn strip_comments(message: &str) -> String {
+    let lines: Vec<&str> = message.lines().filter(|line| !line.starts_with('#')).collect();
+    let message = lines.join("\n");
+    let message = message.trim();
+    if message.is_empty() {
+        String::new()
+    } else {
+        format!("{message}\n")
+    }
+}
+
+/// Lets the user edit `message` in the editor git is configured to use (`$EDITOR` by default).
+pub fn edit_message(sh: &Shell, message: &str) -> Result<String> {
+    let path = cmd!(sh, "git rev-parse --git-path SQUASH_EDITMSG").quiet().read()?;
+    let path = sh.current_dir().join(path);
+    if skip_in_dry_run(format_args!("edit the message in {}", path.display())) {
+        return Ok(strip_comments(message));
+    }
+    fs::write(&path, message)?;
+    let editor = cmd!(sh, "git var GIT_EDITOR").quiet().read()?;
+    let editor = shell_words::split(&editor)
+        .with_context(|| format!("failed to parse the editor command `{editor}`"))?;
+    let Some((program, args)) = editor.split_first() else {
+        bail!("no editor is configured; set `$EDITOR`");
+    };
+    cmd!(sh, "{program} {args...} {path}").interactive().run().context("the editor failed")?;
+    let edited = fs::read_to_string(&path)?;
+    fs::remove_file(&path)?;
+    Ok(strip_comments(&edited))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remotes() {
+        let remotes = "\
+me\tgit@github.com:me/miri.git (fetch)
+me\tgit@github.com:me/miri.git (push)
+upstream\thttps://github.com/rust-lang/miri (fetch)
+upstream\thttps://github.com/rust-lang/miri (push)
+";
+        assert_eq!(upstream_remote(remotes), Some("upstream"));
+        assert_eq!(upstream_remote("origin\thttps://github.com/rust-lang/rust (fetch)"), None);
+    }
+
+    #[test]
+    fn messages() {
+        let commits = [
+            Commit { hash: "0123456789abcdef".into(), subject: "Add a shim".into() },
+            Commit { hash: "fedcba9876543210".into(), subject: "fmt".into() },
+        ];
+        let message = initial_message("Add a shim\n\nIt is useful.\n", &commits);
+        assert!(message.starts_with("Add a shim\n\nIt is useful.\n\n# Please enter"));
+        assert!(message.ends_with("#   0123456789 Add a shim\n#   fedcba9876 fmt\n"));
+        assert_eq!(strip_comments(&message), "Add a shim\n\nIt is useful.\n");
+        assert_eq!(strip_comments("# only comments\n\n"), "");
+    }
+}
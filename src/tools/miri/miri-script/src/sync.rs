@@ -0,0 +1,1019 @@
+//! Helpers for syncing with the rustc repo (`rustc-pull` and `rustc-push`).
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, IsTerminal, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use path_macro::path;
+use serde::{Deserialize, Serialize};
+use xshell::Shell;
+
+use crate::cmdline;
+use crate::output::{self, error, plain, warning};
+use crate::record::{cmd, skip_in_dry_run, Cmd};
+use crate::tee::{self, TimedOut};
+use crate::util::{is_download_hiccup, retry, ScriptCtx, DOWNLOAD_RETRIES, DOWNLOAD_RETRY_DELAY};
+
+/// Used for rustc syncs.
+const JOSH_FILTER: &str =
+    ":rev(75dd959a3a40eb5b4574f8d2e23aa6efbeb33573:prefix=src/tools/miri):/src/tools/miri";
+/// If something is listening on this port, we assume it is a josh-proxy started by the user.
+/// Otherwise we start our own, on this port if possible.
+const JOSH_PORT: u16 = 42042;
+/// How long we give a josh-proxy we started to begin accepting connections.
+const JOSH_STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+/// The josh-proxy release the sync is known to work with. Other versions may compute different
+/// filtered histories, which breaks the round-trip check of `rustc-push`.
+pub const JOSH_VERSION: &str = "r23.12.04";
+const JOSH_REPO: &str = "https://github.com/josh-project/josh";
+const RUST_REPO: &str = "https://github.com/rust-lang/rust";
+/// The variable to set to how many seconds a network command may take (0 for no limit).
+pub const NETWORK_TIMEOUT_VAR: &str = "MIRI_SCRIPT_NETWORK_TIMEOUT";
+/// A fetch through josh that is not cached yet takes a good while, so this is generous.
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// A josh release, e.g. `r23.12.04`. Releases are named after the date they were made.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JoshVersion {
+    year: u32,
+    month: u32,
+    day: u32,
+}
+
+impl JoshVersion {
+    pub fn pinned() -> Self {
+        JOSH_VERSION.parse().expect("`JOSH_VERSION` is not a valid josh version")
+    }
+}
+
+impl FromStr for JoshVersion {
+    type Err = anyhow::Error;
+
+    /// Accepts both the tag name (`r23.12.04`) and the plain version (`23.12.4`). Development
+    /// builds (`r23.12.04-3-g0123abc`) are rejected, since they are not that release.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("invalid josh version `{s}`");
+        let v = s.strip_prefix(['r', 'v']).unwrap_or(s);
+        let mut parts = v.split('.').map(|part| part.parse::<u32>().map_err(|_| invalid()));
+        let (Some(year), Some(month), Some(day), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        Ok(JoshVersion { year: year?, month: month?, day: day? })
+    }
+}
+
+impl fmt::Display for JoshVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "r{:02}.{:02}.{:02}", self.year, self.month, self.day)
+    }
+}
+
+/// Extracts the version from the output of `josh-proxy --version`, e.g. `josh-proxy r23.12.04`.
+fn parse_josh_version(output: &str) -> Option<JoshVersion> {
+    output.split_whitespace().find_map(|word| word.parse().ok())
+}
+
+/// Returns the version of the given josh-proxy binary, or `None` if it cannot be determined.
+pub fn josh_version(josh_proxy: &Path) -> Option<JoshVersion> {
+    let output = process::Command::new(josh_proxy).arg("--version").output().ok()?;
+    parse_josh_version(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// The command that installs the pinned josh-proxy, for display to the user.
+pub fn josh_install_command() -> String {
+    format!(
+        "RUSTFLAGS=\"--cap-lints=warn\" cargo +stable install josh-proxy --locked --git {JOSH_REPO} --tag {JOSH_VERSION}"
+    )
+}
+
+/// The directory we install the pinned josh-proxy into; its binary ends up in `bin/`. Keeping it
+/// separate means we do not replace whatever josh-proxy the user may have on their `PATH`.
+fn josh_install_root() -> Result<PathBuf> {
+    let dirs = directories::ProjectDirs::from("org", "rust-lang", "miri-josh")
+        .context("could not determine the home directory")?;
+    Ok(dirs.data_local_dir().to_owned())
+}
+
+fn josh_binary(root: &Path) -> PathBuf {
+    path!(root / "bin" / format!("josh-proxy{}", std::env::consts::EXE_SUFFIX))
+}
+
+/// All josh-proxy binaries we could use, in order of preference: the one we installed, then
+/// the one on the `PATH`.
+pub fn installed_josh() -> Vec<PathBuf> {
+    let ours = josh_install_root().ok().map(|root| josh_binary(&root));
+    [ours, which::which("josh-proxy").ok()].into_iter().flatten().filter(|p| p.exists()).collect()
+}
+
+/// Finds a josh-proxy of the pinned version: either the one we installed, or the one on the
+/// `PATH`. If there is none, this offers to install it. With `allow_any`, any josh-proxy is
+/// accepted with a warning.
+fn find_josh(allow_any: bool) -> Result<PathBuf> {
+    let pinned = JoshVersion::pinned();
+    let root = josh_install_root()?;
+    let ours = josh_binary(&root);
+    let mut mismatch = None;
+    for josh in installed_josh() {
+        let version = josh_version(&josh);
+        if version == Some(pinned) {
+            return Ok(josh);
+        }
+        mismatch.get_or_insert((josh, version));
+    }
+
+    let problem = match &mismatch {
+        Some((josh, Some(version))) =>
+            format!("{} is josh-proxy {version}, but the sync needs {pinned}", josh.display()),
+        Some((josh, None)) =>
+            format!(
+                "could not determine the version of {}, the sync needs {pinned}",
+                josh.display()
+            ),
+        None => format!("could not find josh-proxy {pinned}"),
+    };
+    if let Some((josh, _)) = mismatch.filter(|_| allow_any) {
+        warning!("{problem}; using it anyway because of `--allow-any-josh`.");
+        return Ok(josh);
+    }
+    eprintln!("{problem}.");
+    if std::io::stdin().is_terminal() {
+        print!("Install josh-proxy {pinned} into {}? [y/N] ", root.display());
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        if answer.trim().eq_ignore_ascii_case("y") {
+            install_josh(&root)?;
+            return Ok(ours);
+        }
+    }
+    bail!(
+        "{problem}; install it with `{}`, or use `--allow-any-josh` to proceed with another version",
+        josh_install_command()
+    );
+}
+
+fn install_josh(root: &Path) -> Result<()> {
+    let sh = ScriptCtx::new()?.sh;
+    // Josh does not build without warnings on current compilers.
+    sh.set_var("RUSTFLAGS", "--cap-lints=warn");
+    // It is built in a temporary dir, not next to Miri's build artifacts.
+    let cmd = cmd!(
+        sh,
+        "cargo +stable install josh-proxy --locked --git {JOSH_REPO} --tag {JOSH_VERSION} --root {root}"
+    )
+    .env_remove("CARGO_TARGET_DIR");
+    retry(
+        DOWNLOAD_RETRIES,
+        DOWNLOAD_RETRY_DELAY,
+        "installing josh-proxy",
+        is_download_hiccup,
+        || cmd.tee(),
+    )
+    .context("failed to install josh-proxy")?;
+    Ok(())
+}
+
+fn is_listening(port: u16) -> bool {
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    TcpStream::connect_timeout(&addr, Duration::from_millis(500)).is_ok()
+}
+
+/// Picks a port for josh-proxy to listen on: the default one if it is free, otherwise whatever
+/// the OS hands out.
+fn free_port() -> Result<u16> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, JOSH_PORT))
+        .or_else(|_| TcpListener::bind((Ipv4Addr::LOCALHOST, 0)))
+        .context("failed to find a free port for josh-proxy")?;
+    Ok(listener.local_addr()?.port())
+}
+
+/// A josh-proxy to run `rustc-pull`/`rustc-push` through. If we started it ourselves, it is
+/// stopped again on drop.
+pub struct Josh {
+    port: u16,
+    /// The process and its log file, if we started it.
+    child: Option<(process::Child, tee::Killer, PathBuf)>,
+}
+
+impl Josh {
+    /// Connects to an already running josh-proxy, or starts a new one. See [`find_josh`] for
+    /// what `allow_any_version` does.
+    pub fn start(target_dir: &Path, allow_any_version: bool) -> Result<Josh> {
+        if is_listening(JOSH_PORT) {
+            // We cannot ask the proxy for its version, so we check the one the user most likely
+            // started it from.
+            let version = which::which("josh-proxy").ok().and_then(|josh| josh_version(&josh));
+            if version != Some(JoshVersion::pinned()) && !allow_any_version {
+                bail!(
+                    "a josh-proxy is already running on port {JOSH_PORT}, but the josh-proxy on \
+                    your PATH is not version {JOSH_VERSION}; stop it so that `./miri` can start \
+                    the right version, or use `--allow-any-josh`"
+                );
+            }
+            println!("Using the josh-proxy already running on port {JOSH_PORT}.");
+            return Ok(Josh { port: JOSH_PORT, child: None });
+        }
+        let josh_proxy = find_josh(allow_any_version)?;
+        if skip_in_dry_run(format_args!("start {} on port {JOSH_PORT}", josh_proxy.display())) {
+            return Ok(Josh { port: JOSH_PORT, child: None });
+        }
+        // Keep the cache next to the build artifacts, so `cargo clean` also cleans it up.
+        let josh_dir = path!(target_dir / "josh");
+        fs::create_dir_all(&josh_dir)
+            .with_context(|| format!("failed to create {}", josh_dir.display()))?;
+        let log_path = path!(josh_dir / "josh-proxy.log");
+        let log = File::create(&log_path)
+            .with_context(|| format!("failed to create {}", log_path.display()))?;
+        let port = free_port()?;
+
+        let mut cmd = process::Command::new(josh_proxy);
+        cmd.arg("--local").arg(path!(josh_dir / "cache"));
+        cmd.arg("--remote").arg("https://github.com");
+        cmd.arg("--port").arg(port.to_string());
+        cmd.arg("--no-background");
+        cmd.stdin(process::Stdio::null());
+        cmd.stdout(log.try_clone()?);
+        cmd.stderr(log);
+        // That way, Ctrl-C stops it as well.
+        let (child, killer) =
+            tee::spawn_killable(&mut cmd).context("failed to start josh-proxy")?;
+        // From here on, dropping `josh` stops the process again.
+        let mut josh = Josh { port, child: Some((child, killer, log_path)) };
+        josh.wait_until_ready()?;
+        Ok(josh)
+    }
+
+    fn wait_until_ready(&mut self) -> Result<()> {
+        let (child, _, log) = self.child.as_mut().unwrap();
+        let start = Instant::now();
+        while !is_listening(self.port) {
+            if let Some(status) = child.try_wait()? {
+                bail!("josh-proxy exited during startup ({status}); see {}", log.display());
+            }
+            if start.elapsed() > JOSH_STARTUP_TIMEOUT {
+                bail!(
+                    "josh-proxy did not start listening on port {} within {}s; see {}",
+                    self.port,
+                    JOSH_STARTUP_TIMEOUT.as_secs(),
+                    log.display()
+                );
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        Ok(())
+    }
+
+    /// The josh URL for the Miri subtree of the GitHub repo `repo` (e.g., `rust-lang/rust`),
+    /// optionally at a particular commit.
+    pub fn url(&self, repo: &str, commit: Option<&str>) -> String {
+        let commit = commit.map(|c| format!("@{c}")).unwrap_or_default();
+        format!("http://localhost:{}/{repo}.git{commit}{JOSH_FILTER}.git", self.port)
+    }
+
+    /// Points to the josh-proxy log if a command that went through josh failed.
+    pub fn check<T>(&self, res: Result<T, impl Into<anyhow::Error>>) -> Result<T> {
+        let res = res.map_err(Into::into);
+        match &self.child {
+            Some((_, _, log)) =>
+                res.with_context(|| format!("the josh-proxy log is at {}", log.display())),
+            None => res,
+        }
+    }
+}
+
+impl Drop for Josh {
+    fn drop(&mut self) {
+        // Leave a josh-proxy that was already running alone.
+        let Some((child, ..)) = &mut self.child else { return };
+        #[cfg(unix)]
+        {
+            // Try to gracefully shut it down.
+            process::Command::new("kill")
+                .args(["-s", "INT", &child.id().to_string()])
+                .output()
+                .expect("failed to SIGINT josh-proxy");
+            // Sadly there is no "wait with timeout"... so we just give it some time to finish.
+            thread::sleep(Duration::from_millis(100));
+            // Now hopefully it is gone.
+            if child.try_wait().expect("failed to wait for josh-proxy").is_some() {
+                return;
+            }
+        }
+        // If that didn't work (or we're not on Unix), kill it hard.
+        warning!(
+            "I have to kill josh-proxy the hard way, let's hope this does not break anything."
+        );
+        child.kill().expect("failed to SIGKILL josh-proxy");
+        let _ = child.wait();
+    }
+}
+
+/// How long we wait before the first retry of a network operation. This doubles for every retry.
+const RETRY_DELAY: Duration = if cfg!(test) { Duration::ZERO } else { Duration::from_secs(2) };
+
+/// Whether an error from a network operation looks like it could go away when we try again.
+/// Errors that are clearly not going to go away (authentication, rejected pushes, missing refs)
+/// are never considered transient.
+fn is_transient(err: &anyhow::Error) -> bool {
+    // Whatever hung will most likely hang again, and we already waited for a long time.
+    if err.is::<TimedOut>() {
+        return false;
+    }
+    let msg = format!("{err:#}").to_lowercase();
+    // Status codes and short words are only matched with the context they appear in, so that e.g.
+    // a commit hash containing `403` does not count.
+    const PERMANENT: &[&str] = &[
+        "authentication failed",
+        "could not read username",
+        "permission denied",
+        "error: 403",
+        "http 403",
+        "403 forbidden",
+        "[rejected]",
+        "non-fast-forward",
+        "conflict (",
+        "couldn't find remote ref",
+        "not our ref",
+    ];
+    const TRANSIENT: &[&str] = &[
+        "connection reset",
+        "connection timed out",
+        "operation timed out",
+        "timeout",
+        "could not resolve host",
+        "early eof",
+        "the remote end hung up unexpectedly",
+        "unexpected disconnect",
+        "returned error: 50",
+        "http 50",
+        "500 internal server error",
+        "502 bad gateway",
+        "503 service unavailable",
+        "504 gateway timeout",
+    ];
+    !PERMANENT.iter().any(|p| msg.contains(p)) && TRANSIENT.iter().any(|t| msg.contains(t))
+}
+
+/// Runs `f`, which does some network operation described by `what`. If it fails with an error that
+/// looks transient, tries again up to `retries` times, with exponential backoff.
+pub fn with_retries<T>(retries: u32, what: &str, f: impl FnMut() -> Result<T>) -> Result<T> {
+    retry(retries, RETRY_DELAY, what, is_transient, f)
+}
+
+/// How long a network command may take (see `MIRI_SCRIPT_NETWORK_TIMEOUT`).
+pub fn network_timeout() -> Result<Option<Duration>> {
+    tee::timeout_from_env(NETWORK_TIMEOUT_VAR, NETWORK_TIMEOUT)
+}
+
+/// Runs a network command for [`with_retries`], returning its stdout. Its stderr is captured, so
+/// that the error says what went wrong. Commands that may have to ask for credentials on the
+/// terminal cannot have a `timeout`.
+pub fn run_captured(cmd: Cmd<'_>, timeout: Option<Duration>) -> Result<String> {
+    let shown = cmd.to_string();
+    let output = cmd.ignore_status().output_with_timeout(timeout)?;
+    if !output.status.success() {
+        bail!("`{shown}` failed ({}):\n{}", output.status, output.output.trim_end());
+    }
+    Ok(output.stdout)
+}
+
+/// How often we report on a fetch when we are not showing git's own progress output.
+const FETCH_REPORT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Extracts the amount of data received so far from a line of git's progress output, e.g.
+/// `12.34 MiB` from `Receiving objects:  45% (4500/10000), 12.34 MiB | 1.20 MiB/s`.
+fn received_amount(line: &str) -> Option<&str> {
+    let rest = line.strip_prefix("Receiving objects:")?;
+    let (_, amount) = rest.split_once("), ")?;
+    Some(amount.split(" |").next().unwrap().trim_end_matches(", done.").trim())
+}
+
+/// Runs `git fetch` for `url` in the current directory of `sh`, showing progress. Fetching through
+/// josh can take many minutes, so we want to make it clear that something is happening. If
+/// stderr is a terminal, we show git's progress output; otherwise that would just be a lot of
+/// noise, so we print a summary every now and then instead. The fetch gets killed when it takes
+/// longer than the network timeout.
+pub fn fetch_with_progress(sh: &Shell, url: &str, refs: &[&str]) -> Result<()> {
+    let shown = [url].iter().chain(refs).copied().collect::<Vec<_>>().join(" ");
+    let argv: Vec<&str> =
+        ["git", "fetch", "--progress", url].into_iter().chain(refs.iter().copied()).collect();
+    let no_env: &[(&str, Option<&str>)] = &[];
+    plain!("$ {}", cmdline::display_command(&argv, None, no_env));
+    if skip_in_dry_run(format_args!("fetch {shown}")) {
+        return Ok(());
+    }
+    let timeout = network_timeout()?;
+    let start = Instant::now();
+    let mut command = process::Command::new("git");
+    command
+        .args(["fetch", "--progress", url])
+        .args(refs)
+        .current_dir(sh.current_dir())
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::piped());
+    let (mut child, killer) =
+        tee::spawn_killable(&mut command).context("failed to run `git fetch`")?;
+    let timed_out = AtomicBool::new(false);
+    let mut stderr = BufReader::new(child.stderr.take().unwrap());
+    let tty = std::io::stderr().is_terminal();
+    let received = Mutex::new(None::<String>);
+    let (done, watchdog_done) = mpsc::channel::<()>();
+    // The non-progress output, for the error message.
+    let mut messages = Vec::new();
+
+    thread::scope(|s| -> Result<()> {
+        let (received, killer, timed_out) = (&received, &killer, &timed_out);
+        s.spawn(move || {
+            let mut next_report = FETCH_REPORT_INTERVAL;
+            loop {
+                // Wake up for the next report, or when the time is up.
+                let mut wait = if tty { Duration::MAX } else { next_report };
+                if let Some(timeout) = timeout {
+                    wait = wait.min(timeout);
+                }
+                if watchdog_done.recv_timeout(wait.saturating_sub(start.elapsed()))
+                    != Err(mpsc::RecvTimeoutError::Timeout)
+                {
+                    return;
+                }
+                if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                    timed_out.store(true, Ordering::Relaxed);
+                    killer.kill();
+                    return;
+                }
+                if !tty && start.elapsed() >= next_report {
+                    let received = received.lock().unwrap();
+                    let received = received.as_deref().unwrap_or("nothing");
+                    plain!(
+                        "[fetch] still fetching after {}s, {received} received",
+                        start.elapsed().as_secs()
+                    );
+                    next_report += FETCH_REPORT_INTERVAL;
+                }
+            }
+        });
+        // Progress updates are terminated by `\r` (so they overwrite each other on a terminal),
+        // everything else by `\n`.
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let mut terminator = None;
+            while terminator.is_none() {
+                let buf = stderr.fill_buf()?;
+                if buf.is_empty() {
+                    break;
+                }
+                let (len, end) = match buf.iter().position(|&b| b == b'\r' || b == b'\n') {
+                    Some(i) => (i + 1, Some(buf[i])),
+                    None => (buf.len(), None),
+                };
+                line.extend_from_slice(&buf[..len]);
+                stderr.consume(len);
+                terminator = end;
+            }
+            if line.is_empty() {
+                break;
+            }
+            let text = String::from_utf8_lossy(&line);
+            let text = text.trim_end_matches(['\r', '\n']);
+            if terminator != Some(b'\r') {
+                messages.push(text.to_owned());
+            }
+            if tty {
+                if terminator == Some(b'\r') {
+                    output::set_progress(Some(format!("[fetch] {text}")));
+                } else {
+                    plain!("[fetch] {text}");
+                }
+            } else {
+                if let Some(amount) = received_amount(text) {
+                    *received.lock().unwrap() = Some(amount.to_owned());
+                }
+                if terminator != Some(b'\r') {
+                    plain!("[fetch] {text}");
+                }
+            }
+        }
+        drop(done);
+        Ok(())
+    })?;
+    if tty {
+        output::set_progress(None);
+    }
+
+    let status = child.wait()?;
+    if timed_out.load(Ordering::Relaxed) {
+        let command = format!("git fetch --progress {shown}");
+        return Err(
+            TimedOut { command, timeout: timeout.unwrap(), output: messages.join("\n") }.into()
+        );
+    }
+    if !status.success() {
+        bail!("`git fetch {shown}` failed ({status}):\n{}", messages.join("\n"));
+    }
+    let new_commits = cmd!(sh, "git rev-list --count HEAD..FETCH_HEAD").quiet().read()?;
+    eprintln!(
+        "[fetch] done after {}s, fetched {new_commits} new commit(s)",
+        start.elapsed().as_secs()
+    );
+    Ok(())
+}
+
+/// Returns the current `HEAD` of rust-lang/rust.
+pub fn rust_head(sh: &Shell, retries: u32) -> Result<String> {
+    let rust_repo_head = with_retries(retries, "querying the rustc HEAD", || {
+        run_captured(cmd!(sh, "git ls-remote {RUST_REPO} HEAD"), network_timeout()?)
+    })?;
+    rust_repo_head
+        .split_whitespace()
+        .next()
+        .map(|front| front.trim().to_owned())
+        .ok_or_else(|| anyhow!("Could not obtain Rust repo HEAD from remote."))
+}
+
+/// Returns a shell in a scratch repo in the target dir, for inspecting rust-lang/rust commits.
+/// To avoid downloading the rustc history (or making the Miri repo shallow), we only ever fetch
+/// commit objects into it, and only as many as we need.
+fn rust_commits_repo(target_dir: &Path, retries: u32) -> Result<Shell> {
+    let sh = ScriptCtx::new()?.sh;
+    let scratch = path!(target_dir / "rust-commits.git");
+    if !scratch.exists() {
+        with_retries(retries, "cloning rust-lang/rust", || {
+            run_captured(
+                cmd!(
+                    sh,
+                    "git clone --quiet --bare --depth=1 --filter=tree:0 {RUST_REPO} {scratch}"
+                ),
+                network_timeout()?,
+            )
+        })
+        .context("failed to set up a repository for inspecting rust-lang/rust commits")?;
+    }
+    sh.change_dir(&scratch);
+    Ok(sh)
+}
+
+/// Returns the commit time of the given rust-lang/rust commit, failing if there is no such commit.
+pub fn rust_commit_time(target_dir: &Path, commit: &str, retries: u32) -> Result<u64> {
+    if commit.len() != 40 || !commit.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!(
+            "`{commit}` is not a full commit hash; GitHub only serves commits by their full hash"
+        );
+    }
+    let sh = rust_commits_repo(target_dir, retries)?;
+    with_retries(retries, "fetching the commit", || {
+        run_captured(cmd!(sh, "git fetch --quiet --depth=1 origin {commit}"), network_timeout()?)
+    })
+    .with_context(|| format!("could not fetch {commit} from rust-lang/rust, does it exist?"))?;
+    let time = cmd!(sh, "git show --no-patch --format=%ct {commit}").quiet().read()?;
+    Ok(time.trim().parse()?)
+}
+
+/// Counts the rust-lang/rust commits that are in `head` but not in `since`.
+pub fn count_rust_commits(target_dir: &Path, since: &str, head: &str, retries: u32) -> Result<u64> {
+    // Fetch everything that was committed after `since`, with a day of slack for commits that
+    // were authored earlier but landed later. That is enough to count, and much less than the
+    // entire history.
+    let since_time = rust_commit_time(target_dir, since, retries)?.saturating_sub(24 * 60 * 60);
+    let since_time = since_time.to_string();
+    let sh = rust_commits_repo(target_dir, retries)?;
+    with_retries(retries, "fetching the new commits", || {
+        run_captured(
+            cmd!(sh, "git fetch --quiet --shallow-since={since_time} origin {head}"),
+            network_timeout()?,
+        )
+    })?;
+    let count = cmd!(sh, "git rev-list --count {since}..{head}").quiet().read()?;
+    Ok(count.trim().parse()?)
+}
+
+/// Extracts `owner/name` from the URL of a GitHub repository, in any of the forms git accepts.
+pub fn github_repo(url: &str) -> Option<String> {
+    let path = url
+        .strip_prefix("https://github.com/")
+        .or_else(|| url.strip_prefix("http://github.com/"))
+        .or_else(|| url.strip_prefix("ssh://git@github.com/"))
+        .or_else(|| url.strip_prefix("git@github.com:"))?;
+    let path = path.trim_end_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+    let (owner, name) = path.split_once('/')?;
+    (!owner.is_empty() && !name.is_empty() && !name.contains('/'))
+        .then(|| format!("{owner}/{name}"))
+}
+
+/// The GitHub repository `rustc-push` pushes to, usually a fork of rust-lang/rust.
+pub struct PushTarget {
+    /// `owner/name`, as used in josh and GitHub URLs.
+    pub repo: String,
+    /// The URL we push the base commit to directly. If the user gave us a remote, this is the URL
+    /// of that remote, so that whatever authentication it is set up for is used.
+    pub url: String,
+}
+
+impl PushTarget {
+    /// Determines the push target from the `--remote` flag (a remote name or URL), or else from
+    /// the GitHub user. If the remote is a URL that is not a remote of the repo yet, we add it.
+    pub fn resolve(sh: &Shell, github_user: Option<&str>, remote: Option<&str>) -> Result<Self> {
+        let Some(remote) = remote else {
+            let user = github_user.expect("either the GitHub user or the remote must be given");
+            let repo = format!("{user}/rust");
+            return Ok(PushTarget { url: format!("https://github.com/{repo}"), repo });
+        };
+        let remotes = cmd!(sh, "git remote").quiet().read()?;
+        let url = if remotes.lines().any(|r| r == remote) {
+            cmd!(sh, "git remote get-url {remote}").quiet().read()?
+        } else {
+            remote.to_owned()
+        };
+        let repo = github_repo(&url).with_context(|| {
+            format!("`{url}` is not a GitHub repository; josh can only push to GitHub")
+        })?;
+        let known = remotes
+            .lines()
+            .any(|r| cmd!(sh, "git remote get-url {r}").quiet().read().is_ok_and(|u| u == url));
+        if !known {
+            let (owner, _) = repo.split_once('/').unwrap();
+            if !remotes.lines().any(|r| r == owner) {
+                cmd!(sh, "git remote add {owner} {url}").run()?;
+                println!("Added the git remote `{owner}` for {url}.");
+            }
+        }
+        Ok(PushTarget { repo, url })
+    }
+
+    /// The URL that opens a rustc PR for the given branch.
+    pub fn pr_url(&self, branch: &str) -> String {
+        let (owner, name) = self.repo.split_once('/').unwrap();
+        // GitHub needs the repository name for forks that are not called `rust`.
+        let head = if name == "rust" {
+            format!("{owner}:{branch}")
+        } else {
+            format!("{owner}:{name}:{branch}")
+        };
+        // Open PR with `subtree update` title to silence the `no-merges` triagebot check
+        // See https://github.com/rust-lang/rust/pull/114157
+        format!(
+            "https://github.com/rust-lang/rust/compare/{head}?quick_pull=1&title=Miri+subtree+update&body=r?+@ghost"
+        )
+    }
+
+    /// Returns the commit the branch points to in the target repository, if it exists.
+    pub fn branch_commit(&self, sh: &Shell, branch: &str, retries: u32) -> Result<Option<String>> {
+        let url = &self.url;
+        let refs = with_retries(retries, "checking for the branch", || {
+            run_captured(
+                cmd!(sh, "git ls-remote {url} refs/heads/{branch}").quiet(),
+                network_timeout()?,
+            )
+        })?;
+        Ok(refs.split_whitespace().next().map(str::to_owned))
+    }
+}
+
+/// What we know about the last sync with rustc. Like [`PullState`], this is stored in the git dir.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SyncState {
+    pub last_pull: Option<PullRecord>,
+    pub last_push: Option<PushRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PullRecord {
+    /// The rustc commit that was pulled.
+    pub commit: String,
+    /// When the pull happened, in seconds since the Unix epoch.
+    pub time: u64,
+    /// The merge commit in the Miri repo.
+    pub merge: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PushRecord {
+    /// The Miri commit that was pushed.
+    pub head: String,
+    /// Where it was pushed to.
+    pub branch: String,
+    /// When the push happened, in seconds since the Unix epoch.
+    pub time: u64,
+}
+
+impl SyncState {
+    fn path(sh: &Shell) -> Result<PathBuf> {
+        let path = cmd!(sh, "git rev-parse --git-path miri-rustc-sync.json")
+            .quiet()
+            .read()
+            .context("failed to determine the git dir")?;
+        Ok(path.into())
+    }
+
+    /// Loads the state; if there is none yet, everything is unknown.
+    pub fn load(sh: &Shell) -> Result<Self> {
+        let path = Self::path(sh)?;
+        if !sh.path_exists(&path) {
+            return Ok(SyncState::default());
+        }
+        let state = sh.read_file(&path)?;
+        serde_json::from_str(&state)
+            .with_context(|| format!("invalid rustc sync state in {}", path.display()))
+    }
+
+    pub fn store(&self, sh: &Shell) -> Result<()> {
+        let path = Self::path(sh)?;
+        if !skip_in_dry_run(format_args!("write {}", path.display())) {
+            sh.write_file(path, serde_json::to_string_pretty(self)?)?;
+        }
+        Ok(())
+    }
+}
+
+/// The output of `./miri rustc-status`.
+#[derive(Debug, Serialize)]
+pub struct RustcStatus {
+    /// The rustc commit in `rust-version`.
+    pub last_pulled_commit: String,
+    /// When that commit was pulled, in seconds since the Unix epoch, if we know it.
+    pub last_pulled_at: Option<u64>,
+    /// The current `HEAD` of rust-lang/rust.
+    pub upstream_head: String,
+    /// How many commits have landed in rust-lang/rust since the last pull.
+    pub upstream_commits_since: u64,
+    /// How many Miri commits there are that have not been pushed to rustc.
+    pub unpushed_commits: u64,
+}
+
+impl RustcStatus {
+    pub fn print_human(&self) {
+        let when = match self.last_pulled_at {
+            Some(time) => {
+                let hours = now().saturating_sub(time) / (60 * 60);
+                if hours < 48 {
+                    format!(", {hours} hours ago")
+                } else {
+                    format!(", {} days ago", hours / 24)
+                }
+            }
+            None => String::new(),
+        };
+        println!("Last pulled rustc commit: {}{when}", self.last_pulled_commit);
+        println!(
+            "rustc HEAD: {} ({} commit(s) since the last pull)",
+            self.upstream_head, self.upstream_commits_since
+        );
+        println!("Miri commits not yet pushed to rustc: {}", self.unpushed_commits);
+    }
+}
+
+/// The current time, in seconds since the Unix epoch.
+pub fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// The state of a `rustc-pull` that stopped due to merge conflicts. This is stored in the git dir,
+/// so that `./miri rustc-pull --continue`/`--abort` can pick up where we left off.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PullState {
+    /// The commit that was checked out before the pull started.
+    pub pre_pull_ref: String,
+    /// The rustc commit that is being pulled.
+    pub commit: String,
+    /// The number of root commits before the merge.
+    pub num_roots_before: u32,
+}
+
+impl PullState {
+    fn path(sh: &Shell) -> Result<PathBuf> {
+        let path = cmd!(sh, "git rev-parse --git-path miri-rustc-pull.json")
+            .quiet()
+            .read()
+            .context("failed to determine the git dir")?;
+        Ok(path.into())
+    }
+
+    /// Loads the state of an interrupted pull, if there is one.
+    pub fn load(sh: &Shell) -> Result<Option<Self>> {
+        let path = Self::path(sh)?;
+        if !sh.path_exists(&path) {
+            return Ok(None);
+        }
+        let state = sh.read_file(&path)?;
+        let state = serde_json::from_str(&state)
+            .with_context(|| format!("invalid rustc-pull state in {}", path.display()))?;
+        Ok(Some(state))
+    }
+
+    pub fn store(&self, sh: &Shell) -> Result<()> {
+        let path = Self::path(sh)?;
+        if !skip_in_dry_run(format_args!("write {}", path.display())) {
+            sh.write_file(path, serde_json::to_string_pretty(self)?)?;
+        }
+        Ok(())
+    }
+
+    pub fn clear(sh: &Shell) -> Result<()> {
+        let path = Self::path(sh)?;
+        if !skip_in_dry_run(format_args!("delete {}", path.display())) {
+            sh.remove_path(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the files that git considers to be in conflict.
+pub fn conflicted_files(sh: &Shell) -> Result<Vec<String>> {
+    let status = cmd!(sh, "git status --porcelain --untracked-files=no").quiet().read()?;
+    Ok(status
+        .lines()
+        .filter_map(|line| {
+            let (xy, path) = line.split_at_checked(3)?;
+            // These are the "unmerged" states, see `git help status`.
+            ["DD ", "AU ", "UD ", "UA ", "DU ", "AA ", "UU "].contains(&xy).then(|| path.to_owned())
+        })
+        .collect())
+}
+
+/// Returns those of the given files that still contain conflict markers.
+pub fn files_with_markers(sh: &Shell, files: &[String]) -> Vec<String> {
+    files
+        .iter()
+        .filter(|file| {
+            // Deleted files and binary files cannot contain markers we care about.
+            sh.read_file(file).is_ok_and(|text| {
+                text.lines().any(|line| {
+                    line.starts_with("<<<<<<< ")
+                        || line == "======="
+                        || line.starts_with(">>>>>>> ")
+                })
+            })
+        })
+        .cloned()
+        .collect()
+}
+
+/// Prints the conflicted files, split into those that also exist in the rustc version of Miri
+/// (i.e., in `MERGE_HEAD`) and those that are only present locally.
+pub fn print_conflicts(sh: &Shell, files: &[String]) {
+    let (subtree, local): (Vec<&String>, Vec<&String>) = files.iter().partition(|file| {
+        cmd!(sh, "git cat-file -e MERGE_HEAD:{file}").quiet().ignore_stderr().run().is_ok()
+    });
+    error!("the merge from rustc has conflicts");
+    if !subtree.is_empty() {
+        eprintln!("\nConflicted files that are also present in rustc:");
+        for file in subtree {
+            eprintln!("    {file}");
+        }
+    }
+    if !local.is_empty() {
+        eprintln!("\nConflicted files that only exist locally:");
+        for file in local {
+            eprintln!("    {file}");
+        }
+    }
+    eprintln!(
+        "\nResolve the conflicts and `git add` the files, then run `./miri rustc-pull --continue`.\n\
+        To go back to the state before the pull, run `./miri rustc-pull --abort`."
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn github_repos() {
+        let repo = |url| github_repo(url);
+        assert_eq!(repo("https://github.com/me/rust").as_deref(), Some("me/rust"));
+        assert_eq!(repo("https://github.com/me/rust.git").as_deref(), Some("me/rust"));
+        assert_eq!(repo("https://github.com/me/rust-fork/").as_deref(), Some("me/rust-fork"));
+        assert_eq!(repo("git@github.com:me/rust.git").as_deref(), Some("me/rust"));
+        assert_eq!(repo("ssh://git@github.com/me/rust.git").as_deref(), Some("me/rust"));
+        assert_eq!(repo("https://gitlab.com/me/rust"), None);
+        assert_eq!(repo("https://github.com/me"), None);
+        assert_eq!(repo("https://github.com/me/rust/tree/master"), None);
+        assert_eq!(repo("origin"), None);
+    }
+
+    #[test]
+    fn pr_urls() {
+        let target = |repo: &str| PushTarget { repo: repo.to_owned(), url: String::new() };
+        assert!(target("me/rust")
+            .pr_url("miri-sync")
+            .starts_with("https://github.com/rust-lang/rust/compare/me:miri-sync?"));
+        assert!(target("me/rust-fork")
+            .pr_url("sync")
+            .starts_with("https://github.com/rust-lang/rust/compare/me:rust-fork:sync?"));
+    }
+
+    #[test]
+    fn transient_errors() {
+        let transient = |msg: &str| is_transient(&anyhow::anyhow!(msg.to_owned()));
+        assert!(transient("fatal: unable to access '...': Could not resolve host: github.com"));
+        assert!(transient("error: RPC failed; curl 56 Recv failure: Connection reset by peer"));
+        assert!(transient("fatal: the remote end hung up unexpectedly"));
+        assert!(transient(
+            "error: RPC failed; HTTP 502 curl 22 The requested URL returned error: 502"
+        ));
+        assert!(transient("fatal: unable to access '...': Operation timed out after 300000 ms"));
+        assert!(!transient("fatal: Authentication failed for 'https://github.com/...'"));
+        assert!(!transient("The requested URL returned error: 403"));
+        assert!(!transient(" ! [rejected]        HEAD -> miri-sync (non-fast-forward)"));
+        assert!(!transient("fatal: couldn't find remote ref miri-sync"));
+        assert!(!transient("CONFLICT (content): Merge conflict in src/lib.rs"));
+        assert!(!transient("fatal: not a git repository"));
+        // Only status codes count, not things that happen to contain them.
+        assert!(transient(
+            "error: RPC failed; curl 56 Recv failure: Connection reset by peer\n\
+            fatal: could not fetch 4f4038c1e0 from https://github.com/rust-lang/miri"
+        ));
+        assert!(!transient(
+            "error: RPC failed; HTTP 403 curl 22 The requested URL returned error: 403"
+        ));
+    }
+
+    #[test]
+    fn retries() {
+        let transient = || anyhow::anyhow!("fatal: the remote end hung up unexpectedly");
+        // Transient errors are retried until we succeed.
+        let mut calls = 0;
+        let res = with_retries(3, "test", || {
+            calls += 1;
+            if calls < 3 {
+                Err(transient())
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(res.unwrap(), 3);
+        // ... but only so often, and then all errors are reported.
+        let mut calls = 0;
+        let err = with_retries(2, "test", || -> Result<()> {
+            calls += 1;
+            Err(transient())
+        })
+        .unwrap_err();
+        assert_eq!(calls, 3);
+        let err = err.to_string();
+        assert!(err.starts_with("test failed after 3 attempts"), "{err}");
+        assert!(err.contains("attempt 1: ") && err.contains("attempt 3: "), "{err}");
+        // Other errors are not retried.
+        let mut calls = 0;
+        let err = with_retries(3, "test", || -> Result<()> {
+            calls += 1;
+            bail!("fatal: Authentication failed")
+        })
+        .unwrap_err();
+        assert_eq!(calls, 1);
+        assert_eq!(err.to_string(), "fatal: Authentication failed");
+    }
+
+    #[test]
+    fn fetch_progress() {
+        let amount = received_amount;
+        assert_eq!(
+            amount("Receiving objects:  45% (4500/10000), 12.34 MiB | 1.20 MiB/s"),
+            Some("12.34 MiB")
+        );
+        assert_eq!(
+            amount("Receiving objects: 100% (10000/10000), 25.00 MiB | 2.00 MiB/s, done."),
+            Some("25.00 MiB")
+        );
+        assert_eq!(amount("Receiving objects: 100% (3/3), 1.02 KiB, done."), Some("1.02 KiB"));
+        // Before the first amount is known, git only prints the object count.
+        assert_eq!(amount("Receiving objects:   1% (100/10000)"), None);
+        assert_eq!(amount("Resolving deltas:  50% (50/100)"), None);
+        assert_eq!(amount("remote: Counting objects: 10"), None);
+    }
+
+    #[test]
+    fn josh_versions() {
+        let v = |year, month, day| JoshVersion { year, month, day };
+        assert_eq!("r23.12.04".parse::<JoshVersion>().unwrap(), v(23, 12, 4));
+        assert_eq!("23.12.4".parse::<JoshVersion>().unwrap(), v(23, 12, 4));
+        assert_eq!("v24.10.04".parse::<JoshVersion>().unwrap(), v(24, 10, 4));
+        assert_eq!(v(23, 12, 4).to_string(), "r23.12.04");
+        for invalid in ["", "r23.12", "r23.12.04.1", "r23.12.04-3-g0123abc", "rx.12.04", "latest"] {
+            assert!(invalid.parse::<JoshVersion>().is_err(), "{invalid}");
+        }
+        assert_eq!(JoshVersion::pinned().to_string(), JOSH_VERSION);
+    }
+
+    #[test]
+    fn josh_version_output() {
+        let pinned = Some(JoshVersion::pinned());
+        assert_eq!(parse_josh_version("josh-proxy r23.12.04\n"), pinned);
+        assert_eq!(parse_josh_version("josh-proxy 23.12.4"), pinned);
+        assert_eq!(parse_josh_version("josh-proxy r23.12.04-3-g0123abc"), None);
+        assert_eq!(parse_josh_version("josh-proxy"), None);
+        assert_eq!(parse_josh_version(""), None);
+    }
+}
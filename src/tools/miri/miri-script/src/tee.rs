@@ -0,0 +1,624 @@
+//! Running a command ourselves instead of through `xshell`: so that its output shows up on the
+//! terminal as it happens and is also kept for later (for the log, or to show again when something
+//! failed), and so that it can be killed when it takes too long, or when the user presses Ctrl-C.
+
+use std::cell::RefCell;
+use std::ffi::OsStr;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::{self, Child, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use crate::ci::format_duration;
+use crate::output::{self, Stream};
+
+/// How much output we keep. When there is more, we keep the end, which is where things usually
+/// say what went wrong.
+const MAX_CAPTURE: usize = 1 << 20;
+
+/// How often we check whether a command with a timeout is done.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The output of a command, in the order in which it arrived on stdout and stderr.
+#[derive(Debug, Default)]
+struct Capture {
+    buf: Vec<u8>,
+    /// How many bytes we dropped from the start.
+    dropped: usize,
+}
+
+impl Capture {
+    fn push(&mut self, bytes: &[u8], max: usize) {
+        self.buf.extend_from_slice(bytes);
+        // Moving everything down is slow, so only do that once we have twice as much as we want.
+        if self.buf.len() > max.saturating_mul(2) {
+            self.trim(max);
+        }
+    }
+
+    /// Drops output from the start until there are at most `max` bytes left.
+    fn trim(&mut self, max: usize) {
+        if self.buf.len() <= max {
+            return;
+        }
+        let excess = self.buf.len() - max;
+        // Drop whole lines, so that the output does not start in the middle of one.
+        let cut = match self.buf[excess - 1..].iter().position(|&b| b == b'\n') {
+            Some(pos) => excess + pos,
+            None => excess,
+        };
+        self.buf.drain(..cut);
+        self.dropped += cut;
+    }
+
+    fn finish(mut self, max: usize) -> String {
+        self.trim(max);
+        let output = String::from_utf8_lossy(&self.buf);
+        if self.dropped == 0 {
+            output.into_owned()
+        } else {
+            format!("... ({} bytes of output dropped) ...\n{output}", self.dropped)
+        }
+    }
+}
+
+/// Copies `from` to `to` (if given) line by line, with `prefix` in front of every line, adding
+/// every line to `capture` as well.
+fn pump(
+    from: impl Read,
+    to: Option<Stream>,
+    prefix: &str,
+    capture: &Mutex<Capture>,
+) -> io::Result<()> {
+    let mut from = BufReader::new(from);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if from.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        // Lock while writing, so that the captured lines are in the order they were shown in.
+        let mut capture = capture.lock().unwrap();
+        if let Some(to) = to {
+            output::write(to, &[prefix.as_bytes(), &line].concat());
+        }
+        capture.push(&line, MAX_CAPTURE);
+    }
+}
+
+/// How the commands run with [`run`] on the current thread are run; see [`in_context`].
+#[derive(Clone, Debug, Default)]
+pub struct RunContext {
+    /// What every line they show gets prefixed with, to tell apart the output of commands that
+    /// run at the same time.
+    pub prefix: String,
+    /// When this gets set, they get killed.
+    pub stop: Option<Arc<AtomicBool>>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<RunContext> = RefCell::default();
+}
+
+/// Runs `f` with the commands it runs with [`run`] (on this thread) run in `context`.
+pub fn in_context<T>(context: RunContext, f: impl FnOnce() -> T) -> T {
+    let outer = CONTEXT.replace(context);
+    let result = f();
+    CONTEXT.set(outer);
+    result
+}
+
+/// The context the commands run on this thread run in.
+pub fn context() -> RunContext {
+    CONTEXT.with_borrow(Clone::clone)
+}
+
+/// How a command run with [`run`] went.
+#[derive(Debug, Default)]
+pub struct Captured {
+    /// `None` if it took too long, or was stopped (see [`RunContext`]), and got killed.
+    pub status: Option<ExitStatus>,
+    /// Its stderr, and its stdout if that was shown, interleaved. Very long output is cut at the
+    /// start.
+    pub output: String,
+    /// Its stdout, if that was not shown.
+    pub stdout: String,
+}
+
+/// Runs `command` like `xshell` does: with `stdin` (or nothing) as its stdin, and capturing its
+/// stdout and stderr if they are piped. An `interactive` command gets the terminal as its stdin
+/// instead, and stays in our process group (see [`with_terminal`]).
+pub fn run_plain(
+    mut command: process::Command,
+    stdin: Option<&[u8]>,
+    interactive: bool,
+) -> io::Result<process::Output> {
+    command.stdin(match (stdin, interactive) {
+        (Some(_), _) => Stdio::piped(),
+        (None, true) => Stdio::inherit(),
+        (None, false) => Stdio::null(),
+    });
+    if interactive {
+        return with_terminal(|| wait_plain(command.spawn()?, stdin));
+    }
+    let (child, _killer) = spawn_killable(&mut command)?;
+    wait_plain(child, stdin)
+}
+
+fn wait_plain(mut child: Child, stdin: Option<&[u8]>) -> io::Result<process::Output> {
+    let child_stdin = child.stdin.take();
+    thread::scope(|s| {
+        if let (Some(mut child_stdin), Some(stdin)) = (child_stdin, stdin) {
+            // The child does not have to read all of it, so a broken pipe is fine.
+            s.spawn(move || child_stdin.write_all(stdin));
+        }
+        child.wait_with_output()
+    })
+}
+
+/// Runs `command`, showing its stdout and stderr as requested and capturing them. When it takes
+/// longer than `timeout`, or when the current [`RunContext`] says to stop, it gets killed, along with
+/// everything it started. The command cannot read from the terminal.
+pub fn run(
+    mut command: process::Command,
+    stdin: Option<&[u8]>,
+    (show_stdout, show_stderr): (bool, bool),
+    timeout: Option<Duration>,
+) -> io::Result<Captured> {
+    command.stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() });
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    let (mut child, killer) = spawn_killable(&mut command)?;
+    let RunContext { prefix, stop } = context();
+    let capture = Mutex::new(Capture::default());
+    let mut stdout = Vec::new();
+    // Every pipe gets its own thread, so that the child never waits for us to read (or write)
+    // another one.
+    let (child_stdin, child_stdout, child_stderr) =
+        (child.stdin.take(), child.stdout.take().unwrap(), child.stderr.take().unwrap());
+    let status = thread::scope(|s| -> io::Result<Option<ExitStatus>> {
+        if let (Some(mut child_stdin), Some(stdin)) = (child_stdin, stdin) {
+            // The child does not have to read all of it, so a broken pipe is fine.
+            s.spawn(move || child_stdin.write_all(stdin));
+        }
+        let (capture, stdout, prefix) = (&capture, &mut stdout, prefix.as_str());
+        let stdout = s.spawn(move || {
+            if show_stdout {
+                pump(child_stdout, Some(Stream::Stdout), prefix, capture)
+            } else {
+                BufReader::new(child_stdout).read_to_end(stdout).map(drop)
+            }
+        });
+        let stderr = s.spawn(move || {
+            pump(child_stderr, show_stderr.then_some(Stream::Stderr), prefix, capture)
+        });
+        let status = match (timeout, &stop) {
+            (None, None) => Some(child.wait()?),
+            _ => wait_until(&mut child, &killer, timeout, stop.as_deref())?,
+        };
+        stdout.join().unwrap()?;
+        stderr.join().unwrap()?;
+        Ok(status)
+    })?;
+    Ok(Captured {
+        status,
+        output: capture.into_inner().unwrap().finish(MAX_CAPTURE),
+        stdout: String::from_utf8_lossy(&stdout).into_owned(),
+    })
+}
+
+/// Waits for `child`, killing it when it takes longer than `timeout` or when `stop` gets set.
+/// Returns `None` then.
+fn wait_until(
+    child: &mut Child,
+    killer: &Killer,
+    timeout: Option<Duration>,
+    stop: Option<&AtomicBool>,
+) -> io::Result<Option<ExitStatus>> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline)
+            || stop.is_some_and(|stop| stop.load(Ordering::Relaxed))
+        {
+            killer.kill();
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// The error for a command that took longer than it was allowed to, and got killed.
+#[derive(Debug)]
+pub struct TimedOut {
+    pub command: String,
+    pub timeout: Duration,
+    /// What it printed before it got killed.
+    pub output: String,
+}
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` did not finish within {} and was killed",
+            self.command,
+            format_duration(self.timeout)
+        )?;
+        let lines: Vec<&str> = self.output.trim_end().lines().collect();
+        if !lines.is_empty() {
+            let last = &lines[lines.len().saturating_sub(10)..];
+            write!(f, "; the last thing it printed was:\n{}", last.join("\n"))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// The error for a command that failed. It has what the command printed (if that was captured),
+/// so that callers can tell what went wrong, and how it exited, so that we can exit the same way.
+#[derive(Debug)]
+pub struct CommandFailed {
+    pub command: String,
+    pub status: ExitStatus,
+    pub output: String,
+}
+
+impl fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The output was shown already, or the caller did not want it shown.
+        match self.status.code() {
+            Some(code) => write!(f, "command exited with non-zero code `{}`: {code}", self.command),
+            None => write!(f, "command was terminated `{}`: {}", self.command, self.status),
+        }
+    }
+}
+
+impl std::error::Error for CommandFailed {}
+
+/// The code to exit with after a command exited with `status`: its exit code, or for a command
+/// killed by a signal on Unix, 128 plus the signal (like shells do).
+fn exit_code_of(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().filter(|&code| code != 0).unwrap_or(1)
+}
+
+/// The code to exit with because of `err`: that of the innermost command that failed, so that a
+/// failing `cargo test` makes us exit with 101 as well, or else 1.
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    err.chain()
+        .filter_map(|err| err.downcast_ref::<CommandFailed>())
+        .last()
+        .map_or(1, |failed| exit_code_of(failed.status))
+}
+
+/// Parses a timeout in seconds, where 0 means no timeout.
+fn parse_timeout(value: &OsStr) -> Option<Option<Duration>> {
+    let secs: u64 = value.to_str()?.trim().parse().ok()?;
+    Some((secs != 0).then(|| Duration::from_secs(secs)))
+}
+
+/// The timeout set in the env var `var` (in seconds, 0 for none), or else `default`.
+pub fn timeout_from_env(var: &str, default: Duration) -> Result<Option<Duration>> {
+    match std::env::var_os(var) {
+        None => Ok(Some(default)),
+        Some(value) =>
+            parse_timeout(&value).with_context(|| format!("`{var}` must be a number of seconds")),
+    }
+}
+
+/// How long we give the commands that are still running to stop after Ctrl-C, before we kill
+/// them.
+const INTERRUPT_GRACE: Duration = Duration::from_secs(2);
+
+/// The exit code after Ctrl-C, like shells use.
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// How we refer to the process group (Unix) or job object (Windows) of a running command.
+#[cfg(unix)]
+type Group = libc::pid_t;
+#[cfg(windows)]
+type Group = windows_sys::Win32::Foundation::HANDLE;
+
+/// The groups of the commands that are running right now. Several can run at once, e.g. for
+/// `--many-seeds`.
+static GROUPS: Mutex<Vec<Group>> = Mutex::new(Vec::new());
+
+/// How many commands are running that use the terminal themselves; see [`with_terminal`].
+static TERMINAL_USERS: AtomicUsize = AtomicUsize::new(0);
+
+/// What [`SIGNAL`] is after Ctrl-C. On Windows, we do not tell the console events apart.
+#[cfg(unix)]
+const CTRL_C: i32 = libc::SIGINT;
+#[cfg(windows)]
+const CTRL_C: i32 = 1;
+
+/// The signal we got (`SIGINT` or `SIGTERM`), or [`CTRL_C`] on Windows. 0 if none.
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// Whether the user pressed Ctrl-C (or we were told to terminate) and we are shutting down. New
+/// commands then fail to start, and loops that run many commands should stop.
+pub fn interrupted() -> bool {
+    SIGNAL.load(Ordering::Relaxed) != 0
+}
+
+#[cfg(unix)]
+extern "C" fn on_signal(signal: libc::c_int) {
+    // Everything else happens on the thread `handle_interrupts` starts, since there is little a
+    // signal handler can safely do.
+    SIGNAL.store(signal, Ordering::Relaxed);
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn on_console_event(_: u32) -> windows_sys::Win32::Foundation::BOOL {
+    SIGNAL.store(CTRL_C, Ordering::Relaxed);
+    // We handled it, so the default handler does not terminate us right away.
+    1
+}
+
+/// Makes Ctrl-C (and `SIGTERM`) stop all the commands we are running, along with everything they
+/// started, before we exit with [`INTERRUPTED_EXIT_CODE`]. The commands run in their own process
+/// groups (see [`spawn_killable`]), so unlike us, they do not get the Ctrl-C from the terminal.
+pub fn handle_interrupts() {
+    #[cfg(unix)]
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: the handler only does an atomic store, which is async-signal-safe.
+        unsafe {
+            libc::signal(signal, on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        }
+    }
+    #[cfg(windows)]
+    // SAFETY: the handler only does an atomic store.
+    unsafe {
+        windows_sys::Win32::System::Console::SetConsoleCtrlHandler(Some(on_console_event), 1);
+    }
+    thread::spawn(|| {
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let signal = SIGNAL.load(Ordering::Relaxed);
+            if signal == 0 {
+                continue;
+            }
+            // Ctrl-C is for the command that uses the terminal, e.g. the editor; it decides
+            // what to do about it.
+            if signal == CTRL_C && TERMINAL_USERS.load(Ordering::Relaxed) > 0 {
+                SIGNAL.store(0, Ordering::Relaxed);
+                continue;
+            }
+            exit_interrupted();
+        }
+    });
+}
+
+/// If we were interrupted, stops everything that is still running and exits.
+pub fn exit_if_interrupted() {
+    if interrupted() {
+        exit_interrupted();
+    }
+}
+
+fn exit_interrupted() -> ! {
+    // This can get called from the thread `handle_interrupts` started, and from whatever thread
+    // noticed that its command got interrupted; the first one does the work.
+    static STOPPING: Once = Once::new();
+    STOPPING.call_once(|| stop_all(SIGNAL.load(Ordering::Relaxed)));
+    process::exit(INTERRUPTED_EXIT_CODE)
+}
+
+/// Passes `signal` on to everything that is running, and kills what has not stopped after
+/// [`INTERRUPT_GRACE`].
+fn stop_all(signal: i32) {
+    let groups = GROUPS.lock().unwrap().clone();
+    if groups.is_empty() {
+        return;
+    }
+    output::plain!("");
+    output::status!("Interrupted; stopping {} running command(s)...", groups.len());
+    // On Windows, everything attached to the console got the Ctrl-C already.
+    #[cfg(unix)]
+    for &pgid in &groups {
+        // SAFETY: `kill` has no memory-safety preconditions.
+        unsafe { libc::kill(-pgid, signal) };
+    }
+    #[cfg(windows)]
+    let _ = signal;
+    // The commands are gone from `GROUPS` once whoever runs them saw them exit.
+    let deadline = Instant::now() + INTERRUPT_GRACE;
+    while Instant::now() < deadline && !GROUPS.lock().unwrap().is_empty() {
+        thread::sleep(POLL_INTERVAL);
+    }
+    let running = GROUPS.lock().unwrap();
+    // On Unix, a group can outlive the command that started it, so we also kill the groups of
+    // the commands that did exit.
+    #[cfg(unix)]
+    for &pgid in groups.iter().chain(running.iter()) {
+        kill_group(pgid);
+    }
+    #[cfg(windows)]
+    for &job in running.iter() {
+        kill_group(job);
+    }
+}
+
+fn kill_group(group: Group) {
+    // If this fails, everything is gone already.
+    #[cfg(unix)]
+    // SAFETY: `kill` has no memory-safety preconditions.
+    unsafe {
+        libc::kill(-group, libc::SIGKILL);
+    }
+    #[cfg(windows)]
+    // SAFETY: `group` is a job object handle that is still open; see `Killer::drop`.
+    unsafe {
+        windows_sys::Win32::System::JobObjects::TerminateJobObject(group, 1);
+    }
+}
+
+/// Runs `f`, which runs a command that uses the terminal itself (like an editor or a debugger), so
+/// it cannot be in a group of its own. Ctrl-C goes to it straight from the terminal then, and we
+/// leave it to the command what to do about that.
+pub fn with_terminal<T>(f: impl FnOnce() -> T) -> T {
+    TERMINAL_USERS.fetch_add(1, Ordering::Relaxed);
+    let result = f();
+    TERMINAL_USERS.fetch_sub(1, Ordering::Relaxed);
+    result
+}
+
+/// Kills a child process along with everything it started. That is a process group on Unix and a
+/// job object on Windows; processes the child starts before it is in the job object escape it,
+/// though. While the `Killer` exists, Ctrl-C stops the child as well (see [`handle_interrupts`]).
+pub struct Killer {
+    group: Group,
+}
+
+impl Killer {
+    pub fn kill(&self) {
+        kill_group(self.group);
+    }
+
+    /// Asks the child and everything it started to stop. On Windows, there is no asking, so this
+    /// kills them.
+    pub fn terminate(&self) {
+        #[cfg(unix)]
+        // SAFETY: `kill` has no memory-safety preconditions.
+        unsafe {
+            libc::kill(-self.group, libc::SIGTERM);
+        }
+        #[cfg(windows)]
+        self.kill();
+    }
+}
+
+impl Drop for Killer {
+    fn drop(&mut self) {
+        let mut groups = GROUPS.lock().unwrap();
+        groups.retain(|&group| group != self.group);
+        #[cfg(windows)]
+        // SAFETY: `group` is a job object handle that we own. We close it while holding the lock,
+        // so that `stop_all` does not use it afterwards.
+        unsafe {
+            windows_sys::Win32::Foundation::CloseHandle(self.group);
+        }
+    }
+}
+
+/// Spawns `command` so that it can be killed along with everything it starts. The command cannot
+/// read from the terminal: that sends a process group like its own to sleep.
+pub fn spawn_killable(command: &mut process::Command) -> io::Result<(Child, Killer)> {
+    // Checking under the lock means that `stop_all` sees every command that gets started.
+    let mut groups = GROUPS.lock().unwrap();
+    if interrupted() {
+        return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+    }
+    #[cfg(unix)]
+    let (child, group) = {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+        let child = command.spawn()?;
+        // The process group has the same ID as the child, since it is the group's leader.
+        let pgid = child.id() as libc::pid_t;
+        (child, pgid)
+    };
+    #[cfg(windows)]
+    let (child, group) = {
+        use std::os::windows::io::AsRawHandle;
+
+        use windows_sys::Win32::Foundation::{CloseHandle, HANDLE};
+        use windows_sys::Win32::System::JobObjects::{AssignProcessToJobObject, CreateJobObjectW};
+
+        let mut child = command.spawn()?;
+        // SAFETY: null pointers ask for the default security attributes, and no name.
+        let job = unsafe { CreateJobObjectW(std::ptr::null(), std::ptr::null()) };
+        if job == 0 {
+            let err = io::Error::last_os_error();
+            let _ = child.kill();
+            return Err(err);
+        }
+        // SAFETY: both handles are valid.
+        if unsafe { AssignProcessToJobObject(job, child.as_raw_handle() as HANDLE) } == 0 {
+            let err = io::Error::last_os_error();
+            // SAFETY: we own `job`.
+            unsafe { CloseHandle(job) };
+            let _ = child.kill();
+            return Err(err);
+        }
+        (child, job)
+    };
+    groups.push(group);
+    Ok((child, Killer { group }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_limit() {
+        let mut capture = Capture::default();
+        for i in 0..10 {
+            capture.push(format!("line {i}\n").as_bytes(), 14);
+        }
+        assert_eq!(capture.finish(14), "... (56 bytes of output dropped) ...\nline 8\nline 9\n");
+
+        let mut capture = Capture::default();
+        capture.push(b"ok\n", 16);
+        capture.push(b"\xff no newline", 16);
+        assert_eq!(capture.finish(16), "ok\n\u{fffd} no newline");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exit_codes() {
+        use std::os::unix::process::ExitStatusExt;
+        let failed = |raw| {
+            CommandFailed {
+                command: "cargo test".into(),
+                status: ExitStatus::from_raw(raw),
+                output: String::new(),
+            }
+        };
+        assert_eq!(exit_code_of(ExitStatus::from_raw(101 << 8)), 101);
+        // SIGSEGV and SIGKILL.
+        assert_eq!(exit_code_of(ExitStatus::from_raw(11)), 139);
+        assert_eq!(exit_code_of(ExitStatus::from_raw(9)), 137);
+        // The status is found behind the context.
+        let err = anyhow::Error::from(failed(2 << 8)).context("ui tests failed");
+        assert_eq!(exit_code(&err.context("while testing")), 2);
+        assert_eq!(exit_code(&anyhow::anyhow!("no such file")), 1);
+        assert_eq!(
+            failed(101 << 8).to_string(),
+            "command exited with non-zero code `cargo test`: 101"
+        );
+    }
+
+    #[test]
+    fn timeouts() {
+        assert_eq!(parse_timeout(OsStr::new("90")), Some(Some(Duration::from_secs(90))));
+        assert_eq!(parse_timeout(OsStr::new("0")), Some(None));
+        assert_eq!(parse_timeout(OsStr::new("1h")), None);
+        let err = TimedOut {
+            command: "git fetch".into(),
+            timeout: Duration::from_secs(90),
+            output: (1..=12).map(|i| format!("{i}\n")).collect(),
+        };
+        assert_eq!(
+            err.to_string(),
+            "`git fetch` did not finish within 1m 30s and was killed; the last thing it printed \
+             was:\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12"
+        );
+    }
+}
@@ -0,0 +1,87 @@
+//! Where the time of a `./miri` invocation went: how long the commands took, by what they did.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::ci::format_duration;
+
+/// What commands that do not say what they do are counted as.
+pub const OTHER: &str = "other commands";
+
+/// Invocations that took less than this do not print a summary, unless asked to.
+const SUMMARY_THRESHOLD: Duration = Duration::from_secs(5);
+
+static TIMINGS: Mutex<Vec<(String, Duration)>> = Mutex::new(Vec::new());
+
+static ALWAYS: AtomicBool = AtomicBool::new(false);
+
+/// Makes `print_summary` print the summary even for quick invocations (`--timings-summary`).
+pub fn always_summarize() {
+    ALWAYS.store(true, Ordering::Relaxed);
+}
+
+pub fn add(label: &str, duration: Duration) {
+    TIMINGS.lock().unwrap().push((label.to_owned(), duration));
+}
+
+/// Adds up the durations by label, longest first.
+fn summarize(timings: &[(String, Duration)]) -> Vec<(&str, Duration, usize)> {
+    let mut summary: Vec<(&str, Duration, usize)> = Vec::new();
+    for (label, duration) in timings {
+        match summary.iter_mut().find(|(l, ..)| l == label) {
+            Some((_, total, count)) => {
+                *total += *duration;
+                *count += 1;
+            }
+            None => summary.push((label, *duration, 1)),
+        }
+    }
+    summary.sort_by_key(|&(_, total, _)| std::cmp::Reverse(total));
+    summary
+}
+
+/// How long the commands took by label (added up), longest first.
+pub fn totals() -> Vec<(String, Duration)> {
+    let timings = TIMINGS.lock().unwrap();
+    summarize(&timings).into_iter().map(|(label, total, _)| (label.to_owned(), total)).collect()
+}
+
+/// Prints how long the commands took, if the invocation (which took `total`) was not quick.
+/// Commands that ran in parallel are added up, so this can be more than `total`.
+pub fn print_summary(total: Duration) {
+    if total < SUMMARY_THRESHOLD && !ALWAYS.load(Ordering::Relaxed) {
+        return;
+    }
+    let timings = TIMINGS.lock().unwrap();
+    let summary = summarize(&timings);
+    if summary.is_empty() {
+        return;
+    }
+    let width = summary.iter().map(|(label, ..)| label.len()).max().unwrap();
+    eprintln!("\nTimings (total: {}):", format_duration(total));
+    for (label, duration, count) in summary {
+        let count = if count > 1 { format!(" ({count}x)") } else { String::new() };
+        eprintln!("  {label:width$}  {:>8}{count}", format_duration(duration));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary() {
+        let secs = Duration::from_secs;
+        let timings = [
+            ("query the toolchain".to_owned(), secs(1)),
+            ("build miri".to_owned(), secs(40)),
+            (OTHER.to_owned(), secs(2)),
+            ("query the toolchain".to_owned(), secs(2)),
+        ];
+        assert_eq!(
+            summarize(&timings),
+            [("build miri", secs(40), 1), ("query the toolchain", secs(3), 2), (OTHER, secs(2), 1)]
+        );
+    }
+}
@@ -1,252 +1,2922 @@
+use std::cell::OnceCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
 use std::ffi::{OsStr, OsString};
+use std::fmt;
 use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::thread;
+use std::time::Duration;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use dunce::canonicalize;
 use path_macro::path;
-use xshell::{cmd, Shell};
+use xshell::Shell;
 
-pub fn miri_dir() -> std::io::Result<PathBuf> {
-    const MIRI_SCRIPT_ROOT_DIR: &str = env!("CARGO_MANIFEST_DIR");
-    Ok(canonicalize(MIRI_SCRIPT_ROOT_DIR)?.parent().unwrap().into())
+use crate::logfile::{self, LogLevel};
+use crate::output::{self, note, plain, status, warning, ColorChoice};
+use crate::record::{self, cmd, is_dry_run, skip_in_dry_run, Cmd};
+use crate::tee::{self, CommandFailed, TimedOut};
+use crate::{annotations, cmdline, cross, junit, load, metrics, sarif};
+use crate::{FormatOutcome, GlobalArgs};
+
+/// The root of the Miri checkout the script was built from.
+pub fn miri_dir() -> Result<PathBuf> {
+    miri_dir_of(Path::new(env!("CARGO_MANIFEST_DIR")))
+}
+
+/// The Miri checkout containing `script_dir`, which is where the `miri-script` crate is.
+fn miri_dir_of(script_dir: &Path) -> Result<PathBuf> {
+    const EXPECTED: &str = "the script expects to be in the `miri-script` dir of a Miri checkout";
+    let script_dir = match canonicalize(script_dir) {
+        Ok(dir) => dir,
+        // This happens on some network drives, and with long paths on Windows (unless they have
+        // been enabled). We can do without, as long as nobody else canonicalizes paths either.
+        Err(err) => {
+            let dir = std::path::absolute(script_dir).with_context(|| {
+                format!("failed to find the script's dir, {}", script_dir.display())
+            })?;
+            static WARNED: AtomicBool = AtomicBool::new(false);
+            if !WARNED.swap(true, Ordering::Relaxed) {
+                warning!(
+                    "failed to canonicalize {} ({err}), using it as it is; paths may be shown \
+                    spelled differently than usual",
+                    dir.display()
+                );
+            }
+            dir
+        }
+    };
+    let Some(miri_dir) = script_dir.parent() else {
+        bail!("{} has no parent dir; {EXPECTED}", script_dir.display());
+    };
+    // Every checkout has this file, and all the syncs need it.
+    if !miri_dir.join("rust-version").is_file() {
+        bail!(
+            "{} is not a Miri checkout (it has no `rust-version`); {EXPECTED}",
+            miri_dir.display()
+        );
+    }
+    Ok(miri_dir.to_owned())
+}
+
+/// `path` relative to `base`, if it is inside of it. On Windows, this ignores the case, like the
+/// file system does: a `base` we could not canonicalize might be spelled differently.
+pub fn relative_to<'a>(path: &'a Path, base: &Path) -> Option<&'a Path> {
+    if let Ok(relative) = path.strip_prefix(base) {
+        return Some(relative);
+    }
+    if !cfg!(windows) {
+        return None;
+    }
+    let mut components = path.components();
+    for base in base.components() {
+        if !components.next()?.as_os_str().eq_ignore_ascii_case(base.as_os_str()) {
+            return None;
+        }
+    }
+    Some(components.as_path())
+}
+
+/// The dir in the target dir that holds the Miri sysroots we build.
+pub const SYSROOTS_DIR: &str = "miri-sysroots";
+
+/// Where we keep the Miri sysroot described by `fingerprint` (what it was built with): one dir
+/// per compiler and target, so that different toolchains do not keep rebuilding each other's.
+pub fn sysroot_dir(target_dir: &Path, fingerprint: &str) -> PathBuf {
+    path!(target_dir / SYSROOTS_DIR / format!("{:016x}", stable_hash(fingerprint)))
+}
+
+/// FNV-1a, for hashes that we store: unlike `DefaultHasher`, this stays the same when `./miri`
+/// gets built by another rustc.
+fn stable_hash(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |hash, b| (hash ^ u64::from(b)).wrapping_mul(0x100000001b3))
+}
+
+/// The variable to set to how many seconds building the sysroot may take (0 for no limit).
+pub const SYSROOT_TIMEOUT_VAR: &str = "MIRI_SCRIPT_SYSROOT_TIMEOUT";
+/// Building the sysroot usually takes a minute or two; when it takes this long, it hangs.
+const SYSROOT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+/// The file in a sysroot we built that says what it was built with.
+const SYSROOT_FINGERPRINT_FILE: &str = "miri-script-fingerprint";
+
+/// Why a Miri sysroot is not the one we want.
+#[derive(Debug, PartialEq)]
+pub enum SysrootProblem {
+    Missing,
+    /// There are no libraries for the target in it.
+    NoTarget(String),
+    /// It does not say what it was built with: we did not build it (`cargo miri setup` did), or
+    /// our build of it was interrupted.
+    Unstamped,
+    /// We built it with something else: how what it was built with differs from what we use, line
+    /// by line.
+    Stale(Vec<(String, String)>),
+}
+
+impl fmt::Display for SysrootProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SysrootProblem::Missing => write!(f, "it does not exist"),
+            SysrootProblem::NoTarget(target) => write!(f, "it has no libraries for {target}"),
+            SysrootProblem::Unstamped => write!(f, "it does not say what it was built with"),
+            SysrootProblem::Stale(changes) => {
+                write!(f, "it was built with something else:")?;
+                for (built, now) in changes {
+                    write!(f, "\n    built with: {built}\n    now using:  {now}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// What is wrong with the Miri sysroot in `dir`, for a sysroot for `target` that `fingerprint`
+/// (see `MiriEnv::sysroot_fingerprint`) describes. Whether we reuse a sysroot we built and
+/// whether we warn about `MIRI_SYSROOT` both go by this.
+pub fn sysroot_problem(dir: &Path, target: &str, fingerprint: &str) -> Option<SysrootProblem> {
+    if !dir.is_dir() {
+        return Some(SysrootProblem::Missing);
+    }
+    if !path!(dir / "lib" / "rustlib" / target / "lib").is_dir() {
+        return Some(SysrootProblem::NoTarget(target.to_owned()));
+    }
+    let Ok(built) = std::fs::read_to_string(path!(dir / SYSROOT_FINGERPRINT_FILE)) else {
+        return Some(SysrootProblem::Unstamped);
+    };
+    let (built, now): (Vec<&str>, Vec<&str>) =
+        (built.lines().collect(), fingerprint.lines().collect());
+    let changes: Vec<(String, String)> = (0..built.len().max(now.len()))
+        .map(|i| {
+            (built.get(i).copied().unwrap_or_default(), now.get(i).copied().unwrap_or_default())
+        })
+        .filter(|(built, now)| built != now)
+        .map(|(built, now)| (built.to_owned(), now.to_owned()))
+        .collect();
+    (!changes.is_empty()).then_some(SysrootProblem::Stale(changes))
+}
+
+/// The dir in the target dir where `MiriEnv::build_if_changed` remembers what it built.
+pub const BUILD_FINGERPRINTS_DIR: &str = "miri-script-builds";
+
+/// Where cargo puts the binary `bin` when building with `cargo_extra_flags`.
+pub fn bin_artifact(target_dir: &Path, cargo_extra_flags: &[String], bin: &str) -> PathBuf {
+    let profile = match arg_flag_value(cargo_extra_flags, "--profile") {
+        Some(profile) if profile == "dev" => "debug".into(),
+        Some(profile) => profile,
+        None if ArgQuery::flag(&["--release", "-r"]).is_present(cargo_extra_flags) =>
+            "release".into(),
+        None => "debug".into(),
+    };
+    match arg_flag_value(cargo_extra_flags, "--target") {
+        // Cross builds go to a subdir for the target.
+        Some(target) => {
+            let suffix = cross::exe_suffix(&target.to_string_lossy());
+            path!(target_dir / target / profile / format!("{bin}{suffix}"))
+        }
+        None => path!(target_dir / profile / format!("{bin}{}", std::env::consts::EXE_SUFFIX)),
+    }
+}
+
+/// Takes an exclusive lock on the file `path` (creating it), which is held until the returned
+/// file is dropped. This also waits for other threads of this process, not just for other
+/// processes.
+pub fn lock_file(path: &Path) -> Result<std::fs::File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let file = std::fs::File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(std::fs::TryLockError::WouldBlock) => {
+            status!("Waiting for the lock on {}...", path.display());
+            file.lock().with_context(|| format!("failed to lock {}", path.display()))?;
+        }
+        Err(std::fs::TryLockError::Error(err)) =>
+            return Err(err).with_context(|| format!("failed to lock {}", path.display())),
+    }
+    Ok(file)
+}
+
+/// Writes a report (`what`, like `the JUnit report`) of a run that went like `result` to `path`,
+/// also when the run failed: the report should show why. If both fail, the run's error wins.
+pub fn write_report(path: &Path, what: &str, contents: String, result: Result<()>) -> Result<()> {
+    if skip_in_dry_run(format_args!("write {what} to {}", path.display())) {
+        return result;
+    }
+    let written = std::fs::write(path, contents)
+        .with_context(|| format!("failed to write {what} to {}", path.display()));
+    match (result, written) {
+        (Err(err), Err(write_err)) => {
+            warning!("{write_err:#}");
+            Err(err)
+        }
+        (result, written) => result.and(written),
+    }
+}
+
+/// Moves the file `from` to `to`. A rename cannot cross file systems (the target dir may well be
+/// on another one than the checkout), so then this copies the file and removes the original.
+pub fn move_file(from: &Path, to: &Path) -> Result<()> {
+    move_file_with(from, to, |from, to| std::fs::rename(from, to))
+}
+
+fn move_file_with(
+    from: &Path,
+    to: &Path,
+    rename: impl FnOnce(&Path, &Path) -> std::io::Result<()>,
+) -> Result<()> {
+    let context = || format!("failed to move {} to {}", from.display(), to.display());
+    match rename(from, to) {
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+            std::fs::copy(from, to).with_context(context)?;
+            std::fs::remove_file(from).with_context(context)
+        }
+        res => res.with_context(context),
+    }
+}
+
+/// Some ID of the file system `path` is on, so that we can tell whether two paths are on the
+/// same one. For a path that does not exist (yet), this is the file system of the closest
+/// ancestor that does.
+fn filesystem_id(path: &Path) -> std::io::Result<u64> {
+    let path = path.ancestors().find(|dir| dir.exists()).unwrap_or(path);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Ok(std::fs::metadata(path)?.dev())
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        use std::os::windows::io::AsRawHandle;
+
+        use windows_sys::Win32::Foundation::HANDLE;
+        use windows_sys::Win32::Storage::FileSystem::{
+            GetVolumeInformationByHandleW, FILE_FLAG_BACKUP_SEMANTICS,
+        };
+
+        // Without this flag, we could not open a dir.
+        let file = std::fs::File::options()
+            .read(true)
+            .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+            .open(path)?;
+        let mut serial = 0;
+        let null = std::ptr::null_mut();
+        // SAFETY: the handle is valid, and we only ask for the serial number.
+        let ok = unsafe {
+            GetVolumeInformationByHandleW(
+                file.as_raw_handle() as HANDLE,
+                null,
+                0,
+                &mut serial,
+                null.cast(),
+                null.cast(),
+                null,
+                0,
+            )
+        };
+        if ok == 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(serial.into())
+    }
+}
+
+/// Whether `a` and `b` are on the same file system, or `None` if we cannot tell.
+pub fn same_filesystem(a: &Path, b: &Path) -> Option<bool> {
+    Some(filesystem_id(a).ok()? == filesystem_id(b).ok()?)
+}
+
+/// A temporary directory that is removed when this is dropped (also if we bail out), unless we
+/// were asked to keep it.
+pub struct TempDir {
+    pub path: PathBuf,
+    pub keep: bool,
+}
+
+impl TempDir {
+    /// Creates a fresh directory `name` in the system's temp dir.
+    pub fn new(name: &str, keep: bool) -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        if skip_in_dry_run(format_args!("create {}", path.display())) {
+            return Ok(TempDir { path, keep });
+        }
+        if path.exists() {
+            std::fs::remove_dir_all(&path)?;
+        }
+        std::fs::create_dir_all(&path)
+            .with_context(|| format!("failed to create {}", path.display()))?;
+        Ok(TempDir { path, keep })
+    }
+}
+
+/// A git worktree with a detached checkout of some revision, which is removed when this is dropped
+/// (also if we bail out).
+pub struct Worktree {
+    /// Where the checkout is.
+    pub dir: PathBuf,
+    /// The checkout this is a worktree of.
+    repo: PathBuf,
+}
+
+impl Worktree {
+    /// Checks out `rev` of the repo `sh` is in at `dir`, replacing a worktree that is left over
+    /// there.
+    pub fn add(sh: &Shell, dir: PathBuf, rev: &str) -> Result<Worktree> {
+        let repo = PathBuf::from(cmd!(sh, "git rev-parse --show-toplevel").read()?);
+        let worktree = Worktree { dir, repo };
+        if worktree.dir.exists() {
+            worktree.remove();
+        }
+        let dir = &worktree.dir;
+        cmd!(sh, "git worktree add --detach --quiet {dir} {rev}")
+            .label("check out the revision to compare with")
+            .run()?;
+        Ok(worktree)
+    }
+
+    fn remove(&self) {
+        if skip_in_dry_run(format_args!("remove the worktree {}", self.dir.display())) {
+            return;
+        }
+        let removed = std::process::Command::new("git")
+            .arg("-C")
+            .arg(&self.repo)
+            .args(["worktree", "remove", "--force"])
+            .arg(&self.dir)
+            .output();
+        if !removed.is_ok_and(|output| output.status.success()) {
+            // Not a worktree (any more) as far as git is concerned; then it is just a dir.
+            let _ = std::fs::remove_dir_all(&self.dir);
+            let _ = std::process::Command::new("git")
+                .arg("-C")
+                .arg(&self.repo)
+                .args(["worktree", "prune"])
+                .output();
+        }
+    }
+}
+
+impl Drop for Worktree {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if is_dry_run() {
+            return;
+        }
+        if self.keep {
+            note!("keeping {}", self.path.display());
+        } else if let Err(err) = std::fs::remove_dir_all(&self.path) {
+            warning!("failed to remove {}: {err}", self.path.display());
+        }
+    }
 }
 
 /// Queries the active toolchain for the Miri dir.
 pub fn active_toolchain() -> Result<String> {
-    let sh = Shell::new()?;
-    sh.change_dir(miri_dir()?);
-    let stdout = cmd!(sh, "rustup show active-toolchain").read()?;
-    Ok(stdout.split_whitespace().next().context("Could not obtain active Rust toolchain")?.into())
+    Ok(crate::rustup::show()?.active_toolchain)
+}
+
+/// How often we retry a download that failed for reasons that are not our fault.
+pub const DOWNLOAD_RETRIES: u32 = 2;
+/// How long we wait before the first retry of a download.
+pub const DOWNLOAD_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Runs `f`, which does something described by `what`. If it fails with an error that `classify`
+/// says could go away, tries again up to `retries` times: first after `backoff`, and then waiting
+/// twice as long every time. Only use this for things like network operations, where trying
+/// again can help; a failing build should just fail.
+pub fn retry<T>(
+    retries: u32,
+    backoff: Duration,
+    what: &str,
+    classify: impl Fn(&anyhow::Error) -> bool,
+    mut f: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut errors: Vec<anyhow::Error> = Vec::new();
+    let mut delay = backoff;
+    loop {
+        let err = match f() {
+            Ok(x) => return Ok(x),
+            Err(err) => err,
+        };
+        let attempt = errors.len() + 1;
+        if attempt > retries as usize || !classify(&err) {
+            if errors.is_empty() {
+                return Err(err);
+            }
+            errors.push(err);
+            let history = errors
+                .iter()
+                .enumerate()
+                .map(|(i, err)| format!("attempt {}: {err:#}", i + 1))
+                .collect::<Vec<_>>()
+                .join("\n");
+            bail!("{what} failed after {} attempts:\n{history}", errors.len());
+        }
+        warning!("{what} failed (attempt {attempt} of {}): {err:#}", retries + 1);
+        status!("Retrying in {}s...", delay.as_secs());
+        thread::sleep(delay);
+        delay *= 2;
+        errors.push(err);
+    }
+}
+
+/// Whether a command that downloads things (rustup, `cargo install`) failed in a way that looks
+/// like the server or the network had a hiccup. For that, it has to have been run with
+/// `Cmd::tee` or with a timeout, so that we can look at its output.
+pub fn is_download_hiccup(err: &anyhow::Error) -> bool {
+    // Whatever hung will most likely hang again, and we already waited for a long time.
+    if err.is::<TimedOut>() {
+        return false;
+    }
+    let output = err.downcast_ref::<CommandFailed>().map_or("", |failed| &failed.output);
+    let msg = format!("{err:#}\n{output}").to_lowercase();
+    const HICCUPS: &[&str] = &[
+        "could not download file",
+        "failed to download",
+        "spurious network error",
+        "error sending request",
+        "connection reset",
+        "timed out",
+        "timeout was reached",
+        "could not resolve host",
+        "dns error",
+        "unexpected eof",
+        "500 internal server error",
+        "502 bad gateway",
+        "503 service unavailable",
+        "504 gateway timeout",
+    ];
+    HICCUPS.iter().any(|hiccup| msg.contains(hiccup))
+}
+
+/// The lock file that cargo says it cannot update because of `--locked`, if `output` says so.
+fn outdated_lock_file(output: &str) -> Option<&str> {
+    output.lines().find_map(|line| {
+        // Older cargos put it another way.
+        let (_, rest) =
+            line.split_once("cannot update the lock file ").or(line.split_once("the lock file "))?;
+        let (file, _) = rest
+            .split_once(" because --locked was passed")
+            .or(rest.split_once(" needs to be updated but --locked was passed"))?;
+        Some(file)
+    })
+}
+
+/// How much the cargo commands we run say, and how much we say about running them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Verbosity {
+    /// `--quiet`: the commands and their output are only shown if they fail.
+    Quiet,
+    #[default]
+    Normal,
+    /// `-v`.
+    Verbose,
+}
+
+impl Verbosity {
+    /// What tells cargo, unless `args` (the other arguments of the cargo command) already say how
+    /// much it should say.
+    fn cargo_flag(self, args: &[impl AsRef<OsStr>]) -> Option<&'static str> {
+        if ArgQuery::flag(&["-q", "--quiet", "-v", "--verbose", "-vv"]).is_present(args) {
+            return None;
+        }
+        match self {
+            Verbosity::Quiet => Some("--quiet"),
+            Verbosity::Normal => None,
+            Verbosity::Verbose => Some("-v"),
+        }
+    }
+}
+
+/// Where the toolchain used by a `MiriEnv` came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ToolchainSource {
+    /// A `+toolchain` first argument on the command line.
+    CommandLine,
+    /// The `RUSTUP_TOOLCHAIN` environment variable.
+    EnvVar,
+    /// Whatever `rustup show` reports as active for the Miri dir.
+    Rustup,
+    /// A locally built rustc given via `--rustc` or `MIRI_SCRIPT_RUSTC`.
+    LocalRustc,
+}
+
+impl std::fmt::Display for ToolchainSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ToolchainSource::CommandLine => "`+toolchain` command-line argument",
+            ToolchainSource::EnvVar => "RUSTUP_TOOLCHAIN environment variable",
+            ToolchainSource::Rustup => "rustup active toolchain",
+            ToolchainSource::LocalRustc => "`--rustc`/MIRI_SCRIPT_RUSTC",
+        })
+    }
+}
+
+/// Determines the toolchain to use. In order of priority, this is the `+toolchain` given on the
+/// command line, the `RUSTUP_TOOLCHAIN` env var, and finally the active rustup toolchain.
+pub fn resolve_toolchain(cli_toolchain: Option<&str>) -> Result<(String, ToolchainSource)> {
+    let env_toolchain = Ambient::get().rustup_toolchain.clone();
+    if let Some(toolchain) = cli_toolchain {
+        if let Some(env_toolchain) = env_toolchain.filter(|t| t != toolchain) {
+            note!(
+                "using `+{toolchain}` from the command line, ignoring RUSTUP_TOOLCHAIN={env_toolchain}"
+            );
+        }
+        return Ok((toolchain.to_owned(), ToolchainSource::CommandLine));
+    }
+    if let Some(toolchain) = env_toolchain {
+        return Ok((toolchain, ToolchainSource::EnvVar));
+    }
+    Ok((active_toolchain()?, ToolchainSource::Rustup))
+}
+
+/// Returns the locally built rustc to use instead of a rustup toolchain, if any.
+pub fn local_rustc(global: &GlobalArgs) -> Option<PathBuf> {
+    global.rustc.clone().or_else(|| Ambient::get().rustc.clone())
+}
+
+/// Strips a leading `+toolchain` argument (as in `./miri +nightly test`) and returns the toolchain.
+pub fn strip_toolchain_arg(
+    args: &mut std::iter::Peekable<impl Iterator<Item = OsString>>,
+) -> Result<Option<String>> {
+    let Some(toolchain) = args.peek().and_then(|arg| arg.to_str()?.strip_prefix('+')) else {
+        return Ok(None);
+    };
+    if toolchain.is_empty() {
+        bail!("`+` must be followed by a toolchain name");
+    }
+    let toolchain = toolchain.to_owned();
+    args.next().unwrap();
+    Ok(Some(toolchain))
+}
+
+/// Splits a list of flags given in an environment variable.
+///
+/// Without quote characters, this splits on whitespace, so that the flags can also be on several
+/// lines (as they often are in CI configs). If there are quotes, the flags are split with shell
+/// rules instead (including backslash escapes), so that flags containing spaces can be passed. We
+/// do not use shell rules by default since that would interpret the backslashes in Windows paths.
+/// Like cargo, we take a blank value as no flags, and so we do for one that is just quotes (as in
+/// `MIRIFLAGS='""'`).
+///
+/// Unlike `split_on_spaces`, this is not for variables that cargo splits as well.
+pub fn flagsplit(flags: &str) -> Result<Vec<String>> {
+    if !flags.contains(['"', '\'']) {
+        return Ok(flags.split_whitespace().map(str::to_owned).collect());
+    }
+    // The shell rules do not take a `\r` as whitespace.
+    let split = shell_words::split(&flags.replace("\r\n", "\n"))
+        .with_context(|| format!("failed to split flags `{flags}`"))?;
+    if split.iter().all(String::is_empty) {
+        return Ok(Vec::new());
+    }
+    Ok(split)
 }
 
-pub fn flagsplit(flags: &str) -> Vec<String> {
+/// Splits flags on spaces, exactly like cargo does for `RUSTFLAGS`: other whitespace stays part of
+/// the flags.
+fn split_on_spaces(flags: &str) -> Vec<String> {
     // This code is taken from `RUSTFLAGS` handling in cargo.
     flags.split(' ').map(str::trim).filter(|s| !s.is_empty()).map(str::to_string).collect()
 }
 
+/// rustc flags that take a value, which can be given as a separate argument.
+const RUSTC_FLAGS_WITH_VALUE: &[&str] = &[
+    "-A",
+    "-C",
+    "-D",
+    "-F",
+    "-L",
+    "-W",
+    "-Z",
+    "-l",
+    "--allow",
+    "--cap-lints",
+    "--cfg",
+    "--check-cfg",
+    "--crate-name",
+    "--crate-type",
+    "--deny",
+    "--edition",
+    "--emit",
+    "--extern",
+    "--forbid",
+    "--force-warn",
+    "--remap-path-prefix",
+    "--sysroot",
+    "--target",
+    "--warn",
+];
+
+/// A rustc flag (and its value, if it is passed as a separate argument), for `merge_flags`.
+struct RustcFlag<'a> {
+    args: &'a [OsString],
+    /// The flag name and value, in a normalized form: `-Wfoo`, `-W foo` and `--warn=foo` all become
+    /// `("-W", "foo")`. `None` for arguments that are not flags, and for those that are not valid
+    /// UTF-8.
+    key: Option<(&'a str, &'a str)>,
+}
+
+impl<'a> RustcFlag<'a> {
+    fn group(args: &'a [OsString]) -> Vec<Self> {
+        let mut flags = Vec::new();
+        let mut i = 0;
+        while i < args.len() {
+            let Some(arg) = args[i].to_str() else {
+                flags.push(RustcFlag { args: &args[i..i + 1], key: None });
+                i += 1;
+                continue;
+            };
+            let (len, key) = if RUSTC_FLAGS_WITH_VALUE.contains(&arg) && i + 1 < args.len() {
+                (2, args[i + 1].to_str().map(|value| (arg, value)))
+            } else if let Some((name, value)) = arg
+                .split_once('=')
+                .filter(|(name, _)| name.starts_with("--") && RUSTC_FLAGS_WITH_VALUE.contains(name))
+            {
+                (1, Some((name, value)))
+            } else if let Some(name) = arg.get(..2).filter(|n| RUSTC_FLAGS_WITH_VALUE.contains(n)) {
+                (1, Some((name, &arg[2..])))
+            } else if arg.starts_with('-') {
+                (1, Some((arg, "")))
+            } else {
+                (1, None)
+            };
+            let key = key.map(|(name, value)| {
+                let name = match name {
+                    "--allow" => "-A",
+                    "--deny" => "-D",
+                    "--forbid" => "-F",
+                    "--warn" => "-W",
+                    name => name,
+                };
+                (name, value)
+            });
+            flags.push(RustcFlag { args: &args[i..i + len], key });
+            i += len;
+        }
+        flags
+    }
+
+    /// If this flag sets a lint level, returns the level and the lint name.
+    fn lint(&self) -> Option<(&'a str, String)> {
+        let (name, lint) = self.key?;
+        // rustc treats `-` and `_` in lint names the same.
+        ["-A", "-D", "-F", "-W", "--force-warn"]
+            .contains(&name)
+            .then(|| (name, lint.replace('-', "_")))
+    }
+
+    /// Whether giving this flag once more changes nothing, so that we can drop a repeat. That is
+    /// not so for the flags that add something each time, like `-L`, `-l`, `--cfg`, `--extern` or
+    /// `-C link-arg`, and we do not assume it for flags we do not know.
+    fn repeat_is_noop(&self) -> bool {
+        let Some((name, value)) = self.key else {
+            return false;
+        };
+        let option = value.split_once('=').map_or(value, |(option, _)| option);
+        match name {
+            "-C" => !matches!(option, "link-arg" | "link-args" | "llvm-args" | "passes" | "remark"),
+            "-Z" => !matches!(option, "crate-attr" | "llvm-plugins"),
+            "-A" | "-D" | "-F" | "-W" | "--force-warn" | "--cap-lints" | "--crate-name"
+            | "--edition" | "--sysroot" | "--target" | "-O" | "-g" => true,
+            _ => false,
+        }
+    }
+}
+
+/// Merges our rustc flags with those given by the user, in this order. Repeats of a flag with the
+/// same value are removed (also if they are spelled differently) if they are no-ops, see
+/// `RustcFlag::repeat_is_noop`. If both lists set a level for the same lint, only the last one is
+/// kept (which is what rustc would use anyway); if that changes the level, a note says so (once).
+/// Apart from that, the order of the flags is preserved.
+pub fn merge_flags(ours: &[impl AsRef<OsStr>], theirs: &[impl AsRef<OsStr>]) -> Vec<OsString> {
+    static NOTED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+    let ours: Vec<OsString> = ours.iter().map(|f| f.as_ref().to_owned()).collect();
+    let theirs: Vec<OsString> = theirs.iter().map(|f| f.as_ref().to_owned()).collect();
+    let mut merged: Vec<RustcFlag<'_>> = Vec::new();
+    for flag in RustcFlag::group(&ours).into_iter().chain(RustcFlag::group(&theirs)) {
+        if flag.repeat_is_noop() && merged.iter().any(|f| f.key == flag.key) {
+            continue;
+        }
+        if let Some((level, lint)) = flag.lint() {
+            let same_lint = |f: &RustcFlag<'_>| f.lint().is_some_and(|(_, l)| l == lint);
+            if let Some(pos) = merged.iter().position(same_lint) {
+                let old = merged.remove(pos);
+                let message = format!(
+                    "`{}` overrides `{}` in the rustc flags",
+                    join_flags(flag.args, " ").to_string_lossy(),
+                    join_flags(old.args, " ").to_string_lossy()
+                );
+                if old.lint().unwrap().0 != level && NOTED.lock().unwrap().insert(message.clone()) {
+                    note!("{message}");
+                }
+            }
+        }
+        merged.push(flag);
+    }
+    merged.into_iter().flat_map(|f| f.args).cloned().collect()
+}
+
+/// `flags`, joined with `sep`.
+fn join_flags(flags: &[impl AsRef<OsStr>], sep: &str) -> OsString {
+    let mut joined = OsString::new();
+    for (i, flag) in flags.iter().enumerate() {
+        if i > 0 {
+            joined.push(sep);
+        }
+        joined.push(flag);
+    }
+    joined
+}
+
+/// The non-empty parts of `value`, split at the ASCII character `sep`.
+fn split_flags(value: &OsStr, sep: u8) -> Vec<OsString> {
+    value
+        .as_encoded_bytes()
+        .split(|&b| b == sep)
+        .filter(|part| !part.is_empty())
+        // SAFETY: The parts are split off at an ASCII character, from `as_encoded_bytes`.
+        .map(|part| unsafe { OsStr::from_encoded_bytes_unchecked(part) }.to_owned())
+        .collect()
+}
+
+/// Returns the environment variable (and its value) to pass the given flags to cargo. We prefer
+/// `RUSTFLAGS`, but if a flag contains a space, that cannot be represented there, so we use
+/// `CARGO_ENCODED_RUSTFLAGS` instead.
+pub fn rustflags_env(flags: &[OsString]) -> (&'static str, OsString) {
+    if flags.iter().any(|f| f.as_encoded_bytes().contains(&b' ')) {
+        ("CARGO_ENCODED_RUSTFLAGS", join_flags(flags, "\x1f"))
+    } else {
+        ("RUSTFLAGS", join_flags(flags, " "))
+    }
+}
+
+/// The flags in the value of `var`, one of the variables `rustflags_env` picks.
+fn decode_rustflags(var: &str, value: &OsStr) -> Vec<OsString> {
+    match (var, value.to_str()) {
+        ("CARGO_ENCODED_RUSTFLAGS", _) => split_flags(value, b'\x1f'),
+        (_, Some(value)) => split_on_spaces(value).into_iter().map(Into::into).collect(),
+        (_, None) => split_flags(value, b' '),
+    }
+}
+
+/// `cmd` with the rustc `flags`, instead of those in the shell it runs in.
+pub fn with_rustflags<'a>(cmd: Cmd<'a>, flags: &[OsString]) -> Cmd<'a> {
+    cmd.env_remove("RUSTFLAGS").env("CARGO_ENCODED_RUSTFLAGS", join_flags(flags, "\x1f"))
+}
+
+/// Like `rustflags_env`, for `RUSTDOCFLAGS`.
+pub fn rustdocflags_env(flags: &[OsString]) -> (&'static str, OsString) {
+    match rustflags_env(flags) {
+        ("RUSTFLAGS", value) => ("RUSTDOCFLAGS", value),
+        (_, value) => ("CARGO_ENCODED_RUSTDOCFLAGS", value),
+    }
+}
+
+/// Returns the value of the first occurrence of `flag` in `args`, either as `flag value` or as
+/// `flag=value`. Stops searching at `--`.
 pub fn arg_flag_value(
     args: impl IntoIterator<Item = impl AsRef<OsStr>>,
     flag: &str,
 ) -> Option<OsString> {
-    let mut args = args.into_iter();
-    while let Some(arg) = args.next() {
+    ArgQuery::new(&[flag]).value(args)
+}
+
+/// Where the first occurrence of `flag` in `args` is, as the range of indices `(start, end)` it
+/// covers with its value: one argument for `flag=value` (or for `flag` without a value, as the last
+/// argument or right before `--`), two for `flag value`. Stops searching at `--`.
+pub fn arg_flag_span(args: &[impl AsRef<OsStr>], flag: &str) -> Option<(usize, usize)> {
+    for (i, arg) in args.iter().enumerate() {
         let arg = arg.as_ref();
         if arg == "--" {
             return None;
         }
-        let Some(arg) = arg.to_str() else {
-            // Skip non-UTF-8 arguments.
-            continue;
-        };
         if arg == flag {
-            // Next one is the value.
-            return Some(args.next()?.as_ref().to_owned());
-        } else if let Some(val) = arg.strip_prefix(flag).and_then(|s| s.strip_prefix("=")) {
-            return Some(val.to_owned().into());
+            let has_value = args.get(i + 1).is_some_and(|val| val.as_ref() != "--");
+            return Some((i, if has_value { i + 2 } else { i + 1 }));
+        }
+        if strip_flag_eq(arg, flag).is_some() {
+            return Some((i, i + 1));
         }
     }
     None
 }
 
-/// Some extra state we track for building Miri, such as the right RUSTFLAGS.
-pub struct MiriEnv {
-    /// miri_dir is the root of the miri repository checkout we are working in.
-    pub miri_dir: PathBuf,
-    /// active_toolchain is passed as `+toolchain` argument to cargo/rustc invocations.
-    pub toolchain: String,
-    /// Extra flags to pass to cargo.
-    pub cargo_extra_flags: Vec<String>,
-    /// The rustc sysroot
-    pub sysroot: PathBuf,
-    /// The shell we use.
-    pub sh: Shell,
+/// Replaces the value of the first occurrence of `flag` in `args` (as `flag value` or as
+/// `flag=value`), if there is one. Stops searching at `--`.
+fn set_flag_value(args: &mut [String], flag: &str, value: &str) {
+    match arg_flag_span(args, flag) {
+        Some((start, end)) if end - start == 2 => args[start + 1] = value.to_owned(),
+        Some((start, _)) if args[start] != flag => args[start] = format!("{flag}={value}"),
+        // Without a value to replace.
+        _ => {}
+    }
 }
 
-impl MiriEnv {
-    pub fn new() -> Result<Self> {
-        let toolchain = active_toolchain()?;
-        let sh = Shell::new()?; // we are preserving the current_dir on this one, so paths resolve properly!
-        let miri_dir = miri_dir()?;
-
-        let sysroot = cmd!(sh, "rustc +{toolchain} --print sysroot").read()?.into();
-        let target_output = cmd!(sh, "rustc +{toolchain} --version --verbose").read()?;
-        let rustc_meta = rustc_version::version_meta_for(&target_output)?;
-        let libdir = path!(sysroot / "lib" / "rustlib" / rustc_meta.host / "lib");
-
-        // Determine some toolchain properties
-        if !libdir.exists() {
-            println!("Something went wrong determining the library dir.");
-            println!("I got {} but that does not exist.", libdir.display());
-            println!("Please report a bug at https://github.com/rust-lang/miri/issues.");
-            std::process::exit(2);
-        }
-        // Share target dir between `miri` and `cargo-miri`.
-        let target_dir = std::env::var_os("CARGO_TARGET_DIR")
-            .unwrap_or_else(|| path!(miri_dir / "target").into());
-        sh.set_var("CARGO_TARGET_DIR", target_dir);
-
-        // We configure dev builds to not be unusably slow.
-        let devel_opt_level =
-            std::env::var_os("CARGO_PROFILE_DEV_OPT_LEVEL").unwrap_or_else(|| "2".into());
-        sh.set_var("CARGO_PROFILE_DEV_OPT_LEVEL", devel_opt_level);
-
-        // Compute rustflags.
-        let rustflags = {
-            let mut flags = OsString::new();
-            // We set the rpath so that Miri finds the private rustc libraries it needs.
-            flags.push("-C link-args=-Wl,-rpath,");
-            flags.push(libdir);
-            // Enable rustc-specific lints (ignored without `-Zunstable-options`).
-            flags.push(
-                " -Zunstable-options -Wrustc::internal -Wrust_2018_idioms -Wunused_lifetimes",
-            );
-            // Add user-defined flags.
-            if let Some(value) = std::env::var_os("RUSTFLAGS") {
-                flags.push(" ");
-                flags.push(value);
+/// Returns the values of all occurrences of `flag` in `args`, in order. Stops searching at `--`.
+pub fn arg_flag_values(
+    args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    flag: &str,
+) -> Vec<OsString> {
+    ArgQuery::new(&[flag]).values(args)
+}
+
+/// Parses the value of the last occurrence of `flag` in `args`, given either as `flag value` or as
+/// `flag=value`. Stops searching at `--`.
+///
+/// If `T` is boolean-like (it accepts `true` and `false`, but not the empty string), the flag
+/// does not take a separate value: `flag` on its own means `true`, and `--no-<name>` means `false`.
+pub fn arg_flag_parse<T>(
+    args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    flag: &str,
+) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Into<anyhow::Error>,
+{
+    let is_bool =
+        "true".parse::<T>().is_ok() && "false".parse::<T>().is_ok() && "".parse::<T>().is_err();
+    let negated = flag.strip_prefix("--").map(|name| format!("--no-{name}"));
+    let mut args = args.into_iter();
+    let mut result = None;
+    while let Some(arg) = args.next() {
+        let arg = arg.as_ref();
+        if arg == "--" {
+            break;
+        }
+        let value: OsString = if arg == flag {
+            if is_bool {
+                "true".into()
+            } else {
+                let value =
+                    args.next().ok_or_else(|| anyhow!("`{flag}` must be followed by a value"))?;
+                value.as_ref().to_owned()
             }
-            flags
+        } else if let Some(negated) = negated.as_deref().filter(|n| arg == *n) {
+            if !is_bool {
+                bail!("`{negated}` is not valid since `{flag}` is not a boolean flag");
+            }
+            "false".into()
+        } else if let Some(value) = strip_flag_eq(arg, flag) {
+            value.to_owned()
+        } else {
+            continue;
         };
-        sh.set_var("RUSTFLAGS", rustflags);
-
-        // Get extra flags for cargo.
-        let cargo_extra_flags = std::env::var("CARGO_EXTRA_FLAGS").unwrap_or_default();
-        let cargo_extra_flags = flagsplit(&cargo_extra_flags);
-
-        Ok(MiriEnv { miri_dir, toolchain, sh, sysroot, cargo_extra_flags })
+        result = Some(parse_flag_value(flag, &value)?);
     }
+    Ok(result)
+}
 
-    pub fn install_to_sysroot(
-        &self,
-        path: impl AsRef<OsStr>,
-        args: impl IntoIterator<Item = impl AsRef<OsStr>>,
-    ) -> Result<()> {
-        let MiriEnv { sysroot, toolchain, cargo_extra_flags, .. } = self;
-        // Install binaries to the miri toolchain's `sysroot` so they do not interact with other toolchains.
-        cmd!(self.sh, "cargo +{toolchain} install {cargo_extra_flags...} --path {path} --force --root {sysroot} {args...}").run()?;
-        Ok(())
+/// Parses `value`, which was given for `flag`, with an error naming both if that fails.
+pub fn parse_flag_value<T>(flag: &str, value: &OsStr) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Into<anyhow::Error>,
+{
+    let Some(text) = value.to_str() else {
+        bail!("the value given for `{flag}` is not valid UTF-8: {value:?}");
+    };
+    match text.parse::<T>() {
+        Ok(value) => Ok(value),
+        // Empty values are fine for string-like types, but for everything else give a clear error
+        // rather than whatever the parser says about empty strings.
+        Err(_) if text.is_empty() => bail!("`{flag}` needs a value, but an empty one was given"),
+        Err(err) => Err(err.into()).with_context(|| format!("invalid value `{text}` for `{flag}`")),
     }
+}
 
-    pub fn build(
-        &self,
-        manifest_path: impl AsRef<OsStr>,
-        args: &[OsString],
-        quiet: bool,
-    ) -> Result<()> {
-        let MiriEnv { toolchain, cargo_extra_flags, .. } = self;
-        let quiet_flag = if quiet { Some("--quiet") } else { None };
-        // We build the tests as well, (a) to avoid having rebuilds when building the tests later
-        // and (b) to have more parallelism during the build of Miri and its tests.
-        let mut cmd = cmd!(
-            self.sh,
-            "cargo +{toolchain} build --bins --tests {cargo_extra_flags...} --manifest-path {manifest_path} {quiet_flag...} {args...}"
-        );
-        cmd.set_quiet(quiet);
-        cmd.run()?;
-        Ok(())
-    }
+/// Removes all occurrences of `flag` (both `flag value` and `flag=value`) before the first `--`,
+/// and returns their values in order.
+pub fn remove_flag(args: &mut Vec<OsString>, flag: &str) -> Vec<OsString> {
+    remove_flag_at(args, flag).1
+}
 
-    pub fn check(&self, manifest_path: impl AsRef<OsStr>, args: &[OsString]) -> Result<()> {
-        let MiriEnv { toolchain, cargo_extra_flags, .. } = self;
-        cmd!(self.sh, "cargo +{toolchain} check {cargo_extra_flags...} --manifest-path {manifest_path} --all-targets {args...}")
-            .run()?;
-        Ok(())
+/// Like `remove_flag`, but also returns the index of the first removed occurrence. Since the
+/// arguments before an occurrence stay as they are, the later ones are where it was.
+fn remove_flag_at(args: &mut Vec<OsString>, flag: &str) -> (Option<usize>, Vec<OsString>) {
+    let mut first = None;
+    let mut values = Vec::new();
+    while let Some((start, end)) = arg_flag_span(args, flag) {
+        let mut removed = args.drain(start..end);
+        let arg = removed.next().unwrap();
+        // The value is the next argument -- if there is one.
+        values.extend(removed.next().or_else(|| strip_flag_eq(&arg, flag).map(Into::into)));
+        first.get_or_insert(start);
     }
+    (first, values)
+}
 
-    pub fn clippy(&self, manifest_path: impl AsRef<OsStr>, args: &[OsString]) -> Result<()> {
-        let MiriEnv { toolchain, cargo_extra_flags, .. } = self;
-        cmd!(self.sh, "cargo +{toolchain} clippy {cargo_extra_flags...} --manifest-path {manifest_path} --all-targets {args...}")
-            .run()?;
-        Ok(())
+/// If `arg` is of the form `flag=value`, returns the value. This works on arbitrary `OsStr`s, so
+/// neither the rest of the argument nor the value need to be valid UTF-8.
+fn strip_flag_eq<'a>(arg: &'a OsStr, flag: &str) -> Option<&'a OsStr> {
+    let value = arg.as_encoded_bytes().strip_prefix(flag.as_bytes())?.strip_prefix(b"=")?;
+    // SAFETY: `value` was obtained from `as_encoded_bytes`, and split off right after `flag=`,
+    // which is a non-empty UTF-8 string.
+    Some(unsafe { OsStr::from_encoded_bytes_unchecked(value) })
+}
+
+/// Sets `flag` to `value`: the first existing occurrence is replaced (and all others are removed),
+/// or if there is none, the flag is added at the end (but before `--`).
+pub fn set_flag(args: &mut Vec<OsString>, flag: &str, value: impl Into<OsString>) {
+    let (first, _) = remove_flag_at(args, flag);
+    let pos = first.unwrap_or_else(|| args.iter().position(|a| a == "--").unwrap_or(args.len()));
+    args.splice(pos..pos, [flag.into(), value.into()]);
+}
+
+/// Splits `args` at the first `--` into our own arguments and the ones to be forwarded.
+///
+/// The first `--` itself is consumed; any later `--` are part of the forwarded arguments and are
+/// passed on literally. If there is no `--`, there are no forwarded arguments (which is different
+/// from an empty list of forwarded arguments, as in `foo --`).
+pub fn split_args(mut args: Vec<OsString>) -> (Vec<OsString>, Option<Vec<OsString>>) {
+    match args.iter().position(|a| a == "--") {
+        Some(pos) => {
+            let forwarded = args.split_off(pos + 1);
+            args.pop(); // the `--`
+            (args, Some(forwarded))
+        }
+        None => (args, None),
     }
+}
 
-    pub fn test(&self, manifest_path: impl AsRef<OsStr>, args: &[OsString]) -> Result<()> {
-        let MiriEnv { toolchain, cargo_extra_flags, .. } = self;
-        cmd!(
-            self.sh,
-            "cargo +{toolchain} test {cargo_extra_flags...} --manifest-path {manifest_path} {args...}"
-        )
-        .run()?;
-        Ok(())
+/// What `./miri test <flags>` passes on: the `--target`s among the flags before the first `--`
+/// (which the test harness does not know), and the arguments for `MiriEnv::test`, which make all
+/// the other flags go to the harness. That includes the first `--` and what follows it, so the
+/// harness gets them just like from `cargo test -- <flags>`.
+pub fn test_harness_args(flags: Vec<OsString>) -> (Vec<OsString>, Vec<OsString>) {
+    let (mut ours, forwarded) = split_args(flags);
+    let targets = remove_flag(&mut ours, "--target");
+    let mut args = vec!["--".into()];
+    args.append(&mut ours);
+    if let Some(mut forwarded) = forwarded {
+        args.push("--".into());
+        args.append(&mut forwarded);
     }
+    (targets, args)
+}
 
-    /// Receives an iterator of files.
-    /// Will format each file with the miri rustfmt config.
-    /// Does not recursively format modules.
-    pub fn format_files(
-        &self,
-        files: impl Iterator<Item = Result<PathBuf, walkdir::Error>>,
-        toolchain: &str,
-        config_path: &Path,
-        flags: &[OsString],
-    ) -> anyhow::Result<()> {
-        use itertools::Itertools;
+/// The arguments for `cargo test` to only build the tests, along with `cargo_args`, saying what
+/// it built.
+fn no_run_args(cargo_args: &[OsString]) -> Vec<OsString> {
+    ["--quiet", "--no-run", "--message-format=json-render-diagnostics"]
+        .into_iter()
+        .map(OsString::from)
+        .chain(cargo_args.iter().cloned())
+        .collect()
+}
 
-        let mut first = true;
+/// Checks that we can create files in `dir`, or (if it does not exist yet) in the closest dir
+/// above it, where it would be created, by creating one. The error says who owns the dir.
+pub fn check_writable(dir: &Path) -> Result<()> {
+    let Some(existing) = dir.ancestors().find(|dir| dir.exists()) else {
+        return Ok(());
+    };
+    let probe = path!(existing / format!(".miri-script-write-test-{}", std::process::id()));
+    match std::fs::File::create_new(&probe) {
+        Ok(_) => {
+            let _ = std::fs::remove_file(&probe);
+            Ok(())
+        }
+        Err(err) =>
+            bail!("cannot create files in {}: {err}{}", existing.display(), ownership(existing)),
+    }
+}
 
-        // Format in batches as not all our files fit into Windows' command argument limit.
-        for batch in &files.chunks(256) {
-            // Build base command.
-            let mut cmd = cmd!(
-                self.sh,
-                "rustfmt +{toolchain} --edition=2021 --config-path {config_path} --unstable-features --skip-children {flags...}"
-            );
-            if first {
-                // Log an abbreviating command, and only once.
-                eprintln!("$ {cmd} ...");
-                first = false;
-            }
-            // Add files.
-            for file in batch {
-                // Make it a relative path so that on platforms with extremely tight argument
-                // limits (like Windows), we become immune to someone cloning the repo
-                // 50 directories deep.
-                let file = file?;
-                let file = file.strip_prefix(&self.miri_dir)?;
-                cmd = cmd.arg(file);
-            }
+/// Who owns `dir` and who we are, as far as we can tell.
+#[cfg(unix)]
+fn ownership(dir: &Path) -> String {
+    use std::os::unix::fs::MetadataExt;
 
-            // Run rustfmt.
-            // We want our own error message, repeating the command is too much.
-            cmd.quiet().run().map_err(|_| anyhow!("`rustfmt` failed"))?;
-        }
+    let Ok(meta) = std::fs::metadata(dir) else {
+        return String::new();
+    };
+    // SAFETY: `getuid` cannot fail, and has no preconditions.
+    let uid = unsafe { libc::getuid() };
+    format!(
+        " (it is owned by uid {}, with mode {:o}; you are uid {uid})",
+        meta.uid(),
+        meta.mode() & 0o7777
+    )
+}
 
-        Ok(())
+#[cfg(not(unix))]
+fn ownership(dir: &Path) -> String {
+    match std::fs::metadata(dir) {
+        Ok(meta) if meta.permissions().readonly() => " (it is read-only)".into(),
+        _ => String::new(),
     }
+}
 
-    /// Run the given closure many times in parallel with access to the shell, once for each value in the `range`.
+/// What makes `cargo install` with `cargo_flags` strip the binaries (for `--config`), if it can:
+/// for the built-in profiles. A custom one would need to say what it `inherits` from, and may strip
+/// on its own terms anyway.
+pub fn strip_config(cargo_flags: &[OsString]) -> Option<String> {
+    let profile = match ArgQuery::new(&["--profile"]).value(cargo_flags) {
+        Some(profile) => profile.into_string().ok()?,
+        None if ArgQuery::flag(&["--debug"]).is_present(cargo_flags) => "dev".into(),
+        None => "release".into(),
+    };
+    matches!(&*profile, "release" | "dev").then(|| format!("profile.{profile}.strip=\"symbols\""))
+}
+
+/// Looks for a flag that can be spelled in several ways, like `-p`/`--package`.
+///
+/// An option that takes a value (see `new`) is recognized as `alias value` and as `alias=value`;
+/// a flag that does not (see `flag`) only as `alias`. Short flags are not looked for inside
+/// clusters (`-vq` does not count as `-q`) and not with a directly attached value (`-pfoo`), since
+/// we cannot know how the tool that receives these arguments interprets them.
+/// Like all our flag helpers, this stops searching at `--`, even right after an option.
+pub struct ArgQuery<'a> {
+    aliases: &'a [&'a str],
+    takes_value: bool,
+}
+
+impl<'a> ArgQuery<'a> {
+    /// An option that takes a value, like `--target`.
+    pub fn new(aliases: &'a [&'a str]) -> Self {
+        ArgQuery { aliases, takes_value: true }
+    }
+
+    /// A flag that takes no value, like `-q`/`--quiet`: the argument after it is never taken as
+    /// its value.
+    pub fn flag(aliases: &'a [&'a str]) -> Self {
+        ArgQuery { aliases, takes_value: false }
+    }
+
+    /// Determines whether any spelling of the flag is present.
+    pub fn is_present(&self, args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> bool {
+        self.occurrences(args).next().is_some()
+    }
+
+    /// Returns the value of the first occurrence of any spelling of the flag.
+    pub fn value(&self, args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Option<OsString> {
+        self.occurrences(args).flatten().next()
+    }
+
+    /// Returns the values of all occurrences of all spellings of the flag, in order.
+    pub fn values(&self, args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Vec<OsString> {
+        self.occurrences(args).flatten().collect()
+    }
+
+    /// Yields each occurrence of the flag, with its value. The value is `None` for a flag that
+    /// takes none, and for an option that is the last argument or is followed by `--`.
+    fn occurrences<'s, I: IntoIterator<Item: AsRef<OsStr>>>(
+        &'s self,
+        args: I,
+    ) -> impl Iterator<Item = Option<OsString>> + 's
+    where
+        I::IntoIter: 's,
+    {
+        let mut args = args.into_iter().peekable();
+        std::iter::from_fn(move || {
+            while let Some(arg) = args.next() {
+                let arg = arg.as_ref();
+                if arg == "--" {
+                    return None;
+                }
+                for flag in self.aliases {
+                    if arg == *flag {
+                        // Next one is the value, if we take one and it is not the end of our args.
+                        let val = args.next_if(|val| self.takes_value && val.as_ref() != "--");
+                        return Some(val.map(|val| val.as_ref().to_owned()));
+                    } else if !self.takes_value {
+                        continue;
+                    } else if let Some(val) = strip_flag_eq(arg, flag) {
+                        return Some(Some(val.to_owned()));
+                    }
+                }
+            }
+            None
+        })
+    }
+}
+
+/// Handling of `MIRIFLAGS`, consistent with how cargo-miri and our test suite interpret it.
+pub mod miriflags {
+    use std::ffi::{OsStr, OsString};
+
+    use anyhow::{bail, Result};
+
+    /// Splits `MIRIFLAGS` exactly like cargo-miri does: on spaces, without any quoting. Like
+    /// cargo-miri, we ignore the variable if it is not valid UTF-8.
+    pub fn parse(value: &OsStr) -> Vec<String> {
+        let Some(value) = value.to_str() else {
+            return Vec::new();
+        };
+        super::split_on_spaces(value)
+    }
+
+    /// Turns `flags` into a value for `MIRIFLAGS` that `parse` turns back into the same flags.
+    /// Fails if that is impossible because some flag is empty or contains a space.
+    pub fn render(flags: &[String]) -> Result<OsString> {
+        if let Some(flag) = flags.iter().find(|flag| flag.is_empty() || flag.contains(' ')) {
+            bail!("the flag {flag:?} cannot be put into MIRIFLAGS, which is split at spaces");
+        }
+        Ok(flags.join(" ").into())
+    }
+
+    /// Adds `flag` to the `existing` value of `MIRIFLAGS`. If there already are flags with the
+    /// same name (i.e., the same text before the `=`, like `-Zmiri-seed=`), the first one is
+    /// replaced and the others are removed. Fails if `flag` cannot be put into `MIRIFLAGS`.
+    pub fn with_flag(existing: &OsStr, flag: &str) -> Result<OsString> {
+        let name = flag.split_once('=').map_or(flag, |(name, _)| name);
+        let same_name = |f: &str| f.split_once('=').map_or(f, |(name, _)| name) == name;
+        let mut flags = parse(existing);
+        match flags.iter().position(|f| same_name(f)) {
+            Some(pos) => {
+                flags[pos] = flag.to_owned();
+                let mut i = 0;
+                flags.retain(|f| {
+                    i += 1;
+                    i - 1 == pos || !same_name(f)
+                });
+            }
+            None => flags.push(flag.to_owned()),
+        }
+        render(&flags)
+    }
+}
+
+/// What we asked a compiler about itself: its sysroot, and what `--version --verbose` says.
+#[derive(Clone)]
+struct RustcInfo {
+    sysroot: PathBuf,
+    /// The output of `--version --verbose`.
+    version: String,
+    host: String,
+    /// What `version` says, unless `rustc_version` could not make sense of it (with why). That
+    /// happens with custom builds and distro compilers; all we really need is `host`.
+    meta: Result<rustc_version::VersionMeta, String>,
+}
+
+/// Finds the host in the output of `rustc --version --verbose`, for when `rustc_version` cannot
+/// parse the rest.
+fn parse_host(version: &str) -> Option<&str> {
+    version.lines().find_map(|line| {
+        let host = line.strip_prefix("host:")?.trim();
+        (!host.is_empty() && !host.contains(char::is_whitespace)).then_some(host)
+    })
+}
+
+/// The answers of `rustc_info`, by toolchain and compiler. They do not change while we run, and
+/// asking again for every `MiriEnv` adds up.
+static RUSTC_INFO: Mutex<BTreeMap<(Option<String>, OsString), RustcInfo>> =
+    Mutex::new(BTreeMap::new());
+
+fn rustc_info(sh: &Shell, rustc: &OsStr, toolchain: Option<&str>) -> Result<RustcInfo> {
+    let key = (toolchain.map(ToOwned::to_owned), rustc.to_owned());
+    if let Some(info) = RUSTC_INFO.lock().unwrap().get(&key) {
+        return Ok(info.clone());
+    }
+    // Not holding the lock while asking: another thread asking at the same time just does the
+    // same work.
+    let toolchain = &toolchain.map(|t| format!("+{t}"));
+    let sysroot: PathBuf = cmd!(sh, "{rustc} {toolchain...} --print sysroot")
+        .label("query the toolchain")
+        .read()?
+        .into();
+    let version = cmd!(sh, "{rustc} {toolchain...} --version --verbose")
+        .label("query the toolchain")
+        .read()?;
+    let (host, meta) = match rustc_version::version_meta_for(&version) {
+        Ok(meta) => (meta.host.clone(), Ok(meta)),
+        Err(err) => {
+            let Some(host) = parse_host(&version) else {
+                bail!(
+                    "failed to parse what `{} --version --verbose` printed ({err}), and it names \
+                    no host:\n{version}",
+                    rustc.to_string_lossy()
+                );
+            };
+            warning!(
+                "failed to parse what `{} --version --verbose` printed ({err}); going on with just \
+                its host, `{host}`",
+                rustc.to_string_lossy()
+            );
+            (host.to_owned(), Err(err.to_string()))
+        }
+    };
+    let info = RustcInfo { sysroot, version, host, meta };
+    RUSTC_INFO.lock().unwrap().insert(key, info.clone());
+    Ok(info)
+}
+
+/// Queries the sysroot of the given rustc, and the directory inside it that contains the
+/// private rustc libraries for the host. `toolchain` is passed as `+toolchain` to `rustc`.
+pub fn rustc_sysroot_and_libdir(
+    sh: &Shell,
+    rustc: &OsStr,
+    toolchain: Option<&str>,
+) -> Result<(PathBuf, PathBuf)> {
+    let RustcInfo { sysroot, host, .. } = rustc_info(sh, rustc, toolchain)?;
+    let libdir = path!(sysroot / "lib" / "rustlib" / host / "lib");
+    Ok((sysroot, libdir))
+}
+
+/// Sets the RUSTFLAGS for building Miri against the rustc libraries in `libdir`.
+fn set_rustflags(sh: &Shell, libdir: &Path) -> Result<()> {
+    let (var, rustflags) = build_rustflags(Some(libdir))?;
+    sh.set_var(var, rustflags);
+    Ok(())
+}
+
+/// The variable to set and its value for building Miri with the rpath `rpath` (if any), with the
+/// user's RUSTFLAGS included.
+fn build_rustflags(rpath: Option<&Path>) -> Result<(&'static str, OsString)> {
+    // Add user-defined flags.
+    let theirs = match &Ambient::get().rustflags {
+        Some(flags) => decode_rustflags("RUSTFLAGS", flags),
+        None => Vec::new(),
+    };
+    let ours = match rpath {
+        Some(rpath) => miri_rustflags(rpath)?,
+        None => lint_flags(),
+    };
+    // If this picks `CARGO_ENCODED_RUSTFLAGS`, cargo ignores the user's `RUSTFLAGS`, but we
+    // have included those in our flags anyway.
+    Ok(rustflags_env(&merge_flags(&ours, &theirs)))
+}
+
+/// The rustc flags we need for building Miri against the rustc libraries in `libdir`.
+fn miri_rustflags(libdir: &Path) -> Result<Vec<OsString>> {
+    let mut flags = rpath_flags(libdir)?;
+    flags.extend(lint_flags());
+    Ok(flags)
+}
+
+/// Enable rustc-specific lints (ignored without `-Zunstable-options`).
+fn lint_flags() -> Vec<OsString> {
+    ["-Zunstable-options", "-Wrustc::internal", "-Wrust_2018_idioms", "-Wunused_lifetimes"]
+        .map(OsString::from)
+        .into()
+}
+
+/// The rustdoc flags for the doctests of Miri: rustdoc links and runs those itself, so they need
+/// the rpath as well. The lints are for the crate, not its doctests.
+fn miri_rustdocflags(libdir: &Path) -> Result<Vec<OsString>> {
+    let mut flags = rpath_flags(libdir)?;
+    flags.push("-Zunstable-options".into());
+    Ok(flags)
+}
+
+/// The flags that set the rpath to `libdir`, so that what we link finds the private rustc
+/// libraries.
+fn rpath_flags(libdir: &Path) -> Result<Vec<OsString>> {
+    // This is the one character that cargo cannot pass on in any of its RUSTFLAGS variables.
+    if let Some(component) = libdir.iter().find(|c| c.as_encoded_bytes().contains(&b'\x1f')) {
+        bail!(
+            "the rustc library dir {} cannot be passed to cargo: `{}` contains a \\x1f character",
+            libdir.display(),
+            component.to_string_lossy().escape_debug()
+        );
+    }
+    let mut flags = Vec::new();
+    // Each `-Xlinker` passes the next argument to the linker as is, so the libdir may contain
+    // spaces and commas (which `-C link-args` and `-Wl,` would split it at).
+    for arg in [OsStr::new("-rpath"), libdir.as_os_str()] {
+        for link_arg in [OsStr::new("-Xlinker"), arg] {
+            let mut flag = OsString::from("link-arg=");
+            flag.push(link_arg);
+            flags.extend(["-C".into(), flag]);
+        }
+    }
+    Ok(flags)
+}
+
+/// The rustfmt configs for the files in the Miri dir, as `MiriEnv::format_files` takes them: the
+/// ui tests have their own, which leaves more of their formatting alone, since their stderr files
+/// depend on it.
+pub fn rustfmt_configs(miri_dir: &Path) -> Vec<(PathBuf, PathBuf)> {
+    vec![
+        (path!(miri_dir / "tests"), path!(miri_dir / "tests" / "rustfmt.toml")),
+        (miri_dir.to_owned(), path!(miri_dir / "rustfmt.toml")),
+    ]
+}
+
+/// How many files we give rustfmt at a time, since not all our files fit into Windows' command
+/// argument limit.
+const RUSTFMT_BATCH_SIZE: usize = 256;
+
+/// What one rustfmt command formats: the files (see `rustfmt_batches`), with the config at
+/// `config`.
+#[derive(Debug, PartialEq)]
+struct RustfmtBatch<'a> {
+    config: &'a Path,
+    files: Vec<&'a Path>,
+}
+
+/// The rustfmt commands for formatting `paths` with `configs` (see `MiriEnv::format_paths`):
+/// those for each config, in order, with at most `RUSTFMT_BATCH_SIZE` files each. Also returns how
+/// many files were skipped since they say `no-rustfmt`.
+fn rustfmt_batches<'a>(
+    miri_dir: &Path,
+    paths: &'a [PathBuf],
+    configs: &'a [(PathBuf, PathBuf)],
+) -> (Vec<RustfmtBatch<'a>>, usize) {
+    let mut skipped = 0;
+    let mut by_config = vec![Vec::new(); configs.len()];
+    for file in paths {
+        if skips_rustfmt(file) {
+            skipped += 1;
+            continue;
+        }
+        if let Some(i) = configs.iter().position(|(root, _)| file.starts_with(root)) {
+            // Make it a relative path so that on platforms with extremely tight argument limits
+            // (like Windows), we become immune to someone cloning the repo 50 directories deep.
+            by_config[i].push(relative_to(file, miri_dir).unwrap_or(file));
+        }
+    }
+    let mut batches = Vec::new();
+    for ((_, config), files) in configs.iter().zip(by_config) {
+        for chunk in files.chunks(RUSTFMT_BATCH_SIZE) {
+            batches.push(RustfmtBatch { config, files: chunk.to_vec() });
+        }
+    }
+    (batches, skipped)
+}
+
+/// Whether the file at `path` says `// no-rustfmt` on one of its first lines, since rustfmt would
+/// change something about it that matters.
+pub fn skips_rustfmt(path: &Path) -> bool {
+    let Ok(contents) = std::fs::read_to_string(path) else {
+        // Then rustfmt has something to say about it.
+        return false;
+    };
+    contents.lines().take(5).any(|line| line.trim() == "// no-rustfmt")
+}
+
+/// All the `.rs` files in `root` that git does not ignore, or all of them with `no_ignore` (but
+/// never those in `.git`). The walk does not enter ignored dirs, so it skips `target` without
+/// looking at all the build artifacts in there, and it uses all cores. The files are sorted, with
+/// the errors first.
+pub fn rust_files(root: &Path, no_ignore: bool) -> Vec<Result<PathBuf, ignore::Error>> {
+    let mut walker = ignore::WalkBuilder::new(root);
+    // Like git, but also when Miri has been unpacked from a tarball.
+    walker.require_git(false);
+    if no_ignore {
+        walker.standard_filters(false).hidden(true);
+    }
+    let files = Mutex::new(Vec::new());
+    walker.build_parallel().run(|| {
+        Box::new(|entry| {
+            let file = entry.map(|entry| {
+                let is_rs = entry.file_type().is_some_and(|ty| ty.is_file())
+                    && entry.path().extension().is_some_and(|ext| ext == "rs");
+                is_rs.then(|| entry.into_path())
+            });
+            if let Some(file) = file.transpose() {
+                files.lock().unwrap().push(file);
+            }
+            ignore::WalkState::Continue
+        })
+    });
+    let mut files = files.into_inner().unwrap();
+    files.sort_by(|a, b| a.as_ref().ok().cmp(&b.as_ref().ok()));
+    files
+}
+
+/// The name of the crate at `path` (a manifest or the directory containing it), for saying what
+/// we spent time on.
+fn crate_name(path: &OsStr) -> String {
+    let path = Path::new(path);
+    let dir = if path.ends_with("Cargo.toml") { path.parent() } else { Some(path) };
+    dir.and_then(Path::file_name)
+        .map_or_else(|| "crate".into(), |name| name.to_string_lossy().into())
+}
+
+/// The dir (in the Miri dir) with the crates whose dependencies the ui tests can use.
+pub const TEST_DEPS_DIR: &str = "test_dependencies";
+
+/// The helper crates of the test suite that `./miri check` and `./miri clippy` cover as well: the
+/// Cargo.toml in `TEST_DEPS_DIR`, and those in the dirs right below it. Returns the manifests to
+/// cover, and those that opt out. That dir missing just means there are none.
+pub fn helper_crates(miri_dir: &Path) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let deps_dir = path!(miri_dir / TEST_DEPS_DIR);
+    let mut manifests = vec![path!(deps_dir / "Cargo.toml")];
+    if let Ok(entries) = std::fs::read_dir(&deps_dir) {
+        let mut below: Vec<PathBuf> = entries
+            .filter_map(|entry| Some(path!(entry.ok()?.path() / "Cargo.toml")))
+            .filter(|manifest| !manifest.starts_with(path!(deps_dir / "target")))
+            .collect();
+        below.sort();
+        manifests.extend(below);
+    }
+    let (mut covered, mut skipped) = (Vec::new(), Vec::new());
+    for manifest in manifests {
+        match std::fs::read_to_string(&manifest) {
+            Ok(contents) if skips_lints(&contents) => skipped.push(manifest),
+            Ok(_) => covered.push(manifest),
+            // Not a crate.
+            Err(_) => {}
+        }
+    }
+    (covered, skipped)
+}
+
+/// Whether a Cargo.toml opts out of `./miri check` and `./miri clippy`, for a crate with odd code
+/// on purpose:
+///
+/// ```toml
+/// [package.metadata.miri-script]
+/// skip-lints = true
+/// ```
+fn skips_lints(manifest: &str) -> bool {
+    let mut section = "";
+    for line in manifest.lines().map(str::trim) {
+        if line.starts_with('[') {
+            section = line;
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        if section == "[package.metadata.miri-script]"
+            && key.trim() == "skip-lints"
+            && value.split('#').next().unwrap().trim() == "true"
+        {
+            return true;
+        }
+    }
+    false
+}
+
+/// What `MiriEnv::doctest` found in a crate that passed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Doctests {
+    /// The crate has no lib, or no doctests in it.
+    None,
+    /// This many ran (or were ignored), and none failed.
+    Passed(usize),
+}
+
+/// Whether the crate in `dir` with the given Cargo.toml has a lib, which is where doctests are.
+fn has_lib(dir: &Path, manifest: &str) -> bool {
+    path!(dir / "src" / "lib.rs").exists() || manifest.lines().any(|line| line.trim() == "[lib]")
+}
+
+/// Finds the executable of the binary `bin` in the JSON messages of a `cargo build`.
+fn executable(messages: &str, bin: &str) -> Option<PathBuf> {
+    messages
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter(|msg| msg["reason"] == "compiler-artifact" && msg["target"]["name"] == bin)
+        .find_map(|msg| msg["executable"].as_str().map(PathBuf::from))
+}
+
+/// The env vars that make cargo rebuild things when they change (besides the `CARGO_*` ones it
+/// sets itself): those it reads, and those the crates we build read at compile time (like
+/// `tests/ui.rs` with `MIRI` and `CC`).
+const BUILD_VARS: &[&str] = &[
+    "RUSTFLAGS",
+    "CARGO_ENCODED_RUSTFLAGS",
+    "RUSTC",
+    "RUSTC_WRAPPER",
+    "CARGO_TARGET_DIR",
+    "CARGO_BUILD_TARGET",
+    "MIRI",
+    "CC",
+];
+
+/// The env vars that change how cargo builds things (and whether it can reuse what it built
+/// before), with the value we use when the user did not set them; `None` leaves it to cargo.
+/// `MiriEnv::new` applies these, `--hermetic` ignores the user's values for a run (as CI does: it
+/// only sets `CARGO_INCREMENTAL=0`, which changes nothing but the size of its caches), and
+/// `./miri doctor` shows them.
+pub const BUILD_SETTINGS: &[(&str, Option<&str>)] = &[
+    // Dev builds of Miri are unusably slow otherwise.
+    ("CARGO_PROFILE_DEV_OPT_LEVEL", Some("2")),
+    ("CARGO_PROFILE_DEV_DEBUG", None),
+    ("CARGO_PROFILE_DEV_DEBUG_ASSERTIONS", None),
+    ("CARGO_PROFILE_DEV_OVERFLOW_CHECKS", None),
+    ("CARGO_PROFILE_DEV_CODEGEN_UNITS", None),
+    ("CARGO_PROFILE_DEV_INCREMENTAL", None),
+    ("CARGO_PROFILE_RELEASE_OPT_LEVEL", None),
+    ("CARGO_PROFILE_RELEASE_DEBUG", None),
+    ("CARGO_PROFILE_RELEASE_DEBUG_ASSERTIONS", None),
+    ("CARGO_PROFILE_RELEASE_INCREMENTAL", None),
+    ("CARGO_INCREMENTAL", None),
+    ("CARGO_BUILD_INCREMENTAL", None),
+    ("RUSTC_FORCE_INCREMENTAL", None),
+];
+
+/// What the environment we were started in says about how to build, read once: every
+/// `ScriptCtx` and `MiriEnv` starts from these values, so that making another one gives the same
+/// result. We never change our own environment; what we set up goes into the shells.
+#[derive(Debug)]
+pub struct Ambient {
+    pub rustflags: Option<OsString>,
+    pub rustdocflags: Option<OsString>,
+    pub cargo_extra_flags: String,
+    pub cargo_target_dir: Option<PathBuf>,
+    pub rustup_toolchain: Option<String>,
+    /// `MIRI_SCRIPT_RUSTC`.
+    pub rustc: Option<PathBuf>,
+    /// The `BUILD_SETTINGS` the user set, unless `--hermetic` ignores them.
+    pub build_settings: Vec<(&'static str, OsString)>,
+    /// The `BUILD_SETTINGS` the user set that `--hermetic` ignores.
+    pub ignored: Vec<&'static str>,
+    /// `MIRI_SYSROOT`, as the user set it.
+    miri_sysroot: Option<OsString>,
+    /// Whether `--ignore-miri-sysroot` was passed.
+    ignore_miri_sysroot: bool,
+}
+
+static AMBIENT: OnceLock<Ambient> = OnceLock::new();
+
+impl Ambient {
+    /// Reads the environment, if that did not happen yet. With `hermetic`, the user's build
+    /// settings are ignored, and with `ignore_miri_sysroot` their `MIRI_SYSROOT`; `main` decides
+    /// that before anything else gets to read it.
+    pub fn init(hermetic: bool, ignore_miri_sysroot: bool) -> &'static Ambient {
+        AMBIENT.get_or_init(|| {
+            let mut ambient = Ambient::capture(|var| std::env::var_os(var), hermetic);
+            ambient.ignore_miri_sysroot = ignore_miri_sysroot;
+            ambient
+        })
+    }
+
+    pub fn get() -> &'static Ambient {
+        Ambient::init(false, false)
+    }
+
+    fn capture(env: impl Fn(&str) -> Option<OsString>, hermetic: bool) -> Ambient {
+        let non_empty = |var| env(var).filter(|value| !value.is_empty());
+        let (ignored, build_settings) = BUILD_SETTINGS
+            .iter()
+            .filter_map(|&(var, _)| Some((var, env(var)?)))
+            .partition::<Vec<_>, _>(|_| hermetic);
+        Ambient {
+            rustflags: env("RUSTFLAGS"),
+            rustdocflags: env("RUSTDOCFLAGS"),
+            cargo_extra_flags: env("CARGO_EXTRA_FLAGS")
+                .and_then(|flags| flags.into_string().ok())
+                .unwrap_or_default(),
+            cargo_target_dir: non_empty("CARGO_TARGET_DIR").map(Into::into),
+            rustup_toolchain: non_empty("RUSTUP_TOOLCHAIN").and_then(|t| t.into_string().ok()),
+            rustc: non_empty("MIRI_SCRIPT_RUSTC").map(Into::into),
+            build_settings,
+            ignored: ignored.into_iter().map(|(var, _)| var).collect(),
+            miri_sysroot: env("MIRI_SYSROOT"),
+            ignore_miri_sysroot: false,
+        }
+    }
+
+    /// The `MIRI_SYSROOT` the user set, unless `--ignore-miri-sysroot` ignores it.
+    pub fn miri_sysroot(&self) -> Option<&OsStr> {
+        self.miri_sysroot.as_deref().filter(|_| !self.ignore_miri_sysroot)
+    }
+
+    /// The build setting `var`, as the user set it (if `--hermetic` does not ignore it).
+    pub fn build_setting(&self, var: &str) -> Option<&OsStr> {
+        self.build_settings.iter().find(|(v, _)| *v == var).map(|(_, value)| &**value)
+    }
+
+    /// The build settings the user set to something other than our default, as `VAR=value`.
+    pub fn build_setting_overrides(&self) -> Vec<String> {
+        BUILD_SETTINGS
+            .iter()
+            .filter_map(|&(var, default)| {
+                let value = self.build_setting(var)?;
+                (default.map(OsStr::new) != Some(value))
+                    .then(|| format!("{var}={}", value.to_string_lossy()))
+            })
+            .collect()
+    }
+
+    /// The vars every command we run has unset: the build settings `--hermetic` ignores that we
+    /// do not set ourselves anyway.
+    pub fn unset_for_commands(&self) -> impl Iterator<Item = &'static str> + '_ {
+        let ours =
+            |var: &str| BUILD_SETTINGS.iter().any(|&(v, default)| v == var && default.is_some());
+        self.ignored.iter().copied().filter(move |var| !ours(var))
+    }
+}
+
+/// The options `MiriEnv::build` and `MiriEnv::test` share.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct CargoOptions {
+    toolchain: Option<String>,
+    extra_flags: Vec<String>,
+    manifest_path: OsString,
+    /// The values of the `BUILD_VARS`.
+    vars: Vec<(&'static str, Option<String>)>,
+}
+
+impl CargoOptions {
+    /// Describes how `other` differs from these options, if it does.
+    fn difference(&self, other: &CargoOptions) -> Option<String> {
+        let show = |value: &Option<String>| {
+            match value {
+                Some(value) => format!("`{value}`"),
+                None => "unset".to_owned(),
+            }
+        };
+        let mut differences = Vec::new();
+        if self.toolchain != other.toolchain {
+            let toolchain = |t: &Option<String>| {
+                show(&t.as_ref().map(|t| t.trim_start_matches('+').to_owned()))
+            };
+            differences.push(format!(
+                "the toolchain was {}, and is now {}",
+                toolchain(&self.toolchain),
+                toolchain(&other.toolchain)
+            ));
+        }
+        if self.extra_flags != other.extra_flags {
+            differences.push(format!(
+                "the extra cargo flags were `{}`, and are now `{}`",
+                self.extra_flags.join(" "),
+                other.extra_flags.join(" ")
+            ));
+        }
+        for ((var, before), (_, now)) in self.vars.iter().zip(&other.vars) {
+            if before != now {
+                differences.push(format!("{var} was {}, and is now {}", show(before), show(now)));
+            }
+        }
+        (!differences.is_empty()).then(|| differences.join("; "))
+    }
+}
+
+/// Artifacts of a cargo build, as package and target (including whether it is a test, which makes
+/// it a different artifact).
+type Artifacts = BTreeSet<(String, String)>;
+
+/// What `MiriEnv::build` last built for each manifest, and with which options.
+static BUILDS: Mutex<BTreeMap<OsString, (CargoOptions, Artifacts)>> = Mutex::new(BTreeMap::new());
+
+/// The artifacts in the JSON messages of a cargo build; only those with the given freshness, if
+/// any.
+fn artifacts(messages: &str, fresh: Option<bool>) -> Artifacts {
+    messages
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter(|msg| msg["reason"] == "compiler-artifact")
+        .filter(|msg| fresh.is_none_or(|fresh| msg["fresh"].as_bool() == Some(fresh)))
+        .map(|msg| {
+            let target = msg["target"]["name"].as_str().unwrap_or_default();
+            let target = if msg["profile"]["test"] == true {
+                format!("{target} (test)")
+            } else {
+                target.to_owned()
+            };
+            (msg["package_id"].as_str().unwrap_or_default().to_owned(), target)
+        })
+        .collect()
+}
+
+/// The test targets in the JSON messages of a `cargo test --no-run`, as the kind for selecting
+/// them (like `test` for `--test`) and their name; with `doc` for the doc tests of the lib, if
+/// `doc` is set and it has those.
+fn test_targets(messages: &str, doc: bool) -> Vec<(&'static str, String)> {
+    const LIB_KINDS: &[&str] = &["lib", "rlib", "dylib", "cdylib", "staticlib", "proc-macro"];
+    let mut targets = Vec::new();
+    for msg in
+        messages.lines().filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+    {
+        if msg["reason"] != "compiler-artifact" {
+            continue;
+        }
+        let target = &msg["target"];
+        let name = target["name"].as_str().unwrap_or_default().to_owned();
+        let kinds: Vec<&str> =
+            target["kind"].as_array().into_iter().flatten().filter_map(|k| k.as_str()).collect();
+        let is_lib = kinds.iter().any(|kind| LIB_KINDS.contains(kind));
+        if msg["profile"]["test"] == true && !msg["executable"].is_null() {
+            let kind = match kinds.first() {
+                _ if is_lib => "lib",
+                Some(&"bin") => "bin",
+                Some(&"bench") => "bench",
+                Some(&"example") => "example",
+                _ => "test",
+            };
+            targets.push((kind, name));
+        } else if doc && is_lib && target["doctest"] == true {
+            targets.push(("doc", name));
+        }
+    }
+    targets.dedup();
+    targets
+}
+
+/// Something to do with a `MiriEnv`, for [`MiriEnv::concurrently`].
+pub type Task<'a> = dyn Fn(&MiriEnv) -> Result<()> + Sync + 'a;
+
+/// Some extra state we track for building Miri, such as the right RUSTFLAGS.
+///
+/// Create one with [`MiriEnv::builder`].
+#[derive(Clone)]
+pub struct MiriEnv {
+    /// miri_dir is the root of the miri repository checkout we are working in.
+    #[doc(hidden)]
+    pub miri_dir: PathBuf,
+    /// active_toolchain is passed as `+toolchain` argument to cargo/rustc invocations.
+    /// This is `None` when using a locally built rustc.
+    #[doc(hidden)]
+    pub toolchain: Option<String>,
+    /// Where `toolchain` was determined from.
+    #[doc(hidden)]
+    pub toolchain_source: ToolchainSource,
+    /// The locally built rustc we use instead of a rustup toolchain, if any.
+    #[doc(hidden)]
+    pub rustc: Option<PathBuf>,
+    /// The `+toolchain` given on the command line, used to determine a fallback toolchain for tools
+    /// that a locally built rustc does not have.
+    cli_toolchain: Option<String>,
+    /// Extra flags to pass to cargo.
+    #[doc(hidden)]
+    pub cargo_extra_flags: Vec<String>,
+    /// The cargo target dir, shared by `miri` and `cargo-miri`.
+    #[doc(hidden)]
+    pub target_dir: PathBuf,
+    /// Whether `build_if_changed` may skip builds (unless `--no-skip-build` was given).
+    skip_build: bool,
+    /// Whether cargo gets `--locked` (see `shared_cargo_flags`).
+    #[doc(hidden)]
+    pub locked: bool,
+    /// The rustc flags the cargo commands get on top of ours and the user's `RUSTFLAGS` (see
+    /// `merged_rustflags`), from `--rustflags`.
+    #[doc(hidden)]
+    pub extra_rustflags: Vec<String>,
+    /// How much the cargo commands say (see `cargo_cmd`).
+    #[doc(hidden)]
+    pub verbosity: Verbosity,
+    /// Whether we and the commands we run color the output, as decided by `output::init`: `Always`
+    /// or `Never`. Output that tools read is never colored.
+    #[doc(hidden)]
+    pub color: ColorChoice,
+    /// What the compiler says about itself, once we asked it (see `rustc_info`).
+    rustc_info: OnceCell<RustcInfo>,
+    /// Whether `sh` has been set up for building against the rustc libraries (see `build_sh`).
+    build_env: OnceCell<()>,
+    /// The shell we use. Commands that build something get it from `build_sh`.
+    #[doc(hidden)]
+    pub sh: Shell,
+}
+
+/// What every command has to agree on, including the ones that do not need a toolchain: where
+/// Miri is, where the build artifacts go, and a shell that knows about both. All the shells we
+/// run commands in are clones of this one (`MiriEnv` starts from it as well); clippy keeps us
+/// from creating any others.
+#[derive(Clone)]
+pub struct ScriptCtx {
+    /// The root of the Miri checkout.
+    pub miri_dir: PathBuf,
+    /// The cargo target dir, shared by `miri` and `cargo-miri`. This is an absolute path, so that
+    /// it is the same for all the commands, whatever dir they run in. All the artifacts the script
+    /// manages itself go somewhere in here.
+    pub target_dir: PathBuf,
+    /// Extra flags to pass to cargo, from `CARGO_EXTRA_FLAGS` (with the `--target-dir` made
+    /// absolute as well).
+    pub cargo_extra_flags: Vec<String>,
+    /// A shell in the current dir (so that paths given by the user resolve properly), with
+    /// `CARGO_TARGET_DIR` set to `target_dir`, and the variables that tell the commands whether to
+    /// color (see `output::child_env`).
+    pub sh: Shell,
+}
+
+impl ScriptCtx {
+    pub fn new() -> Result<Self> {
+        let ambient = Ambient::get();
+        let cargo_extra_flags =
+            flagsplit(&ambient.cargo_extra_flags).context("invalid CARGO_EXTRA_FLAGS")?;
+        let cwd = std::env::current_dir().context("failed to determine the current dir")?;
+        Self::with_flags(miri_dir()?, cargo_extra_flags, ambient.cargo_target_dir.clone(), &cwd)
+    }
+
+    fn with_flags(
+        miri_dir: PathBuf,
+        mut cargo_extra_flags: Vec<String>,
+        env_target_dir: Option<PathBuf>,
+        cwd: &Path,
+    ) -> Result<Self> {
+        // Share target dir between `miri` and `cargo-miri`. A `--target-dir` in the extra flags
+        // takes precedence for cargo, so it does for us as well. Cargo resolves relative dirs
+        // against the dir it runs in, and we run it in different dirs, so we resolve them against
+        // the dir we were started in instead (and tell cargo the result).
+        let target_dir = match arg_flag_value(&cargo_extra_flags, "--target-dir") {
+            Some(dir) => {
+                let dir = cwd.join(dir);
+                let value = dir.to_str().context("the `--target-dir` is not valid UTF-8")?;
+                set_flag_value(&mut cargo_extra_flags, "--target-dir", value);
+                dir
+            }
+            None => env_target_dir.map_or_else(|| path!(miri_dir / "target"), |dir| cwd.join(dir)),
+        };
+        #[allow(clippy::disallowed_methods)] // the one place that creates a shell
+        let sh = Shell::new()?;
+        sh.set_var("CARGO_TARGET_DIR", &target_dir);
+        // A shell cannot unset variables; `Cmd` does that for the others.
+        for (var, value) in output::child_env(output::color()) {
+            if let Some(value) = value {
+                sh.set_var(var, value);
+            }
+        }
+        Ok(ScriptCtx { miri_dir, target_dir, cargo_extra_flags, sh })
+    }
+
+    /// A clone of the shell, in the Miri dir.
+    pub fn miri_dir_shell(&self) -> Shell {
+        let sh = self.sh.clone();
+        sh.change_dir(&self.miri_dir);
+        sh
+    }
+}
+
+#[doc(hidden)]
+impl MiriEnv {
+    pub fn new(global: &GlobalArgs) -> Result<Self> {
+        let rustc = local_rustc(global);
+        let (toolchain, toolchain_source) = if rustc.is_some() {
+            (None, ToolchainSource::LocalRustc)
+        } else {
+            let (toolchain, source) = resolve_toolchain(global.toolchain.as_deref())?;
+            (Some(toolchain), source)
+        };
+        let ScriptCtx { miri_dir, target_dir, cargo_extra_flags, sh } = ScriptCtx::new()?;
+        metrics::set_toolchain(
+            toolchain
+                .clone()
+                .unwrap_or_else(|| rustc.as_ref().unwrap().to_string_lossy().into_owned()),
+        );
+        if same_filesystem(&miri_dir, &target_dir) == Some(false) {
+            static WARNED: AtomicBool = AtomicBool::new(false);
+            if !WARNED.swap(true, Ordering::Relaxed) {
+                note!(
+                    "the target dir {} is on another file system than the Miri checkout, {}; \
+                    files that get moved between them are copied instead",
+                    target_dir.display(),
+                    miri_dir.display()
+                );
+            }
+        }
+
+        if let Some(rustc) = &rustc {
+            // Make cargo use this rustc. We do not touch `RUSTC_WRAPPER`, so a user-provided
+            // wrapper still wraps the local rustc.
+            sh.set_var("RUSTC", rustc);
+        }
+
+        // The user's build settings win over ours. Builds then differ from those in CI, so that
+        // goes into the log.
+        let ambient = Ambient::get();
+        for &(var, default) in BUILD_SETTINGS {
+            if let Some(default) = default.filter(|_| ambient.build_setting(var).is_none()) {
+                sh.set_var(var, default);
+            }
+        }
+        let overrides = ambient.build_setting_overrides();
+        if !overrides.is_empty() {
+            let message = format!(
+                "build settings that differ from ours (`--hermetic` ignores them): {}",
+                overrides.join(" ")
+            );
+            logfile::write(LogLevel::Debug, &message);
+        }
+
+        // Asking the compiler about itself takes a while, so that waits until a command needs it.
+        let e = MiriEnv {
+            miri_dir,
+            toolchain,
+            toolchain_source,
+            rustc,
+            cli_toolchain: global.toolchain.clone(),
+            sh,
+            target_dir,
+            cargo_extra_flags,
+            skip_build: !global.no_skip_build,
+            locked: global.locked,
+            extra_rustflags: global.rustflags.clone(),
+            verbosity: global.verbosity,
+            color: output::choice(),
+            rustc_info: OnceCell::new(),
+            build_env: OnceCell::new(),
+        };
+        // With `--verbose`, every command says which compiler it uses, and why (but only once, as
+        // some commands set up several environments).
+        static PRINTED: AtomicBool = AtomicBool::new(false);
+        if e.verbosity == Verbosity::Verbose && !PRINTED.swap(true, Ordering::Relaxed) {
+            e.toolchain_status();
+        }
+        Ok(e)
+    }
+
+    /// What the compiler we use says about itself. It is asked the first time this is needed.
+    fn rustc_info(&self) -> Result<&RustcInfo> {
+        if let Some(info) = self.rustc_info.get() {
+            return Ok(info);
+        }
+        let rustc = self.rustc.as_deref().map_or(OsStr::new("rustc"), |rustc| rustc.as_os_str());
+        let info = rustc_info(&self.sh, rustc, self.toolchain.as_deref())?;
+        Ok(self.rustc_info.get_or_init(|| info))
+    }
+
+    /// The sysroot of the compiler we use.
+    pub fn sysroot(&self) -> Result<&Path> {
+        Ok(&self.rustc_info()?.sysroot)
+    }
+
+    /// The shell for building Miri and for running what we built: `sh`, with the RUSTFLAGS for
+    /// linking against the private rustc libraries. Setting those up needs the compiler's
+    /// sysroot, so commands that do not build anything never ask for it.
+    pub fn build_sh(&self) -> Result<&Shell> {
+        if self.build_env.get().is_none() {
+            let RustcInfo { sysroot, host, .. } = self.rustc_info()?;
+            let libdir = path!(sysroot / "lib" / "rustlib" / host / "lib");
+            if !libdir.exists() {
+                match (&self.toolchain, &self.rustc) {
+                    (Some(toolchain), _) =>
+                        bail!(
+                            "the library dir of toolchain `{toolchain}`, {}, does not exist",
+                            libdir.display()
+                        ),
+                    (None, rustc) =>
+                        bail!(
+                            "the library dir of {}, {}, does not exist",
+                            rustc.as_deref().unwrap_or(Path::new("rustc")).display(),
+                            libdir.display()
+                        ),
+                }
+            }
+            set_rustflags(&self.sh, &libdir)?;
+            let _ = self.build_env.set(());
+        }
+        Ok(&self.sh)
+    }
+
+    /// A copy of this environment that uses the rustup toolchain `toolchain` instead, with what
+    /// depends on the toolchain determined anew.
+    pub fn clone_for_toolchain(&self, toolchain: &str) -> Result<MiriEnv> {
+        // We cannot take back setting `RUSTC` in the shell.
+        if self.rustc.is_some() {
+            bail!("an environment with a locally built rustc cannot switch to a toolchain");
+        }
+        let mut e = self.clone();
+        e.toolchain = Some(toolchain.to_owned());
+        e.toolchain_source = ToolchainSource::CommandLine;
+        e.cli_toolchain = Some(toolchain.to_owned());
+        e.rustc_info = OnceCell::new();
+        e.build_env = OnceCell::new();
+        // Fail early if the toolchain cannot build Miri.
+        e.build_sh()?;
+        Ok(e)
+    }
+
+    /// The environment variables we set up for cargo and rustc, plus `MIRI_SCRIPT_TOOLCHAIN` (if we
+    /// use a rustup toolchain), so that the environment can be reproduced outside of `./miri`.
+    /// These include the RUSTFLAGS for building, so this has to ask the compiler about itself.
+    pub fn exported_vars(&self) -> Result<Vec<(&'static str, OsString)>> {
+        self.build_sh()?;
+        const VARS: &[&str] =
+            &["RUSTC", "CARGO_TARGET_DIR", "RUSTFLAGS", "CARGO_ENCODED_RUSTFLAGS", "MIRI_SYSROOT"];
+        let vars = VARS.iter().chain(BUILD_SETTINGS.iter().map(|(var, _)| var));
+        let mut vars: Vec<_> = vars.filter_map(|&var| Some((var, self.sh.var_os(var)?))).collect();
+        if let Some(toolchain) = &self.toolchain {
+            vars.push(("MIRI_SCRIPT_TOOLCHAIN", toolchain.into()));
+        }
+        Ok(vars)
+    }
+
+    /// The host triple of the rustc we use.
+    pub fn host(&self) -> Result<&str> {
+        Ok(&self.rustc_info()?.host)
+    }
+
+    /// What `--version --verbose` of the rustc we use says. When that could not be parsed (and
+    /// we only know the host), this fails, so only ask when the rest matters.
+    pub fn rustc_meta(&self) -> Result<rustc_version::VersionMeta> {
+        match &self.rustc_info()?.meta {
+            Ok(meta) => Ok(meta.clone()),
+            Err(err) =>
+                bail!(
+                    "this needs to know the version of the compiler, but what \
+                    `rustc --version --verbose` printed could not be parsed: {err}"
+                ),
+        }
+    }
+
+    /// The first line of `--version --verbose` of the rustc we use, like `rustc 1.80.0-nightly
+    /// (8679004 2024-05-03)`.
+    pub fn rustc_version(&self) -> Result<&str> {
+        Ok(self.rustc_info()?.version.lines().next().unwrap_or_default())
+    }
+
+    /// Changes the target dir used by all cargo invocations.
+    pub fn set_target_dir(&mut self, target_dir: PathBuf) {
+        self.sh.set_var("CARGO_TARGET_DIR", &target_dir);
+        self.target_dir = target_dir;
+    }
+
+    /// The `+toolchain` argument to pass to cargo/rustc invocations, if any.
+    pub fn toolchain_flag(&self) -> Option<String> {
+        self.toolchain.as_ref().map(|t| format!("+{t}"))
+    }
+
+    /// Prints which compiler we are using, unless we are to be quiet, or are verbose (and so
+    /// already did in `new`).
+    pub fn print_toolchain(&self) {
+        if self.verbosity != Verbosity::Normal {
+            return;
+        }
+        self.toolchain_status();
+    }
+
+    fn toolchain_status(&self) {
+        match (&self.toolchain, &self.rustc) {
+            (Some(toolchain), _) =>
+                status!("$ (using toolchain `{toolchain}` from {})", self.toolchain_source),
+            (None, Some(rustc)) =>
+                status!("$ (using {} from {})", rustc.display(), self.toolchain_source),
+            (None, None) => unreachable!("either a toolchain or a local rustc must be set"),
+        }
+    }
+
+    /// The `+toolchain` flag to use for `tool`. When using a locally built rustc, which usually
+    /// lacks tools like rustfmt and clippy, we fall back to the regular rustup toolchain.
+    pub fn tool_toolchain(&self, tool: &str) -> Result<Option<String>> {
+        if self.rustc.is_none() {
+            return Ok(self.toolchain_flag());
+        }
+        let (toolchain, source) = resolve_toolchain(self.cli_toolchain.as_deref())?;
+        warning!(
+            "a locally built rustc usually does not have {tool}; using toolchain `{toolchain}` (from {source}) instead"
+        );
+        Ok(Some(format!("+{toolchain}")))
+    }
+
+    /// The flags every cargo command we run gets: `CARGO_EXTRA_FLAGS`, and with `--locked`, that
+    /// as well (unless one of them or `args`, which the command gets too, already has it).
+    pub fn shared_cargo_flags(&self, args: &[OsString]) -> Vec<String> {
+        let mut flags = self.cargo_extra_flags.clone();
+        let locked = ArgQuery::flag(&["--locked"]);
+        if self.locked && !locked.is_present(&flags) && !locked.is_present(args) {
+            flags.push("--locked".into());
+        }
+        flags
+    }
+
+    /// The rustc flags of the build shell with `extra_rustflags` merged in, for a single command
+    /// (which `with_rustflags` gives them), or `None` if there are no extra flags. The shell keeps
+    /// its flags, so other commands do not get these.
+    pub fn merged_rustflags(&self, extra_rustflags: &[String]) -> Result<Option<Vec<OsString>>> {
+        if extra_rustflags.is_empty() {
+            return Ok(None);
+        }
+        let sh = self.build_sh()?;
+        let ours = match sh.var_os("CARGO_ENCODED_RUSTFLAGS") {
+            Some(flags) => decode_rustflags("CARGO_ENCODED_RUSTFLAGS", &flags),
+            None => decode_rustflags("RUSTFLAGS", &sh.var_os("RUSTFLAGS").unwrap_or_default()),
+        };
+        Ok(Some(self.merge_extra_rustflags(&ours, extra_rustflags)))
+    }
+
+    /// `merge_flags` for `merged_rustflags`, which says what comes out with `--verbose`.
+    fn merge_extra_rustflags(
+        &self,
+        ours: &[OsString],
+        extra_rustflags: &[String],
+    ) -> Vec<OsString> {
+        let flags = merge_flags(ours, extra_rustflags);
+        if self.verbosity == Verbosity::Verbose {
+            let shown = flags.iter().map(|flag| flag.to_string_lossy());
+            note!("rustc flags with `--rustflags`: {}", shell_words::join(shown));
+        }
+        flags
+    }
+
+    /// `cargo <subcommand>` in the build shell, with what every cargo command we run gets: the
+    /// toolchain, the `shared_cargo_flags`, the flag for the `verbosity` and the
+    /// `extra_rustflags`. The wrappers below
+    /// add their own arguments to it, and those of the user, `args`. With `Verbosity::Quiet`, the
+    /// command line is not shown; `run_cargo` shows the output only if they fail then.
+    pub fn cargo_cmd(&self, subcommand: &str, args: &[OsString]) -> Result<Cmd<'_>> {
+        let (sh, cargo_flags) = (self.build_sh()?, self.shared_cargo_flags(args));
+        let toolchain = match subcommand {
+            "clippy" => self.tool_toolchain(subcommand)?,
+            _ => self.toolchain_flag(),
+        };
+        let verbosity =
+            self.verbosity.cargo_flag(&cargo_flags).and(self.verbosity.cargo_flag(args));
+        let mut cmd = cmd!(sh, "cargo {toolchain...} {subcommand} {cargo_flags...} {verbosity...}");
+        cmd.set_quiet(self.verbosity == Verbosity::Quiet);
+        if let Some(flags) = self.merged_rustflags(&self.extra_rustflags)? {
+            cmd = with_rustflags(cmd, &flags);
+        }
+        if subcommand == "clippy" && self.rustc.is_some() {
+            // clippy needs the rustc of its own toolchain.
+            cmd = cmd.env_remove("RUSTC");
+        }
+        Ok(cmd)
+    }
+
+    pub fn install_to_sysroot(
+        &self,
+        path: impl AsRef<OsStr>,
+        args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    ) -> Result<()> {
+        let name = crate_name(path.as_ref());
+        let args: Vec<OsString> = args.into_iter().map(|arg| arg.as_ref().to_owned()).collect();
+        let cmd = self.install_cmd(path.as_ref(), &args)?;
+        // The dependencies may have to be downloaded.
+        retry(
+            DOWNLOAD_RETRIES,
+            DOWNLOAD_RETRY_DELAY,
+            &format!("installing {name}"),
+            is_download_hiccup,
+            || cmd.tee(),
+        )
+        .map_err(|err| {
+            let manifest_path = path!(Path::new(path.as_ref()) / "Cargo.toml");
+            self.explain_locked(err, manifest_path.as_os_str(), &args)
+        })?;
+        Ok(())
+    }
+
+    fn install_cmd(&self, path: &OsStr, args: &[OsString]) -> Result<Cmd<'_>> {
+        // Install binaries to the miri toolchain's `sysroot` so they do not interact with other
+        // toolchains, unless the user says otherwise.
+        let root_flag = if ArgQuery::new(&["--root"]).is_present(args) {
+            None
+        } else {
+            Some(self.install_root(args)?)
+        };
+        let cmd = self
+            .cargo_cmd("install", args)?
+            .arg("--path")
+            .arg(path)
+            .arg("--force")
+            .args(root_flag.iter().flat_map(|root| [OsStr::new("--root"), root.as_os_str()]))
+            .args(args)
+            .label(format!("install {}", crate_name(path)));
+        self.for_cargo_target(cmd, args)
+    }
+
+    /// Where `install_to_sysroot` with `cargo_flags` installs: the sysroot of the toolchain,
+    /// unless they say `--root`. Binaries for another target go into the target dir instead, in
+    /// `<target>/install`.
+    pub fn install_root(&self, cargo_flags: &[OsString]) -> Result<PathBuf> {
+        if let Some(root) = ArgQuery::new(&["--root"]).value(cargo_flags) {
+            return Ok(root.into());
+        }
+        match self.foreign_target(cargo_flags)? {
+            Some(target) => Ok(path!(self.target_dir / target / "install")),
+            None => Ok(self.sysroot()?.to_owned()),
+        }
+    }
+
+    /// Where `install_to_sysroot` with `cargo_flags` puts the binaries of Miri and cargo-miri.
+    pub fn installed_binaries(&self, cargo_flags: &[OsString]) -> Result<[PathBuf; 2]> {
+        let bin = path!(self.install_root(cargo_flags)? / "bin");
+        let suffix = match self.foreign_target(cargo_flags)? {
+            Some(target) => cross::exe_suffix(&target),
+            None => std::env::consts::EXE_SUFFIX,
+        };
+        Ok(["miri", "cargo-miri"].map(|name| path!(bin / format!("{name}{suffix}"))))
+    }
+
+    /// The `--target` that `cargo_flags` give, unless it is the host.
+    pub fn foreign_target(&self, cargo_flags: &[OsString]) -> Result<Option<String>> {
+        let Some(target) = ArgQuery::new(&["--target"]).value(cargo_flags) else {
+            return Ok(None);
+        };
+        let target =
+            target.into_string().map_err(|_| anyhow!("the `--target` is not valid UTF-8"))?;
+        Ok((target != self.host()?).then_some(target))
+    }
+
+    /// Checks that the toolchain has what building Miri for the foreign `target` needs.
+    pub fn check_cross_target(&self, target: &str) -> Result<()> {
+        let missing = cross::missing_components(self.sysroot()?, target);
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let (names, components) = (missing.join(" and "), missing.join(" "));
+        match &self.toolchain {
+            Some(toolchain) =>
+                bail!(
+                    "toolchain `{toolchain}` does not have {names} for `{target}`, which building \
+                    Miri for it needs; install them with `rustup component add --toolchain \
+                    {toolchain} --target {target} {components}`"
+                ),
+            None =>
+                bail!(
+                    "{} does not have {names} for `{target}`, which building Miri for it needs",
+                    self.rustc.as_deref().unwrap_or(Path::new("rustc")).display()
+                ),
+        }
+    }
+
+    /// `cmd`, a cargo command with `cargo_flags`, set up for building Miri for the target those
+    /// give, if that is not the host: with the rpath for there (see `cross::rpath`).
+    fn for_cargo_target<'a>(&self, cmd: Cmd<'a>, cargo_flags: &[OsString]) -> Result<Cmd<'a>> {
+        let Some(target) = self.foreign_target(cargo_flags)? else {
+            return Ok(cmd);
+        };
+        self.check_cross_target(&target)?;
+        let (var, rustflags) = build_rustflags(cross::rpath(&target).map(Path::new))?;
+        if !self.extra_rustflags.is_empty() {
+            let ours = decode_rustflags(var, &rustflags);
+            return Ok(with_rustflags(
+                cmd,
+                &self.merge_extra_rustflags(&ours, &self.extra_rustflags),
+            ));
+        }
+        Ok(cmd.env_remove("RUSTFLAGS").env_remove("CARGO_ENCODED_RUSTFLAGS").env(var, rustflags))
+    }
+
+    /// A tool of the `llvm-tools` component of the toolchain, like `llvm-strip`.
+    pub fn llvm_tool(&self, tool: &str) -> Result<PathBuf> {
+        let path = path!(
+            self.sysroot()?
+                / "lib"
+                / "rustlib"
+                / self.host()?
+                / "bin"
+                / format!("{tool}{}", std::env::consts::EXE_SUFFIX)
+        );
+        if !path.exists() {
+            let path = path.display();
+            bail!("{path} does not exist; does the toolchain have the `llvm-tools` component?");
+        }
+        Ok(path)
+    }
+
+    /// What `build` and `test` pass to cargo for the crate at `manifest_path`. Both go through
+    /// this, so that the tests get built the way the crate was; any difference makes cargo build
+    /// everything again.
+    fn cargo_options(&self, manifest_path: &OsStr) -> CargoOptions {
+        CargoOptions {
+            toolchain: self.toolchain_flag(),
+            extra_flags: self.cargo_extra_flags.clone(),
+            manifest_path: manifest_path.to_owned(),
+            vars: BUILD_VARS
+                .iter()
+                .chain(BUILD_SETTINGS.iter().map(|(var, _)| var))
+                .map(|&var| (var, self.sh.var_os(var).map(|v| v.to_string_lossy().into_owned())))
+                .collect(),
+        }
+    }
+
+    /// Runs `cmd`, a command from `cargo_cmd`: with `Verbosity::Quiet`, such that its output is
+    /// only shown if it fails.
+    fn run_cargo(&self, cmd: Cmd<'_>) -> Result<()> {
+        match self.verbosity {
+            Verbosity::Quiet => cmd.run_hushed(),
+            Verbosity::Normal | Verbosity::Verbose => cmd.run(),
+        }
+    }
+
+    /// Runs `f` with `verbosity` instead of the one given on the command line.
+    pub fn with_verbosity<T>(&mut self, verbosity: Verbosity, f: impl FnOnce(&mut Self) -> T) -> T {
+        let old = std::mem::replace(&mut self.verbosity, verbosity);
+        let res = f(self);
+        self.verbosity = old;
+        res
+    }
+
+    pub fn build(&self, manifest_path: impl AsRef<OsStr>, args: &[OsString]) -> Result<()> {
+        let manifest_path = manifest_path.as_ref();
+        let cmd = self.build_cmd(manifest_path, args)?;
+        // After `build_cmd` set up the RUSTFLAGS, which the options include.
+        let options = self.cargo_options(manifest_path);
+        let explain = |err| self.explain_locked(err, manifest_path, args);
+        // We want to know what got built, for `test`; unless the user wants the messages.
+        if ArgQuery::new(&["--message-format"]).is_present(args) {
+            return self.run_cargo(cmd).map_err(explain);
+        }
+        let messages = cmd.read().map_err(explain)?;
+        BUILDS
+            .lock()
+            .unwrap()
+            .insert(options.manifest_path.clone(), (options, artifacts(&messages, None)));
+        Ok(())
+    }
+
+    fn build_cmd(&self, manifest_path: &OsStr, args: &[OsString]) -> Result<Cmd<'_>> {
+        let message_format = (!ArgQuery::new(&["--message-format"]).is_present(args))
+            .then_some("--message-format=json-render-diagnostics");
+        // We build the tests as well, (a) to avoid having rebuilds when building the tests later
+        // and (b) to have more parallelism during the build of Miri and its tests.
+        let cmd = self
+            .cargo_cmd("build", args)?
+            .args(["--bins", "--tests", "--manifest-path"])
+            .arg(manifest_path)
+            .args(message_format)
+            .args(args)
+            .label(format!("build {}", crate_name(manifest_path)));
+        self.for_cargo_target(cmd, args)
+    }
+
+    /// Like `build` (with no extra arguments), but skips running cargo when nothing changed since
+    /// we last built `bin` from `manifest_path` this way: the tracked files in the crate dir, the
+    /// toolchain and the build settings are all the same, the tree is clean, and the binary is
+    /// still there. Even a cargo that has nothing to do takes a few seconds for that.
+    pub fn build_if_changed(&self, manifest_path: &Path, bin: &str) -> Result<()> {
+        // The fingerprint includes the RUSTFLAGS.
+        self.build_sh()?;
+        let stamp = path!(self.target_dir / BUILD_FINGERPRINTS_DIR / bin);
+        let fingerprint = if self.skip_build {
+            self.build_fingerprint(manifest_path)
+        } else {
+            Err(anyhow!("`--no-skip-build` was given"))
+        };
+        let up_to_date = fingerprint.as_ref().map_err(|err| format!("{err:#}")).and_then(|f| {
+            let artifact = bin_artifact(&self.target_dir, &self.cargo_extra_flags, bin);
+            if !artifact.exists() {
+                return Err(format!("{} does not exist", artifact.display()));
+            }
+            if std::fs::read_to_string(&stamp).ok().as_ref() != Some(f) {
+                return Err("something changed since the last build".into());
+            }
+            Ok(())
+        });
+        match up_to_date {
+            Ok(()) => {
+                if self.verbosity != Verbosity::Quiet {
+                    note!("{bin} is up to date, not building it (`--no-skip-build` builds anyway)");
+                }
+                return Ok(());
+            }
+            Err(reason) => logfile::write(LogLevel::Info, &format!("building {bin}: {reason}")),
+        }
+        if !is_dry_run() {
+            // The old fingerprint does not describe what this build leaves behind, also if it
+            // fails.
+            if let Err(err) = std::fs::remove_file(&stamp) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    return Err(err)
+                        .with_context(|| format!("failed to remove {}", stamp.display()));
+                }
+            }
+        }
+        self.build(manifest_path, &[])?;
+        if let (Ok(fingerprint), false) = (fingerprint, is_dry_run()) {
+            std::fs::create_dir_all(stamp.parent().unwrap())?;
+            std::fs::write(&stamp, fingerprint)
+                .with_context(|| format!("failed to write {}", stamp.display()))?;
+        }
+        Ok(())
+    }
+
+    /// What `build_if_changed` compares to decide whether to build the crate at `manifest_path`,
+    /// or why it cannot tell.
+    fn build_fingerprint(&self, manifest_path: &Path) -> Result<String> {
+        let crate_dir = manifest_path.parent().context("the manifest path has no parent")?;
+        let changes = cmd!(self.sh, "git -C {crate_dir} status --porcelain -- .")
+            .label("check for changes")
+            .quiet()
+            .read()?;
+        if !changes.is_empty() {
+            bail!("the tree has uncommitted changes");
+        }
+        let files = cmd!(self.sh, "git -C {crate_dir} ls-files -s -- .")
+            .label("check for changes")
+            .quiet()
+            .read()?;
+        let version = &self.rustc_info()?.version;
+        let var = |name| self.sh.var_os(name).unwrap_or_default().to_string_lossy().into_owned();
+        let settings = format!(
+            "{version}\nflags: {:?}\nRUSTFLAGS: {} {:?}\nRUSTC: {}\n",
+            self.cargo_extra_flags,
+            var("RUSTFLAGS"),
+            self.extra_rustflags,
+            var("RUSTC"),
+        );
+        let settings = BUILD_SETTINGS
+            .iter()
+            .fold(settings, |settings, (name, _)| format!("{settings}{name}: {}\n", var(name)));
+        Ok(format!("{:016x} {:016x}", stable_hash(&settings), stable_hash(&files)))
+    }
+
+    /// Builds the binary `bin` of the crate at `manifest_path`, and returns where cargo put it.
+    pub fn build_bin(&self, manifest_path: impl AsRef<OsStr>, bin: &str) -> Result<PathBuf> {
+        let manifest_path = manifest_path.as_ref();
+        let cmd = self
+            .cargo_cmd("build", &[])?
+            .arg("--manifest-path")
+            .arg(manifest_path)
+            .args(["--bin", bin, "--message-format=json-render-diagnostics"])
+            .label(format!("build {bin}"));
+        let messages = cmd.read().map_err(|err| self.explain_locked(err, manifest_path, &[]))?;
+        if is_dry_run() {
+            return Ok(path!(self.target_dir / "debug" / bin));
+        }
+        executable(&messages, bin)
+            .with_context(|| format!("cargo did not say where it put the `{bin}` binary"))
+    }
+
+    pub fn check(&self, manifest_path: impl AsRef<OsStr>, args: &[OsString]) -> Result<()> {
+        let cmd = self.check_cmd("check", manifest_path.as_ref(), args)?;
+        self.run_annotated(cmd, manifest_path.as_ref(), self.json_message_format(args).is_some())
+            .map_err(|err| self.explain_locked(err, manifest_path.as_ref(), args))
+    }
+
+    pub fn clippy(&self, manifest_path: impl AsRef<OsStr>, args: &[OsString]) -> Result<()> {
+        let cmd = self.check_cmd("clippy", manifest_path.as_ref(), args)?;
+        self.run_annotated(cmd, manifest_path.as_ref(), self.json_message_format(args).is_some())
+            .map_err(|err| self.explain_locked(err, manifest_path.as_ref(), args))
+    }
+
+    /// The command of `check` or `clippy`, which is `subcommand`.
+    fn check_cmd(
+        &self,
+        subcommand: &str,
+        manifest_path: &OsStr,
+        args: &[OsString],
+    ) -> Result<Cmd<'_>> {
+        Ok(self
+            .cargo_cmd(subcommand, args)?
+            .arg("--manifest-path")
+            .arg(manifest_path)
+            .arg("--all-targets")
+            .args(self.json_message_format(args))
+            .args(args)
+            .label(format!("{subcommand} {}", crate_name(manifest_path))))
+    }
+
+    /// The `--message-format` for `check` and `clippy` with `--github-annotations` or `--sarif`,
+    /// unless the user picked one.
+    fn json_message_format(&self, args: &[OsString]) -> Option<&'static str> {
+        let wanted = annotations::enabled() || sarif::recording();
+        if !wanted || ArgQuery::new(&["--message-format"]).is_present(args) {
+            return None;
+        }
+        Some(if self.color == ColorChoice::Always {
+            "--message-format=json-diagnostic-rendered-ansi"
+        } else {
+            "--message-format=json"
+        })
+    }
+
+    /// Runs `cmd`, a `cargo check` or `clippy` of the crate at `manifest_path`. With `json`, it
+    /// prints JSON messages, which we show, annotate and add to the SARIF log, also when it fails.
+    fn run_annotated(&self, cmd: Cmd<'_>, manifest_path: &OsStr, json: bool) -> Result<()> {
+        if !json {
+            return self.run_cargo(cmd);
+        }
+        let base = Path::new(manifest_path).parent().unwrap_or(Path::new("."));
+        let root = annotations::root(&self.miri_dir);
+        match cmd.read() {
+            Ok(messages) => {
+                annotations::cargo_diagnostics(&messages, base, &root);
+                sarif::add(&messages, base, &root);
+                Ok(())
+            }
+            Err(err) => {
+                if let Some(failed) = err.chain().find_map(|e| e.downcast_ref::<CommandFailed>()) {
+                    annotations::cargo_diagnostics(&failed.output, base, &root);
+                    sarif::add(&failed.output, base, &root);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// `err`, from a cargo command with `args` for the crate at `manifest_path`, with what to do
+    /// if it failed because `--locked` kept cargo from updating the lock file. Cargo says that on
+    /// stderr, which we mostly do not capture; then we ask `cargo metadata`, which resolves the
+    /// dependencies like the other commands do.
+    fn explain_locked(
+        &self,
+        err: anyhow::Error,
+        manifest_path: &OsStr,
+        args: &[OsString],
+    ) -> anyhow::Error {
+        let flag = ArgQuery::flag(&["--locked"]);
+        let locked =
+            self.locked || flag.is_present(&self.cargo_extra_flags) || flag.is_present(args);
+        let Some(failed) = err.chain().find_map(|e| e.downcast_ref::<CommandFailed>()) else {
+            return err;
+        };
+        if !locked {
+            return err;
+        }
+        let lock_file = match outdated_lock_file(&failed.output) {
+            Some(file) => file.to_owned(),
+            None => {
+                let toolchain = self.toolchain_flag();
+                let probe = cmd!(
+                    self.sh,
+                    "cargo {toolchain...} metadata --format-version 1 --locked --offline --manifest-path {manifest_path}"
+                )
+                .label("check the lock file")
+                .quiet()
+                .ignore_stdout()
+                .ignore_status()
+                .output();
+                let Ok(probe) = probe else {
+                    return err;
+                };
+                match outdated_lock_file(&String::from_utf8_lossy(&probe.stderr)) {
+                    Some(file) => file.to_owned(),
+                    None => return err,
+                }
+            }
+        };
+        err.context(format!(
+            "the lock file {lock_file} is out of date, and `--locked` keeps cargo from updating \
+            it; run `cargo update -p <pkg>` for the dependencies that changed, or drop --locked"
+        ))
+    }
+
+    pub fn test(&self, manifest_path: impl AsRef<OsStr>, args: &[OsString]) -> Result<()> {
+        let manifest_path = manifest_path.as_ref();
+        self.run_tests(manifest_path, args)
+            .map_err(|err| self.explain_locked(err, manifest_path, args))
+    }
+
+    fn run_tests(&self, manifest_path: &OsStr, args: &[OsString]) -> Result<()> {
+        // The options include the RUSTFLAGS.
+        self.build_sh()?;
+        let options = self.cargo_options(manifest_path);
+        let (cargo_args, _) = split_args(args.to_vec());
+        let no_run = ArgQuery::flag(&["--no-run"]).is_present(&cargo_args);
+        let build = BUILDS.lock().unwrap().get(&options.manifest_path).cloned();
+        // After a `build`, first build the tests on their own and check that this did not build
+        // again what the `build` built: that takes a while and goes unnoticed otherwise.
+        if let (Some((built_with, built)), false) = (build, is_dry_run()) {
+            let messages = self.test_cmd(manifest_path, &no_run_args(&cargo_args))?.read()?;
+            let rebuilt = artifacts(&messages, Some(false));
+            let rebuilt: Vec<&str> =
+                built.intersection(&rebuilt).map(|(_, target)| &**target).collect();
+            if !rebuilt.is_empty() {
+                let why = built_with.difference(&options).unwrap_or_else(|| {
+                    "the flags and env vars we know of are the same, so something else changed"
+                        .into()
+                });
+                warning!(
+                    "`cargo test` built {} again after `cargo build` had just built it: {why}",
+                    rebuilt.join(", ")
+                );
+            }
+            if no_run {
+                return Ok(());
+            }
+        }
+        if junit::recording() && !no_run {
+            return self.test_suites(manifest_path, args);
+        }
+        let cmd = self.test_cmd(manifest_path, args)?;
+        if !annotations::enabled() || no_run {
+            return self.run_cargo(cmd);
+        }
+        // We need the output to find the failures in, so it does not go to the terminal.
+        let Err(err) = cmd.tee() else {
+            return Ok(());
+        };
+        if let Some(failed) = err.chain().find_map(|e| e.downcast_ref::<CommandFailed>()) {
+            let base = Path::new(manifest_path).parent().unwrap_or(Path::new("."));
+            annotations::test_failures_of(&failed.output, base, &annotations::root(&self.miri_dir));
+        }
+        Err(err)
+    }
+
+    fn test_cmd(&self, manifest_path: &OsStr, args: &[OsString]) -> Result<Cmd<'_>> {
+        Ok(self
+            .cargo_cmd("test", args)?
+            .arg("--manifest-path")
+            .arg(manifest_path)
+            .args(args)
+            .label(format!("cargo test {}", crate_name(manifest_path))))
+    }
+
+    /// Like `test`, but runs the test targets of the crate one by one, and adds what they report
+    /// to the JUnit report: each harness reports in its own way, and we can only ask libtest for
+    /// JSON when all of the targets use it. Like with `cargo test --no-fail-fast`, a target that
+    /// fails does not keep the others from running.
+    fn test_suites(&self, manifest_path: &OsStr, args: &[OsString]) -> Result<()> {
+        let krate = crate_name(manifest_path);
+        let (cargo_args, harness_args) = split_args(args.to_vec());
+        let (cargo_args, harness_args) = (&cargo_args, &harness_args.unwrap_or_default());
+        let messages = self.test_cmd(manifest_path, &no_run_args(cargo_args))?.read()?;
+        let manifest = std::fs::read_to_string(manifest_path)
+            .with_context(|| format!("failed to read {}", Path::new(manifest_path).display()))?;
+        let base = Path::new(manifest_path).parent().unwrap_or(Path::new("."));
+        let mut failed = Vec::new();
+        // Doc tests only run when no targets were picked.
+        for (kind, name) in test_targets(&messages, cargo_args.is_empty()) {
+            let (select, suite): (&[&str], &str) = match kind {
+                "lib" => (&["--lib"], "lib"),
+                "doc" => (&["--doc"], "doc"),
+                _ => (&[&format!("--{kind}"), &name], &name),
+            };
+            let libtest = kind == "doc" || junit::uses_libtest(&manifest, kind, &name);
+            let format: &[&str] = if libtest {
+                &["-Zunstable-options", "--format", "json", "--report-time"]
+            } else {
+                &[]
+            };
+            let cmd = self
+                .test_cmd(manifest_path, cargo_args)?
+                .args(select)
+                .arg("--")
+                .args(harness_args)
+                .args(format)
+                .ignore_status();
+            let start = std::time::Instant::now();
+            let teed = if libtest { cmd.tee_stderr()? } else { cmd.tee()? };
+            let time = start.elapsed();
+            let (mut cases, output) = if libtest {
+                let cases = junit::parse_libtest(&teed.stdout);
+                let summary = junit::summary(&cases);
+                plain!("{summary}");
+                (cases, summary)
+            } else {
+                (junit::parse_text(&teed.output), teed.output.clone())
+            };
+            if !teed.status.success() {
+                if annotations::enabled() {
+                    annotations::test_failures_of(
+                        &output,
+                        base,
+                        &annotations::root(&self.miri_dir),
+                    );
+                }
+                if !cases.iter().any(|case| matches!(case.outcome, junit::Outcome::Failed { .. })) {
+                    cases.push(junit::Case {
+                        name: suite.to_owned(),
+                        time: None,
+                        outcome: junit::Outcome::Error {
+                            message: format!("`cargo test` failed ({})", teed.status),
+                            output: teed.output.clone(),
+                        },
+                    });
+                }
+                let err = CommandFailed { command: cmd.to_string(), status: teed.status, output };
+                failed.push((suite.to_owned(), err));
+            }
+            junit::add(junit::Suite {
+                name: format!("{krate}/{suite}"),
+                toolchain: self.toolchain.clone(),
+                time,
+                cases,
+            });
+        }
+        let names: Vec<String> =
+            failed.iter().map(|(suite, _)| format!("{krate}/{suite}")).collect();
+        match failed.into_iter().next() {
+            None => Ok(()),
+            Some((_, err)) => Err(err).context(format!("test suites failed: {}", names.join(", "))),
+        }
+    }
+
+    /// Runs the doctests of the crate at `manifest_path` with `cargo test --doc`; `args` go to
+    /// cargo, and after a `--` to the harness. rustdoc builds those itself, so the RUSTDOCFLAGS get
+    /// the rpath that the RUSTFLAGS have. Like `test_suites`, this adds them to the JUnit report.
+    pub fn doctest(&self, manifest_path: impl AsRef<OsStr>, args: &[OsString]) -> Result<Doctests> {
+        let manifest_path = manifest_path.as_ref();
+        let krate = crate_name(manifest_path);
+        let manifest = std::fs::read_to_string(manifest_path)
+            .with_context(|| format!("failed to read {}", Path::new(manifest_path).display()))?;
+        let base = Path::new(manifest_path).parent().unwrap_or(Path::new("."));
+        if !has_lib(base, &manifest) {
+            return Ok(Doctests::None);
+        }
+        let RustcInfo { sysroot, host, .. } = self.rustc_info()?;
+        let theirs = match &Ambient::get().rustdocflags {
+            Some(flags) => decode_rustflags("RUSTDOCFLAGS", flags),
+            None => Vec::new(),
+        };
+        let libdir = path!(sysroot / "lib" / "rustlib" / host / "lib");
+        let (var, rustdocflags) =
+            rustdocflags_env(&merge_flags(&miri_rustdocflags(&libdir)?, &theirs));
+
+        let (cargo_args, harness_args) = split_args(args.to_vec());
+        let (cargo_args, harness_args) = (&cargo_args, &harness_args.unwrap_or_default());
+        // For the report, we need to know how each test went, which libtest only says in JSON.
+        let json = junit::recording();
+        let format: &[&str] =
+            if json { &["-Zunstable-options", "--format", "json", "--report-time"] } else { &[] };
+        let doc_args: Vec<OsString> =
+            std::iter::once("--doc".into()).chain(cargo_args.iter().cloned()).collect();
+        let cmd = self
+            .test_cmd(manifest_path, &doc_args)?
+            .arg("--")
+            .args(harness_args)
+            .args(format)
+            .label(format!("cargo test --doc {krate}"))
+            .env(var, rustdocflags)
+            .ignore_status();
+        let start = std::time::Instant::now();
+        let teed = if json { cmd.tee_stderr()? } else { cmd.tee()? };
+        let time = start.elapsed();
+        let (mut cases, output) = if json {
+            let cases = junit::parse_libtest(&teed.stdout);
+            let summary = junit::summary(&cases);
+            plain!("{summary}");
+            (cases, summary)
+        } else {
+            (junit::parse_text(&teed.output), teed.output.clone())
+        };
+        let result = if teed.status.success() {
+            Ok(if cases.is_empty() { Doctests::None } else { Doctests::Passed(cases.len()) })
+        } else {
+            if annotations::enabled() {
+                annotations::test_failures_of(&output, base, &annotations::root(&self.miri_dir));
+            }
+            if !cases.iter().any(|case| matches!(case.outcome, junit::Outcome::Failed { .. })) {
+                cases.push(junit::Case {
+                    name: "doc".into(),
+                    time: None,
+                    outcome: junit::Outcome::Error {
+                        message: format!("`cargo test --doc` failed ({})", teed.status),
+                        output: teed.output.clone(),
+                    },
+                });
+            }
+            Err(CommandFailed { command: cmd.to_string(), status: teed.status, output }.into())
+        };
+        junit::add(junit::Suite {
+            name: format!("{krate}/doc"),
+            toolchain: self.toolchain.clone(),
+            time,
+            cases,
+        });
+        result.map_err(|err| self.explain_locked(err, manifest_path, args))
+    }
+
+    /// Receives an iterator of files, as the walk of `rust_files` yields them: the first error
+    /// is returned before anything gets formatted. Otherwise like `format_paths`.
+    pub fn format_files<E: std::error::Error + Send + Sync + 'static>(
+        &self,
+        files: impl IntoIterator<Item = Result<PathBuf, E>>,
+        toolchain: Option<&str>,
+        configs: &[(PathBuf, PathBuf)],
+        flags: &[OsString],
+        check: bool,
+    ) -> anyhow::Result<FormatOutcome> {
+        let paths = files.into_iter().collect::<Result<Vec<_>, E>>()?;
+        self.format_paths(&paths, toolchain, configs, flags, check)
+    }
+
+    /// Will format each of `paths` with the config of the first of the `configs` (a root dir and
+    /// the rustfmt config for the files in it) whose root it is in, or with `check` only find out
+    /// which are not formatted. Files that are in none of the roots, or that say `no-rustfmt`
+    /// (see `skips_rustfmt`), are left alone.
+    /// Does not recursively format modules.
+    pub fn format_paths(
+        &self,
+        paths: &[PathBuf],
+        toolchain: Option<&str>,
+        configs: &[(PathBuf, PathBuf)],
+        flags: &[OsString],
+        check: bool,
+    ) -> anyhow::Result<FormatOutcome> {
+        let check_flags: &[&str] = if check { &["--check", "--files-with-diff"] } else { &[] };
+        // With `--check`, we read the list of files it prints.
+        let color = if check { ColorChoice::Never } else { self.color };
+        let color_flag = &(!ArgQuery::new(&["--color"]).is_present(flags))
+            .then(|| format!("--color={}", color.as_str()));
+        let (batches, skipped) = rustfmt_batches(&self.miri_dir, paths, configs);
+        let mut outcome = FormatOutcome { skipped, ..FormatOutcome::default() };
+
+        let mut last_config = None;
+        for RustfmtBatch { config: config_path, files } in batches {
+            // Build base command.
+            let mut cmd = cmd!(
+                self.sh,
+                "rustfmt {toolchain...} --edition=2021 --config-path {config_path} --unstable-features --skip-children {check_flags...} {color_flag...} {flags...}"
+            )
+            .label("rustfmt");
+            if last_config.replace(config_path) != Some(config_path) {
+                // Log an abbreviating command, and only once per config.
+                plain!("$ {cmd} ...");
+            }
+            outcome.files += files.len();
+            cmd = cmd.args(files);
+
+            // Run rustfmt.
+            // The command with all its files is too much to lead with; it comes after our
+            // message.
+            if !check {
+                cmd.quiet().run().context("`rustfmt` failed")?;
+                continue;
+            }
+            // It fails when some file is not formatted, and then lists those on stdout.
+            let output = cmd.quiet().ignore_status().output()?;
+            let unformatted = String::from_utf8_lossy(&output.stdout);
+            if !output.status.success() && unformatted.trim().is_empty() {
+                bail!("`rustfmt` failed: {}", String::from_utf8_lossy(&output.stderr).trim_end());
+            }
+            outcome.unformatted.extend(unformatted.lines().map(|file| self.miri_dir.join(file)));
+        }
+
+        Ok(outcome)
+    }
+
+    /// Runs `tasks` at the same time, each with its own copy of the environment, with the output
+    /// of the commands they `tee` prefixed by their name. When one of them fails, the commands of
+    /// the others get stopped, and the error is that of the one that failed first.
+    pub fn concurrently(&self, tasks: &[(&str, &Task<'_>)]) -> Result<()> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let width = tasks.iter().map(|(name, _)| name.len() + 2).max().unwrap_or(0);
+        let failure = Mutex::new(None);
+        thread::scope(|s| {
+            for &(name, task) in tasks {
+                let e = self.clone();
+                let prefix = format!("{:width$} ", format!("[{name}]"));
+                let context = tee::RunContext { prefix, stop: Some(stop.clone()) };
+                let (stop, failure) = (&stop, &failure);
+                s.spawn(move || {
+                    let Err(err) = tee::in_context(context, || task(&e)) else { return };
+                    // The others fail as well once they get stopped; that is not news.
+                    if !stop.swap(true, Ordering::Relaxed) {
+                        *failure.lock().unwrap() = Some(err.context(format!("{name} failed")));
+                    }
+                });
+            }
+        });
+        match failure.into_inner().unwrap() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Run the given closure many times in parallel with access to the shell, once for each value in the `range`.
+    /// With `max_load`, no further runs start while the load of the machine is above it (see
+    /// `load::wait_below`).
     pub fn run_many_times(
         &self,
-        range: Range<u32>,
-        run: impl Fn(&Shell, u32) -> Result<()> + Sync,
+        range: Range<u32>,
+        jobs: Option<usize>,
+        max_load: Option<f64>,
+        run: impl Fn(&Shell, u32) -> Result<()> + Sync,
+    ) -> Result<()> {
+        // Annotate the commands of each run with its seed.
+        self.run_queue(range, jobs, max_load, |sh, seed| record::with_seed(seed, || run(sh, seed)))
+    }
+
+    /// Like `run_many_times`, for any work `items`: runs `run` for each of them, several at the
+    /// same time. The error of an item that failed says which one it was, with `label`.
+    pub fn run_many<T: Send, I: IntoIterator<Item = T, IntoIter: Send>>(
+        &self,
+        items: I,
+        jobs: Option<usize>,
+        max_load: Option<f64>,
+        label: impl Fn(&T) -> String + Sync,
+        run: impl Fn(&Shell, &T) -> Result<()> + Sync,
+    ) -> Result<()> {
+        self.run_queue(items, jobs, max_load, |sh, item| {
+            run(sh, &item).with_context(|| format!("{} failed", label(&item)))
+        })
+    }
+
+    /// The worker pool of `run_many_times` and `run_many`: `jobs` threads (or one per core) that
+    /// each take the next of the `items` until there are none left, or one of them failed.
+    fn run_queue<T: Send, I: IntoIterator<Item = T, IntoIter: Send>>(
+        &self,
+        items: I,
+        jobs: Option<usize>,
+        max_load: Option<f64>,
+        run: impl Fn(&Shell, T) -> Result<()> + Sync,
     ) -> Result<()> {
-        // `next` is atomic so threads can concurrently fetch their next value to run.
-        let next = AtomicU32::new(range.start);
-        let end = range.end; // exclusive!
+        // The threads take the items from here, so they need not be `Clone`.
+        let queue = Mutex::new(items.into_iter());
         let failed = AtomicBool::new(false);
         thread::scope(|s| {
             let mut handles = Vec::new();
-            // Spawn one worker per core.
-            for _ in 0..thread::available_parallelism()?.get() {
+            // Spawn the requested number of workers, or one per core.
+            let jobs = match jobs {
+                Some(jobs) => jobs,
+                None => thread::available_parallelism()?.get(),
+            };
+            for _ in 0..jobs {
                 // Create a copy of the shell for this thread.
                 let local_shell = self.sh.clone();
-                let handle = s.spawn(|| -> Result<()> {
-                    let local_shell = local_shell; // move the copy into this thread.
-                    // Each worker thread keeps asking for numbers until we're all done.
+                let (queue, failed, run) = (&queue, &failed, &run);
+                let handle = s.spawn(move || -> Result<()> {
+                    // Each worker thread keeps asking for items until we're all done.
                     loop {
-                        let cur = next.fetch_add(1, Ordering::Relaxed);
-                        if cur >= end {
-                            // We hit the upper limit and are done.
+                        if let Some(max_load) = max_load {
+                            load::wait_below(max_load);
+                        }
+                        // After Ctrl-C, we are about to exit; do not start any more runs.
+                        if tee::interrupted() {
                             break;
                         }
-                        // Run the command with this seed.
-                        run(&local_shell, cur).map_err(|err| {
+                        // Not holding the lock while running the item.
+                        let Some(item) = queue.lock().unwrap().next() else {
+                            // There are no more items; we are done.
+                            break;
+                        };
+                        run(&local_shell, item).inspect_err(|_| {
                             // If we failed, tell everyone about this.
                             failed.store(true, Ordering::Relaxed);
-                            err
                         })?;
                         // Check if some other command failed (in which case we'll stop as well).
                         if failed.load(Ordering::Relaxed) {
@@ -267,3 +2937,1422 @@ pub fn run_many_times(
         })
     }
 }
+
+/// The `MIRI_SYSROOT` of the commands run in `sh`: the sysroot we built (see
+/// `MiriEnv::build_miri_sysroot`), or else the user's, unless that is ignored. `Cmd` unsets the
+/// user's for the commands then.
+pub fn miri_sysroot(sh: &Shell) -> Option<OsString> {
+    let ambient = Ambient::get();
+    let value = sh.var_os("MIRI_SYSROOT")?;
+    // Without a value of its own, the shell gives us the one we were started with.
+    if ambient.ignore_miri_sysroot && ambient.miri_sysroot.as_ref() == Some(&value) {
+        return None;
+    }
+    Some(value)
+}
+
+#[doc(hidden)]
+impl MiriEnv {
+    /// Returns the location of the sysroot.
+    ///
+    /// If the target is None the sysroot will be built for the host machine.
+    pub fn build_miri_sysroot(&mut self, target: Option<&OsStr>) -> Result<PathBuf> {
+        if let Some(miri_sysroot) = miri_sysroot(&self.sh) {
+            // Sysroot already set, use that. If the user set it, it may well be a leftover.
+            if Ambient::get().miri_sysroot() == Some(&miri_sysroot) {
+                self.check_miri_sysroot(Path::new(&miri_sysroot), target)?;
+            }
+            return Ok(miri_sysroot.into());
+        }
+        let manifest_path = path!(self.miri_dir / "cargo-miri" / "Cargo.toml");
+        let toolchain = &self.toolchain_flag();
+        let cargo_flags = &self.shared_cargo_flags(&[]);
+
+        // Make sure everything is built. Also Miri itself.
+        self.build_if_changed(&path!(self.miri_dir / "Cargo.toml"), "miri")?;
+        self.build_if_changed(&manifest_path, "cargo-miri")?;
+
+        let target_flag =
+            if let Some(target) = target { vec![OsStr::new("--target"), target] } else { vec![] };
+        let target_flag = &target_flag;
+
+        // Everything that needs this sysroot (parallel workers, other `./miri` invocations) builds
+        // it in the same place, one at a time; the others wait and then find it up to date.
+        let fingerprint = self.sysroot_fingerprint(target)?;
+        let sysroot_dir = sysroot_dir(&self.target_dir, &fingerprint);
+        let stamp = path!(sysroot_dir / SYSROOT_FINGERPRINT_FILE);
+        let _lock = if is_dry_run() {
+            None
+        } else {
+            let lock = lock_file(&sysroot_dir.with_extension("lock"))?;
+            // Never reuse a sysroot that was built with something else (or whose build was
+            // interrupted before we could tell).
+            let target = self.target_or_host(target)?;
+            if sysroot_dir.exists()
+                && sysroot_problem(&sysroot_dir, &target, &fingerprint).is_some()
+            {
+                std::fs::remove_dir_all(&sysroot_dir)
+                    .with_context(|| format!("failed to remove {}", sysroot_dir.display()))?;
+            }
+            Some(lock)
+        };
+
+        if self.verbosity != Verbosity::Quiet {
+            if let Some(target) = target {
+                status!("$ (building Miri sysroot for {})", target.to_string_lossy());
+            } else {
+                status!("$ (building Miri sysroot)");
+            }
+        }
+
+        let timeout = tee::timeout_from_env(SYSROOT_TIMEOUT_VAR, SYSROOT_TIMEOUT)?;
+        let sh = self.build_sh()?;
+        let output =
+            cmd!(sh,
+            "cargo {toolchain...} --quiet run {cargo_flags...} --manifest-path {manifest_path} --
+             miri setup --print-sysroot {target_flag...}"
+        )
+            .env("MIRI_SYSROOT", &sysroot_dir)
+            .label("build the sysroot")
+            .read_with_timeout(timeout);
+        let output = match output {
+            Ok(output) => output,
+            // Trying again would just hang again.
+            Err(err) if err.is::<TimedOut>() => return Err(err),
+            Err(_) => {
+                // Run it again (without `--print-sysroot` or `--quiet`) so the user can see the
+                // error.
+                cmd!(
+                    sh,
+                    "cargo {toolchain...} run {cargo_flags...} --manifest-path {manifest_path} --
+                    miri setup {target_flag...}"
+                )
+                .env("MIRI_SYSROOT", &sysroot_dir)
+                .label("build the sysroot")
+                .run_with_timeout(timeout)
+                .with_context(|| "`cargo miri setup` failed")?;
+                panic!("`cargo miri setup` didn't fail again the 2nd time?");
+            }
+        };
+        if !is_dry_run() {
+            std::fs::create_dir_all(&sysroot_dir)?;
+            std::fs::write(&stamp, &fingerprint)
+                .with_context(|| format!("failed to write {}", stamp.display()))?;
+        }
+        // All the commands we run from now on (also on other threads, which clone this shell) use
+        // that sysroot.
+        self.sh.set_var("MIRI_SYSROOT", &output);
+        Ok(output.into())
+    }
+
+    /// Warns if the `MIRI_SYSROOT` the user set is not what we would build for `target` (the host
+    /// if `None`).
+    fn check_miri_sysroot(&self, miri_sysroot: &Path, target: Option<&OsStr>) -> Result<()> {
+        let target_name = self.target_or_host(target)?;
+        let Some(problem) =
+            sysroot_problem(miri_sysroot, &target_name, &self.sysroot_fingerprint(target)?)
+        else {
+            return Ok(());
+        };
+        // A sysroot that `cargo miri setup` built is fine, if it is for the right target.
+        if problem != SysrootProblem::Unstamped {
+            warning!(
+                "MIRI_SYSROOT is set to {}, but {problem}\n\
+                Miri is likely to fail in confusing ways with it. Unset MIRI_SYSROOT, or pass \
+                `--ignore-miri-sysroot` (like `./miri --ignore-miri-sysroot test`) to ignore it \
+                this once.",
+                miri_sysroot.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// `target`, or the host if that is `None`.
+    fn target_or_host(&self, target: Option<&OsStr>) -> Result<String> {
+        Ok(target.map_or(self.host()?.to_owned(), |t| t.to_string_lossy().into_owned()))
+    }
+
+    /// Describes what the sysroot for `target` (the host if `None`) gets built with.
+    fn sysroot_fingerprint(&self, target: Option<&OsStr>) -> Result<String> {
+        let var = |name| self.sh.var_os(name).unwrap_or_default().to_string_lossy().into_owned();
+        let target = self.target_or_host(target)?;
+        Ok(format!(
+            "{}\nrustc: {}\ntarget: {target}\nMIRI_NO_STD: {}\nMIRI_LIB_SRC: {}\n",
+            self.rustc_version()?,
+            self.rustc.as_deref().map_or("rustup".into(), |rustc| rustc.display().to_string()),
+            var("MIRI_NO_STD"),
+            var("MIRI_LIB_SRC"),
+        ))
+    }
+
+    /// Prepares running the driver with the `flags` given to `./miri run`: sets up a sysroot and
+    /// returns the flags for the driver, and the arguments for the interpreted program.
+    pub fn prepare_run(
+        &mut self,
+        flags: Vec<OsString>,
+    ) -> Result<(Vec<OsString>, Option<Vec<OsString>>)> {
+        // Everything after `--` is for the interpreted program; we only look at the flags before.
+        let (mut flags, program_args) = split_args(flags);
+        let target = match &arg_flag_values(&flags, "--target")[..] {
+            [] => None,
+            [target] => Some(target.clone()),
+            _ => bail!("`--target` must not be given more than once"),
+        };
+
+        // Scan for "--edition", set one ourselves if that flag is not present.
+        let have_edition = ArgQuery::new(&["--edition"]).is_present(&flags);
+        if !have_edition {
+            flags.push("--edition=2021".into()); // keep in sync with `tests/ui.rs`.`
+        }
+
+        // Prepare a sysroot, and add it to the flags (replacing any sysroot the user might have set,
+        // since that would not work with Miri anyway).
+        let miri_sysroot = self.build_miri_sysroot(target.as_deref())?;
+        set_flag(&mut flags, "--sysroot", miri_sysroot);
+        Ok((flags, program_args))
+    }
+}
+
+/// The shells `./miri env` can produce output for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShellKind {
+    Sh,
+    Fish,
+    Powershell,
+    Cmd,
+}
+
+impl FromStr for ShellKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "sh" | "bash" | "zsh" => ShellKind::Sh,
+            "fish" => ShellKind::Fish,
+            "powershell" | "pwsh" => ShellKind::Powershell,
+            "cmd" => ShellKind::Cmd,
+            _ => bail!("unknown shell `{s}`, expected one of: sh, fish, powershell, cmd"),
+        })
+    }
+}
+
+impl ShellKind {
+    /// Returns a command that sets the environment variable `key` to `value` in this shell.
+    pub fn export(self, key: &str, value: &str) -> Result<String> {
+        Ok(match self {
+            ShellKind::Sh => format!("export {key}={}", cmdline::quote(self, value)),
+            ShellKind::Fish => format!("set -gx {key} {}", cmdline::quote(self, value)),
+            ShellKind::Powershell => format!("$env:{key} = {}", cmdline::powershell_string(value)),
+            ShellKind::Cmd => {
+                // cmd has no way of quoting these.
+                if value.contains(['"', '\n', '\r']) {
+                    bail!("the value of {key} cannot be represented in cmd: {value:?}");
+                }
+                format!("set \"{key}={value}\"")
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(Into::into).collect()
+    }
+
+    fn args_str(args: &[&str]) -> Vec<String> {
+        args.iter().map(|&a| a.to_owned()).collect()
+    }
+
+    #[test]
+    fn flagsplit_cases() {
+        let cases: &[(&str, &[&str])] = &[
+            ("", &[]),
+            ("   ", &[]),
+            (" \t\r\n ", &[]),
+            ("-a -b", &["-a", "-b"]),
+            ("  -a   -b  ", &["-a", "-b"]),
+            // Without quotes, backslashes are kept as-is.
+            (r"--path C:\dir\file", &["--path", r"C:\dir\file"]),
+            (r#"--config "a b""#, &["--config", "a b"]),
+            ("--config 'a b' -c", &["--config", "a b", "-c"]),
+            (
+                r#"--config "build.rustflags=[\"-Cdebuginfo=1\"]""#,
+                &["--config", r#"build.rustflags=["-Cdebuginfo=1"]"#],
+            ),
+            (r#"a\ b "c""#, &["a b", "c"]),
+            // Just quotes are no flags either, but an empty value is kept.
+            (r#""""#, &[]),
+            (r#" "" '' "#, &[]),
+            ("--cfg ''", &["--cfg", ""]),
+            // Any whitespace separates flags.
+            ("-a\t-b", &["-a", "-b"]),
+            ("\n-a\n  -b\n", &["-a", "-b"]),
+            ("-a\r\n-b\r\n", &["-a", "-b"]),
+            ("--config 'a b'\r\n\t-c\r\n", &["--config", "a b", "-c"]),
+            ("--config \"a\tb\"\n-c", &["--config", "a\tb", "-c"]),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(flagsplit(input).unwrap(), *expected, "input: {input:?}");
+        }
+        // But not in the variables cargo splits.
+        assert_eq!(split_on_spaces("-a\n-b -c"), ["-a\n-b", "-c"]);
+    }
+
+    #[test]
+    fn flagsplit_unterminated() {
+        assert!(flagsplit(r#"--config "a b"#).is_err());
+        assert!(flagsplit("--config 'a b").is_err());
+    }
+
+    #[test]
+    fn flag_values_repeated() {
+        let a = args(&["--features", "a", "-L", "x", "--features", "b"]);
+        assert_eq!(arg_flag_values(&a, "--features"), args(&["a", "b"]));
+        assert_eq!(arg_flag_values(&a, "-L"), args(&["x"]));
+        assert_eq!(arg_flag_value(&a, "--features"), Some("a".into()));
+        assert_eq!(arg_flag_values(&a, "--target"), args(&[]));
+    }
+
+    #[test]
+    fn flag_values_stop_at_dashdash() {
+        let a = args(&["--features", "a", "--", "--features", "b"]);
+        assert_eq!(arg_flag_values(&a, "--features"), args(&["a"]));
+        let a = args(&["--", "--features", "b"]);
+        assert_eq!(arg_flag_values(&a, "--features"), args(&[]));
+        assert_eq!(arg_flag_value(&a, "--features"), None);
+    }
+
+    #[test]
+    fn flag_values_mixed_forms() {
+        let a = args(&["--features=a", "--features", "b", "--features=", "--featuresx=c"]);
+        assert_eq!(arg_flag_values(&a, "--features"), args(&["a", "b", ""]));
+        // A flag without its value at the very end.
+        let a = args(&["--features=a", "--features"]);
+        assert_eq!(arg_flag_values(&a, "--features"), args(&["a"]));
+    }
+
+    #[test]
+    fn windows_paths_in_flags() {
+        // Spaces, drive letters and backslashes are nothing special, in either form of a flag.
+        let manifest = r"C:\Program Files\miri\Cargo.toml";
+        let mut a = args(&[
+            "--manifest-path",
+            manifest,
+            r"--target-dir=D:\my target\",
+            "--",
+            r"E:\a b.rs",
+            "--target-dir",
+            r"F:\",
+        ]);
+        assert_eq!(arg_flag_value(&a, "--manifest-path"), Some(manifest.into()));
+        assert_eq!(arg_flag_value(&a, "--target-dir"), Some(r"D:\my target\".into()));
+        set_flag(&mut a, "--sysroot", r"\\server\share\sysroot");
+        assert_eq!(remove_flag(&mut a, "--target-dir"), args(&[r"D:\my target\"]));
+        let (flags, forwarded) = split_args(a);
+        assert_eq!(
+            flags,
+            args(&["--manifest-path", manifest, "--sysroot", r"\\server\share\sysroot"])
+        );
+        assert_eq!(forwarded, Some(args(&[r"E:\a b.rs", "--target-dir", r"F:\"])));
+        // In CARGO_EXTRA_FLAGS, a path with spaces has to be quoted; that keeps the backslashes.
+        let flags = flagsplit(r#"--target-dir "C:\my dir\t" --config 'D:\a b\c.toml'"#).unwrap();
+        assert_eq!(flags, ["--target-dir", r"C:\my dir\t", "--config", r"D:\a b\c.toml"]);
+    }
+
+    #[test]
+    fn remove_flag_forms() {
+        let mut a = args(&["a", "--target", "x", "--target=y", "b", "--", "--target", "z"]);
+        assert!(ArgQuery::new(&["--target"]).is_present(&a));
+        assert_eq!(remove_flag(&mut a, "--target"), args(&["x", "y"]));
+        assert_eq!(a, args(&["a", "b", "--", "--target", "z"]));
+        assert!(!ArgQuery::new(&["--target"]).is_present(&a));
+        // Adjacent duplicates.
+        let mut a = args(&["--target", "x", "--target", "y", "--target=z"]);
+        assert_eq!(remove_flag(&mut a, "--target"), args(&["x", "y", "z"]));
+        assert_eq!(a, args(&[]));
+        // The flag is the last argument, with its value missing.
+        let mut a = args(&["a", "--target"]);
+        assert_eq!(remove_flag(&mut a, "--target"), args(&[]));
+        assert_eq!(a, args(&["a"]));
+    }
+
+    #[test]
+    fn set_flag_forms() {
+        // Replace the first occurrence in place, remove the others.
+        let mut a = args(&["a", "--sysroot=x", "b", "--sysroot", "y", "c"]);
+        set_flag(&mut a, "--sysroot", "z");
+        assert_eq!(a, args(&["a", "--sysroot", "z", "b", "c"]));
+        // Append if missing, but before `--`.
+        let mut a = args(&["a", "--", "--sysroot", "x"]);
+        set_flag(&mut a, "--sysroot", "z");
+        assert_eq!(a, args(&["a", "--sysroot", "z", "--", "--sysroot", "x"]));
+        let mut a = args(&["a"]);
+        set_flag(&mut a, "--sysroot", "z");
+        assert_eq!(a, args(&["a", "--sysroot", "z"]));
+        // The flag is the last argument, with its value missing.
+        let mut a = args(&["a", "--sysroot"]);
+        set_flag(&mut a, "--sysroot", "z");
+        assert_eq!(a, args(&["a", "--sysroot", "z"]));
+    }
+
+    #[test]
+    fn flag_spans() {
+        let span = |a: &[&str]| arg_flag_span(a, "--target");
+        assert_eq!(span(&["a", "--target", "x", "b"]), Some((1, 3)));
+        assert_eq!(span(&["a", "--target=x", "--target", "y"]), Some((1, 2)));
+        assert_eq!(span(&["a", "--target"]), Some((1, 2)));
+        assert_eq!(span(&["--target", "--", "x"]), Some((0, 1)));
+        let mut a = args(&["--target", "--", "x"]);
+        assert!(remove_flag(&mut a, "--target").is_empty());
+        assert_eq!(a, args(&["--", "x"]));
+        assert_eq!(span(&["--targets", "x", "--", "--target", "y"]), None);
+        let mut a = args_str(&["--target-dir=a", "--target", "x", "b"]);
+        set_flag_value(&mut a, "--target-dir", "c");
+        set_flag_value(&mut a, "--target", "y");
+        assert_eq!(a, ["--target-dir=c", "--target", "y", "b"]);
+        let mut a = args_str(&["b", "--target"]);
+        set_flag_value(&mut a, "--target", "y");
+        assert_eq!(a, ["b", "--target"]);
+    }
+
+    #[test]
+    fn flag_spans_all_short_args() {
+        // All the argument lists of up to 5 of these.
+        let words = ["--target", "--target=x", "x", "--targets", "--"];
+        let mut lists: Vec<Vec<&str>> = vec![vec![]];
+        for len in 1..=5 {
+            let longer: Vec<_> = lists.iter().filter(|l| l.len() == len - 1).cloned().collect();
+            for list in longer {
+                lists.extend(words.iter().map(|word| [&list[..], &[word]].concat()));
+            }
+        }
+        for list in lists {
+            let mut rest = args(&list);
+            let mut found = Vec::new();
+            while let Some((start, end)) = arg_flag_span(&rest, "--target") {
+                assert!(start < end && end <= rest.len() && end - start <= 2, "{list:?}");
+                assert!(!rest[..start].contains(&"--".into()), "{list:?}");
+                // What is removed is never found again: each span takes one occurrence.
+                found.push(rest.drain(start..end).collect::<Vec<_>>());
+                assert!(found.len() <= list.len(), "{list:?}");
+            }
+            assert!(!ArgQuery::new(&["--target"]).is_present(&rest), "{list:?}");
+            // `remove_flag` finds the same, and leaves the other arguments in their order.
+            let mut removed = args(&list);
+            let values: Vec<_> = found.iter().filter_map(|span| span.get(1).cloned()).collect();
+            let eq_values = found.iter().filter(|span| span[0] == "--target=x").count();
+            assert_eq!(remove_flag(&mut removed, "--target").len(), values.len() + eq_values);
+            assert_eq!(removed, rest, "{list:?}");
+        }
+    }
+
+    #[test]
+    fn flag_parse_numbers() {
+        let jobs = |a: &[&str]| arg_flag_parse::<usize>(args(a), "--jobs");
+        assert_eq!(jobs(&["--jobs", "4"]).unwrap(), Some(4));
+        assert_eq!(jobs(&["--jobs=4", "--jobs", "8"]).unwrap(), Some(8));
+        assert_eq!(jobs(&["a", "--", "--jobs=4"]).unwrap(), None);
+        let err = jobs(&["--jobs=-1"]).unwrap_err().to_string();
+        assert_eq!(err, "invalid value `-1` for `--jobs`");
+        let err = jobs(&["--jobs", "-1"]).unwrap_err().to_string();
+        assert_eq!(err, "invalid value `-1` for `--jobs`");
+        let err = jobs(&["--jobs="]).unwrap_err().to_string();
+        assert_eq!(err, "`--jobs` needs a value, but an empty one was given");
+        assert!(jobs(&["--jobs"]).is_err());
+        assert!(jobs(&["--no-jobs"]).is_err());
+        // Negative numbers are fine for signed types.
+        assert_eq!(arg_flag_parse::<i32>(args(&["--offset", "-3"]), "--offset").unwrap(), Some(-3));
+        // Empty values are fine for strings.
+        assert_eq!(
+            arg_flag_parse::<String>(args(&["--name="]), "--name").unwrap(),
+            Some(String::new())
+        );
+    }
+
+    #[test]
+    fn flag_parse_bools() {
+        let color = |a: &[&str]| arg_flag_parse::<bool>(args(a), "--color");
+        assert_eq!(color(&["--color", "x"]).unwrap(), Some(true));
+        assert_eq!(color(&["--color", "--no-color"]).unwrap(), Some(false));
+        assert_eq!(color(&["--no-color", "--color=true"]).unwrap(), Some(true));
+        assert_eq!(color(&["x"]).unwrap(), None);
+        assert!(color(&["--color=yes"]).is_err());
+        assert!(color(&["--color="]).is_err());
+    }
+
+    #[test]
+    fn merge_flags_dedup() {
+        let ours = args(&["-C", "link-args=-Wl,-rpath,/lib", "-Zunstable-options", "-Wfoo"]);
+        let theirs = args(&["-Z", "unstable-options", "-Wfoo", "-C", "debuginfo=1", "-g"]);
+        assert_eq!(
+            merge_flags(&ours, &theirs),
+            args(&[
+                "-C",
+                "link-args=-Wl,-rpath,/lib",
+                "-Zunstable-options",
+                "-Wfoo",
+                "-C",
+                "debuginfo=1",
+                "-g"
+            ])
+        );
+        // Flags that add something each time are kept as often as they are given.
+        let theirs =
+            args(&["--cfg", "a", "a", "--cfg=a", "a", "-L", "a", "-Cdebuginfo=1", "-L", "a"]);
+        assert_eq!(merge_flags(&ours, &theirs)[4..], theirs);
+        let theirs = args(&["-Clink-arg=-Xlinker", "-C", "link-arg=-Xlinker", "--extern", "d"]);
+        assert_eq!(merge_flags(&args(&["--extern", "d"]), &theirs)[2..], theirs);
+        // Arguments that are not valid UTF-8 are never dropped.
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let odd = OsStr::from_bytes(b"link-arg=/\xff/lib").to_owned();
+            let flags = vec!["-C".into(), odd.clone(), "-C".into(), odd];
+            assert_eq!(merge_flags(&flags, &flags).len(), 8);
+        }
+    }
+
+    #[test]
+    fn merge_flags_lint_conflicts() {
+        let ours = args(&["-Wunused-lifetimes", "-Zunstable-options", "-Drust_2018_idioms"]);
+        let theirs = args(&["-A", "unused_lifetimes", "--warn=rust-2018-idioms", "-Dfoo"]);
+        assert_eq!(
+            merge_flags(&ours, &theirs),
+            args(&[
+                "-Zunstable-options",
+                "-A",
+                "unused_lifetimes",
+                "--warn=rust-2018-idioms",
+                "-Dfoo"
+            ])
+        );
+        // Within one list, the last setting wins as well.
+        let theirs = args(&["-Afoo", "--deny", "foo", "-Wbar", "-Wfoo"]);
+        assert_eq!(merge_flags(&args(&[]), &theirs), args(&["-Wbar", "-Wfoo"]));
+    }
+
+    #[test]
+    fn rustflags_odd_libdir() {
+        let tmp = TempDir::new("miri-script rustflags, test", false).unwrap();
+        let libdir = path!(tmp.path / "lib" / "rustlib" / "x86_64-unknown-linux-gnu" / "lib");
+        let libdir_str = libdir.to_str().unwrap();
+        let theirs = split_on_spaces("-C link-arg=-Xlinker -Cdebuginfo=1");
+        let (var, value) = rustflags_env(&merge_flags(&miri_rustflags(&libdir).unwrap(), &theirs));
+        assert_eq!(var, "CARGO_ENCODED_RUSTFLAGS");
+        // Decode like cargo does, and take out the linker arguments like rustc does.
+        let value = value.into_string().unwrap();
+        let flags: Vec<&str> = value.split('\x1f').collect();
+        let link_args: Vec<&str> = flags
+            .windows(2)
+            .filter(|w| w[0] == "-C")
+            .filter_map(|w| w[1].strip_prefix("link-arg="))
+            .collect();
+        assert_eq!(link_args, ["-Xlinker", "-rpath", "-Xlinker", libdir_str, "-Xlinker"]);
+        assert!(flags.ends_with(&["-C", "link-arg=-Xlinker", "-Cdebuginfo=1"]));
+
+        // Without spaces, `RUSTFLAGS` does, split like cargo splits it.
+        let (var, value) = rustflags_env(&miri_rustflags(Path::new("/a,b/lib")).unwrap());
+        assert_eq!(var, "RUSTFLAGS");
+        assert_eq!(
+            split_on_spaces(value.to_str().unwrap())[..8],
+            [
+                "-C",
+                "link-arg=-Xlinker",
+                "-C",
+                "link-arg=-rpath",
+                "-C",
+                "link-arg=-Xlinker",
+                "-C",
+                "link-arg=/a,b/lib"
+            ]
+        );
+    }
+
+    #[test]
+    fn rustflags_libdir_characters() {
+        for libdir in ["/opt/a,b/lib", "/opt/my rust/lib", "/opt/\"q\" 'q'/lib", "/opt/rüst/lib"] {
+            let (var, value) = rustflags_env(&miri_rustflags(Path::new(libdir)).unwrap());
+            let flags = decode_rustflags(var, &value);
+            assert_eq!(flags[6..8], args(&["-C", &format!("link-arg={libdir}")]), "{libdir}");
+        }
+        // A libdir that is not valid UTF-8 is passed on as it is.
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let libdir = Path::new(OsStr::from_bytes(b"/opt/r\xfcst/lib"));
+            let (var, value) = rustflags_env(&miri_rustflags(libdir).unwrap());
+            assert_eq!(var, "RUSTFLAGS");
+            let flags = decode_rustflags(var, &value);
+            assert_eq!(flags[7].as_encoded_bytes(), b"link-arg=/opt/r\xfcst/lib");
+            assert_eq!(flags[8..], lint_flags());
+        }
+        let err = miri_rustflags(Path::new("/opt/a\x1fb/lib")).unwrap_err().to_string();
+        assert!(err.contains("`a\\u{1f}b` contains a \\x1f character"), "{err}");
+    }
+
+    #[test]
+    fn rustflags_env_spaces() {
+        // The rpath to a libdir with spaces in its path cannot go into `RUSTFLAGS`.
+        let ours = args(&["-C", "link-args=-Wl,-rpath,/my libs/lib", "-Zunstable-options"]);
+        let theirs = split_on_spaces("-Z unstable-options  -Cdebuginfo=1");
+        let (var, value) = rustflags_env(&merge_flags(&ours, &theirs));
+        assert_eq!(var, "CARGO_ENCODED_RUSTFLAGS");
+        assert_eq!(
+            value.to_str().unwrap().split('\x1f').collect::<Vec<_>>(),
+            ["-C", "link-args=-Wl,-rpath,/my libs/lib", "-Zunstable-options", "-Cdebuginfo=1"]
+        );
+        let (var, value) = rustflags_env(&args(&["-C", "link-args=-Wl,-rpath,/lib", "-g"]));
+        assert_eq!(var, "RUSTFLAGS");
+        assert_eq!(value, "-C link-args=-Wl,-rpath,/lib -g");
+    }
+
+    #[test]
+    fn rustfmt_config_per_root() {
+        let miri_dir = miri_dir().unwrap();
+        let configs = rustfmt_configs(&miri_dir);
+        let config = |file: &Path| {
+            let (_, config) = configs.iter().find(|(root, _)| file.starts_with(root)).unwrap();
+            config.strip_prefix(&miri_dir).unwrap().to_owned()
+        };
+        assert_eq!(
+            config(&path!(miri_dir / "tests" / "pass" / "a.rs")),
+            Path::new("tests/rustfmt.toml")
+        );
+        assert_eq!(config(&path!(miri_dir / "src" / "lib.rs")), Path::new("rustfmt.toml"));
+        assert!(configs.iter().all(|(_, config)| config.exists()));
+
+        let tmp = TempDir::new("miri-script-no-rustfmt-test", false).unwrap();
+        let file = path!(tmp.path / "a.rs");
+        std::fs::write(&file, "//@compile-flags: -Zmiri-disable-isolation\n  // no-rustfmt\n")
+            .unwrap();
+        assert!(skips_rustfmt(&file));
+        std::fs::write(&file, "fn main() {}\n\n\n\n\n// no-rustfmt\n").unwrap();
+        assert!(!skips_rustfmt(&file));
+        assert!(!skips_rustfmt(&path!(tmp.path / "missing.rs")));
+    }
+
+    #[test]
+    fn rustfmt_batches_per_config() {
+        let tmp = TempDir::new("miri-script-rustfmt-batches-test", false).unwrap();
+        let miri_dir = &tmp.path;
+        let configs = rustfmt_configs(miri_dir);
+        std::fs::create_dir(path!(miri_dir / "tests")).unwrap();
+        let skipped = path!(miri_dir / "tests" / "skipped.rs");
+        std::fs::write(&skipped, "// no-rustfmt\n").unwrap();
+        let mut paths = vec![
+            path!(miri_dir / "tests" / "a.rs"),
+            skipped,
+            path!(miri_dir / "src" / "lib.rs"),
+            // In none of the roots.
+            PathBuf::from("/elsewhere/b.rs"),
+        ];
+        paths
+            .extend((0..RUSTFMT_BATCH_SIZE).map(|i| path!(miri_dir / "tests" / format!("{i}.rs"))));
+        let (batches, skipped) = rustfmt_batches(miri_dir, &paths, &configs);
+        assert_eq!(skipped, 1);
+        let summary: Vec<_> =
+            batches.iter().map(|batch| (batch.config, batch.files.len())).collect();
+        let (tests_config, root_config) = (&*configs[0].1, &*configs[1].1);
+        assert_eq!(
+            summary,
+            [(tests_config, RUSTFMT_BATCH_SIZE), (tests_config, 1), (root_config, 1)]
+        );
+        // In order, and relative to the Miri dir.
+        assert_eq!(batches[0].files[0], Path::new("tests/a.rs"));
+        assert_eq!(batches[1].files, [Path::new("tests/255.rs")]);
+        assert_eq!(batches[2].files, [Path::new("src/lib.rs")]);
+        let (batches, _) = rustfmt_batches(miri_dir, &[], &configs);
+        assert!(batches.is_empty());
+    }
+
+    #[test]
+    fn finds_helper_crates() {
+        let tmp = TempDir::new("miri-script-helper-crates-test", false).unwrap();
+        assert_eq!(helper_crates(&tmp.path), (vec![], vec![]));
+        let deps = path!(tmp.path / TEST_DEPS_DIR);
+        for dir in ["", "weird", "aux", "target", "not-a-crate"] {
+            std::fs::create_dir_all(path!(deps / dir)).unwrap();
+        }
+        for dir in ["", "aux", "target"] {
+            std::fs::write(path!(deps / dir / "Cargo.toml"), "[package]\nname = \"x\"\n").unwrap();
+        }
+        std::fs::write(
+            path!(deps / "weird" / "Cargo.toml"),
+            "[package.metadata.miri-script]\nskip-lints = true # because\n",
+        )
+        .unwrap();
+        let (covered, skipped) = helper_crates(&tmp.path);
+        assert_eq!(covered, [path!(deps / "Cargo.toml"), path!(deps / "aux" / "Cargo.toml")]);
+        assert_eq!(skipped, [path!(deps / "weird" / "Cargo.toml")]);
+        assert!(!skips_lints("[package.metadata.other]\nskip-lints = true\n"));
+    }
+
+    #[test]
+    fn doctest_flags() {
+        let flags = miri_rustdocflags(Path::new("/my rust/lib")).unwrap();
+        assert_eq!(flags[..8], miri_rustflags(Path::new("/my rust/lib")).unwrap()[..8]);
+        assert_eq!(flags[8..], ["-Zunstable-options"]);
+        let (var, _) = rustdocflags_env(&flags);
+        assert_eq!(var, "CARGO_ENCODED_RUSTDOCFLAGS");
+        let (var, value) = rustdocflags_env(&miri_rustdocflags(Path::new("/lib")).unwrap());
+        assert_eq!(var, "RUSTDOCFLAGS");
+        let value = value.into_string().unwrap();
+        assert!(value.ends_with("-C link-arg=/lib -Zunstable-options"), "{value}");
+
+        let miri_dir = miri_dir().unwrap();
+        assert!(has_lib(&miri_dir, ""));
+        assert!(!has_lib(&path!(miri_dir / "cargo-miri"), "[package]\nname = \"cargo-miri\""));
+        assert!(has_lib(&path!(miri_dir / "cargo-miri"), "[lib]\npath = \"lib.rs\""));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn flag_values_non_utf8() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let non_utf8 = || OsString::from_vec(vec![b'a', 0xff, b'b']);
+        let mut eq = OsString::from("--target=");
+        eq.push(non_utf8());
+        let a = vec![non_utf8(), "--target".into(), non_utf8(), eq];
+        assert_eq!(arg_flag_values(&a, "--target"), [non_utf8(), non_utf8()]);
+        // A non-UTF-8 argument that merely starts like the flag does not match.
+        let mut similar = OsString::from("--target");
+        similar.push(non_utf8());
+        assert!(!ArgQuery::new(&["--target"]).is_present([similar]));
+        let mut a = a;
+        assert_eq!(remove_flag(&mut a, "--target").len(), 2);
+        assert_eq!(a, [non_utf8()]);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn flag_values_non_utf16() {
+        use std::os::windows::ffi::OsStringExt;
+
+        // Contains an unpaired surrogate, so this is not valid UTF-16.
+        let unpaired = || OsString::from_wide(&[b'a' as u16, 0xD800, b'b' as u16]);
+        let mut eq = OsString::from("--target=");
+        eq.push(unpaired());
+        let a = vec![unpaired(), "--target".into(), unpaired(), eq];
+        assert_eq!(arg_flag_values(&a, "--target"), [unpaired(), unpaired()]);
+        let mut similar = OsString::from("--target");
+        similar.push(unpaired());
+        assert!(!ArgQuery::new(&["--target"]).is_present([similar]));
+        let mut a = a;
+        assert_eq!(remove_flag(&mut a, "--target").len(), 2);
+        assert_eq!(a, [unpaired()]);
+    }
+
+    #[test]
+    fn miriflags_roundtrip() {
+        let cases: &[(&str, &[&str])] = &[
+            ("", &[]),
+            ("-Zmiri-disable-isolation", &["-Zmiri-disable-isolation"]),
+            (" -Zmiri-seed=1   -O ", &["-Zmiri-seed=1", "-O"]),
+            // Quotes and backslashes have no special meaning.
+            (r#"-Zmiri-env-set="a b" C:\dir"#, &[r#"-Zmiri-env-set="a"#, r#"b""#, r"C:\dir"]),
+        ];
+        for (input, expected) in cases {
+            let flags = miriflags::parse(input.as_ref());
+            assert_eq!(flags, *expected, "input: {input:?}");
+            let rendered = miriflags::render(&flags).unwrap();
+            assert_eq!(miriflags::parse(&rendered), flags, "input: {input:?}");
+        }
+        assert!(miriflags::render(&args_str(&["-O", ""])).is_err());
+        assert!(miriflags::render(&args_str(&["-Zmiri-env-set=A=a b"])).is_err());
+    }
+
+    #[test]
+    fn miriflags_with_flag() {
+        let with = |existing: &str, flag| miriflags::with_flag(existing.as_ref(), flag).unwrap();
+        assert_eq!(with("", "-Zmiri-seed=1"), "-Zmiri-seed=1");
+        assert_eq!(with("-O  -Zmiri-seed=1 -g", "-Zmiri-seed=2"), "-O -Zmiri-seed=2 -g");
+        assert_eq!(with("-Zmiri-seed=1 -O -Zmiri-seed=3", "-Zmiri-seed=2"), "-Zmiri-seed=2 -O");
+        assert_eq!(with("-Zmiri-seeds=1..2", "-Zmiri-seed=2"), "-Zmiri-seeds=1..2 -Zmiri-seed=2");
+        // Flags without a value are not duplicated.
+        assert_eq!(with("-O -g", "-O"), "-O -g");
+        assert_eq!(with("-g", "-O"), "-g -O");
+        let err = miriflags::with_flag("-O".as_ref(), "-Zmiri-env-set=A=a b").unwrap_err();
+        assert!(err.to_string().contains(r#""-Zmiri-env-set=A=a b""#), "{err}");
+    }
+
+    #[test]
+    fn split_args_cases() {
+        // No separator.
+        assert_eq!(split_args(args(&["a", "b"])), (args(&["a", "b"]), None));
+        assert_eq!(split_args(args(&[])), (args(&[]), None));
+        // Empty left side.
+        assert_eq!(split_args(args(&["--", "a"])), (args(&[]), Some(args(&["a"]))));
+        // Empty right side.
+        assert_eq!(split_args(args(&["a", "--"])), (args(&["a"]), Some(args(&[]))));
+        assert_eq!(split_args(args(&["--"])), (args(&[]), Some(args(&[]))));
+        // Only the first separator is consumed, the others are forwarded.
+        assert_eq!(
+            split_args(args(&["a", "--", "b", "--", "c", "--"])),
+            (args(&["a"]), Some(args(&["b", "--", "c", "--"])))
+        );
+        assert_eq!(split_args(args(&["--", "--"])), (args(&[]), Some(args(&["--"]))));
+    }
+
+    #[test]
+    fn test_harness_args_keep_dashdash() {
+        let harness = |a: &[&str]| test_harness_args(args(a));
+        assert_eq!(harness(&["a", "--", "b"]), (args(&[]), args(&["--", "a", "--", "b"])));
+        assert_eq!(harness(&["a", "--"]), (args(&[]), args(&["--", "a", "--"])));
+        assert_eq!(harness(&["a"]), (args(&[]), args(&["--", "a"])));
+        assert_eq!(
+            harness(&["--target", "t", "a", "--", "--target", "u"]),
+            (args(&["t"]), args(&["--", "a", "--", "--target", "u"]))
+        );
+    }
+
+    #[test]
+    fn sysroot_problems() {
+        let dir = TempDir::new("miri-script-sysroot-problem-test", false).unwrap();
+        let sysroot = path!(dir.path / "sysroot");
+        let fingerprint = "rustc 1.80.0-nightly\ntarget: x86_64-unknown-linux-gnu\n";
+        let problem = |target| sysroot_problem(&sysroot, target, fingerprint);
+        assert_eq!(problem("x86_64-unknown-linux-gnu"), Some(SysrootProblem::Missing));
+        std::fs::create_dir_all(path!(sysroot / "lib/rustlib/x86_64-unknown-linux-gnu/lib"))
+            .unwrap();
+        assert_eq!(
+            problem("i686-pc-windows-gnu"),
+            Some(SysrootProblem::NoTarget("i686-pc-windows-gnu".into()))
+        );
+        assert_eq!(problem("x86_64-unknown-linux-gnu"), Some(SysrootProblem::Unstamped));
+        let stamp = path!(sysroot / SYSROOT_FINGERPRINT_FILE);
+        std::fs::write(&stamp, "rustc 1.79.0-nightly\ntarget: x86_64-unknown-linux-gnu\n").unwrap();
+        let stale = problem("x86_64-unknown-linux-gnu").unwrap();
+        assert_eq!(
+            stale,
+            SysrootProblem::Stale(vec![(
+                "rustc 1.79.0-nightly".into(),
+                "rustc 1.80.0-nightly".into()
+            )])
+        );
+        assert!(stale.to_string().ends_with("now using:  rustc 1.80.0-nightly"), "{stale}");
+        std::fs::write(&stamp, fingerprint).unwrap();
+        assert_eq!(problem("x86_64-unknown-linux-gnu"), None);
+    }
+
+    #[test]
+    fn writable_dirs() {
+        let dir = TempDir::new("miri-script-writable-test", false).unwrap();
+        check_writable(&path!(dir.path / "not" / "there")).unwrap();
+        assert_eq!(std::fs::read_dir(&dir.path).unwrap().count(), 0);
+        let file = path!(dir.path / "file");
+        std::fs::write(&file, "").unwrap();
+        let err = format!("{:#}", check_writable(&path!(file / "bin")).unwrap_err());
+        assert!(err.starts_with(&format!("cannot create files in {}: ", file.display())), "{err}");
+    }
+
+    #[test]
+    fn strip_configs() {
+        let config = |flags: &[&str]| strip_config(&args(flags));
+        assert_eq!(config(&["--locked"]).as_deref(), Some("profile.release.strip=\"symbols\""));
+        assert_eq!(config(&["--debug"]).as_deref(), Some("profile.dev.strip=\"symbols\""));
+        assert_eq!(config(&["--profile=dev"]).as_deref(), Some("profile.dev.strip=\"symbols\""));
+        assert_eq!(config(&["--profile", "dist"]), None);
+    }
+
+    #[test]
+    fn build_executable() {
+        let messages = r#"{"reason":"compiler-artifact","target":{"name":"miri"},"executable":null}
+{"reason":"compiler-artifact","target":{"name":"cargo-miri"},"executable":"/t/debug/cargo-miri"}
+not json
+{"reason":"compiler-artifact","target":{"name":"miri"},"executable":"/t/debug/miri"}
+{"reason":"build-finished","success":true}"#;
+        assert_eq!(executable(messages, "miri"), Some(PathBuf::from("/t/debug/miri")));
+        assert_eq!(executable(messages, "miri-script"), None);
+    }
+
+    #[test]
+    fn shell_exports() {
+        let value = r"it's a \test";
+        assert_eq!(ShellKind::Sh.export("A", value).unwrap(), r"export A='it'\''s a \test'");
+        assert_eq!(ShellKind::Fish.export("A", value).unwrap(), r"set -gx A 'it\'s a \\test'");
+        assert_eq!(ShellKind::Powershell.export("A", value).unwrap(), r"$env:A = 'it''s a \test'");
+        assert_eq!(ShellKind::Cmd.export("A", value).unwrap(), r#"set "A=it's a \test""#);
+        assert!(ShellKind::Cmd.export("A", "say \"hi\"").is_err());
+        // The separator of `CARGO_ENCODED_RUSTFLAGS` survives quoting.
+        assert_eq!(ShellKind::Sh.export("A", "-a\x1f-b").unwrap(), "export A=-a\x1f-b");
+        assert_eq!("pwsh".parse::<ShellKind>().unwrap(), ShellKind::Powershell);
+        assert!("tcsh".parse::<ShellKind>().is_err());
+    }
+
+    #[test]
+    fn arg_query_aliases() {
+        let package = ArgQuery::new(&["-p", "--package"]);
+        let a = args(&["--package=a", "-p", "b", "--package", "c", "-p=d"]);
+        assert_eq!(package.values(&a), args(&["a", "b", "c", "d"]));
+        // The first occurrence wins, no matter which spelling it uses.
+        assert_eq!(package.value(&a), Some("a".into()));
+        assert_eq!(package.value(&a[1..]), Some("b".into()));
+        assert!(package.is_present(&a));
+        assert!(!package.is_present(args(&["--", "-p", "a"])));
+    }
+
+    #[test]
+    fn arg_query_short_flags() {
+        let quiet = ArgQuery::flag(&["-q", "--quiet"]);
+        assert!(quiet.is_present(args(&["build", "-q"])));
+        assert!(quiet.is_present(args(&["--quiet", "build"])));
+        // Clusters and attached values are not recognized.
+        assert!(!quiet.is_present(args(&["-vq"])));
+        assert!(!ArgQuery::new(&["-p"]).is_present(args(&["-pfoo"])));
+        // A flag at the end is present, but has no value.
+        let package = ArgQuery::new(&["-p", "--package"]);
+        assert!(package.is_present(args(&["-p"])));
+        assert_eq!(package.value(args(&["-p"])), None);
+    }
+
+    #[test]
+    fn arg_query_stops_at_dashdash() {
+        // A flag never takes the next argument as its value, so `--` still ends the search.
+        let quiet = ArgQuery::flag(&["-q", "--quiet"]);
+        assert_eq!(quiet.value(args(&["-q", "x"])), None);
+        assert!(!quiet.is_present(args(&["--quiet=x"])));
+        let target = ArgQuery::new(&["--target", "-t"]);
+        assert_eq!(target.value(args(&["-q", "--", "--target", "x"])), None);
+        // Neither does an option.
+        let a = args(&["--target", "--", "--target", "x", "-t", "y"]);
+        assert!(target.is_present(&a));
+        assert_eq!(target.values(&a), Vec::<OsString>::new());
+        let a = args(&["-q", "-t", "x", "--", "--target", "y"]);
+        assert_eq!(target.values(&a), args(&["x"]));
+    }
+
+    /// A command that fails with `output` the first `failures` times it runs, like rustup or
+    /// cargo would.
+    fn flaky(failures: usize, output: &str) -> impl FnMut() -> Result<usize> + '_ {
+        let mut runs = 0;
+        move || {
+            runs += 1;
+            if runs <= failures {
+                let command = "rustup component add rust-src".to_owned();
+                let output = output.to_owned();
+                Err(CommandFailed { command, status: Default::default(), output }.into())
+            } else {
+                Ok(runs)
+            }
+        }
+    }
+
+    #[test]
+    fn download_retries() {
+        let hiccup = "error: could not download file from 'https://static.rust-lang.org/x'";
+        let retry = |f| retry(2, Duration::ZERO, "test", is_download_hiccup, f);
+        assert_eq!(retry(flaky(2, hiccup)).unwrap(), 3);
+        let err = retry(flaky(3, hiccup)).unwrap_err().to_string();
+        assert!(err.starts_with("test failed after 3 attempts"), "{err}");
+        // A failing build fails once.
+        let err = retry(flaky(1, "error[E0308]: mismatched types")).unwrap_err();
+        assert!(err.is::<CommandFailed>());
+        let timed_out = anyhow::Error::from(TimedOut {
+            command: "cargo install".into(),
+            timeout: Duration::from_secs(60),
+            output: "Updating crates.io index".into(),
+        });
+        assert!(!is_download_hiccup(&timed_out));
+    }
+
+    #[test]
+    fn script_ctx() {
+        let flags = vec!["--locked".to_owned(), "--target-dir=/tmp/elsewhere".to_owned()];
+        let env_dir = Some("/tmp/ignored".into());
+        let ctx =
+            ScriptCtx::with_flags("/src/miri".into(), flags, env_dir, Path::new("/")).unwrap();
+        assert_eq!(ctx.target_dir, Path::new("/tmp/elsewhere"));
+        assert_eq!(ctx.sh.var("CARGO_TARGET_DIR").unwrap(), "/tmp/elsewhere");
+        // The shells we hand out start from the same environment, but do not share changes.
+        let sh = ctx.miri_dir_shell();
+        assert_eq!(sh.current_dir(), Path::new("/src/miri"));
+        assert_eq!(sh.var("CARGO_TARGET_DIR").unwrap(), "/tmp/elsewhere");
+        sh.set_var("CARGO_TARGET_DIR", "/tmp/other");
+        assert_eq!(ctx.sh.var("CARGO_TARGET_DIR").unwrap(), "/tmp/elsewhere");
+        assert_ne!(ctx.sh.current_dir(), Path::new("/src/miri"));
+    }
+
+    #[test]
+    fn script_ctx_relative_target_dirs() {
+        let cwd = Path::new("/home/ferris/work");
+        let ctx = |flags: &[&str], env_dir: Option<&str>| {
+            let flags = args_str(flags);
+            ScriptCtx::with_flags("/src/miri".into(), flags, env_dir.map(Into::into), cwd).unwrap()
+        };
+        let c = ctx(&[], None);
+        assert_eq!(c.target_dir, Path::new("/src/miri/target"));
+        let c = ctx(&[], Some("tgt"));
+        assert_eq!(c.target_dir, Path::new("/home/ferris/work/tgt"));
+        assert_eq!(c.sh.var("CARGO_TARGET_DIR").unwrap(), "/home/ferris/work/tgt");
+        assert_eq!(c.miri_dir_shell().var("CARGO_TARGET_DIR").unwrap(), "/home/ferris/work/tgt");
+        // Cargo gets the absolute dir in the extra flags as well.
+        let c = ctx(&["--target-dir", "../tgt", "--locked"], Some("/ignored"));
+        assert_eq!(c.target_dir, Path::new("/home/ferris/work/../tgt"));
+        assert_eq!(c.cargo_extra_flags, ["--target-dir", "/home/ferris/work/../tgt", "--locked"]);
+        let c = ctx(&["--target-dir=tgt"], None);
+        assert_eq!(c.cargo_extra_flags, ["--target-dir=/home/ferris/work/tgt"]);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn script_ctx_windows_target_dirs() {
+        let cwd = Path::new(r"D:\my work");
+        let ctx = |flags: &[&str], env_dir: Option<&str>| {
+            let flags = args_str(flags);
+            let miri_dir = r"C:\src\my miri".into();
+            ScriptCtx::with_flags(miri_dir, flags, env_dir.map(Into::into), cwd).unwrap()
+        };
+        assert_eq!(ctx(&[], None).target_dir, Path::new(r"C:\src\my miri\target"));
+        assert_eq!(ctx(&[], Some(r"E:\tgt")).target_dir, Path::new(r"E:\tgt"));
+        assert_eq!(ctx(&[], Some("tgt")).target_dir, Path::new(r"D:\my work\tgt"));
+        let c = ctx(&["--target-dir", r"..\tgt"], None);
+        assert_eq!(c.cargo_extra_flags, ["--target-dir", r"D:\my work\..\tgt"]);
+        assert_eq!(c.sh.var("CARGO_TARGET_DIR").unwrap(), r"D:\my work\..\tgt");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn script_ctx_non_utf8_target_dir() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let dir = PathBuf::from(OsString::from_vec(b"/tmp/t\xffrget".to_vec()));
+        let ctx =
+            ScriptCtx::with_flags("/src/miri".into(), vec![], Some(dir.clone()), Path::new("/"))
+                .unwrap();
+        assert_eq!(ctx.target_dir, dir);
+        assert_eq!(ctx.sh.var_os("CARGO_TARGET_DIR").unwrap(), dir.as_os_str());
+        assert!(sysroot_dir(&ctx.target_dir, "x").starts_with(&dir));
+        let relative = PathBuf::from(OsString::from_vec(b"t\xffrget".to_vec()));
+        let ctx =
+            ScriptCtx::with_flags("/src/miri".into(), vec![], Some(relative), Path::new("/a"))
+                .unwrap();
+        assert_eq!(ctx.target_dir.as_os_str().as_encoded_bytes(), b"/a/t\xffrget");
+    }
+
+    #[test]
+    fn rustc_versions() {
+        let nightly = "\
+rustc 1.80.0-nightly (8679004 2024-05-03)
+binary: rustc
+commit-hash: 86790043fe2d6d671a7fa3bf45ae0e7f9f36f2d3
+commit-date: 2024-05-03
+host: x86_64-unknown-linux-gnu
+release: 1.80.0-nightly
+LLVM version: 18.1.4
+";
+        assert_eq!(
+            rustc_version::version_meta_for(nightly).unwrap().host,
+            parse_host(nightly).unwrap()
+        );
+        // Ones that `rustc_version` gives up on: a local patch in the version, a distro's LLVM,
+        // and missing lines.
+        let linux = "x86_64-unknown-linux-gnu";
+        let malformed = [
+            (nightly.replace("release: 1.80.0-nightly", "release: 1.80.0-mypatch"), linux),
+            (nightly.replace("LLVM version: 18.1.4", "LLVM version: 18.1.4 (Fedora)"), linux),
+            (nightly.replace("release: 1.80.0-nightly\n", ""), linux),
+            ("rustc 1.80.0-dev\nhost:aarch64-apple-darwin\n".to_owned(), "aarch64-apple-darwin"),
+        ];
+        for (version, host) in &malformed {
+            assert!(rustc_version::version_meta_for(version).is_err(), "{version}");
+            assert_eq!(parse_host(version), Some(*host), "{version}");
+        }
+        assert_eq!(parse_host(&nightly.replace("host: x86_64-unknown-linux-gnu\n", "")), None);
+        assert_eq!(parse_host("rustc 1.80.0\nhost: \nhost: not a triple"), None);
+    }
+
+    #[test]
+    fn build_settings() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |var: &str| vars.iter().find(|(v, _)| *v == var).map(|(_, value)| value.into())
+        };
+        let overrides = |vars| Ambient::capture(env(vars), false).build_setting_overrides();
+        assert!(overrides(&[]).is_empty());
+        // Setting what we use anyway is not an override, and other vars are none of our business.
+        assert!(overrides(&[("CARGO_PROFILE_DEV_OPT_LEVEL", "2"), ("CARGO_HOME", "/c")]).is_empty());
+        let theirs = &[("CARGO_PROFILE_DEV_OPT_LEVEL", "0"), ("CARGO_INCREMENTAL", "0")];
+        assert_eq!(overrides(theirs), ["CARGO_PROFILE_DEV_OPT_LEVEL=0", "CARGO_INCREMENTAL=0"]);
+
+        let hermetic = Ambient::capture(env(theirs), true);
+        assert!(hermetic.build_setting_overrides().is_empty());
+        assert_eq!(hermetic.ignored, ["CARGO_PROFILE_DEV_OPT_LEVEL", "CARGO_INCREMENTAL"]);
+        // We set the opt-level ourselves.
+        assert_eq!(hermetic.unset_for_commands().collect::<Vec<_>>(), ["CARGO_INCREMENTAL"]);
+
+        let mut ambient = Ambient::capture(env(&[("MIRI_SYSROOT", "/sysroot")]), false);
+        assert_eq!(ambient.miri_sysroot(), Some(OsStr::new("/sysroot")));
+        ambient.ignore_miri_sysroot = true;
+        assert_eq!(ambient.miri_sysroot(), None);
+    }
+
+    #[test]
+    fn miri_envs_agree() {
+        // Uses the toolchain the tests run with (from `RUSTUP_TOOLCHAIN`).
+        let global = GlobalArgs::default();
+        let rustflags = |e: &MiriEnv| {
+            let sh = e.build_sh().unwrap();
+            (sh.var_os("RUSTFLAGS"), sh.var_os("CARGO_ENCODED_RUSTFLAGS"))
+        };
+        let first = MiriEnv::new(&global).unwrap();
+        let second = MiriEnv::new(&global).unwrap();
+        assert_eq!(rustflags(&first), rustflags(&second));
+        assert_eq!(rustflags(&MiriEnv::new(&global).unwrap()), rustflags(&first));
+        let (plain, encoded) = rustflags(&first);
+        assert!(plain.or(encoded).is_some_and(|flags| flags.to_string_lossy().contains("-rpath")));
+    }
+
+    #[test]
+    fn extra_rustflags_per_command() {
+        let mut e = MiriEnv::new(&GlobalArgs::default()).unwrap();
+        let sh = e.build_sh().unwrap();
+        let shell_flags = (sh.var_os("RUSTFLAGS"), sh.var_os("CARGO_ENCODED_RUSTFLAGS"));
+        let rustflags = |cmd: Result<Cmd<'_>>| {
+            let command = std::process::Command::from(cmd.unwrap());
+            let var = |name: &str| {
+                let value = command.get_envs().find(|&(var, _)| var == name).map(|(_, v)| v);
+                value.flatten().map(|value| value.to_str().unwrap().to_owned())
+            };
+            assert_eq!(var("RUSTFLAGS"), None);
+            var("CARGO_ENCODED_RUSTFLAGS").unwrap().split('\x1f').map(str::to_owned).collect()
+        };
+        e.extra_rustflags = args_str(&["-Zrandomize-layout", "-Wrust_2018_idioms"]);
+        let first: Vec<String> = rustflags(e.cargo_cmd("build", &[]));
+        e.extra_rustflags = args_str(&["-Cdebuginfo=1"]);
+        let second: Vec<String> = rustflags(e.cargo_cmd("check", &[]));
+        assert!(first.contains(&"-Zrandomize-layout".into()), "{first:?}");
+        assert!(!first.contains(&"-Cdebuginfo=1".into()), "{first:?}");
+        assert!(second.contains(&"-Cdebuginfo=1".into()), "{second:?}");
+        assert!(!second.contains(&"-Zrandomize-layout".into()), "{second:?}");
+        // Merged with ours, which have that lint already.
+        assert_eq!(first.iter().filter(|&f| f == "-Wrust_2018_idioms").count(), 1);
+        assert_eq!(first[..first.len() - 1], second[..second.len() - 1]);
+
+        // The shell, and so the commands without extra flags, are as before.
+        let sh = e.build_sh().unwrap();
+        assert_eq!((sh.var_os("RUSTFLAGS"), sh.var_os("CARGO_ENCODED_RUSTFLAGS")), shell_flags);
+        e.extra_rustflags.clear();
+        assert_eq!(e.merged_rustflags(&e.extra_rustflags).unwrap(), None);
+    }
+
+    #[test]
+    fn cargo_commands() {
+        let mut e = MiriEnv::new(&GlobalArgs::default()).unwrap();
+        let sysroot = e.sysroot().unwrap().to_str().unwrap().to_owned();
+        e.build_sh().unwrap();
+        e.toolchain = Some("tc".into());
+        e.cargo_extra_flags = args_str(&["--offline", "-j4"]);
+        let manifest = "miri/Cargo.toml";
+        let m = OsStr::new(manifest);
+        let line = |cmd: Result<Cmd<'_>>| cmd.unwrap().to_string();
+        // The command lines the wrappers built before `cargo_cmd`, except that `build` had its
+        // `--bins --tests` before the extra flags, and its quiet flag after `--manifest-path`.
+        let before = |subcommand: &str, rest: &str| {
+            let line = format!("cargo +tc {subcommand} --offline -j4 {rest}");
+            let argv: Vec<&str> = line.split(' ').collect();
+            crate::cmdline::display_command(&argv, None, &[] as &[(&str, Option<&str>)])
+        };
+        let json = "--message-format=json-render-diagnostics";
+        e.verbosity = Verbosity::Quiet;
+        assert_eq!(
+            line(e.build_cmd(m, &args(&["--features", "x"]))),
+            before(
+                "build",
+                &format!("--quiet --bins --tests --manifest-path {manifest} {json} --features x")
+            )
+        );
+        assert_eq!(
+            line(e.build_cmd(m, &args(&["-q", "--message-format=short"]))),
+            before(
+                "build",
+                &format!("--bins --tests --manifest-path {manifest} -q --message-format=short")
+            )
+        );
+        e.verbosity = Verbosity::Normal;
+        for subcommand in ["check", "clippy"] {
+            assert_eq!(
+                line(e.check_cmd(subcommand, m, &args(&["--locked"]))),
+                before(subcommand, &format!("--manifest-path {manifest} --all-targets --locked"))
+            );
+        }
+        assert_eq!(
+            line(e.test_cmd(m, &args(&["--", "--nocapture"]))),
+            before("test", &format!("--manifest-path {manifest} -- --nocapture"))
+        );
+        assert_eq!(
+            line(e.test_cmd(m, &no_run_args(&args(&["--lib"])))),
+            before("test", &format!("--manifest-path {manifest} --quiet --no-run {json} --lib"))
+        );
+        assert_eq!(
+            line(e.install_cmd(OsStr::new("miri"), &args(&["--locked"]))),
+            before("install", &format!("--path miri --force --root {sysroot} --locked"))
+        );
+        assert_eq!(
+            line(e.install_cmd(OsStr::new("miri"), &args(&["--root", "/r"]))),
+            before("install", "--path miri --force --root /r")
+        );
+
+        // With `--locked`, every command gets it once, also if the user passed it already.
+        e.locked = true;
+        let locked = |cmd: Result<Cmd<'_>>| {
+            let cmd = cmd.unwrap();
+            cmd.to_string().split(' ').filter(|&arg| arg == "--locked").count()
+        };
+        for user_args in [args(&["--features", "x"]), args(&["--locked"])] {
+            let no_run = no_run_args(&user_args);
+            assert_eq!(locked(e.build_cmd(m, &user_args)), 1);
+            assert_eq!(locked(e.check_cmd("check", m, &user_args)), 1);
+            assert_eq!(locked(e.check_cmd("clippy", m, &user_args)), 1);
+            assert_eq!(locked(e.test_cmd(m, &user_args)), 1);
+            assert_eq!(locked(e.test_cmd(m, &no_run)), 1);
+            assert_eq!(locked(e.install_cmd(OsStr::new("miri"), &user_args)), 1);
+        }
+        assert_eq!(locked(e.cargo_cmd("build", &[])), 1);
+        e.cargo_extra_flags.push("--locked".into());
+        assert_eq!(locked(e.test_cmd(m, &[])), 1);
+        assert_eq!(e.shared_cargo_flags(&[]), ["--offline", "-j4", "--locked"]);
+        // After a `--`, it is for the test harness.
+        e.cargo_extra_flags.pop();
+        assert_eq!(locked(e.test_cmd(m, &args(&["--", "--locked"]))), 2);
+
+        // `--quiet` and `--verbose` reach all commands, unless the user said how much cargo says:
+        // each gets one such flag.
+        let flags = |cmd: Result<Cmd<'_>>| {
+            let cmd = cmd.unwrap().to_string();
+            cmd.split(' ')
+                .filter(|&arg| ["-q", "--quiet", "-v", "--verbose"].contains(&arg))
+                .count()
+        };
+        for verbosity in [Verbosity::Quiet, Verbosity::Verbose] {
+            e.verbosity = verbosity;
+            for user_args in [args(&["--lib"]), args(&["-q"]), args(&["--verbose"])] {
+                assert_eq!(flags(e.build_cmd(m, &user_args)), 1);
+                assert_eq!(flags(e.check_cmd("check", m, &user_args)), 1);
+                assert_eq!(flags(e.check_cmd("clippy", m, &user_args)), 1);
+                assert_eq!(flags(e.test_cmd(m, &user_args)), 1);
+                assert_eq!(flags(e.install_cmd(OsStr::new("miri"), &user_args)), 1);
+            }
+        }
+        assert!(line(e.check_cmd("check", m, &[])).contains(" -v --manifest-path "));
+        e.verbosity = Verbosity::Normal;
+        assert_eq!(flags(e.check_cmd("check", m, &[])), 0);
+
+        assert_eq!(
+            outdated_lock_file(
+                "error: cannot update the lock file /m/Cargo.lock because --locked was passed to \
+                prevent this\nhelp: ..."
+            ),
+            Some("/m/Cargo.lock")
+        );
+        assert_eq!(
+            outdated_lock_file(
+                "error: the lock file /m/Cargo.lock needs to be updated but --locked was passed to \
+                prevent this"
+            ),
+            Some("/m/Cargo.lock")
+        );
+        assert_eq!(outdated_lock_file("error: could not compile `miri`"), None);
+    }
+
+    #[test]
+    fn miri_dirs() {
+        assert_eq!(miri_dir().unwrap(), canonicalize("..").unwrap());
+        let err = miri_dir_of(Path::new("/")).unwrap_err().to_string();
+        assert!(err.contains("has no parent dir"), "{err}");
+
+        // A copy of the script on its own, as when it gets vendored.
+        let tmp = TempDir::new("miri-script-vendored-test", false).unwrap();
+        let script_dir = path!(tmp.path / "miri-script");
+        std::fs::create_dir_all(&script_dir).unwrap();
+        std::fs::copy("Cargo.toml", path!(script_dir / "Cargo.toml")).unwrap();
+        let err = miri_dir_of(&script_dir).unwrap_err().to_string();
+        assert!(err.contains("is not a Miri checkout"), "{err}");
+        assert!(err.contains(&*canonicalize(&tmp.path).unwrap().to_string_lossy()), "{err}");
+        std::fs::write(path!(tmp.path / "rust-version"), "").unwrap();
+        assert_eq!(miri_dir_of(&script_dir).unwrap(), canonicalize(&tmp.path).unwrap());
+        // When canonicalizing fails (simulated by a dir that does not exist yet), the path is used
+        // as it is.
+        let missing = path!(tmp.path / "not-yet");
+        assert_eq!(miri_dir_of(&missing).unwrap(), tmp.path);
+        let err = miri_dir_of(&path!(missing / "miri-script")).unwrap_err().to_string();
+        assert!(err.contains("is not a Miri checkout"), "{err}");
+    }
+
+    #[test]
+    fn moving_files() {
+        let from = TempDir::new("miri-script-move-from-test", false).unwrap();
+        let to = TempDir::new("miri-script-move-to-test", false).unwrap();
+        let (a, b) = (path!(from.path / "a"), path!(to.path / "b"));
+        std::fs::write(&a, "a").unwrap();
+        move_file(&a, &b).unwrap();
+        assert!(!a.exists());
+        assert_eq!(std::fs::read_to_string(&b).unwrap(), "a");
+
+        // What a rename between two file systems does.
+        let cross_device = |_: &Path, _: &Path| Err(std::io::ErrorKind::CrossesDevices.into());
+        move_file_with(&b, &a, cross_device).unwrap();
+        assert!(!b.exists());
+        assert_eq!(std::fs::read_to_string(&a).unwrap(), "a");
+        // Other errors are not papered over.
+        let denied = |_: &Path, _: &Path| Err(std::io::ErrorKind::PermissionDenied.into());
+        let err = move_file_with(&a, &b, denied).unwrap_err();
+        assert!(format!("{err:#}").contains("failed to move"), "{err:#}");
+        assert!(a.exists() && !b.exists());
+        // Neither is a copy that fails.
+        assert!(move_file_with(&b, &a, cross_device).is_err());
+        assert_eq!(std::fs::read_to_string(&a).unwrap(), "a");
+
+        assert_eq!(same_filesystem(&from.path, &path!(to.path / "not-yet" / "b")), Some(true));
+    }
+
+    #[test]
+    fn relative_paths() {
+        let base = Path::new("/src/miri");
+        assert_eq!(
+            relative_to(Path::new("/src/miri/src/lib.rs"), base),
+            Some(Path::new("src/lib.rs"))
+        );
+        assert_eq!(relative_to(Path::new("/src/other/lib.rs"), base), None);
+        assert_eq!(relative_to(Path::new("/src/miri"), base), Some(Path::new("")));
+        // The file system may not care about the case, but we only know that on Windows.
+        let spelled = relative_to(Path::new("C:\\Src\\Miri\\lib.rs"), Path::new("c:\\src\\miri"));
+        assert_eq!(spelled, cfg!(windows).then_some(Path::new("lib.rs")));
+    }
+
+    #[test]
+    fn sysroot_dirs() {
+        let dir = |fingerprint| sysroot_dir(Path::new("target"), fingerprint);
+        // The name must not change between builds of `./miri`.
+        assert_eq!(dir(""), path!("target" / SYSROOTS_DIR / "cbf29ce484222325"));
+        assert_ne!(dir("rustc 1.80.0\ntarget: x86_64"), dir("rustc 1.80.0\ntarget: i686"));
+    }
+
+    #[test]
+    fn bin_artifacts() {
+        let artifact = |flags: &[&str]| {
+            let flags: Vec<String> = flags.iter().map(|&f| f.to_owned()).collect();
+            bin_artifact(Path::new("target"), &flags, "miri")
+        };
+        let exe = |profile: &str| {
+            path!("target" / profile / format!("miri{}", std::env::consts::EXE_SUFFIX))
+        };
+        assert_eq!(artifact(&[]), exe("debug"));
+        assert_eq!(artifact(&["--release"]), exe("release"));
+        assert_eq!(artifact(&["--profile=dev"]), exe("debug"));
+        assert_eq!(artifact(&["--profile", "dist"]), exe("dist"));
+        // Cross builds go to a subdir for the target.
+        assert_eq!(
+            artifact(&["--target", "x86_64-pc-windows-gnu", "-r"]),
+            path!("target" / "x86_64-pc-windows-gnu" / "release" / "miri.exe")
+        );
+    }
+
+    #[test]
+    fn cargo_rebuilds() {
+        let messages = r#"{"reason":"compiler-artifact","package_id":"miri 0.1.0","target":{"name":"miri"},"profile":{"test":false},"fresh":true}
+{"reason":"compiler-artifact","package_id":"miri 0.1.0","target":{"name":"ui"},"profile":{"test":true},"fresh":false}
+{"reason":"build-finished","success":true}"#;
+        let artifact = |target: &str| ("miri 0.1.0".to_owned(), target.to_owned());
+        assert_eq!(artifacts(messages, None), [artifact("miri"), artifact("ui (test)")].into());
+        assert_eq!(artifacts(messages, Some(false)), [artifact("ui (test)")].into());
+
+        let options = CargoOptions {
+            toolchain: Some("+miri".into()),
+            extra_flags: vec![],
+            manifest_path: "Cargo.toml".into(),
+            vars: vec![("RUSTFLAGS", Some("-Dwarnings".into())), ("CC", None)],
+        };
+        assert_eq!(options.difference(&options.clone()), None);
+        let mut other = options.clone();
+        other.toolchain = Some("+nightly".into());
+        other.vars[0].1 = None;
+        other.vars[1].1 = Some("clang".into());
+        assert_eq!(
+            options.difference(&other).unwrap(),
+            "the toolchain was `miri`, and is now `nightly`; RUSTFLAGS was `-Dwarnings`, and is \
+             now unset; CC was unset, and is now `clang`"
+        );
+    }
+
+    #[test]
+    fn finds_rust_files() {
+        let tmp = TempDir::new("miri-script-rust-files-test", false).unwrap();
+        let root = &tmp.path;
+        for dir in ["src/sub", "target/debug", ".git"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        std::fs::write(root.join(".gitignore"), "target\n").unwrap();
+        for file in ["src/lib.rs", "src/sub/mod.rs", "src/notes.txt", "target/debug/out.rs"] {
+            std::fs::write(root.join(file), "").unwrap();
+        }
+        std::fs::write(root.join(".git/hook.rs"), "").unwrap();
+        let found = |no_ignore| -> Vec<PathBuf> {
+            let files = rust_files(root, no_ignore).into_iter().map(Result::unwrap);
+            files.map(|file| file.strip_prefix(root).unwrap().to_owned()).collect()
+        };
+        assert_eq!(found(false), [Path::new("src/lib.rs"), Path::new("src/sub/mod.rs")]);
+        assert_eq!(
+            found(true),
+            [
+                Path::new("src/lib.rs"),
+                Path::new("src/sub/mod.rs"),
+                Path::new("target/debug/out.rs")
+            ]
+        );
+    }
+
+    #[test]
+    fn file_locks() {
+        let tmp = TempDir::new("miri-script-lock-test", false).unwrap();
+        let path = tmp.path.join("lock");
+        let lock = lock_file(&path).unwrap();
+        let other = std::fs::File::open(&path).unwrap();
+        assert!(matches!(other.try_lock(), Err(std::fs::TryLockError::WouldBlock)));
+        drop(lock);
+        other.try_lock().unwrap();
+    }
+
+    #[test]
+    fn run_many_items() {
+        let e = MiriEnv::new(&GlobalArgs::default()).unwrap();
+        // Not `Clone`.
+        #[derive(Debug)]
+        struct Item(u32);
+        let done = Mutex::new(Vec::new());
+        let label = |item: &Item| format!("{item:?}");
+        e.run_many((0..20).map(Item), Some(3), None, label, |_, item| {
+            done.lock().unwrap().push(item.0);
+            Ok(())
+        })
+        .unwrap();
+        let mut done = done.into_inner().unwrap();
+        done.sort();
+        assert_eq!(done, (0..20).collect::<Vec<_>>());
+
+        // After a failure, no further items are started.
+        let started = std::sync::atomic::AtomicUsize::new(0);
+        let err = e
+            .run_many((0..100).map(Item), Some(1), None, label, |_, item| {
+                started.fetch_add(1, Ordering::Relaxed);
+                if item.0 == 3 {
+                    bail!("broken")
+                } else {
+                    Ok(())
+                }
+            })
+            .unwrap_err();
+        assert_eq!(format!("{err:#}"), "Item(3) failed: broken");
+        assert_eq!(started.into_inner(), 4);
+        // `run_many_times` still passes the seeds.
+        let seeds = Mutex::new(Vec::new());
+        e.run_many_times(5..8, Some(1), Some(f64::MAX), |_, seed| {
+            seeds.lock().unwrap().push(seed);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(seeds.into_inner().unwrap(), [5, 6, 7]);
+    }
+}
@@ -0,0 +1,234 @@
+//! `./miri watch`: re-running a command whenever the sources change.
+//!
+//! We subscribe to the file system events (via `notify`), so that waiting for changes costs
+//! nothing, however large the tree.
+
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, ExitStatus};
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use itertools::Itertools;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
+
+use crate::tee::{self, Killer};
+
+/// The directories we watch, relative to the Miri checkout.
+pub const WATCHED_DIRS: &[&str] = &["src", "cargo-miri/src", "tests"];
+
+/// How long `Watcher::poll` waits for changes, and thus how quickly we notice that a run is done.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How long no files must change before we start a new run, to not start a run for each file of a
+/// `git checkout` or of a formatter.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Whether this file can affect a run. This skips the temporary files editors create.
+fn is_relevant(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    !(name.starts_with('.') || name.ends_with('~') || name.ends_with(".swp"))
+}
+
+pub struct Watcher {
+    roots: Vec<PathBuf>,
+    /// Directories we do not care about, like the target dir.
+    excluded: Vec<PathBuf>,
+    events: Receiver<notify::Result<Event>>,
+    /// Stops watching when dropped.
+    _watcher: RecommendedWatcher,
+}
+
+impl Watcher {
+    pub fn new(roots: Vec<PathBuf>, excluded: Vec<PathBuf>) -> Result<Self> {
+        // The events name the files the way the OS sees them (e.g. `/private/tmp` for `/tmp` on
+        // macOS), so that is how we have to compare them.
+        let canonicalize = |dirs: Vec<PathBuf>| -> Vec<PathBuf> {
+            dirs.into_iter().map(|dir| dunce::canonicalize(&dir).unwrap_or(dir)).collect()
+        };
+        let (roots, excluded) = (canonicalize(roots), canonicalize(excluded));
+        let (sender, events) = mpsc::channel();
+        let mut watcher =
+            notify::recommended_watcher(sender).context("failed to watch for file changes")?;
+        for root in &roots {
+            watcher
+                .watch(root, RecursiveMode::Recursive)
+                .with_context(|| format!("failed to watch {} for changes", root.display()))?;
+        }
+        Ok(Watcher { roots, excluded, events, _watcher: watcher })
+    }
+
+    /// The files of `event` that can affect a run.
+    fn relevant(&self, event: notify::Result<Event>) -> Vec<PathBuf> {
+        // Errors just mean we may miss a change; the next one triggers a run all the same.
+        let Ok(event) = event else {
+            return Vec::new();
+        };
+        if event.need_rescan() {
+            // Some events got lost, so anything may have changed.
+            return self.roots.clone();
+        }
+        if matches!(event.kind, EventKind::Access(_)) {
+            return Vec::new();
+        }
+        event
+            .paths
+            .into_iter()
+            .filter(|path| {
+                let Some(relative) =
+                    self.roots.iter().find_map(|root| path.strip_prefix(root).ok())
+                else {
+                    return false;
+                };
+                is_relevant(path)
+                    && !relative.components().any(|dir| dir.as_os_str() == "target")
+                    && !self.excluded.iter().any(|dir| path.starts_with(dir))
+            })
+            .collect()
+    }
+
+    /// Returns the files that were added, changed, or removed since the last call, without
+    /// waiting for more.
+    pub fn changes(&mut self) -> Vec<PathBuf> {
+        let events: Vec<_> = self.events.try_iter().collect();
+        events.into_iter().flat_map(|event| self.relevant(event)).collect()
+    }
+
+    /// Waits up to `timeout` for files to change, and returns them.
+    fn wait(&mut self, timeout: Duration) -> Vec<PathBuf> {
+        let deadline = Instant::now() + timeout;
+        let mut changes = self.changes();
+        while changes.is_empty() {
+            let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+                break;
+            };
+            match self.events.recv_timeout(left) {
+                Ok(event) => changes = self.relevant(event),
+                Err(_) => break,
+            }
+        }
+        changes
+    }
+
+    /// Waits a bit for files to change. If some did, waits until they stop changing for a bit and
+    /// returns all of them.
+    pub fn poll(&mut self) -> Option<Vec<PathBuf>> {
+        let mut changes = self.wait(POLL_INTERVAL);
+        if changes.is_empty() {
+            return None;
+        }
+        loop {
+            let more = self.wait(DEBOUNCE);
+            if more.is_empty() || tee::interrupted() {
+                changes.sort();
+                changes.dedup();
+                return Some(changes);
+            }
+            changes.extend(more);
+        }
+    }
+}
+
+/// Names the changed files for humans.
+pub fn describe(changes: &[PathBuf], base: &Path) -> String {
+    const SHOWN: usize = 3;
+    let mut names =
+        changes.iter().take(SHOWN).map(|path| path.strip_prefix(base).unwrap_or(path).display());
+    let mut description = names.join(", ");
+    if changes.len() > SHOWN {
+        description.push_str(&format!(" and {} more", changes.len() - SHOWN));
+    }
+    description
+}
+
+/// The separator we print before each run.
+pub fn separator(time: u64, command: &str) -> String {
+    let (h, m, s) = (time / 3600 % 24, time / 60 % 60, time % 60);
+    format!("──────── {h:02}:{m:02}:{s:02} UTC ──────── {command}")
+}
+
+/// A run of the watched command. Ctrl-C stops it along with us.
+pub struct Run {
+    child: Child,
+    killer: Killer,
+}
+
+impl Run {
+    pub fn start(mut cmd: process::Command) -> Result<Self> {
+        // The run cannot use the terminal's stdin, since it is in a process group of its own.
+        cmd.stdin(process::Stdio::null());
+        let (child, killer) =
+            tee::spawn_killable(&mut cmd).context("failed to start the command")?;
+        Ok(Run { child, killer })
+    }
+
+    /// Returns the exit status if the run is done.
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        Ok(self.child.try_wait()?)
+    }
+
+    /// Stops the run and everything it started.
+    pub fn cancel(mut self) -> Result<()> {
+        self.killer.terminate();
+        self.child.wait()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn relevant_files() {
+        assert!(is_relevant(Path::new("src/shims/time.rs")));
+        assert!(is_relevant(Path::new("tests/pass/foo.stderr")));
+        assert!(!is_relevant(Path::new("src/.time.rs.swp")));
+        assert!(!is_relevant(Path::new("src/time.rs~")));
+        assert!(!is_relevant(Path::new("src/.#time.rs")));
+    }
+
+    #[test]
+    fn separators() {
+        assert_eq!(separator(0, "test"), "──────── 00:00:00 UTC ──────── test");
+        assert_eq!(
+            separator(86400 + 3723, "test shims"),
+            "──────── 01:02:03 UTC ──────── test shims"
+        );
+    }
+
+    #[test]
+    fn descriptions() {
+        let files =
+            ["/m/src/a.rs", "/m/src/b.rs", "/m/tests/c.rs", "/m/tests/d.rs"].map(PathBuf::from);
+        assert_eq!(describe(&files[..1], Path::new("/m")), "src/a.rs");
+        assert_eq!(describe(&files, Path::new("/m")), "src/a.rs, src/b.rs, tests/c.rs and 1 more");
+    }
+
+    #[test]
+    fn watching() {
+        let dir = std::env::temp_dir().join(format!("miri-watch-{}", process::id()));
+        let excluded = dir.join("out");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("target")).unwrap();
+        fs::create_dir_all(&excluded).unwrap();
+        fs::write(dir.join("src/lib.rs"), "").unwrap();
+        let mut watcher = Watcher::new(vec![dir.clone()], vec![excluded.clone()]).unwrap();
+        // The events name the canonical paths.
+        let dir = dunce::canonicalize(&dir).unwrap();
+        assert_eq!(watcher.poll(), None);
+
+        fs::write(dir.join("src/.new.rs.swp"), "").unwrap();
+        fs::write(dir.join("target/foo.rlib"), "").unwrap();
+        fs::write(dir.join("out/foo.stderr"), "").unwrap();
+        fs::write(dir.join("src/new.rs"), "").unwrap();
+        assert_eq!(watcher.poll(), Some(vec![dir.join("src/new.rs")]));
+        fs::remove_file(dir.join("src/lib.rs")).unwrap();
+        assert_eq!(watcher.poll(), Some(vec![dir.join("src/lib.rs")]));
+        assert_eq!(watcher.poll(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
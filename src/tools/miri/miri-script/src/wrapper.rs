@@ -0,0 +1,168 @@
+//! The scripts in the Miri dir that build this crate and run it: `./miri` itself, and `miri.bat`
+//! and `miri.ps1` for Windows, which cannot run that. `./miri self-install-wrapper` writes the ones
+//! for this platform; `./miri` and `miri.bat` are checked in, and a test keeps them as they are
+//! here.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use path_macro::path;
+
+use crate::cmdline::current_shell;
+use crate::output::{note, success};
+use crate::record::skip_in_dry_run;
+use crate::util::ShellKind;
+
+pub struct Wrapper {
+    /// The name of the script in the Miri dir.
+    pub file: &'static str,
+    /// The shell that runs it.
+    pub shell: ShellKind,
+    pub contents: &'static str,
+}
+
+const SH: Wrapper = Wrapper {
+    file: "miri",
+    shell: ShellKind::Sh,
+    contents: r#"#!/usr/bin/env bash
+set -e
+# Instead of doing just `cargo run --manifest-path .. $@`, we invoke miri-script binary directly. Invoking `cargo run` goes through
+# rustup (that sets it's own environmental variables), which is undesirable.
+MIRI_SCRIPT_TARGET_DIR="$(dirname "$0")"/miri-script/target
+cargo +stable build $CARGO_EXTRA_FLAGS -q --target-dir "$MIRI_SCRIPT_TARGET_DIR" --manifest-path "$(dirname "$0")"/miri-script/Cargo.toml
+"$MIRI_SCRIPT_TARGET_DIR"/debug/miri-script "$@"
+"#,
+};
+
+const CMD: Wrapper = Wrapper {
+    file: "miri.bat",
+    shell: ShellKind::Cmd,
+    contents: r#":: This makes execution of ./miri on Linux and Windows the same.
+:: Windows will not execute the bash script, and select this.
+:: This is written by `./miri self-install-wrapper`; change it there.
+@echo off
+:: `%~dp0` is the dir of this file, with a trailing backslash; quoted, it may contain spaces.
+set "MIRI_SCRIPT_TARGET_DIR=%~dp0miri-script\target"
+
+:: If any other steps are added, the "|| exit /b" must be appended to early
+:: return from the script. If not, it will continue execution.
+cargo +stable build %CARGO_EXTRA_FLAGS% -q --target-dir "%MIRI_SCRIPT_TARGET_DIR%" --manifest-path "%~dp0miri-script\Cargo.toml" || exit /b
+
+:: Forwards all arguments to this file to the executable.
+:: We invoke the binary directly to avoid going through rustup, which would set some extra
+:: env vars that we do not want.
+"%MIRI_SCRIPT_TARGET_DIR%\debug\miri-script" %*
+"#,
+};
+
+const POWERSHELL: Wrapper = Wrapper {
+    file: "miri.ps1",
+    shell: ShellKind::Powershell,
+    contents: r#"# ./miri for PowerShell. Unlike miri.bat, this passes the arguments on as they are, without cmd
+# interpreting characters like `%`, `^` or `&` in them.
+# This is written by `./miri self-install-wrapper`; change it there.
+$ErrorActionPreference = 'Stop'
+$TargetDir = Join-Path $PSScriptRoot 'miri-script\target'
+# Split on whitespace, like miri-script splits them.
+$ExtraFlags = @("$env:CARGO_EXTRA_FLAGS" -split '\s+' | Where-Object { $_ })
+# We invoke the binary directly to avoid going through rustup, which would set some extra env vars
+# that we do not want.
+& cargo +stable build @ExtraFlags -q --target-dir $TargetDir --manifest-path (Join-Path $PSScriptRoot 'miri-script\Cargo.toml')
+if ($LASTEXITCODE -ne 0) { exit $LASTEXITCODE }
+& (Join-Path $TargetDir 'debug\miri-script') @args
+exit $LASTEXITCODE
+"#,
+};
+
+/// The wrappers for this platform.
+pub fn for_host() -> &'static [Wrapper] {
+    if cfg!(windows) {
+        &[CMD, POWERSHELL]
+    } else {
+        &[SH]
+    }
+}
+
+/// Whether `path` already has `contents`. Git may have checked it out with CRLF line endings.
+fn is_current(path: &Path, contents: &str) -> bool {
+    fs::read_to_string(path).is_ok_and(|old| old.replace("\r\n", "\n") == contents)
+}
+
+/// Writes those of `wrappers` into `dir` that are not there as they should be, and returns their
+/// names.
+fn write(dir: &Path, wrappers: &[Wrapper]) -> Result<Vec<&'static str>> {
+    let mut written = Vec::new();
+    for wrapper in wrappers {
+        let path = path!(dir / wrapper.file);
+        if is_current(&path, wrapper.contents) {
+            continue;
+        }
+        written.push(wrapper.file);
+        if skip_in_dry_run(format_args!("write {}", path.display())) {
+            continue;
+        }
+        fs::write(&path, wrapper.contents)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        #[cfg(unix)]
+        if wrapper.shell == ShellKind::Sh {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+        }
+    }
+    Ok(written)
+}
+
+/// `./miri self-install-wrapper`: writes the wrappers for this platform into `miri_dir`, unless
+/// they are up to date, and says which one to use.
+pub fn install(miri_dir: &Path) -> Result<()> {
+    let wrappers = for_host();
+    let written = write(miri_dir, wrappers)?;
+    for wrapper in wrappers {
+        if written.contains(&wrapper.file) {
+            success!("wrote {}", path!(miri_dir / wrapper.file).display());
+        } else {
+            note!("{} is up to date", wrapper.file);
+        }
+    }
+    match current_shell() {
+        ShellKind::Powershell => note!("in PowerShell, run `.\\miri.ps1 <command>`"),
+        ShellKind::Cmd => note!("in cmd, run `miri <command>`"),
+        ShellKind::Sh | ShellKind::Fish => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::{miri_dir, TempDir};
+
+    #[test]
+    fn checked_in_wrappers_are_current() {
+        let miri_dir = miri_dir().unwrap();
+        for wrapper in [SH, CMD] {
+            let path = path!(miri_dir / wrapper.file);
+            assert!(
+                is_current(&path, wrapper.contents),
+                "{} is not as in wrapper.rs",
+                wrapper.file
+            );
+        }
+    }
+
+    #[test]
+    fn writes_wrappers_once() {
+        let dir = TempDir::new("miri-script-wrappers-test", false).unwrap();
+        let all = [SH, CMD, POWERSHELL];
+        fs::write(path!(dir.path / "miri.bat"), CMD.contents.replace('\n', "\r\n")).unwrap();
+        assert_eq!(write(&dir.path, &all).unwrap(), ["miri", "miri.ps1"]);
+        assert!(write(&dir.path, &all).unwrap().is_empty());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(path!(dir.path / "miri")).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o755);
+        }
+    }
+}
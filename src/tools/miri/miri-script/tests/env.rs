@@ -0,0 +1,64 @@
+//! Checks the environment the commands run tools in, with a fake `rustc` and `rustfmt` on the
+//! PATH.
+
+#![cfg(unix)]
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::{env, fs};
+
+/// Fails, after writing its arguments to `$FAKE_RUSTC_LOG`: formatting need not ask rustc.
+const FAKE_RUSTC: &str = r#"#!/bin/sh
+echo "$@" >> "$FAKE_RUSTC_LOG"
+exit 1
+"#;
+
+/// Writes its toolchain argument and `CARGO_TARGET_DIR` to `$FAKE_RUSTFMT_LOG`, once per batch.
+const FAKE_RUSTFMT: &str = r#"#!/bin/sh
+echo "$1 $CARGO_TARGET_DIR" >> "$FAKE_RUSTFMT_LOG"
+"#;
+
+fn write_script(path: &Path, script: &str) {
+    fs::write(path, script).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[test]
+fn fmt_sees_toolchain_and_target_dir_without_rustc() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("fake-fmt");
+    let _ = fs::remove_dir_all(&dir);
+    let bin = dir.join("bin");
+    fs::create_dir_all(&bin).unwrap();
+    write_script(&bin.join("rustc"), FAKE_RUSTC);
+    write_script(&bin.join("rustfmt"), FAKE_RUSTFMT);
+    let log = dir.join("log");
+    let rustc_log = dir.join("rustc-log");
+    let target_dir = dir.join("target");
+
+    let path = env::join_paths(
+        std::iter::once(bin.clone()).chain(env::split_paths(&env::var_os("PATH").unwrap())),
+    )
+    .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_miri-script"))
+        .args(["+mytc", "fmt"])
+        .env("PATH", path)
+        .env("FAKE_RUSTC_LOG", &rustc_log)
+        .env("FAKE_RUSTFMT_LOG", &log)
+        .env("CARGO_EXTRA_FLAGS", format!("--target-dir {}", target_dir.display()))
+        .env("CARGO_TARGET_DIR", dir.join("ignored"))
+        .env("MIRI_AUTO_OPS", "no")
+        .env("MIRI_SCRIPT_LOG", "off")
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .env_remove("MIRI_SCRIPT_RUSTC")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!rustc_log.exists(), "rustc was asked: {}", fs::read_to_string(&rustc_log).unwrap());
+
+    let calls = fs::read_to_string(&log).unwrap();
+    assert!(!calls.is_empty());
+    for call in calls.lines() {
+        assert_eq!(call, format!("+mytc {}", target_dir.display()));
+    }
+}
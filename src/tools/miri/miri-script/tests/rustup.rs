@@ -0,0 +1,52 @@
+//! Checks how often `./miri` asks rustup, with a fake `rustup` on the PATH that counts.
+
+#![cfg(unix)]
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::Command;
+use std::{env, fs};
+
+/// Answers like rustup 1.28 would, and writes its arguments to `$FAKE_RUSTUP_LOG`.
+const FAKE_RUSTUP: &str = r#"#!/bin/sh
+echo "$@" >> "$FAKE_RUSTUP_LOG"
+case "$1" in
+show)
+    printf 'installed toolchains\n--------------------\nmiri\n\n'
+    printf 'active toolchain\n----------------\nname: miri\nactive because: overridden\n'
+    ;;
+component)
+    printf 'rust-src\nrustc-dev-x86_64-unknown-linux-gnu\nllvm-tools-x86_64-unknown-linux-gnu\n'
+    ;;
+esac
+"#;
+
+#[test]
+fn doctor_asks_rustup_twice() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("fake-rustup");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let rustup = dir.join("rustup");
+    fs::write(&rustup, FAKE_RUSTUP).unwrap();
+    fs::set_permissions(&rustup, fs::Permissions::from_mode(0o755)).unwrap();
+    let log = dir.join("log");
+
+    let path = env::join_paths(
+        std::iter::once(dir.clone()).chain(env::split_paths(&env::var_os("PATH").unwrap())),
+    )
+    .unwrap();
+    // Whether the checks pass does not matter here (the toolchain is made up).
+    Command::new(env!("CARGO_BIN_EXE_miri-script"))
+        .arg("doctor")
+        .env("PATH", path)
+        .env("FAKE_RUSTUP_LOG", &log)
+        .env("MIRI_SCRIPT_LOG", "off")
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .env_remove("MIRI_SCRIPT_RUSTC")
+        .output()
+        .unwrap();
+
+    let calls = fs::read_to_string(&log).unwrap();
+    let calls: Vec<&str> = calls.lines().collect();
+    assert_eq!(calls, ["show", "component list --installed --toolchain miri"]);
+}
@@ -1,13 +1,15 @@
 :: This makes execution of ./miri on Linux and Windows the same.
 :: Windows will not execute the bash script, and select this.
+:: This is written by `./miri self-install-wrapper`; change it there.
 @echo off
-set MIRI_SCRIPT_TARGET_DIR=%0\..\miri-script\target
+:: `%~dp0` is the dir of this file, with a trailing backslash; quoted, it may contain spaces.
+set "MIRI_SCRIPT_TARGET_DIR=%~dp0miri-script\target"
 
 :: If any other steps are added, the "|| exit /b" must be appended to early
 :: return from the script. If not, it will continue execution.
-cargo +stable build %CARGO_EXTRA_FLAGS% -q --target-dir %MIRI_SCRIPT_TARGET_DIR% --manifest-path %0\..\miri-script\Cargo.toml || exit /b
+cargo +stable build %CARGO_EXTRA_FLAGS% -q --target-dir "%MIRI_SCRIPT_TARGET_DIR%" --manifest-path "%~dp0miri-script\Cargo.toml" || exit /b
 
 :: Forwards all arguments to this file to the executable.
 :: We invoke the binary directly to avoid going through rustup, which would set some extra
 :: env vars that we do not want.
-%MIRI_SCRIPT_TARGET_DIR%\debug\miri-script %*
+"%MIRI_SCRIPT_TARGET_DIR%\debug\miri-script" %*
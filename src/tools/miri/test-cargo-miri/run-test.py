@@ -50,7 +50,12 @@ def check_output(actual, path, name):
     print(f"--- END diff {name} ---")
     return False
 
+def selected(name):
+    return not ARGS.filters or any(f in name for f in ARGS.filters)
+
 def test(name, cmd, stdout_ref, stderr_ref, stdin=b'', env=None):
+    if not selected(name):
+        return
     if env is None:
         env = {}
     print("Testing {}...".format(name))
@@ -77,6 +82,8 @@ def test(name, cmd, stdout_ref, stderr_ref, stdin=b'', env=None):
     fail("exit code was {}".format(p.returncode))
 
 def test_no_rebuild(name, cmd, env=None):
+    if not selected(name):
+        return
     if env is None:
         env = {}
     print("Testing {}...".format(name))
@@ -186,6 +193,7 @@ def test_cargo_miri_test():
 args_parser = argparse.ArgumentParser(description='`cargo miri` testing')
 args_parser.add_argument('--target', help='the target to test')
 args_parser.add_argument('--bless', help='bless the reference files', action='store_true')
+args_parser.add_argument('filters', nargs='*', help='only run the tests whose name contains one of these')
 ARGS = args_parser.parse_args()
 
 os.chdir(os.path.dirname(os.path.realpath(__file__)))
@@ -201,6 +209,8 @@ test_cargo_miri_test()
 
 # Ensure we did not create anything outside the expected target dir.
 for target_dir in ["target", "custom-run", "custom-test", "config-cli"]:
+    if ARGS.filters and not os.path.exists(target_dir):
+        continue # the tests using it did not run
     if os.listdir(target_dir) != ["miri"]:
         fail(f"`{target_dir}` contains unexpected files")
     # Ensure something exists inside that target dir.
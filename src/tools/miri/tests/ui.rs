@@ -27,12 +27,25 @@ pub fn flagsplit(flags: &str) -> Vec<String> {
     flags.split(' ').map(str::trim).filter(|s| !s.is_empty()).map(str::to_string).collect()
 }
 
+/// The directory the `tests` directory is in. `./miri bless` runs each suite in its own copy of
+/// `tests`, so that they can be blessed in parallel.
+fn test_root() -> PathBuf {
+    env::var_os("MIRI_UI_ROOT").map(PathBuf::from).unwrap_or_default()
+}
+
+/// Target directory that we can write to.
+fn out_dir() -> PathBuf {
+    match env::var_os("MIRI_UI_ROOT") {
+        // Keep the output of parallel `./miri bless` runs apart.
+        Some(root) => PathBuf::from(root).join("out"),
+        None => PathBuf::from(env::var_os("CARGO_TARGET_DIR").unwrap()),
+    }
+}
+
 // Build the shared object file for testing native function calls.
 fn build_native_lib() -> PathBuf {
     let cc = option_env!("CC").unwrap_or("cc");
-    // Target directory that we can write to.
-    let so_target_dir =
-        Path::new(&env::var_os("CARGO_TARGET_DIR").unwrap()).join("miri-native-lib");
+    let so_target_dir = out_dir().join("miri-native-lib");
     // Create the directory if it does not already exist.
     std::fs::create_dir_all(&so_target_dir)
         .expect("Failed to create directory for shared object file");
@@ -73,12 +86,12 @@ fn miri_config(target: &str, path: &str, mode: Mode, with_dependencies: bool) ->
         stdout_filters: stdout_filters().into(),
         mode,
         program,
-        out_dir: PathBuf::from(std::env::var_os("CARGO_TARGET_DIR").unwrap()).join("ui"),
+        out_dir: out_dir().join("ui"),
         edition: Some("2021".into()), // keep in sync with `./miri run`
         threads: std::env::var("MIRI_TEST_THREADS")
             .ok()
             .map(|threads| NonZero::new(threads.parse().unwrap()).unwrap()),
-        ..Config::rustc(path)
+        ..Config::rustc(test_root().join(path))
     };
 
     if with_dependencies {
@@ -244,6 +257,10 @@ fn ui(
     with_dependencies: Dependencies,
     tmpdir: &Path,
 ) -> Result<()> {
+    // `./miri bless` runs only one suite per test harness.
+    if env::var("MIRI_UI_SUITE").is_ok_and(|suite| suite != path) {
+        return Ok(());
+    }
     let msg = format!("## Running ui tests in {path} for {target}");
     eprintln!("{}", msg.green().bold());
 